@@ -0,0 +1,48 @@
+//! Benchmarks for the work done on every request that isn't waiting on the network.
+//! Run with `cargo bench`; inputs are the captured responses in `tests/fixtures`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use city17::generate_id;
+use city17::gql::{
+    access_token_request, parse_access_token_response, Variables, PLAYBACK_ACCESS_TOKEN_HASH,
+};
+use city17::playlist::{is_vp9_dominant, CODECS};
+
+const MASTER_LIVE: &[u8] = include_bytes!("../tests/fixtures/master_live.m3u8");
+const TOKEN_LIVE: &[u8] = include_bytes!("../tests/fixtures/token_live.json");
+const TOKEN_VOD: &[u8] = include_bytes!("../tests/fixtures/token_vod.json");
+
+fn ids(c: &mut Criterion) {
+    c.bench_function("generate_id", |b| b.iter(generate_id));
+}
+
+fn gql(c: &mut Criterion) {
+    let live = Variables::Channel("examplechannel".to_owned());
+    c.bench_function("access_token_request", |b| {
+        b.iter(|| {
+            serde_json::to_vec(&access_token_request(black_box(&live), PLAYBACK_ACCESS_TOKEN_HASH))
+        })
+    });
+    c.bench_function("parse_access_token_response/live", |b| {
+        b.iter(|| parse_access_token_response(black_box(TOKEN_LIVE)).unwrap())
+    });
+    c.bench_function("parse_access_token_response/vod", |b| {
+        b.iter(|| parse_access_token_response(black_box(TOKEN_VOD)).unwrap())
+    });
+}
+
+fn usher(c: &mut Criterion) {
+    let token = parse_access_token_response(TOKEN_LIVE).unwrap().data.playback_access_token;
+    let session = generate_id().to_lowercase();
+    c.bench_function("gen_query", |b| {
+        b.iter(|| black_box(token.gen_query(black_box("1234567"), &session, CODECS, true)))
+    });
+}
+
+fn playlist(c: &mut Criterion) {
+    c.bench_function("is_vp9_dominant", |b| b.iter(|| is_vp9_dominant(black_box(MASTER_LIVE))));
+}
+
+criterion_group!(benches, ids, gql, usher, playlist);
+criterion_main!(benches);
@@ -0,0 +1,53 @@
+//! Fetch a playlist through the library alone, as another program embedding City17 would:
+//!
+//! ```text
+//! cargo run --example embed --no-default-features -- <channel>
+//! cargo run --example embed --no-default-features -- --vod <id>
+//! ```
+//!
+//! Prints each rendition and its URL, or the error's kind and message.
+
+use std::env;
+use std::process;
+
+use city17::{City17Client, Config, Options};
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let client = match City17Client::new(Config::default()) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("couldn't build the client: {}", e);
+            process::exit(1);
+        }
+    };
+    let options = Options::default();
+    let fetched = match args.as_slice() {
+        [flag, id] if flag == "--vod" => match id.trim_start_matches('v').parse() {
+            Ok(id) => client.get_vod_playlist(id, options).await,
+            Err(_) => {
+                eprintln!("{:?} isn't a VOD ID", id);
+                process::exit(2);
+            }
+        },
+        [channel] => client.get_live_playlist(channel, options).await,
+        _ => {
+            eprintln!("usage: embed <channel> | embed --vod <id>");
+            process::exit(2);
+        }
+    };
+    let fetched = match fetched {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            eprintln!("{:?}: {}", e.kind(), e);
+            process::exit(1);
+        }
+    };
+    if let Some(started) = fetched.info.started_at {
+        println!("live since {}", started);
+    }
+    for variant in fetched.variants() {
+        println!("{}\t{}", variant.name, variant.url);
+    }
+}
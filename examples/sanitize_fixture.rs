@@ -0,0 +1,27 @@
+//! Scrub a captured GQL or usher response so it can be checked in under `tests/fixtures`:
+//!
+//! ```text
+//! cargo run --example sanitize_fixture < captured.json > tests/fixtures/token_something.json
+//! ```
+//!
+//! Signatures, IPs, user and device IDs, request IDs, and the signed parts of URLs are replaced
+//! with the placeholders the existing fixtures use. Read the output before committing it anyway.
+//! `city17 dump-gql --sanitize` does the same to a fresh capture.
+
+use std::io::{self, Read, Write};
+
+use city17::fixture::{sanitize_json, sanitize_playlist};
+use serde_json::Value;
+
+fn main() -> io::Result<()> {
+    let mut input = String::new();
+    io::stdin().read_to_string(&mut input)?;
+    let output = match serde_json::from_str::<Value>(&input) {
+        Ok(mut json) => {
+            sanitize_json(&mut json);
+            format!("{}\n", json)
+        }
+        Err(_) => sanitize_playlist(&input),
+    };
+    io::stdout().write_all(output.as_bytes())
+}
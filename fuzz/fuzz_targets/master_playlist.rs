@@ -0,0 +1,18 @@
+//! Whatever usher might send in place of a master playlist, through everything that looks
+//! inside one. The corpus starts out as the test fixtures; run with
+//! `cargo +nightly fuzz run master_playlist`.
+#![no_main]
+
+use city17::playlist::{is_audio_only, is_vp9_dominant, limit_renditions};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|m3u8: &[u8]| {
+    is_vp9_dominant(m3u8);
+    is_audio_only(m3u8);
+    let text = String::from_utf8_lossy(m3u8);
+    for max in [0, 1, 3] {
+        assert!(limit_renditions(m3u8, max).len() <= text.len());
+    }
+    // with nothing to trim, nothing may be lost either
+    assert_eq!(limit_renditions(m3u8, usize::MAX), text);
+});
@@ -0,0 +1,195 @@
+//! Live playlists are cached for a moment, and identical fetches in flight are shared, so a
+//! crowd watching one channel costs one trip upstream.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures_util::future::{BoxFuture, FutureExt, Shared};
+use once_cell::sync::Lazy;
+
+use crate::config::{get_playlist_cache_bytes, Upstream, DEFAULT_PLAYLIST_CACHE_BYTES};
+use crate::error::{ErrorResponder, ResultExt};
+use crate::gql::{Variables, PLAYER_TYPE};
+use crate::playlist::stream_started_at;
+use crate::usher::{fetch_playlist, Attempts, FetchInfo};
+use crate::Error;
+
+/// How a playlist response was produced, sent to the client as `X-Cache`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// Served from the live playlist cache.
+    Hit,
+    /// Fetched from upstream by this request.
+    Miss,
+    /// Shared from another request's fetch that was already in flight.
+    Coalesced,
+    /// Never cached (VODs).
+    Bypass,
+}
+
+impl CacheStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Hit => "HIT",
+            Self::Miss => "MISS",
+            Self::Coalesced => "COALESCED",
+            Self::Bypass => "BYPASS",
+        }
+    }
+
+    /// The status an `X-Cache` value names, as sent by an instance relayed through.
+    pub fn from_header(value: &str) -> Option<Self> {
+        [Self::Hit, Self::Miss, Self::Coalesced, Self::Bypass]
+            .iter()
+            .copied()
+            .find(|s| s.as_str() == value)
+    }
+}
+
+/// A live playlist and what else we learned fetching it, as cached.
+pub type LivePlaylist = (Bytes, FetchInfo);
+
+/// A live playlist fetch that any number of requests can wait on.
+type SharedFetch =
+    Shared<BoxFuture<'static, Result<LivePlaylist, (Arc<Error>, &'static str, Attempts)>>>;
+
+/// A [`SharedFetch`] and the deadline it has to finish by.
+type InFlight = (SharedFetch, Option<Instant>);
+
+/// Live playlist fetches currently in progress, so a burst of requests for one channel that
+/// arrives before the first fetch finishes (and lands in the cache) still goes upstream once.
+/// Each runs as its own task, so it finishes and leaves the map even if everyone waiting on it
+/// has gone.
+static IN_FLIGHT: Lazy<Mutex<HashMap<Variables, InFlight>>> = Lazy::new(Mutex::default);
+
+/// Fetch a live playlist and cache it, or wait on an identical fetch that's already running.
+///
+/// Only for the default player type, so trying another doesn't mix with what viewers get.
+/// VODs aren't coalesced since they're streamed straight through to a single client. A failed
+/// fetch's tries are copied into `attempts`; a successful one has them in its info.
+///
+/// A fetch that's joined was started with another request's `upstream`, which only differs from
+/// this one's in its deadline. That's earlier than this request's, having started first, so
+/// waiting on it never takes longer than this request has; one already past it isn't joined.
+pub(crate) async fn fetch_live(
+    var: Variables,
+    upstream: &Upstream,
+    attempts: &mut Attempts,
+) -> Result<(LivePlaylist, CacheStatus), ErrorResponder> {
+    let (fetch, status) = {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        match in_flight.get(&var) {
+            Some((fetch, deadline)) if !past(*deadline) => (fetch.clone(), CacheStatus::Coalesced),
+            _ => {
+                let fetch = shared_fetch(var.clone(), upstream.clone());
+                in_flight.insert(var, (fetch.clone(), upstream.deadline));
+                (fetch, CacheStatus::Miss)
+            }
+        }
+    };
+    match fetch.await {
+        Ok(live) => Ok((live, status)),
+        Err((e, stage, tries)) => {
+            *attempts = tries;
+            Err(ErrorResponder(Error::Shared(e), stage))
+        }
+    }
+}
+
+/// Whether `deadline` has gone by.
+fn past(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| deadline <= Instant::now())
+}
+
+/// Take `var`'s fetch out of [`IN_FLIGHT`], unless it's been replaced by one with another
+/// deadline since.
+fn forget(var: &Variables, deadline: Option<Instant>) {
+    let mut in_flight = IN_FLIGHT.lock().unwrap();
+    if in_flight.get(var).is_some_and(|(_, started_for)| *started_for == deadline) {
+        in_flight.remove(var);
+    }
+}
+
+fn shared_fetch(var: Variables, upstream: Upstream) -> SharedFetch {
+    let (key, deadline) = (var.clone(), upstream.deadline);
+    let task = tokio::spawn(async move {
+        let mut attempts = Attempts::new();
+        let result = async {
+            let (playlist, mut info) =
+                fetch_playlist(&var, PLAYER_TYPE, &upstream, &mut attempts).await?;
+            let body = playlist.collect().await.map_err(Error::from).into_responder("M3U")?;
+            info.started_at = stream_started_at(&body);
+            Ok((body, info))
+        }
+        .await;
+        forget(&var, upstream.deadline);
+        match result {
+            Ok(live) => {
+                PLAYLIST_CACHE.insert(var, live.clone());
+                Ok(live)
+            }
+            Err(ErrorResponder(e, stage)) => Err((Arc::new(e), stage, attempts)),
+        }
+    });
+    async move {
+        match task.await {
+            Ok(result) => result,
+            // it panicked before it could take itself out of the map
+            Err(_) => {
+                forget(&key, deadline);
+                Err((Arc::new(Error::Panicked), "internal", Attempts::new()))
+            }
+        }
+    }
+    .boxed()
+    .shared()
+}
+
+/// How long a live playlist is reused for. Short enough that nobody falls behind the stream,
+/// long enough that a burst of viewers polling the same channel costs one upstream fetch.
+pub const PLAYLIST_TTL: Duration = Duration::from_secs(1);
+
+/// Capped by `CITY17_PLAYLIST_CACHE_BYTES`, which stops the server at launch if it isn't a number.
+pub(crate) static PLAYLIST_CACHE: Lazy<PlaylistCache> = Lazy::new(|| {
+    let max = get_playlist_cache_bytes().unwrap_or(DEFAULT_PLAYLIST_CACHE_BYTES);
+    PlaylistCache::new(max)
+});
+
+/// Recently fetched live playlists, evicted by age and capped by total size.
+#[derive(Debug)]
+pub struct PlaylistCache {
+    entries: Mutex<HashMap<Variables, (Instant, LivePlaylist)>>,
+    max_bytes: usize,
+}
+
+impl PlaylistCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self { entries: Mutex::default(), max_bytes }
+    }
+
+    pub fn get(&self, key: &Variables) -> Option<LivePlaylist> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(key).filter(|(at, _)| at.elapsed() < PLAYLIST_TTL).map(|(_, live)| live.clone())
+    }
+
+    pub fn insert(&self, key: Variables, live: LivePlaylist) {
+        let len = live.0.len();
+        if len > self.max_bytes {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (at, _)| at.elapsed() < PLAYLIST_TTL);
+        let mut size: usize = entries.values().map(|(_, (body, _))| body.len()).sum();
+        while size + len > self.max_bytes {
+            // everything left is under a second old, so just drop the oldest
+            let oldest = entries.iter().min_by_key(|(_, (at, _))| *at).map(|(k, _)| k.clone());
+            match oldest.and_then(|k| entries.remove(&k)) {
+                Some((_, (evicted, _))) => size -= evicted.len(),
+                None => break,
+            }
+        }
+        entries.insert(key, (Instant::now(), live));
+    }
+}
@@ -0,0 +1,693 @@
+//! Commands for a terminal: `city17 fetch` gets a playlist without deploying anything,
+//! `city17 selftest` checks a host can reach Twitch before deploying there, and `city17 bench`
+//! measures how long Twitch takes to answer from there. All of them go through the same client,
+//! upstream requests, and environment settings as the server, minus the cache. `city17 probe-ips`
+//! looks for addresses to replace the client's built-in ones with, and `city17 dump-gql` captures
+//! responses for test fixtures.
+
+use std::future::Future;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::time::sleep;
+
+use crate::client::{client, probe_client, RESOLVE_OVERRIDES};
+use crate::config::{parse_port, Upstream};
+use crate::error::{ErrorResponder, ResultExt};
+use crate::fixture::{redact_playlist_queries, sanitize_json, sanitize_playlist};
+use crate::gql::{
+    self, get_access_token, parse_access_token_response, validate_channel, Variables, PLAYER_TYPE,
+};
+use crate::latency::percentile;
+use crate::playlist::{rendition_url, stream_started_at, CODECS};
+use crate::usher::{self, fetch_playlist, get_m3u8, session_id, Attempts};
+use crate::Error;
+
+pub const USAGE: &str = "usage: city17 fetch live <channel> [--quality <name>] [--json]
+       city17 fetch vod <id> [--quality <name>] [--json]
+       city17 selftest [<channel>]
+       city17 bench [--channel <channel>] [--count <n>] [--concurrency <n>] [--delay-ms <ms>]
+                    [--token-only]
+       city17 probe-ips [--attempts <n>]
+       city17 dump-gql live <channel> [--sanitize] [--usher]
+       city17 dump-gql vod <id> [--sanitize] [--usher]
+       city17 --port <port>
+
+Without arguments, or with just --port, runs the server.";
+
+/// Channel the self-test and benchmark ask for when none is given. Any channel works for the
+/// token; the playlist needs one that's live.
+pub const DEFAULT_CHANNEL: &str = "monstercat";
+
+/// Most requests `city17 bench` will have in flight at once, so it stays a measurement.
+pub const MAX_BENCH_CONCURRENCY: usize = 8;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Fetch(Fetch),
+    SelfTest {
+        channel: String,
+    },
+    Bench(Bench),
+    /// Handshake with each of these many times.
+    ProbeIps {
+        attempts: usize,
+    },
+    DumpGql(Dump),
+    /// Run the server, on this port rather than the one from the environment.
+    Serve {
+        port: u16,
+    },
+}
+
+/// What `city17 fetch` was asked for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fetch {
+    pub var: Variables,
+    /// Print just this rendition's URL, by the name players show, e.g. `720p60` or `source`.
+    pub quality: Option<String>,
+    /// Print `{"playlist": ...}` with the token's expiry instead of the bare playlist.
+    pub json: bool,
+}
+
+/// What `city17 dump-gql` was asked for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dump {
+    pub var: Variables,
+    /// Scrub the output so it's safe to commit as a fixture.
+    pub sanitize: bool,
+    /// Also fetch the playlist with the token, and dump that after the JSON.
+    pub usher: bool,
+}
+
+/// What `city17 bench` was asked for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bench {
+    pub channel: String,
+    /// How many times to run the stages, in total.
+    pub count: usize,
+    /// How many runs are in flight at once, up to [`MAX_BENCH_CONCURRENCY`].
+    pub concurrency: usize,
+    /// How long each of the concurrent runners waits between runs. A second unless asked
+    /// otherwise, so a careless invocation doesn't hammer Twitch from the deployment's IP.
+    pub delay: Duration,
+    /// Only ask GQL for tokens, leaving usher alone.
+    pub token_only: bool,
+}
+
+impl Default for Bench {
+    fn default() -> Self {
+        Self {
+            channel: DEFAULT_CHANNEL.to_owned(),
+            count: 20,
+            concurrency: 1,
+            delay: Duration::from_secs(1),
+            token_only: false,
+        }
+    }
+}
+
+/// Parse the arguments after the program name. `Ok(None)` means there weren't any, so the
+/// server should run as usual.
+pub fn parse(args: &[String]) -> Result<Option<Command>, String> {
+    let mut args = args.iter().map(String::as_str);
+    let command = match args.next() {
+        None => return Ok(None),
+        Some("fetch") => Command::Fetch(parse_fetch(args)?),
+        Some("selftest") => {
+            let channel = match (args.next(), args.next()) {
+                (None, _) => DEFAULT_CHANNEL.to_owned(),
+                (Some(channel), None) => validate_channel(channel).map_err(|e| e.to_string())?,
+                (Some(_), Some(extra)) => return Err(format!("unexpected argument {:?}", extra)),
+            };
+            Command::SelfTest { channel }
+        }
+        Some("bench") => Command::Bench(parse_bench(args)?),
+        Some("probe-ips") => Command::ProbeIps { attempts: parse_probe_ips(args)? },
+        Some("dump-gql") => Command::DumpGql(parse_dump(args)?),
+        Some("--port") => Command::Serve { port: parse_serve(args)? },
+        Some(other) => return Err(format!("unknown command {:?}", other)),
+    };
+    Ok(Some(command))
+}
+
+fn parse_fetch<'a>(mut args: impl Iterator<Item = &'a str>) -> Result<Fetch, String> {
+    let (mut positional, mut quality, mut json) = (Vec::new(), None, false);
+    while let Some(arg) = args.next() {
+        match arg {
+            "--json" => json = true,
+            "--quality" => quality = Some(value(&mut args, arg)?.to_owned()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+    Ok(Fetch { var: target(&positional)?, quality, json })
+}
+
+fn parse_dump<'a>(args: impl Iterator<Item = &'a str>) -> Result<Dump, String> {
+    let (mut positional, mut sanitize, mut usher) = (Vec::new(), false, false);
+    for arg in args {
+        match arg {
+            "--sanitize" => sanitize = true,
+            "--usher" => usher = true,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+    Ok(Dump { var: target(&positional)?, sanitize, usher })
+}
+
+/// The `live <channel>` or `vod <id>` a command is about.
+fn target(positional: &[&str]) -> Result<Variables, String> {
+    match positional {
+        ["live", channel] => {
+            Ok(Variables::Channel(validate_channel(channel).map_err(|e| e.to_string())?))
+        }
+        ["vod", id] => match id.trim_start_matches('v').parse::<u64>() {
+            Ok(id) => Ok(Variables::VOD(id.to_string())),
+            Err(_) => Err(format!("{:?} isn't a VOD ID", id)),
+        },
+        _ => Err("expected `live <channel>` or `vod <id>`".to_owned()),
+    }
+}
+
+fn parse_bench<'a>(mut args: impl Iterator<Item = &'a str>) -> Result<Bench, String> {
+    let mut bench = Bench::default();
+    while let Some(arg) = args.next() {
+        match arg {
+            "--channel" => {
+                bench.channel =
+                    validate_channel(value(&mut args, arg)?).map_err(|e| e.to_string())?
+            }
+            "--count" => bench.count = number(&mut args, arg)?,
+            "--concurrency" => bench.concurrency = number(&mut args, arg)?,
+            "--delay-ms" => bench.delay = Duration::from_millis(number(&mut args, arg)?),
+            "--token-only" => bench.token_only = true,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => return Err(format!("unexpected argument {:?}", arg)),
+        }
+    }
+    if bench.count == 0 {
+        return Err("--count must be at least 1".to_owned());
+    }
+    if !(1..=MAX_BENCH_CONCURRENCY).contains(&bench.concurrency) {
+        return Err(format!("--concurrency must be 1 to {}", MAX_BENCH_CONCURRENCY));
+    }
+    Ok(bench)
+}
+
+/// How many attempts `city17 probe-ips` was asked to make.
+fn parse_probe_ips<'a>(mut args: impl Iterator<Item = &'a str>) -> Result<usize, String> {
+    let mut attempts = 3;
+    while let Some(arg) = args.next() {
+        match arg {
+            "--attempts" => attempts = number(&mut args, arg)?,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => return Err(format!("unexpected argument {:?}", arg)),
+        }
+    }
+    if !(1..=10).contains(&attempts) {
+        return Err("--attempts must be 1 to 10".to_owned());
+    }
+    Ok(attempts)
+}
+
+/// The port after `--port`, with nothing after it.
+fn parse_serve<'a>(mut args: impl Iterator<Item = &'a str>) -> Result<u16, String> {
+    let port = parse_port(Some(value(&mut args, "--port")?), 0)?;
+    if port == 0 {
+        return Err("--port must not be 0".to_owned());
+    }
+    match args.next() {
+        None => Ok(port),
+        Some(extra) => Err(format!("unexpected argument {:?}", extra)),
+    }
+}
+
+/// The value following `option`.
+fn value<'a>(args: &mut impl Iterator<Item = &'a str>, option: &str) -> Result<&'a str, String> {
+    args.next().ok_or_else(|| format!("{} needs a value", option))
+}
+
+fn number<'a, T: FromStr>(
+    args: &mut impl Iterator<Item = &'a str>,
+    option: &str,
+) -> Result<T, String> {
+    let raw = value(args, option)?;
+    raw.parse().map_err(|_| format!("{} must be a number, not {:?}", option, raw))
+}
+
+/// Run a command, returning the exit code.
+pub async fn run(command: Command, upstream: &Upstream) -> i32 {
+    match command {
+        Command::Fetch(fetch) => run_fetch(fetch, upstream).await,
+        Command::SelfTest { channel } => self_test(&channel, upstream).await,
+        Command::Bench(bench) => run_bench(&bench, upstream).await,
+        Command::ProbeIps { attempts } => probe_ips(attempts).await,
+        Command::DumpGql(dump) => match print_dump(&dump, upstream).await {
+            Ok(()) => 0,
+            Err(ErrorResponder(e, stage)) => {
+                eprintln!("city17: {} failed: {}", stage, describe(&e));
+                1
+            }
+        },
+        // the binary launches the server itself, since it might not be built in
+        Command::Serve { .. } => {
+            eprintln!("city17: the server is only run from the binary");
+            2
+        }
+    }
+}
+
+/// Fetch and print the playlist, or print why it couldn't be to stderr.
+async fn run_fetch(fetch: Fetch, upstream: &Upstream) -> i32 {
+    match print(&fetch, upstream).await {
+        Ok(()) => 0,
+        Err(ErrorResponder(e, stage)) => {
+            if fetch.json {
+                eprintln!("{}", e.to_json(stage));
+            } else {
+                eprintln!("city17: {} failed: {} ({:?})", stage, e, e);
+            }
+            1
+        }
+    }
+}
+
+async fn print(fetch: &Fetch, upstream: &Upstream) -> Result<(), ErrorResponder> {
+    let (playlist, info) =
+        fetch_playlist(&fetch.var, PLAYER_TYPE, upstream, &mut Attempts::new()).await?;
+    let body = playlist.collect().await.map_err(Error::from).into_responder("M3U")?;
+    let body = String::from_utf8_lossy(&body);
+    let url = match &fetch.quality {
+        Some(name) => Some(
+            rendition_url(&body, name)
+                .ok_or(Error::Input("no rendition has that name".into()))
+                .into_responder("quality")?,
+        ),
+        None => None,
+    };
+    let output = if fetch.json {
+        let mut json = json!({ "playlist": body, "expires": info.expires });
+        if let (Variables::Channel(_), Some(started)) =
+            (&fetch.var, stream_started_at(body.as_bytes()))
+        {
+            json["started_at"] = started.into();
+        }
+        if let Some(url) = url {
+            json["url"] = url.into();
+        }
+        format!("{}\n", json)
+    } else {
+        match url {
+            Some(url) => format!("{}\n", url),
+            None => body.into_owned(),
+        }
+    };
+    // a closed pipe, like from `| head`, isn't worth reporting
+    let _ = io::stdout().write_all(output.as_bytes());
+    Ok(())
+}
+
+/// One line of the self-test's table.
+struct Step {
+    name: &'static str,
+    /// Whether failing this fails the whole test. The probes only tell you where a problem is.
+    required: bool,
+    took: Duration,
+    result: Result<String, String>,
+}
+
+async fn step(
+    name: &'static str,
+    required: bool,
+    check: impl Future<Output = Result<String, String>>,
+) -> Step {
+    let started = Instant::now();
+    let result = check.await;
+    Step { name, required, took: started.elapsed(), result }
+}
+
+/// A line about what went wrong. HTTP errors get their cause rather than their URL, which for
+/// usher is mostly the token.
+fn describe(e: &Error) -> String {
+    match e {
+        Error::Http(e) => match e.status() {
+            Some(status) => format!("answered {}", status),
+            None if e.is_timeout() => "timed out".to_owned(),
+            None => {
+                let mut cause: &dyn std::error::Error = e;
+                while let Some(source) = cause.source() {
+                    cause = source;
+                }
+                cause.to_string()
+            }
+        },
+        Error::Shared(e) => describe(e),
+        e => e.to_string(),
+    }
+}
+
+/// Check everything a deployment needs, printing how each step went, and fail if a required
+/// step did.
+async fn self_test(channel: &str, upstream: &Upstream) -> i32 {
+    let var = Variables::Channel(channel.to_owned());
+    let steps = [
+        // the binary only gets this far if the settings were good, so this shows what's tested
+        step("settings", true, async {
+            let front = upstream.usher_front.as_deref().unwrap_or("none");
+            Ok(format!(
+                "usher {} via {}, {}s timeout",
+                upstream.usher_base,
+                front,
+                upstream.timeout.as_secs()
+            ))
+        })
+        .await,
+        step("client", true, async { client().map(|_| String::new()).map_err(|e| describe(&e)) })
+            .await,
+        step("gql-tls", false, async {
+            let probe = gql::probe_front(upstream).await;
+            probe.map(|_| upstream.gql_url.clone()).map_err(|e| describe(&e))
+        })
+        .await,
+        step("usher-tls", false, async {
+            let probe = usher::probe_front(upstream).await;
+            probe.map(|_| upstream.usher_base.clone()).map_err(|e| describe(&e))
+        })
+        .await,
+        step("token", true, async {
+            let token =
+                get_access_token(&var, PLAYER_TYPE, upstream).await.map_err(|e| describe(&e))?;
+            match token.data.playback_access_token.expires() {
+                Some(expires) => Ok(format!("for {}, expires at {}", channel, expires)),
+                None => Ok(format!("for {}", channel)),
+            }
+        })
+        .await,
+        step("playlist", true, async {
+            match fetch_playlist(&var, PLAYER_TYPE, upstream, &mut Attempts::new()).await {
+                Ok((playlist, _)) => {
+                    let body = playlist.collect().await.map_err(|e| describe(&e.into()))?;
+                    Ok(format!("{} bytes", body.len()))
+                }
+                // usher answering at all is what's being tested
+                Err(ErrorResponder(e, "M3U")) if e.status_code() == 404 => {
+                    Ok(format!("usher answered, but {} isn't live", channel))
+                }
+                Err(ErrorResponder(e, stage)) => Err(format!("{}: {}", stage, describe(&e))),
+            }
+        })
+        .await,
+    ];
+    let mut failed = false;
+    for step in &steps {
+        let (status, detail) = match &step.result {
+            Ok(detail) => ("pass", detail),
+            Err(detail) if step.required => ("FAIL", detail),
+            Err(detail) => ("warn", detail),
+        };
+        failed |= step.required && step.result.is_err();
+        let took = step.took.as_secs_f64() * 1000.0;
+        println!("{:<10} {}  {:>8.1}ms  {}", step.name, status, took, detail);
+    }
+    i32::from(failed)
+}
+
+/// How long each stage of one run took, or what class of error stopped it, like `GQL 504`.
+type BenchRun = Result<Vec<(&'static str, Duration)>, String>;
+
+async fn bench_once(var: &Variables, token_only: bool, upstream: &Upstream) -> BenchRun {
+    let class = |stage, e: &Error| format!("{} {}", stage, e.status_code());
+    let started = Instant::now();
+    if token_only {
+        get_access_token(var, PLAYER_TYPE, upstream).await.map_err(|e| class("GQL", &e))?;
+        return Ok(vec![("gql", started.elapsed())]);
+    }
+    let (playlist, mut info) = fetch_playlist(var, PLAYER_TYPE, upstream, &mut Attempts::new())
+        .await
+        .map_err(|ErrorResponder(e, stage)| class(stage, &e))?;
+    playlist.collect().await.map_err(|e| class("M3U", &e.into()))?;
+    info.timings.push(("total", started.elapsed()));
+    Ok(info.timings)
+}
+
+/// Run the upstream stages over and over, then print each one's latency distribution and how
+/// many runs failed, by class. Fails only if every run did.
+async fn run_bench(bench: &Bench, upstream: &Upstream) -> i32 {
+    let var = Variables::Channel(bench.channel.clone());
+    let started = AtomicUsize::new(0);
+    let runner = || async {
+        let mut runs = Vec::new();
+        while started.fetch_add(1, Ordering::Relaxed) < bench.count {
+            if !runs.is_empty() {
+                sleep(bench.delay).await;
+            }
+            runs.push(bench_once(&var, bench.token_only, upstream).await);
+        }
+        runs
+    };
+    let runs: Vec<BenchRun> =
+        join_all((0..bench.concurrency).map(|_| runner())).await.into_iter().flatten().collect();
+
+    // stages in the order they first appear
+    let mut stages: Vec<(&str, Vec<Duration>)> = Vec::new();
+    let mut errors: Vec<(String, usize)> = Vec::new();
+    for run in runs {
+        match run {
+            Ok(timings) => {
+                for (stage, took) in timings {
+                    match stages.iter_mut().find(|(name, _)| *name == stage) {
+                        Some((_, samples)) => samples.push(took),
+                        None => stages.push((stage, vec![took])),
+                    }
+                }
+            }
+            Err(class) => match errors.iter_mut().find(|(name, _)| *name == class) {
+                Some((_, count)) => *count += 1,
+                None => errors.push((class, 1)),
+            },
+        }
+    }
+    println!(
+        "{:<10} {:>5} {:>9} {:>9} {:>9} {:>9} {:>9}",
+        "stage (ms)", "n", "min", "p50", "p90", "p99", "max"
+    );
+    for (stage, samples) in &mut stages {
+        samples.sort();
+        let ms = |p| format!("{:.1}", percentile(samples, p).as_secs_f64() * 1000.0);
+        let (min, max) = (ms(0.0), ms(100.0));
+        let (p50, p90, p99) = (ms(50.0), ms(90.0), ms(99.0));
+        println!(
+            "{:<10} {:>5} {:>9} {:>9} {:>9} {:>9} {:>9}",
+            stage,
+            samples.len(),
+            min,
+            p50,
+            p90,
+            p99,
+            max
+        );
+    }
+    let failed: usize = errors.iter().map(|(_, count)| count).sum();
+    println!("{} of {} runs failed", failed, bench.count);
+    for (class, count) in &errors {
+        println!("  {:<10} {:>5}", class, count);
+    }
+    i32::from(failed == bench.count)
+}
+
+/// DNS-over-HTTPS resolvers with a JSON API, asked alongside the system's. AliDNS answers from
+/// inside China; Cloudflare gives a view from outside it, when it can be reached.
+const DOH_SERVERS: &[(&str, &str)] = &[
+    ("alidns", "https://dns.alidns.com/resolve"),
+    ("cloudflare", "https://cloudflare-dns.com/dns-query"),
+];
+
+/// An address to try for one of the client's hosts, and everywhere it was found.
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub host: &'static str,
+    pub ip: IpAddr,
+    pub sources: Vec<&'static str>,
+    /// How long each successful handshake and response took.
+    pub latencies: Vec<Duration>,
+    pub last_error: Option<String>,
+}
+
+impl Candidate {
+    fn median(&self) -> Duration {
+        let mut sorted = self.latencies.clone();
+        sorted.sort();
+        percentile(&sorted, 50.0)
+    }
+}
+
+/// Put the candidates that answered every time first, then the fastest.
+pub fn rank(candidates: &mut [Candidate]) {
+    candidates.sort_by_key(|c| (c.host, std::cmp::Reverse(c.latencies.len()), c.median()));
+}
+
+fn add_candidate(
+    candidates: &mut Vec<Candidate>,
+    host: &'static str,
+    ip: IpAddr,
+    source: &'static str,
+) {
+    match candidates.iter_mut().find(|c| c.host == host && c.ip == ip) {
+        Some(candidate) if candidate.sources.contains(&source) => {}
+        Some(candidate) => candidate.sources.push(source),
+        None => candidates.push(Candidate {
+            host,
+            ip,
+            sources: vec![source],
+            latencies: Vec::new(),
+            last_error: None,
+        }),
+    }
+}
+
+/// Look `host` up with a DNS-over-HTTPS JSON API.
+async fn doh_lookup(server: &str, host: &str) -> Result<Vec<IpAddr>, Error> {
+    #[derive(Deserialize)]
+    struct Response {
+        #[serde(rename = "Answer", default)]
+        answer: Vec<Answer>,
+    }
+    #[derive(Deserialize)]
+    struct Answer {
+        #[serde(rename = "type")]
+        kind: u16,
+        data: String,
+    }
+    let body = client()?
+        .get(server)
+        .query(&[("name", host), ("type", "A")])
+        .header("Accept", "application/dns-json")
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    let response: Response = serde_json::from_slice(&body)?;
+    // 1 is an A record; the rest are CNAMEs on the way there
+    Ok(response.answer.iter().filter(|a| a.kind == 1).filter_map(|a| a.data.parse().ok()).collect())
+}
+
+/// Every address we can find for the client's hosts.
+async fn gather_candidates() -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+    for (host, _, ips) in RESOLVE_OVERRIDES {
+        ips.iter()
+            .for_each(|ip| add_candidate(&mut candidates, host, IpAddr::from(*ip), "built-in"));
+    }
+    for (host, lookup, _) in RESOLVE_OVERRIDES {
+        match tokio::net::lookup_host((*lookup, 443)).await {
+            Ok(addrs) => addrs.for_each(|a| add_candidate(&mut candidates, host, a.ip(), "dns")),
+            Err(e) => eprintln!("city17: system DNS couldn't resolve {}: {}", lookup, e),
+        }
+        for (name, server) in DOH_SERVERS {
+            match doh_lookup(server, lookup).await {
+                Ok(ips) => {
+                    ips.into_iter().for_each(|ip| add_candidate(&mut candidates, host, ip, name))
+                }
+                Err(e) => {
+                    eprintln!("city17: {} couldn't resolve {}: {}", name, lookup, describe(&e))
+                }
+            }
+        }
+    }
+    candidates
+}
+
+/// Connect to `candidate` `attempts` times, each over a new connection with the SNI its host
+/// gets in production. Any answer counts, since it's the connection and TLS being tested.
+async fn probe_candidate(mut candidate: Candidate, attempts: usize) -> Candidate {
+    let client = match probe_client(candidate.host, SocketAddr::new(candidate.ip, 443)) {
+        Ok(client) => client,
+        Err(e) => {
+            candidate.last_error = Some(describe(&e.into()));
+            return candidate;
+        }
+    };
+    for _ in 0..attempts {
+        let started = Instant::now();
+        match client.head(format!("https://{}/", candidate.host)).send().await {
+            Ok(_) => candidate.latencies.push(started.elapsed()),
+            Err(e) => candidate.last_error = Some(describe(&e.into())),
+        }
+    }
+    candidate
+}
+
+/// Find, probe, and rank addresses for the client's hosts, then print the best as a
+/// `CITY17_RESOLVE` setting. Fails if some host has no address that answered every time.
+async fn probe_ips(attempts: usize) -> i32 {
+    let candidates = gather_candidates().await;
+    let probes = candidates.into_iter().map(|c| probe_candidate(c, attempts));
+    let mut candidates = join_all(probes).await;
+    rank(&mut candidates);
+    println!("{:<16} {:<40} {:>5} {:>9} {:>9}  sources", "host", "ip", "ok", "p50 ms", "min ms");
+    for c in &candidates {
+        let ms = |took: Duration| format!("{:.1}", took.as_secs_f64() * 1000.0);
+        let ok = format!("{}/{}", c.latencies.len(), attempts);
+        let (p50, min) = match c.latencies.iter().min() {
+            Some(min) => (ms(c.median()), ms(*min)),
+            None => ("-".to_owned(), "-".to_owned()),
+        };
+        let mut sources = c.sources.join(", ");
+        if let Some(e) = &c.last_error {
+            sources = format!("{} ({})", sources, e);
+        }
+        println!("{:<16} {:<40} {:>5} {:>9} {:>9}  {}", c.host, c.ip, ok, p50, min, sources);
+    }
+    let mut best = Vec::new();
+    for (host, _, _) in RESOLVE_OVERRIDES {
+        // ranked, so the first one for the host is the best
+        match candidates.iter().find(|c| c.host == *host && c.latencies.len() == attempts) {
+            Some(c) => best.push(format!("{}={}", host, c.ip)),
+            None => eprintln!("city17: no address for {} answered every time", host),
+        }
+    }
+    println!();
+    println!("CITY17_RESOLVE={}", best.join(","));
+    i32::from(best.len() < RESOLVE_OVERRIDES.len())
+}
+
+/// Print GQL's response pretty-printed, and with `--usher` the playlist after it.
+async fn print_dump(dump: &Dump, upstream: &Upstream) -> Result<(), ErrorResponder> {
+    let body = gql::access_token_body(&dump.var, upstream).await.into_responder("GQL")?;
+    let mut json: Value = serde_json::from_slice(&body)
+        .map_err(|_| Error::NotJson(body.len()))
+        .into_responder("GQL")?;
+    if dump.sanitize {
+        sanitize_json(&mut json);
+    }
+    let mut output = serde_json::to_string_pretty(&json).expect("JSON values serialize");
+    output.push('\n');
+    if dump.usher {
+        let token = parse_access_token_response(&body).into_responder("GQL")?;
+        let token = token.data.playback_access_token;
+        let url = dump.var.get_url(&upstream.usher_base);
+        let session = session_id(upstream);
+        let playlist = get_m3u8(&url, &token, &session, CODECS, true, upstream).await;
+        let playlist = playlist.into_responder("M3U")?.0.collect().await;
+        let playlist = playlist.map_err(Error::from).into_responder("M3U")?;
+        let playlist = String::from_utf8_lossy(&playlist);
+        output.push('\n');
+        // rendition URLs are signed even when the rest isn't being scrubbed
+        output.push_str(&if dump.sanitize {
+            sanitize_playlist(&playlist)
+        } else {
+            redact_playlist_queries(&playlist)
+        });
+    }
+    let _ = io::stdout().write_all(output.as_bytes());
+    Ok(())
+}
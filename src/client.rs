@@ -0,0 +1,489 @@
+//! The HTTP client everything upstream goes through, and the DNS tricks that keep it working
+//! from inside China.
+
+use std::collections::HashMap;
+#[cfg(feature = "resolve")]
+use std::env;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use futures_util::future;
+use hyper::client::connect::dns::Name;
+use once_cell::sync::{Lazy, OnceCell};
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::{Client, ClientBuilder, RequestBuilder, Response};
+#[cfg(feature = "server")]
+use rocket::fairing::AdHoc;
+
+#[cfg(feature = "resolve")]
+use crate::config::{first_refresh_delay, jittered_interval, DEFAULT_REFRESH_JITTER};
+use crate::config::{get_negative_ttl, get_resolve_dns, resolve_entries, DEFAULT_NEGATIVE_TTL};
+use crate::dns;
+use crate::Error;
+
+/// Connecting to a service blocked in China gets silently dropped, so we need a timeout.
+/// Around 10 seconds is the max time it takes to handle everything from Shanghai.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(7);
+
+/// How long a whole playlist fetch gets, GQL and usher and every retry. Past it, nothing more is
+/// tried, so a viewer hears back within the 10 seconds or so that all of it takes from Shanghai
+/// on a bad day.
+pub const REQUEST_BUDGET: Duration = Duration::from_secs(10);
+
+/// How long connecting to a front may take, shared between its addresses so that one being
+/// dropped leaves time to try the next.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(4);
+
+/// How many more times a token request is sent when the connection to the front dropped or
+/// timed out. From inside China that's often bad luck, and the next try gets through.
+pub const GQL_RETRIES: u32 = 2;
+
+/// How long to wait before the first of those retries. Each one after waits twice as long as
+/// the one before, plus up to as much again at random so instances don't retry in step.
+pub const GQL_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// The server builds these during ignition (see `client_fairing`) so that the first viewer
+/// doesn't pay for TLS setup, and so a broken client stops the launch instead of failing
+/// requests. As a library they're built by [`City17Client::new`](crate::City17Client::new).
+static CLIENT: OnceCell<Client> = OnceCell::new();
+static FRONT_CLIENT: OnceCell<Client> = OnceCell::new();
+
+/// The same clients without the resolver overrides, for when an address from them stops
+/// taking connections. Only built if that happens.
+static DNS_CLIENT: OnceCell<Client> = OnceCell::new();
+static DNS_FRONT_CLIENT: OnceCell<Client> = OnceCell::new();
+
+/// [`FRONT_CLIENT`] starting from each host's second built-in address, for trying again
+/// somewhere else after the first stalled. Only built if that happens.
+static ALT_FRONT_CLIENT: OnceCell<Client> = OnceCell::new();
+
+/// The client for anything that isn't fronted, which checks certificates properly.
+pub fn client() -> Result<&'static Client, Error> {
+    CLIENT.get_or_try_init(build_client).map_err(Error::from)
+}
+
+/// The client for requests sent to a front with another `Host`, i.e. GQL and usher.
+pub fn front_client() -> Result<&'static Client, Error> {
+    FRONT_CLIENT.get_or_try_init(build_front_client).map_err(Error::from)
+}
+
+/// [`front_client`] with each host's built-in addresses tried starting from the next one.
+pub fn alt_front_client() -> Result<&'static Client, Error> {
+    ALT_FRONT_CLIENT.get_or_try_init(build_alt_front_client).map_err(Error::from)
+}
+
+/// [`client`] with system DNS for every host.
+pub fn dns_client() -> Result<&'static Client, Error> {
+    DNS_CLIENT
+        .get_or_try_init(|| base_builder().dns_resolver(dns_resolver()).build())
+        .map_err(Error::from)
+}
+
+/// [`front_client`] with system DNS for every host.
+pub fn dns_front_client() -> Result<&'static Client, Error> {
+    let build = || front_builder().dns_resolver(dns_resolver()).build();
+    DNS_FRONT_CLIENT.get_or_try_init(build).map_err(Error::from)
+}
+
+fn dns_resolver() -> Arc<FailFastResolver> {
+    Arc::new(FailFastResolver)
+}
+
+pub fn build_client() -> reqwest::Result<Client> {
+    base_builder().insert_resolve_overrides().build()
+}
+
+pub fn build_front_client() -> reqwest::Result<Client> {
+    front_builder().insert_resolve_overrides().build()
+}
+
+pub fn build_alt_front_client() -> reqwest::Result<Client> {
+    front_builder().insert_rotated_overrides(1).build()
+}
+
+/// A client that only connects to `host` at `addr`, with the same TLS settings as the front
+/// client and no connection reuse, so every request pays for its own handshake.
+pub fn probe_client(host: &str, addr: SocketAddr) -> reqwest::Result<Client> {
+    front_builder().resolve(host, addr).pool_max_idle_per_host(0).build()
+}
+
+fn base_builder() -> ClientBuilder {
+    ClientBuilder::new().timeout(REQUEST_TIMEOUT)
+}
+
+fn front_builder() -> ClientBuilder {
+    // Fronts' certificates don't always name the host they're reached by, so only the client
+    // for them accepts that.
+    base_builder().danger_accept_invalid_hostnames(true).connect_timeout(CONNECT_TIMEOUT)
+}
+
+/// `response` as an error if its status is one, with server errors from `upstream` (GQL or
+/// usher) told apart so an outage isn't mistaken for a problem with the front. Their bodies are
+/// usually the front's HTML error page, which is of no use to anyone, so they aren't read.
+pub(crate) fn check_status(response: Response, upstream: &'static str) -> Result<Response, Error> {
+    let status = response.status();
+    if status.is_server_error() {
+        return Err(Error::UpstreamDown { upstream, status: status.as_u16() });
+    }
+    Ok(response.error_for_status()?)
+}
+
+/// Send `request`, and if it couldn't connect to an address from the resolver overrides, send
+/// it again through `fallback` (one of [`dns_client`] and [`dns_front_client`]), which looks
+/// the host up instead. The built-in addresses save a lookup that sometimes fails in China,
+/// but if the front stops answering on one, DNS may well know where it went.
+pub(crate) async fn send(
+    request: RequestBuilder,
+    fallback: fn() -> Result<&'static Client, Error>,
+) -> Result<Response, Error> {
+    let retry = request.try_clone();
+    let e = match request.send().await {
+        Ok(response) => return Ok(response),
+        Err(e) => Error::from(e),
+    };
+    let host = match &e {
+        Error::Http(http) => http.url().and_then(|url| url.host_str()).map(str::to_owned),
+        _ => None,
+    };
+    match (retry, host) {
+        (Some(retry), Some(host)) if e.is_unreachable() && is_overridden(&host) => {
+            log::warn!("couldn't connect to {}'s built-in address, trying DNS: {}", host, e);
+            Ok(fallback()?.execute(retry.build()?).await?)
+        }
+        _ => Err(e),
+    }
+}
+
+/// Whether requests to `host` go to an address from the resolver overrides.
+pub fn is_overridden(host: &str) -> bool {
+    override_ips(host).is_some()
+}
+
+/// The addresses requests to `host` go to in place of a lookup, in the order they're tried, if
+/// it has any.
+pub fn override_ips(host: &str) -> Option<Vec<IpAddr>> {
+    OVERRIDES.read().unwrap().get(host).cloned()
+}
+
+/// Builds [`CLIENT`] and [`FRONT_CLIENT`] before launch, aborting it if that fails.
+#[cfg(feature = "server")]
+pub fn client_fairing() -> AdHoc {
+    AdHoc::try_on_ignite("HTTP client", |rocket| async {
+        match client().and_then(|_| front_client()) {
+            Ok(_) => Ok(rocket),
+            Err(e) => {
+                log::error!("failed to build the HTTP client: {:?}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+trait ClientBuilderExt {
+    fn insert_resolve_overrides(self) -> Self;
+    fn insert_rotated_overrides(self, by: usize) -> Self;
+}
+
+impl ClientBuilderExt for ClientBuilder {
+    /// Resolver overrides with a few IPs hard-coded. Sometimes the Chinese DNS won't resolve
+    /// Twitch's domains. It's inconsistent enough that I could *probably* just retry it,
+    /// but these IPs have been stable for years so save time and hardcode them.
+    ///
+    /// Doing this appears to reduce latency variation even when the DNS is working.
+    ///
+    /// If these IPs start changing, `city17 probe-ips` finds new ones and `CITY17_RESOLVE`
+    /// puts them to use without a rebuild. With the `resolve` feature they're also looked up
+    /// now and then, see [`refresh_fairing`].
+    ///
+    /// The overrides are read on every connection rather than fixed into the client, so a
+    /// refresh applies to clients already built.
+    ///
+    /// Each host has a few addresses, tried in the order they're listed until one connects.
+    fn insert_resolve_overrides(self) -> Self {
+        self.insert_rotated_overrides(0)
+    }
+
+    /// The resolver overrides with each host's addresses rotated `by` places, so the first
+    /// tried is another one.
+    fn insert_rotated_overrides(self, by: usize) -> Self {
+        self.dns_resolver(Arc::new(OverridingResolver { rotate: by, ..Default::default() }))
+    }
+}
+
+/// The hosts the client connects to for Twitch, the name whose addresses it really wants for
+/// each, and the addresses it uses for them. The fronts' own addresses are no use: the requests
+/// have to reach Twitch's edge, which is what the second name leads to.
+pub const RESOLVE_OVERRIDES: &[(&str, &str, &[[u8; 4]])] = &[
+    ("fastly.net", "twitch.map.fastly.net", FASTLY_NET_IPS),
+    ("www.fastly.com", "usher.ttvnw.net", WWW_FASTLY_COM_IPS),
+];
+
+/// GQL's front.
+pub const FASTLY_NET_IPS: &[[u8; 4]] = &[[151, 101, 110, 167]];
+
+/// Usher's front. The second has been the one that answers from Shanghai before.
+pub const WWW_FASTLY_COM_IPS: &[[u8; 4]] = &[[192, 108, 239, 254], [23, 160, 0, 254]];
+
+/// The entries from `CITY17_FRONT_IP` and `CITY17_RESOLVE`, or the config file's in their
+/// place, as read by [`Settings::with_file`](crate::config::Settings::with_file). Without them
+/// the environment's are read, for embedders that never load settings.
+static RESOLVE_SETTING: OnceCell<Vec<(String, SocketAddr)>> = OnceCell::new();
+
+/// Use `entries` in place of lookups. Only the first call counts, and only if it's made before
+/// the client is built.
+pub fn configure_resolve(entries: Vec<(String, SocketAddr)>) {
+    let _ = RESOLVE_SETTING.set(entries);
+}
+
+/// Addresses from `CITY17_FRONT_IP` and `CITY17_RESOLVE`, used in place of all the built-in ones
+/// for the same host. See [`resolve_entries`] and [`parse_resolve`]. A value that doesn't parse
+/// stops the server at launch, see [`Settings::from_env`](crate::config::Settings::from_env), so
+/// here it's only logged.
+static RESOLVE: Lazy<Vec<(String, Vec<IpAddr>)>> = Lazy::new(|| {
+    let entries = match RESOLVE_SETTING.get() {
+        Some(entries) => entries.clone(),
+        None => resolve_entries(&[]).unwrap_or_else(|e| {
+            log::error!("not overriding any addresses, {}", e);
+            Vec::new()
+        }),
+    };
+    for (host, addr) in &entries {
+        if addr.port() != 443 {
+            log::warn!("the port in {}={} in CITY17_RESOLVE is ignored", host, addr);
+        }
+    }
+    group_by_host(entries)
+});
+
+/// Check a `CITY17_RESOLVE` value: a list of `host=ip` or `host=ip:port` entries, separated by
+/// commas or semicolons. A host can be listed more than once to give it several addresses, which
+/// are tried in the order they're listed.
+pub fn parse_resolve(raw: &str) -> Result<Vec<(String, SocketAddr)>, String> {
+    let entries = raw.split([',', ';']).map(str::trim).filter(|entry| !entry.is_empty());
+    entries
+        .map(|entry| match resolve_entry(entry) {
+            Some((host, addr)) => Ok((host.to_owned(), addr)),
+            None => Err(format!("has {:?}, which isn't host=ip[:port]", entry)),
+        })
+        .collect()
+}
+
+/// Each host's addresses from a list of entries, with the hosts and their addresses in the order
+/// they first came up in.
+pub fn group_by_host(
+    entries: impl IntoIterator<Item = (String, SocketAddr)>,
+) -> Vec<(String, Vec<IpAddr>)> {
+    let mut hosts: Vec<(String, Vec<IpAddr>)> = Vec::new();
+    for (host, addr) in entries {
+        match hosts.iter_mut().find(|(known, _)| *known == host) {
+            Some((_, ips)) if ips.contains(&addr.ip()) => {}
+            Some((_, ips)) => ips.push(addr.ip()),
+            None => hosts.push((host, vec![addr.ip()])),
+        }
+    }
+    hosts
+}
+
+/// The addresses in use for overridden hosts: the built-in ones, replaced by those from
+/// `CITY17_RESOLVE` and by refreshes.
+static OVERRIDES: Lazy<RwLock<HashMap<String, Vec<IpAddr>>>> = Lazy::new(|| {
+    let built_in = RESOLVE_OVERRIDES.iter().map(|(host, _, ips)| {
+        (host.to_string(), ips.iter().map(|ip| Ipv4Addr::from(*ip).into()).collect())
+    });
+    let configured = RESOLVE.iter().cloned();
+    RwLock::new(built_in.chain(configured).collect())
+});
+
+/// Parse one `host=ip` or `host=ip:port` entry of `CITY17_RESOLVE`. Without a port it's 443.
+/// IPv6 addresses with a port go in brackets, as in `[2a04:4e42::1]:443`. The client only takes
+/// the IP from an override and connects to the URL's port, so any other port does nothing.
+pub fn resolve_entry(entry: &str) -> Option<(&str, SocketAddr)> {
+    let (host, addr) = entry.split_once('=')?;
+    let (host, addr) = (host.trim(), addr.trim());
+    if host.is_empty() {
+        return None;
+    }
+    let addr = addr.parse().or_else(|_| addr.parse().map(|ip: IpAddr| SocketAddr::new(ip, 443)));
+    addr.ok().map(|addr| (host, addr))
+}
+
+/// How long a failed lookup is remembered for, so that a DNS outage fails requests quickly
+/// instead of making each one wait out the resolver. Set `CITY17_DNS_NEGATIVE_TTL` in seconds
+/// to change it; 0 turns it off, and anything else that isn't a number stops the server at
+/// launch.
+static NEGATIVE_TTL: Lazy<Duration> =
+    Lazy::new(|| get_negative_ttl().unwrap_or(DEFAULT_NEGATIVE_TTL));
+
+/// [`OVERRIDES`] for the hosts in it, starting `rotate` addresses in, and [`FailFastResolver`]
+/// for the rest.
+#[derive(Clone, Debug, Default)]
+struct OverridingResolver {
+    rotate: usize,
+    dns: FailFastResolver,
+}
+
+impl Resolve for OverridingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        match override_ips(name.as_str()).filter(|ips| !ips.is_empty()) {
+            Some(mut ips) => {
+                let start = self.rotate % ips.len();
+                ips.rotate_left(start);
+                // the port is the URL's either way
+                let addrs = ips.into_iter().map(|ip| SocketAddr::new(ip, 443));
+                Box::pin(future::ready(Ok(Box::new(addrs) as Addrs)))
+            }
+            None => self.dns.resolve(name),
+        }
+    }
+}
+
+/// How often the built-in addresses are looked up again, from `CITY17_RESOLVE_REFRESH` in
+/// minutes. 0 turns it off. See [`parse_refresh_interval`].
+#[cfg(feature = "resolve")]
+static REFRESH_INTERVAL: Lazy<Duration> = Lazy::new(|| {
+    const DEFAULT: Duration = Duration::from_secs(30 * 60);
+    match env::var("CITY17_RESOLVE_REFRESH") {
+        Ok(raw) => parse_refresh_interval(&raw).unwrap_or_else(|e| {
+            log::warn!("ignoring CITY17_RESOLVE_REFRESH, it {}", e);
+            DEFAULT
+        }),
+        Err(_) => DEFAULT,
+    }
+});
+
+/// The longest `CITY17_RESOLVE_REFRESH` can be, a week.
+pub const MAX_REFRESH_MINUTES: u64 = 7 * 24 * 60;
+
+/// Check a `CITY17_RESOLVE_REFRESH` value, in minutes up to [`MAX_REFRESH_MINUTES`].
+pub fn parse_refresh_interval(raw: &str) -> Result<Duration, String> {
+    match raw.trim().parse::<u64>() {
+        Ok(minutes) if minutes <= MAX_REFRESH_MINUTES => Ok(Duration::from_secs(minutes * 60)),
+        _ => {
+            Err(format!("must be a number of minutes up to {}, not {:?}", MAX_REFRESH_MINUTES, raw))
+        }
+    }
+}
+
+/// Looks up the built-in hosts with system DNS a little while after the server is up and then
+/// about every [`REFRESH_INTERVAL`], so the client follows the fronts when they move. Both waits
+/// are jittered, so a fleet that started together doesn't look them up in step. Only built with
+/// the `resolve` feature, for deployments where system DNS can be trusted with Twitch's domains,
+/// or that have `CITY17_RESOLVE_DNS` pointing at a server that can.
+#[cfg(feature = "resolve")]
+pub fn refresh_fairing() -> AdHoc {
+    AdHoc::on_liftoff("Resolver refresh", |rocket| {
+        let shutdown = rocket.shutdown();
+        Box::pin(async move {
+            if !REFRESH_INTERVAL.is_zero() {
+                tokio::spawn(refresh_until_shutdown(*REFRESH_INTERVAL, shutdown));
+            }
+        })
+    })
+}
+
+#[cfg(feature = "resolve")]
+async fn refresh_until_shutdown(interval: Duration, mut shutdown: rocket::Shutdown) {
+    let mut rng = crate::get_rng();
+    let mut wait = first_refresh_delay(&mut rng, interval, DEFAULT_REFRESH_JITTER);
+    loop {
+        if tokio::time::timeout(wait, &mut shutdown).await.is_ok() {
+            return;
+        }
+        refresh_overrides().await;
+        wait = jittered_interval(&mut rng, interval, DEFAULT_REFRESH_JITTER);
+    }
+}
+
+/// The DNS server refreshes ask over TCP, from `CITY17_RESOLVE_DNS` as `ip` or `ip:port`, e.g.
+/// `8.8.8.8`. Without it they use system DNS. One that doesn't parse stops the server at launch,
+/// see [`get_resolve_dns`].
+static REFRESH_DNS: Lazy<Option<SocketAddr>> = Lazy::new(|| {
+    get_resolve_dns().unwrap_or_else(|e| {
+        log::error!("refreshing with system DNS, {}", e);
+        None
+    })
+});
+
+/// `host`'s addresses from [`REFRESH_DNS`], or system DNS if that isn't set.
+async fn refresh_lookup(host: &str) -> io::Result<Vec<IpAddr>> {
+    match *REFRESH_DNS {
+        Some(server) => {
+            let lookup = tokio::time::timeout(REQUEST_TIMEOUT, dns::lookup_a(server, host));
+            let ips = lookup.await.map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+            Ok(ips.into_iter().map(IpAddr::from).collect())
+        }
+        None => Ok(tokio::net::lookup_host((host, 443)).await?.map(|addr| addr.ip()).collect()),
+    }
+}
+
+/// Look up the name behind each built-in host, and use what DNS says for it from now on. A host
+/// whose lookup fails keeps the addresses it had, and those set in `CITY17_RESOLVE` or
+/// `CITY17_FRONT_IP` aren't looked up at all.
+pub async fn refresh_overrides() {
+    for (host, lookup, _) in RESOLVE_OVERRIDES {
+        if RESOLVE.iter().any(|(configured, _)| configured == host) {
+            continue;
+        }
+        let mut ips = match refresh_lookup(lookup).await {
+            Ok(ips) => ips,
+            Err(e) => {
+                log::warn!("couldn't look up {} for {}, keeping its IPs: {}", lookup, host, e);
+                continue;
+            }
+        };
+        if ips.is_empty() {
+            continue;
+        }
+        // the built-in addresses are all IPv4, which is what hosts here can surely reach, so
+        // those go first
+        ips.sort_by_key(|ip| !ip.is_ipv4());
+        let previous = OVERRIDES.write().unwrap().insert(host.to_string(), ips.clone());
+        if previous.as_ref() != Some(&ips) {
+            log::info!("{} is now at {:?}, {}'s addresses", host, ips, lookup);
+        }
+    }
+}
+
+/// Hosts whose lookup failed, and until when that's remembered. Shared by every client, so in
+/// a DNS outage one failed lookup fails the rest fast whichever client they're for.
+static FAILED_LOOKUPS: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(Mutex::default);
+
+/// System DNS (for everything not overridden) that remembers failures for [`NEGATIVE_TTL`] in
+/// [`FAILED_LOOKUPS`].
+#[derive(Clone, Debug, Default)]
+struct FailFastResolver;
+
+impl Resolve for FailFastResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_owned();
+        let now = Instant::now();
+        if let Some(&until) = FAILED_LOOKUPS.lock().unwrap().get(&host) {
+            if now < until {
+                let err = io::Error::other(format!("lookup of {} failed recently", host));
+                return Box::pin(future::ready(Err(err.into())));
+            }
+        }
+        Box::pin(async move {
+            let lookup = tokio::net::lookup_host((host.as_str(), 0)).await;
+            let lookup = lookup.map(|addrs| addrs.collect::<Vec<_>>());
+            match lookup {
+                Ok(addrs) => {
+                    FAILED_LOOKUPS.lock().unwrap().remove(&host);
+                    Ok(Box::new(addrs.into_iter()) as Addrs)
+                }
+                Err(e) => {
+                    if !NEGATIVE_TTL.is_zero() {
+                        let now = Instant::now();
+                        let mut failed = FAILED_LOOKUPS.lock().unwrap();
+                        // so hosts that failed once and never came up again don't pile up
+                        failed.retain(|_, until| now < *until);
+                        failed.insert(host, now + *NEGATIVE_TTL);
+                    }
+                    Err(e.into())
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,61 @@
+//! Clips. The MP4s are on Twitch's CDN, but the signature that lets them be downloaded only
+//! comes from GQL, which is blocked where this runs.
+
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Serialize;
+
+use crate::config::Upstream;
+use crate::gql::{clip_access, ClipAccess};
+use crate::Error;
+
+/// Check a clip's slug before it goes to GQL. Old slugs are a few CamelCase words, new ones
+/// have a dash and a random suffix; neither is lowercased, since slugs are case-sensitive.
+pub fn validate_clip_slug(slug: &str) -> Result<String, Error> {
+    let slug = slug.trim();
+    let allowed = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    if slug.is_empty() || slug.len() > 100 || !slug.chars().all(allowed) {
+        return Err(Error::Input("clip must be 1-100 characters of A-Z, 0-9, _, and -".into()));
+    }
+    Ok(slug.to_owned())
+}
+
+/// One quality of a clip, with a URL that can be downloaded from.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ClipUrl {
+    /// The height, like `1080`.
+    pub quality: String,
+    pub frame_rate: Option<f64>,
+    pub url: String,
+}
+
+/// Every quality of a clip, in the order GQL lists them (best first), with signed URLs.
+pub fn signed_urls(access: &ClipAccess) -> Vec<ClipUrl> {
+    let token = &access.token;
+    let signature = utf8_percent_encode(&token.signature, NON_ALPHANUMERIC);
+    let value = utf8_percent_encode(&token.value, NON_ALPHANUMERIC);
+    let urls = access.qualities.iter().map(|quality| ClipUrl {
+        quality: quality.quality.clone(),
+        frame_rate: quality.frame_rate,
+        url: format!("{}?sig={}&token={}", quality.source_url, signature, value),
+    });
+    urls.collect()
+}
+
+/// The quality called `name`, like `720` or `720p`. `source` picks the best.
+pub fn pick_quality<'a>(urls: &'a [ClipUrl], name: &str) -> Result<&'a ClipUrl, Error> {
+    let found = if name.eq_ignore_ascii_case("source") {
+        urls.iter().max_by_key(|url| url.quality.parse::<u32>().unwrap_or(0))
+    } else {
+        let name = name.strip_suffix('p').unwrap_or(name);
+        urls.iter().find(|url| url.quality == name)
+    };
+    found.ok_or(Error::Input("the clip has no quality with that name".into()))
+}
+
+/// The clip `slug`'s qualities, with signed URLs.
+pub async fn clip_urls(slug: &str, upstream: &Upstream) -> Result<Vec<ClipUrl>, Error> {
+    match clip_access(slug, upstream).await? {
+        Some(access) => Ok(signed_urls(&access)),
+        None => Err(Error::NoClip(slug.to_owned())),
+    }
+}
@@ -0,0 +1,27 @@
+//! Gzip for playlist responses. Only built for Azure and standalone, since Aliyun doesn't allow
+//! gzip.
+
+use std::io::{self, Write};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// Below this, gzip's header and the extra work aren't worth it.
+pub const GZIP_MIN_BYTES: usize = 1024;
+
+/// Whether an `Accept-Encoding` value allows gzip. `gzip;q=0` is a refusal.
+pub fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|coding| {
+        let mut params = coding.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        let refused =
+            params.any(|p| p.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+    })
+}
+
+pub fn gzip(body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 4), Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
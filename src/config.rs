@@ -0,0 +1,745 @@
+//! Server settings, read from the environment or worked out from the host.
+
+use std::env;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(feature = "server")]
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+#[cfg(feature = "server")]
+use serde::Deserialize;
+
+use crate::client::{configure_resolve, parse_resolve, REQUEST_BUDGET, REQUEST_TIMEOUT};
+use crate::latency::MIN_TIMEOUT;
+use crate::Error;
+
+/// What the server needs to know before it's built. Everything else is read from the
+/// environment when it's first needed.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub port: u16,
+    pub address: IpAddr,
+    /// `None` picks from the CPUs available; see [`workers_for_cpus`].
+    pub workers: Option<usize>,
+    /// Seconds idle client connections are kept open. 0 closes them after each response.
+    pub keep_alive: u32,
+    /// Send `Access-Control-Allow-Origin: *` on every response. Off with `CITY17_DISABLE_CORS`,
+    /// for operators who set CORS at their CDN instead.
+    pub cors: bool,
+    /// Send the `Permissions-Policy` header that opts out of FLoC. Off with
+    /// `CITY17_DISABLE_PERMISSIONS_POLICY`.
+    pub permissions_policy: bool,
+    /// Ping the deployment now and then so it isn't scaled to zero, from `CITY17_KEEPWARM_URL`.
+    pub keep_warm: Option<KeepWarm>,
+    /// What every route's path starts with, from `CITY17_ROUTE_PREFIX`. See
+    /// [`DEFAULT_ROUTE_PREFIX`].
+    pub route_prefix: String,
+    pub upstream: Upstream,
+}
+
+/// Where the routes are mounted unless `CITY17_ROUTE_PREFIX` says otherwise: where each platform
+/// passes requests on to a custom handler, or the root when standalone. Aliyun's has the service
+/// and function names in it, `a` and `prx` here, so a function named anything else needs the
+/// setting.
+pub const DEFAULT_ROUTE_PREFIX: &str = if cfg!(feature = "azure") {
+    "/api"
+} else if cfg!(feature = "standalone") {
+    "/"
+} else {
+    "/2016-08-15/proxy/a/prx/invoke"
+};
+
+/// A deployment's own URL to GET now and then, so a consumption-plan host keeps it running and
+/// the next viewer doesn't wait on a cold start.
+#[derive(Clone, Debug)]
+pub struct KeepWarm {
+    /// What to GET, through the deployment's public hostname so the platform counts it as
+    /// traffic. From `CITY17_KEEPWARM_URL`.
+    pub url: String,
+    /// About how long between pings, from `CITY17_KEEPWARM_INTERVAL` in seconds. Each wait is
+    /// shifted by up to [`DEFAULT_REFRESH_JITTER`] of this.
+    pub interval: Duration,
+    /// When to let the deployment go cold instead, from `CITY17_KEEPWARM_QUIET`.
+    pub quiet: Option<QuietHours>,
+}
+
+/// How often the keep-warm ping goes out unless `CITY17_KEEPWARM_INTERVAL` says otherwise.
+/// Consumption plans scale an idle app down after several minutes.
+pub const DEFAULT_KEEPWARM_INTERVAL: Duration = Duration::from_secs(300);
+
+/// A span of UTC hours, e.g. `1-7` for 01:00 until 07:00, which can wrap past midnight.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct QuietHours {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl QuietHours {
+    /// Whether `hour` (0-23) falls in the span, which doesn't include its end hour.
+    pub fn contains(&self, hour: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&hour)
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+/// Where upstream requests go. The defaults are Twitch's; tests point them at mock servers.
+#[derive(Clone, Debug)]
+pub struct Upstream {
+    /// The GQL endpoint, sent `Host: gql.twitch.tv`.
+    pub gql_url: String,
+    /// What playlist paths are appended to, sent `Host: usher.ttvnw.net`. Set with
+    /// `CITY17_USHER_BASE`, e.g. to try a hostname that resolves better from some region.
+    pub usher_base: String,
+    /// The host usher requests connect to in place of usher's own, so its name never shows up
+    /// in the TLS handshake. `None` connects to usher directly. Set with `CITY17_USHER_FRONT`.
+    pub usher_front: Option<String>,
+    /// How long each upstream request gets.
+    pub timeout: Duration,
+    /// How long a whole playlist fetch gets, GQL and usher and their retries together, or
+    /// [`timeout`](Self::timeout) if that's longer. [`REQUEST_BUDGET`] outside of tests.
+    pub budget: Duration,
+    /// When the playlist fetch this is for has to be done by. Only ever set for one fetch, see
+    /// [`with_deadline`](Self::with_deadline).
+    pub deadline: Option<Instant>,
+    /// Only for tests, which want to know exactly what upstream was sent. Never set from the
+    /// environment, so a real server always sends fresh random IDs.
+    pub fixed_ids: Option<FixedIds>,
+    /// Another City17 to get playlists from instead of going to Twitch. Only the server relays.
+    pub relay: Option<Relay>,
+    /// GQL without the front, for when the front drops requests or isn't needed. `None` only
+    /// ever goes through the front. Set with `CITY17_DIRECT_GQL`.
+    pub direct_gql: Option<DirectGql>,
+    /// The viewer's own Twitch token, sent to GQL along with the token request. Only ever set for
+    /// one request, from what it came with, never from the environment.
+    pub oauth: Option<OAuthToken>,
+}
+
+/// A viewer's own Twitch OAuth token. Its `Debug` leaves the token out, so it can't end up in a
+/// log line or error body by way of the [`Upstream`] it's in.
+#[derive(Clone, PartialEq, Eq)]
+pub struct OAuthToken(String);
+
+impl OAuthToken {
+    /// Check a token a request came with: `OAuth <token>` as in an `Authorization` header, or
+    /// just the token. Twitch's are letters and digits. The error doesn't repeat it.
+    pub fn parse(raw: &str) -> Result<Self, Error> {
+        let raw = raw.trim();
+        let token = raw.strip_prefix("OAuth ").unwrap_or(raw).trim();
+        if token.is_empty()
+            || token.len() > 128
+            || !token.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            return Err(Error::Input(
+                "the OAuth token must be Twitch's, letters and digits only".into(),
+            ));
+        }
+        Ok(Self(token.to_owned()))
+    }
+
+    /// The token itself, for the header that goes to GQL. Nowhere else.
+    pub fn secret(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for OAuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OAuthToken(<redacted>)")
+    }
+}
+
+/// GQL reached directly rather than through its front, from `CITY17_DIRECT_GQL`.
+#[derive(Clone, Debug)]
+pub struct DirectGql {
+    /// The endpoint, reached with system DNS and checked certificates like any other host.
+    pub url: String,
+    /// Send every GQL request here (`always`), rather than only those the front dropped
+    /// (`fallback`). Outside China the front is just an extra hop.
+    pub always: bool,
+}
+
+/// Another City17 instance that playlist requests are passed on to, from `CITY17_UPSTREAM`.
+#[derive(Clone, Debug)]
+pub struct Relay {
+    /// The other instance's route prefix, e.g. `https://host/2016-08-15/proxy/a/prx/invoke`.
+    pub base: String,
+    /// Sent as `X-API-Key`, from `CITY17_UPSTREAM_KEY`, for an instance behind a gateway that
+    /// checks one.
+    pub key: Option<String>,
+}
+
+/// IDs sent upstream in place of random ones.
+#[derive(Clone, Debug)]
+pub struct FixedIds {
+    pub device_id: String,
+    /// Sent as-is, so it should already be lowercase.
+    pub play_session_id: String,
+    /// usher's `p` parameter.
+    pub p: u32,
+}
+
+/// Where playlists are fetched from unless `CITY17_USHER_BASE` says otherwise.
+pub const DEFAULT_USHER_BASE: &str = "https://usher.ttvnw.net/";
+
+/// What GQL requests go through unless `CITY17_FRONT_HOST` says otherwise.
+pub const DEFAULT_GQL_FRONT: &str = "fastly.net";
+
+/// GQL's own endpoint, for [`DirectGql`].
+pub const DIRECT_GQL_URL: &str = "https://gql.twitch.tv/gql";
+
+/// What usher requests go through unless `CITY17_USHER_FRONT` says otherwise.
+/// This isn't 100% unblocked but it seems to be more reliable than a bare IP.
+/// Also: I'm pretty sure Usher is being weirdly permissive, here.
+pub const DEFAULT_USHER_FRONT: &str = "www.fastly.com";
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Self {
+            gql_url: gql_url(DEFAULT_GQL_FRONT),
+            usher_base: DEFAULT_USHER_BASE.to_owned(),
+            usher_front: Some(DEFAULT_USHER_FRONT.to_owned()),
+            timeout: REQUEST_TIMEOUT,
+            budget: REQUEST_BUDGET,
+            deadline: None,
+            fixed_ids: None,
+            relay: None,
+            direct_gql: Some(DirectGql { url: DIRECT_GQL_URL.to_owned(), always: false }),
+            oauth: None,
+        }
+    }
+}
+
+impl Upstream {
+    /// This for one playlist fetch starting now, with its [`budget`](Self::budget) to spend.
+    pub fn with_deadline(&self) -> Self {
+        let deadline = Some(Instant::now() + self.budget.max(self.timeout));
+        Self { deadline, ..self.clone() }
+    }
+
+    /// `timeout` for one request, cut short to what's left before the deadline.
+    pub fn within_deadline(&self, timeout: Duration) -> Duration {
+        match self.deadline {
+            Some(deadline) => timeout.min(deadline.saturating_duration_since(Instant::now())),
+            None => timeout,
+        }
+    }
+
+    /// Whether there's still time for another try at a request given `timeout`, after waiting
+    /// `delay` first: all of that timeout, or at least [`MIN_TIMEOUT`] of it.
+    pub fn has_time_for(&self, delay: Duration, timeout: Duration) -> bool {
+        let left = self.within_deadline(delay + timeout).saturating_sub(delay);
+        left >= timeout.min(MIN_TIMEOUT)
+    }
+}
+
+impl Settings {
+    /// Read settings from the environment, or say which one is wrong.
+    pub fn from_env() -> Result<Self, String> {
+        Self::read(&[])
+    }
+
+    /// [`Settings::from_env`], with the config file's settings for variables that aren't set.
+    #[cfg(feature = "server")]
+    pub fn with_file(file: &ConfigFile) -> Result<Self, String> {
+        Self::read(&file.variables())
+    }
+
+    /// Read settings from the environment, falling back to `file`'s stand-ins for its
+    /// variables.
+    fn read(file: &[(&'static str, String)]) -> Result<Self, String> {
+        configure_resolve(resolve_entries(file)?);
+        // read again where they're used, but a typo should stop the launch
+        get_max_header_bytes()?;
+        get_negative_ttl()?;
+        get_playlist_cache_bytes()?;
+        get_resolve_dns()?;
+        Ok(Self {
+            port: get_port(setting(PORT_KEY, file))?,
+            address: get_address()?,
+            workers: env_number("CITY17_WORKERS", "workers", true)?.map(|n| n as usize),
+            keep_alive: get_keep_alive()?,
+            cors: !env_flag("CITY17_DISABLE_CORS"),
+            permissions_policy: !env_flag("CITY17_DISABLE_PERMISSIONS_POLICY"),
+            keep_warm: get_keep_warm()?,
+            route_prefix: get_route_prefix()?,
+            upstream: Upstream {
+                gql_url: gql_url(&get_gql_front(setting("CITY17_FRONT_HOST", file))?),
+                usher_base: get_usher_base()?,
+                usher_front: get_usher_front(setting("CITY17_USHER_FRONT", file))?,
+                relay: get_relay()?,
+                timeout: get_timeout(setting("CITY17_TIMEOUT", file))?,
+                direct_gql: get_direct_gql()?,
+                ..Upstream::default()
+            },
+        })
+    }
+}
+
+/// Settings from `city17.toml`, for platforms where a file is easier to manage than a pile of
+/// environment variables. Each one stands in for an environment variable, and the variable wins
+/// when both are set. See [`Settings::with_file`].
+#[cfg(feature = "server")]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// `CITY17_RESOLVE`'s entries, e.g. `["fastly.net=151.101.110.167"]`.
+    pub resolve: Option<Vec<String>>,
+    /// `CITY17_TIMEOUT`, in seconds.
+    pub timeout: Option<u64>,
+    /// [`PORT_KEY`].
+    pub port: Option<u16>,
+    /// `CITY17_USHER_FRONT`.
+    pub usher_front: Option<String>,
+    /// `CITY17_FRONT_HOST`.
+    pub front_host: Option<String>,
+    /// `CITY17_FRONT_IP`'s addresses, e.g. `["151.101.2.167", "151.101.66.167"]`.
+    pub front_ip: Option<Vec<String>>,
+}
+
+#[cfg(feature = "server")]
+impl ConfigFile {
+    /// Parse a config file's contents, saying what's wrong with them if they don't parse.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        toml::from_str(raw).map_err(|e| e.to_string())
+    }
+
+    /// Read the config file: `CITY17_CONFIG` if set, otherwise `city17.toml` next to the
+    /// binary. There being no file at the default path is fine and gives an empty config;
+    /// there being none at a path that was asked for isn't.
+    pub fn load() -> Result<Self, String> {
+        let (path, default) = match env::var_os("CITY17_CONFIG") {
+            Some(path) => (PathBuf::from(path), false),
+            None => match env::current_exe() {
+                Ok(exe) => (exe.with_file_name("city17.toml"), true),
+                Err(_) => return Ok(Self::default()),
+            },
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(raw) => Self::parse(&raw).map_err(|e| format!("{} {}", path.display(), e)),
+            Err(e) if default && e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("couldn't read {}: {}", path.display(), e)),
+        }
+    }
+
+    /// The environment variables this stands in for, and their values.
+    pub fn variables(&self) -> Vec<(&'static str, String)> {
+        let mut variables = Vec::new();
+        if let Some(resolve) = &self.resolve {
+            variables.push(("CITY17_RESOLVE", resolve.join(",")));
+        }
+        if let Some(timeout) = self.timeout {
+            variables.push(("CITY17_TIMEOUT", timeout.to_string()));
+        }
+        if let Some(port) = self.port {
+            variables.push((PORT_KEY, port.to_string()));
+        }
+        if let Some(usher_front) = &self.usher_front {
+            variables.push(("CITY17_USHER_FRONT", usher_front.clone()));
+        }
+        if let Some(front_host) = &self.front_host {
+            variables.push(("CITY17_FRONT_HOST", front_host.clone()));
+        }
+        if let Some(front_ip) = &self.front_ip {
+            variables.push(("CITY17_FRONT_IP", front_ip.join(",")));
+        }
+        variables
+    }
+}
+
+/// An environment variable's value, or if it isn't set, its stand-in from `file`, as given by
+/// `ConfigFile::variables`.
+fn setting(key: &str, file: &[(&'static str, String)]) -> Option<String> {
+    let from_file = || file.iter().find(|(k, _)| *k == key).map(|(_, value)| value.clone());
+    env::var(key).ok().or_else(from_file)
+}
+
+/// How many Rocket workers to run on a host with `cpus` available. At least 2 so one slow
+/// upstream can't hold everything up, and at most 8 since the work is mostly waiting on Twitch.
+pub fn workers_for_cpus(cpus: usize) -> usize {
+    cpus.clamp(2, 8)
+}
+
+/// Default fraction of a background refresh's interval that its waits are randomly shifted by.
+pub const DEFAULT_REFRESH_JITTER: f64 = 0.2;
+
+/// How long a background refresh waits before its first run: up to `jitter` of the interval,
+/// so instances that scale up together don't all hit the DNS resolver at the same moment.
+pub fn first_refresh_delay(rng: &mut impl Rng, interval: Duration, jitter: f64) -> Duration {
+    interval.mul_f64(rng.gen_range(0.0..=jitter.clamp(0.0, 1.0)))
+}
+
+/// A background refresh's interval, shifted by up to `jitter` of itself either way so instances
+/// that did start in step drift apart.
+pub fn jittered_interval(rng: &mut impl Rng, interval: Duration, jitter: f64) -> Duration {
+    let jitter = jitter.clamp(0.0, 1.0);
+    interval.mul_f64(rng.gen_range(1.0 - jitter..=1.0 + jitter))
+}
+
+/// Read a port environment variable's value, or `default` if it isn't set. A value that isn't
+/// a port is an error describing it, so the caller can say so before falling back. 0 parses
+/// fine here; whether it's acceptable is up to the caller.
+pub fn parse_port(raw: Option<&str>, default: u16) -> Result<u16, String> {
+    match raw {
+        None => Ok(default),
+        Some(raw) => raw.trim().parse().map_err(|_| format!("{:?} is not a port", raw)),
+    }
+}
+
+/// Where the port comes from. Azure's variable for custom handlers, which can be set in Aliyun
+/// if wanted; off the platforms, the usual `PORT`.
+pub const PORT_KEY: &str =
+    if cfg!(feature = "standalone") { "PORT" } else { "FUNCTIONS_CUSTOMHANDLER_PORT" };
+
+/// Get port from defaults or environment variable.
+///
+/// Azure has been seen to hand out garbage here during platform hiccups, so that falls back to
+/// the default instead of stopping the handler.
+fn get_port(raw: Option<String>) -> Result<u16, String> {
+    const DEFAULT: u16 = if cfg!(feature = "azure") { 8080 } else { 9000 };
+    let port = parse_port(raw.as_deref(), DEFAULT).unwrap_or_else(|e| {
+        // logging isn't set up yet
+        eprintln!("city17: {} {}, using {}", PORT_KEY, e, DEFAULT);
+        DEFAULT
+    });
+    if port == 0 {
+        return Err(format!("{} must not be 0", PORT_KEY));
+    }
+    Ok(port)
+}
+
+/// Get the address to listen on. `CITY17_BIND_ADDRESS` if set, otherwise `::`, which on most
+/// systems takes IPv4 clients as well. Hosts without IPv6 fall back to `0.0.0.0`.
+fn get_address() -> Result<IpAddr, String> {
+    if let Ok(address) = env::var("CITY17_BIND_ADDRESS") {
+        return address
+            .parse()
+            .map_err(|_| format!("CITY17_BIND_ADDRESS must be an IP address, not {:?}", address));
+    }
+    let any_v6 = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+    match std::net::TcpListener::bind((any_v6, 0)) {
+        Ok(_) => Ok(any_v6),
+        Err(_) => Ok(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+    }
+}
+
+/// Get how long idle client connections are kept open, from `CITY17_KEEP_ALIVE` in seconds.
+/// Off by default since serverless platforms bill for the open connection; on a real server it
+/// saves polling clients a handshake over a slow link every time.
+fn get_keep_alive() -> Result<u32, String> {
+    match env::var("CITY17_KEEP_ALIVE") {
+        Err(_) => Ok(0),
+        Ok(seconds) => seconds.parse().map_err(|_| {
+            format!("CITY17_KEEP_ALIVE must be a number of seconds, not {:?}", seconds)
+        }),
+    }
+}
+
+/// A whole number from the environment variable `key`, if it's set: a count of `unit`, above 0
+/// if `above_zero`.
+fn env_number(key: &str, unit: &str, above_zero: bool) -> Result<Option<u64>, String> {
+    let raw = match env::var(key) {
+        Ok(raw) if !raw.trim().is_empty() => raw,
+        _ => return Ok(None),
+    };
+    match raw.trim().parse() {
+        Ok(n) if n > 0 || !above_zero => Ok(Some(n)),
+        _ => {
+            let above = if above_zero { " above 0" } else { "" };
+            Err(format!("{} must be a number of {}{}, not {:?}", key, unit, above, raw))
+        }
+    }
+}
+
+/// The most a request's headers may add up to unless `CITY17_MAX_HEADER_BYTES` says otherwise.
+pub const DEFAULT_MAX_HEADER_BYTES: usize = 8 * 1024;
+
+/// Get the cap on the size of a request's headers from `CITY17_MAX_HEADER_BYTES`.
+pub fn get_max_header_bytes() -> Result<usize, String> {
+    let max = env_number("CITY17_MAX_HEADER_BYTES", "bytes", true)?;
+    Ok(max.map_or(DEFAULT_MAX_HEADER_BYTES, |max| max as usize))
+}
+
+/// How long a failed lookup is remembered unless `CITY17_DNS_NEGATIVE_TTL` says otherwise.
+pub const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(5);
+
+/// Get how long a failed lookup is remembered from `CITY17_DNS_NEGATIVE_TTL` in seconds, where 0
+/// turns that off.
+pub fn get_negative_ttl() -> Result<Duration, String> {
+    let secs = env_number("CITY17_DNS_NEGATIVE_TTL", "seconds", false)?;
+    Ok(secs.map_or(DEFAULT_NEGATIVE_TTL, Duration::from_secs))
+}
+
+/// Size cap for all cached live playlists together unless `CITY17_PLAYLIST_CACHE_BYTES` says
+/// otherwise. A master playlist is a few KB.
+pub const DEFAULT_PLAYLIST_CACHE_BYTES: usize = 1024 * 1024;
+
+/// Get the live playlist cache's size cap from `CITY17_PLAYLIST_CACHE_BYTES`. 0 caches nothing.
+pub fn get_playlist_cache_bytes() -> Result<usize, String> {
+    let max = env_number("CITY17_PLAYLIST_CACHE_BYTES", "bytes", false)?;
+    Ok(max.map_or(DEFAULT_PLAYLIST_CACHE_BYTES, |max| max as usize))
+}
+
+/// Check a `CITY17_TIMEOUT` value, a whole number of seconds each upstream request gets.
+pub fn parse_timeout(raw: &str) -> Result<Duration, String> {
+    match raw.trim().parse() {
+        Ok(seconds) if seconds > 0 => Ok(Duration::from_secs(seconds)),
+        _ => Err(format!("must be a number of seconds above 0, not {:?}", raw)),
+    }
+}
+
+/// Get how long each upstream request gets from `CITY17_TIMEOUT`, or the built-in
+/// [`REQUEST_TIMEOUT`] if it isn't set.
+fn get_timeout(raw: Option<String>) -> Result<Duration, String> {
+    match raw {
+        Some(raw) if !raw.trim().is_empty() => {
+            parse_timeout(&raw).map_err(|e| format!("CITY17_TIMEOUT {}", e))
+        }
+        _ => Ok(REQUEST_TIMEOUT),
+    }
+}
+
+/// Check a `CITY17_KEEPWARM_QUIET` value, `start-end` in UTC hours.
+pub fn parse_quiet_hours(raw: &str) -> Result<QuietHours, String> {
+    let hour = |h: &str| h.trim().parse().ok().filter(|h| *h < 24);
+    let hours = raw.split_once('-').and_then(|(start, end)| Some((hour(start)?, hour(end)?)));
+    match hours {
+        Some((start, end)) if start != end => Ok(QuietHours { start, end }),
+        _ => Err(format!("must be UTC hours like 1-7, not {:?}", raw)),
+    }
+}
+
+/// Get the keep-warm ping's settings, if `CITY17_KEEPWARM_URL` is set.
+fn get_keep_warm() -> Result<Option<KeepWarm>, String> {
+    let url = match env::var("CITY17_KEEPWARM_URL") {
+        Ok(url) if !url.trim().is_empty() => url.trim().to_owned(),
+        _ => return Ok(None),
+    };
+    if !url.starts_with("https://") {
+        return Err(format!("CITY17_KEEPWARM_URL must be an https:// URL, not {:?}", url));
+    }
+    let interval = match env::var("CITY17_KEEPWARM_INTERVAL") {
+        Err(_) => DEFAULT_KEEPWARM_INTERVAL,
+        Ok(seconds) => match seconds.parse() {
+            Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+            _ => {
+                let e = format!("must be a number of seconds above 0, not {:?}", seconds);
+                return Err(format!("CITY17_KEEPWARM_INTERVAL {}", e));
+            }
+        },
+    };
+    let quiet = match env::var("CITY17_KEEPWARM_QUIET") {
+        Ok(raw) if !raw.trim().is_empty() => {
+            Some(parse_quiet_hours(&raw).map_err(|e| format!("CITY17_KEEPWARM_QUIET {}", e))?)
+        }
+        _ => None,
+    };
+    Ok(Some(KeepWarm { url, interval, quiet }))
+}
+
+/// Check a `CITY17_ROUTE_PREFIX` value, a path like `/2016-08-15/proxy/svc/fn/invoke`, returning
+/// it without the trailing slash. `/` mounts the routes at the root.
+pub fn parse_route_prefix(raw: &str) -> Result<String, String> {
+    let raw = raw.trim();
+    let valid = raw.starts_with('/')
+        && !raw.contains("//")
+        && raw.chars().all(|c| c.is_ascii_alphanumeric() || "/-._~".contains(c));
+    if !valid {
+        return Err(format!("must be a path like {}, not {:?}", DEFAULT_ROUTE_PREFIX, raw));
+    }
+    match raw.trim_end_matches('/') {
+        "" => Ok("/".to_owned()),
+        prefix => Ok(prefix.to_owned()),
+    }
+}
+
+/// Get the route prefix from `CITY17_ROUTE_PREFIX`, or the platform's if it isn't set.
+fn get_route_prefix() -> Result<String, String> {
+    match env::var("CITY17_ROUTE_PREFIX") {
+        Ok(raw) if !raw.trim().is_empty() => {
+            parse_route_prefix(&raw).map_err(|e| format!("CITY17_ROUTE_PREFIX {}", e))
+        }
+        _ => Ok(DEFAULT_ROUTE_PREFIX.to_owned()),
+    }
+}
+
+/// Check a `CITY17_USHER_BASE` value, returning it with the trailing slash playlist paths are
+/// appended after. It has to be https, since the token goes out in the query.
+pub fn parse_usher_base(raw: &str) -> Result<String, String> {
+    let url =
+        reqwest::Url::parse(raw.trim()).map_err(|e| format!("{:?} is not a URL: {}", raw, e))?;
+    if url.scheme() != "https" || url.host_str().is_none() {
+        return Err(format!("{:?} must be an https:// URL", raw));
+    }
+    let base = url.as_str();
+    Ok(if base.ends_with('/') { base.to_owned() } else { format!("{}/", base) })
+}
+
+/// Get usher's base URL from `CITY17_USHER_BASE`, or the real one if it isn't set.
+fn get_usher_base() -> Result<String, String> {
+    match env::var("CITY17_USHER_BASE") {
+        Ok(raw) if !raw.trim().is_empty() => {
+            parse_usher_base(&raw).map_err(|e| format!("CITY17_USHER_BASE {}", e))
+        }
+        _ => Ok(DEFAULT_USHER_BASE.to_owned()),
+    }
+}
+
+/// Check a `CITY17_USHER_FRONT` value: a bare hostname to front usher with, or `off` to
+/// connect to usher directly.
+pub fn parse_usher_front(raw: &str) -> Result<Option<String>, String> {
+    let raw = raw.trim();
+    if raw == "off" {
+        return Ok(None);
+    }
+    if !is_hostname(raw) {
+        return Err(format!(
+            "must be a hostname like {} or off, not {:?}",
+            DEFAULT_USHER_FRONT, raw
+        ));
+    }
+    Ok(Some(raw.to_ascii_lowercase()))
+}
+
+/// Whether `raw` looks like a bare hostname, without a scheme, port, or path.
+fn is_hostname(raw: &str) -> bool {
+    !raw.is_empty()
+        && raw.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+        && !raw.starts_with(['.', '-'])
+}
+
+/// GQL's endpoint through the front at `front`.
+fn gql_url(front: &str) -> String {
+    format!("https://{}/gql", front)
+}
+
+/// Check a `CITY17_FRONT_HOST` value, a bare hostname for GQL requests to connect to.
+pub fn parse_front_host(raw: &str) -> Result<String, String> {
+    let raw = raw.trim();
+    if !is_hostname(raw) {
+        return Err(format!("must be a hostname like {}, not {:?}", DEFAULT_GQL_FRONT, raw));
+    }
+    Ok(raw.to_ascii_lowercase())
+}
+
+/// Get GQL's front from `CITY17_FRONT_HOST`, or the built-in one if it isn't set.
+pub fn get_gql_front(raw: Option<String>) -> Result<String, String> {
+    match raw {
+        Some(raw) if !raw.trim().is_empty() => {
+            parse_front_host(&raw).map_err(|e| format!("CITY17_FRONT_HOST {}", e))
+        }
+        _ => Ok(DEFAULT_GQL_FRONT.to_owned()),
+    }
+}
+
+/// Check a `CITY17_FRONT_IP` value: one or more comma-separated addresses for GQL's front, tried
+/// in the order listed.
+pub fn parse_front_ips(raw: &str) -> Result<Vec<IpAddr>, String> {
+    let ips = split_list(raw)
+        .map(|ip| ip.parse().map_err(|_| format!("has {:?}, which isn't an IP address", ip)));
+    ips.collect()
+}
+
+/// Get the addresses `CITY17_FRONT_IP` gives for GQL's front, if it's set.
+fn get_front_ips(raw: Option<String>) -> Result<Vec<IpAddr>, String> {
+    match raw {
+        Some(raw) if !raw.trim().is_empty() => {
+            parse_front_ips(&raw).map_err(|e| format!("CITY17_FRONT_IP {}", e))
+        }
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// The addresses the client uses in place of lookups: GQL's front at those from
+/// `CITY17_FRONT_IP`, then the entries of `CITY17_RESOLVE`, each read from the environment or
+/// else from `file`. Checked here so that a typo stops the launch instead of leaving the built-in
+/// addresses in use without a word.
+pub fn resolve_entries(
+    file: &[(&'static str, String)],
+) -> Result<Vec<(String, SocketAddr)>, String> {
+    let front = get_gql_front(setting("CITY17_FRONT_HOST", file))?;
+    let front_ips = get_front_ips(setting("CITY17_FRONT_IP", file))?;
+    let mut entries: Vec<_> =
+        front_ips.into_iter().map(|ip| (front.clone(), SocketAddr::new(ip, 443))).collect();
+    if let Some(raw) = setting("CITY17_RESOLVE", file) {
+        entries.extend(parse_resolve(&raw).map_err(|e| format!("CITY17_RESOLVE {}", e))?);
+    }
+    Ok(entries)
+}
+
+/// Get usher's front from `CITY17_USHER_FRONT`, or the built-in one if it isn't set.
+fn get_usher_front(raw: Option<String>) -> Result<Option<String>, String> {
+    match raw {
+        Some(raw) if !raw.trim().is_empty() => {
+            parse_usher_front(&raw).map_err(|e| format!("CITY17_USHER_FRONT {}", e))
+        }
+        _ => Ok(Some(DEFAULT_USHER_FRONT.to_owned())),
+    }
+}
+
+/// Check a `CITY17_RESOLVE_DNS` value, a DNS server as `ip` or `ip:port`, e.g. `8.8.8.8`.
+pub fn parse_dns_server(raw: &str) -> Result<SocketAddr, String> {
+    let raw = raw.trim();
+    let server = raw.parse().or_else(|_| raw.parse().map(|ip: IpAddr| SocketAddr::new(ip, 53)));
+    server.map_err(|_| format!("must be ip or ip:port, not {:?}", raw))
+}
+
+/// Get the DNS server refreshes of the built-in addresses ask from `CITY17_RESOLVE_DNS`, if
+/// it's set.
+pub fn get_resolve_dns() -> Result<Option<SocketAddr>, String> {
+    match env::var("CITY17_RESOLVE_DNS") {
+        Ok(raw) if !raw.trim().is_empty() => {
+            parse_dns_server(&raw).map(Some).map_err(|e| format!("CITY17_RESOLVE_DNS {}", e))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Get the instance to relay through from `CITY17_UPSTREAM`, if there is one.
+fn get_relay() -> Result<Option<Relay>, String> {
+    let base = match env::var("CITY17_UPSTREAM") {
+        Ok(base) if !base.trim().is_empty() => base.trim().trim_end_matches('/').to_owned(),
+        _ => return Ok(None),
+    };
+    if !base.starts_with("http://") && !base.starts_with("https://") {
+        return Err(format!("CITY17_UPSTREAM must be an http:// or https:// URL, not {:?}", base));
+    }
+    let key = env::var("CITY17_UPSTREAM_KEY").ok().filter(|k| !k.is_empty());
+    Ok(Some(Relay { base, key }))
+}
+
+/// Check a `CITY17_DIRECT_GQL` value: `always` to skip GQL's front, `fallback` to go direct only
+/// when the front drops a request, or `never`.
+pub fn parse_direct_gql(raw: &str) -> Result<Option<DirectGql>, String> {
+    let always = match raw.trim().to_ascii_lowercase().as_str() {
+        "always" => true,
+        "fallback" => false,
+        "never" => return Ok(None),
+        _ => return Err(format!("must be always, fallback, or never, not {:?}", raw)),
+    };
+    Ok(Some(DirectGql { url: DIRECT_GQL_URL.to_owned(), always }))
+}
+
+/// Get whether GQL is reached directly from `CITY17_DIRECT_GQL`, by default as a fallback.
+fn get_direct_gql() -> Result<Option<DirectGql>, String> {
+    match env::var("CITY17_DIRECT_GQL") {
+        Ok(raw) if !raw.trim().is_empty() => {
+            parse_direct_gql(&raw).map_err(|e| format!("CITY17_DIRECT_GQL {}", e))
+        }
+        _ => Ok(Upstream::default().direct_gql),
+    }
+}
+
+/// The entries of a comma-separated list like `CITY17_VOD_ALLOWLIST`, trimmed, skipping blanks.
+pub fn split_list(raw: &str) -> impl Iterator<Item = &str> {
+    raw.split(',').map(str::trim).filter(|entry| !entry.is_empty())
+}
+
+/// Whether an on/off environment variable is set to on.
+pub(crate) fn env_flag(key: &str) -> bool {
+    matches!(env::var(key).as_deref(), Ok("1") | Ok("true"))
+}
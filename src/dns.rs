@@ -0,0 +1,107 @@
+//! Looking hosts up through a DNS server of our choosing, over TCP. Where the local resolver
+//! can't be trusted with Twitch's domains, a public one usually still answers over TCP.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// The query ID. Nothing else shares the connection, so any will do.
+const QUERY_ID: u16 = 0x1717;
+
+/// `host`'s IPv4 addresses according to `server`.
+pub async fn lookup_a(server: SocketAddr, host: &str) -> io::Result<Vec<Ipv4Addr>> {
+    let query = a_query(host)?;
+    let mut stream = TcpStream::connect(server).await?;
+    // over TCP, each message starts with its length
+    let mut message = (query.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(&query);
+    stream.write_all(&message).await?;
+    let length = stream.read_u16().await?;
+    let mut response = vec![0; usize::from(length)];
+    stream.read_exact(&mut response).await?;
+    if let Some(e) = response_error(&response) {
+        return Err(e);
+    }
+    parse_a_records(&response)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed DNS response"))
+}
+
+/// What's wrong with `response` by its own header: cut short (TC), which over TCP means the
+/// server gave up on it, or an RCODE other than NOERROR, like SERVFAIL or NXDOMAIN. `None` for
+/// a response that says it's fine, or is too short to say anything.
+pub fn response_error(response: &[u8]) -> Option<io::Error> {
+    let flags = response.get(2..4)?;
+    if flags[0] & 0x02 != 0 {
+        return Some(io::Error::new(io::ErrorKind::InvalidData, "truncated DNS response"));
+    }
+    let e = match flags[1] & 0x0F {
+        0 => return None,
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        _ => "an error",
+    };
+    Some(io::Error::other(format!("the DNS server answered {}", e)))
+}
+
+/// A query for `host`'s A records, recursion desired.
+pub fn a_query(host: &str) -> io::Result<Vec<u8>> {
+    let mut query = QUERY_ID.to_be_bytes().to_vec();
+    // flags with RD set, one question, no records
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            let e = format!("{:?} isn't a hostname", host);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    // end of the name, then type A, class IN
+    query.extend_from_slice(&[0, 0, 1, 0, 1]);
+    Ok(query)
+}
+
+/// The A records in the answer section of `response`, or `None` if it isn't a well-formed
+/// answer to our query. CNAMEs on the way there are skipped.
+pub fn parse_a_records(response: &[u8]) -> Option<Vec<Ipv4Addr>> {
+    let header = response.get(..12)?;
+    if header[..2] != QUERY_ID.to_be_bytes() || header[2] & 0x80 == 0 {
+        return None;
+    }
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let answers = u16::from_be_bytes([header[6], header[7]]);
+    let mut at = 12;
+    for _ in 0..questions {
+        at = skip_name(response, at)? + 4;
+    }
+    let mut ips = Vec::new();
+    for _ in 0..answers {
+        at = skip_name(response, at)?;
+        let fixed = response.get(at..at + 10)?;
+        let kind = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let length = usize::from(u16::from_be_bytes([fixed[8], fixed[9]]));
+        let data = response.get(at + 10..at + 10 + length)?;
+        if kind == 1 && length == 4 {
+            ips.push(Ipv4Addr::new(data[0], data[1], data[2], data[3]));
+        }
+        at += 10 + length;
+    }
+    Some(ips)
+}
+
+/// Where the name starting at `at` ends: after its last label, or after a pointer to the rest.
+fn skip_name(message: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        let length = *message.get(at)?;
+        match length {
+            0 => return Some(at + 1),
+            _ if length & 0xC0 == 0xC0 => return Some(at + 2),
+            _ => at += 1 + usize::from(length),
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! `?dryrun=1`: the upstream requests a playlist request would make, described instead of sent.
+//! For debugging a deployment or writing a client, so it only works with `CITY17_DEBUG=1`.
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::config::{env_flag, Upstream};
+use crate::gql::{
+    access_token_request, headers_for, latest_vod_request, stream_metadata_request, viewer_gql_url,
+    PlaybackAccessToken, Variables, GQL_HASHES,
+};
+use crate::playlist::CODECS;
+use crate::relay::relay_request;
+use crate::usher::{front_url, USHER_HOST};
+
+/// Whether `?dryrun=1` is allowed, from `CITY17_DEBUG`.
+pub(crate) static DEBUG: Lazy<bool> = Lazy::new(|| env_flag("CITY17_DEBUG"));
+
+/// Stands in for whatever would be random, secret, or only known once GQL has answered.
+pub const PLACEHOLDER: &str = "<placeholder>";
+
+/// The requests that fetching `var` as `player_type` would send: to another instance if
+/// relaying, otherwise GQL's token request and usher's playlist request, and with `meta` GQL's
+/// stream metadata request. Retries aren't shown.
+pub(crate) fn plan(
+    var: &Variables,
+    player_type: &str,
+    meta: bool,
+    hops: u32,
+    upstream: &Upstream,
+) -> Value {
+    json!({ "dry_run": true, "requests": fetch_requests(var, player_type, meta, hops, upstream) })
+}
+
+/// [`plan`] for a channel's latest VOD, whose ID GQL is asked for first.
+pub(crate) fn plan_latest_vod(
+    channel: &str,
+    player_type: &str,
+    hops: u32,
+    upstream: &Upstream,
+) -> Value {
+    let mut requests = vec![gql(&latest_vod_request(channel), upstream)];
+    let vod = Variables::VOD(PLACEHOLDER.to_owned());
+    requests.extend(fetch_requests(&vod, player_type, false, hops, upstream));
+    json!({ "dry_run": true, "requests": requests })
+}
+
+fn fetch_requests(
+    var: &Variables,
+    player_type: &str,
+    meta: bool,
+    hops: u32,
+    upstream: &Upstream,
+) -> Vec<Value> {
+    if let Some(relay) = &upstream.relay {
+        let (url, headers) = relay_request(var, player_type, meta, relay, hops);
+        let headers = headers.iter().map(|(name, value)| (*name, value.as_str()));
+        let mut headers = pairs(&headers.collect::<Vec<_>>());
+        if relay.key.is_some() {
+            headers.insert("X-API-Key".to_owned(), PLACEHOLDER.into());
+        }
+        if upstream.oauth.is_some() {
+            headers.insert("Authorization".to_owned(), PLACEHOLDER.into());
+        }
+        return vec![json!({ "stage": "relay", "method": "GET", "url": url, "headers": headers })];
+    }
+    let mut request = access_token_request(var, &GQL_HASHES[0]);
+    request.variables.player_type = player_type;
+    let mut requests = vec![gql(&request, upstream), usher(var, upstream)];
+    if let (true, Variables::Channel(channel)) = (meta, var) {
+        requests.push(gql(&stream_metadata_request(channel), upstream));
+    }
+    requests
+}
+
+fn gql<T: Serialize>(body: &T, upstream: &Upstream) -> Value {
+    let ids = upstream.fixed_ids.as_ref();
+    let device_id = ids.map_or(PLACEHOLDER, |ids| ids.device_id.as_str());
+    let mut headers = pairs(&headers_for(device_id, upstream));
+    let mut url = upstream.gql_url.as_str();
+    if upstream.oauth.is_some() {
+        headers.insert("Authorization".to_owned(), PLACEHOLDER.into());
+        url = viewer_gql_url(upstream);
+    }
+    json!({
+        "stage": "gql",
+        "method": "POST",
+        "url": url,
+        "headers": headers,
+        "body": body,
+    })
+}
+
+fn usher(var: &Variables, upstream: &Upstream) -> Value {
+    let token = PlaybackAccessToken {
+        value: PLACEHOLDER.to_owned(),
+        signature: PLACEHOLDER.to_owned(),
+        typename: "PlaybackAccessToken".to_owned(),
+    };
+    let ids = upstream.fixed_ids.as_ref();
+    let p = ids.map_or_else(|| PLACEHOLDER.to_owned(), |ids| ids.p.to_string());
+    let session = ids.map_or(PLACEHOLDER, |ids| ids.play_session_id.as_str());
+    json!({
+        "stage": "usher",
+        "method": "GET",
+        "url": front_url(&var.get_url(&upstream.usher_base), upstream),
+        "headers": pairs(&[("Host", USHER_HOST)]),
+        "query": pairs(&token.gen_query(&p, session, CODECS, true)),
+    })
+}
+
+fn pairs(pairs: &[(&str, &str)]) -> Map<String, Value> {
+    pairs.iter().map(|(name, value)| (name.to_string(), (*value).into())).collect()
+}
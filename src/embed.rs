@@ -0,0 +1,93 @@
+//! The token, fronting, and usher pipeline without the server, for programs that want playlists
+//! directly. Build with `default-features = false` to leave Rocket out entirely.
+//!
+//! None of the server's policies apply here: there's no cache, maintenance mode, VOD allowlist,
+//! or audio-only handling, so what to do about those is up to the caller.
+
+use crate::client::{client, front_client};
+use crate::config::Upstream;
+use crate::error::ErrorResponder;
+use crate::gql::{validate_channel, Variables, PLAYER_TYPE};
+use crate::playlist::{is_audio_only, limit_renditions, stream_started_at, variants, Variant};
+use crate::usher::{fetch_playlist, Attempts, FetchInfo};
+use crate::Error;
+
+/// How a [`City17Client`] reaches Twitch.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Where GQL and usher requests go, and how long each gets. The default is Twitch, through
+    /// the same fronts the server uses.
+    pub upstream: Upstream,
+}
+
+/// What to do to a playlist before it's returned.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Keep only this many renditions, highest bandwidth first. audio_only is always kept.
+    pub max_renditions: Option<usize>,
+}
+
+/// A fetched master playlist, read in full.
+#[derive(Clone, Debug)]
+pub struct Fetched {
+    /// The playlist as usher sent it, less any renditions [`Options`] dropped.
+    pub m3u8: String,
+    /// The token's expiry, when the stream started, how long each stage took, and so on.
+    pub info: FetchInfo,
+}
+
+impl Fetched {
+    /// The renditions in the playlist, in its order.
+    pub fn variants(&self) -> Vec<Variant<'_>> {
+        variants(&self.m3u8)
+    }
+}
+
+/// Fetches playlists the way the server does. Cheap to clone; the underlying HTTP client is
+/// shared by the whole process.
+#[derive(Clone, Debug)]
+pub struct City17Client {
+    upstream: Upstream,
+}
+
+impl City17Client {
+    /// Build the HTTP clients now, so that a broken TLS setup shows up here rather than on the
+    /// first fetch.
+    pub fn new(config: Config) -> Result<Self, Error> {
+        client()?;
+        front_client()?;
+        Ok(Self { upstream: config.upstream })
+    }
+
+    /// `channel`'s live playlist. An offline channel is a 404 from usher.
+    pub async fn get_live_playlist(
+        &self,
+        channel: &str,
+        options: Options,
+    ) -> Result<Fetched, Error> {
+        let channel = validate_channel(channel)?;
+        self.fetch(Variables::Channel(channel), options).await
+    }
+
+    /// The playlist of the VOD `id`.
+    pub async fn get_vod_playlist(&self, id: u64, options: Options) -> Result<Fetched, Error> {
+        self.fetch(Variables::VOD(id.to_string()), options).await
+    }
+
+    async fn fetch(&self, var: Variables, options: Options) -> Result<Fetched, Error> {
+        let (playlist, mut info) =
+            fetch_playlist(&var, PLAYER_TYPE, &self.upstream, &mut Attempts::new())
+                .await
+                .map_err(|ErrorResponder(e, _)| e)?;
+        let body = playlist.collect().await?;
+        if matches!(var, Variables::Channel(_)) {
+            info.started_at = stream_started_at(&body);
+        }
+        info.audio_only = is_audio_only(&body);
+        let m3u8 = match options.max_renditions {
+            Some(max) => limit_renditions(&body, max),
+            None => String::from_utf8_lossy(&body).into_owned(),
+        };
+        Ok(Fetched { m3u8, info })
+    }
+}
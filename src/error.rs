@@ -0,0 +1,487 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::gql::GqlError;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("http error")]
+    Http(#[from] reqwest::Error),
+    #[error("serde error: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[cfg(feature = "fast-json")]
+    #[error("simd-json error: {0}")]
+    SimdJson(#[from] simd_json::Error),
+    #[error("bad input: {0}")]
+    Input(Cow<'static, str>),
+    /// Looking a name up for `/resolve` failed.
+    #[cfg(feature = "resolve")]
+    #[error("couldn't resolve: {0}")]
+    Resolve(std::io::Error),
+    #[error("usher response is not a playlist")]
+    NotPlaylist,
+    /// Usher answered with a playlist, but one without a single variant to play.
+    #[error("usher's playlist has no variants")]
+    NoVariants,
+    /// Usher answered with its JSON error list instead of a playlist. Holds the status it
+    /// answered with, and its `error_code` and `error` for the first error.
+    #[error("usher: {message} ({code})")]
+    Usher { status: u16, code: String, message: String },
+    #[error("only audio is available")]
+    AudioOnly,
+    /// Usher refused the source or some bitrates to an anonymous viewer.
+    #[error("quality is restricted to subscribers")]
+    QualityRestricted,
+    #[error("preview response is not an image")]
+    NotImage,
+    /// Holds the limit that was passed.
+    #[error("upstream response is over {0} bytes")]
+    TooLarge(usize),
+    /// GQL answered 429, or did recently enough that we're not asking yet. Holds how much
+    /// longer to wait.
+    #[error("GQL is rate limiting, retry in {}s", ceil_secs(*.0))]
+    Throttled(Duration),
+    /// GQL or usher answered with a 5xx, which happens in bursts while Twitch is down or under
+    /// maintenance. Nothing on our side needs fixing, so clients are told to try again.
+    #[error("{upstream} answered {status}, Twitch may be having an outage")]
+    UpstreamDown { upstream: &'static str, status: u16 },
+    #[error("no persisted query hash was recognized by GQL")]
+    PersistedQueryNotFound,
+    /// GQL answered with the token `null`: the channel doesn't exist, or the VOD was deleted
+    /// or never was.
+    #[error("GQL has no token for it, the channel or VOD may not exist")]
+    NoToken,
+    /// GQL answered 200, but with errors instead of data.
+    #[error("GQL answered with errors: {}", gql_messages(.0).join("; "))]
+    Gql(Vec<GqlError>),
+    /// GQL always sends JSON, so this came from whatever is in front of it.
+    #[error("empty or non-JSON upstream response, front may be misconfigured")]
+    NotJson(usize),
+    #[error("down for maintenance: {0}")]
+    Maintenance(String),
+    /// GQL has no stream for the channel, so there's no preview of it.
+    #[error("{0} isn't live")]
+    Offline(String),
+    /// The channel has no VODs, or doesn't exist; GQL doesn't tell those apart.
+    #[error("{0} has no VODs")]
+    NoVods(String),
+    /// GQL has no clip with the slug. It may have been deleted.
+    #[error("there's no clip {0}")]
+    NoClip(String),
+    #[error("not supported: {0}")]
+    Unsupported(&'static str),
+    #[error("not allowed: {0}")]
+    NotAllowed(&'static str),
+    #[error("panicked while handling the request")]
+    Panicked,
+    /// The request already came through this many instances relaying to each other.
+    #[error("relayed through {0} instances already, CITY17_UPSTREAM may go in a circle")]
+    TooManyHops(u32),
+    /// The instance relayed through answered with an error, whose status and body are passed
+    /// on as they were.
+    #[error("relayed: {}", .body["display"].as_str().unwrap_or("no description"))]
+    Relayed { status: u16, body: serde_json::Value },
+    /// An error from a fetch shared between several requests.
+    #[error(transparent)]
+    Shared(Arc<Error>),
+}
+
+/// What went wrong, as clients see it in an error body's `kind`. The status code we answer with
+/// follows from it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// An upstream request took too long.
+    Timeout,
+    /// An upstream host's name didn't resolve.
+    Dns,
+    /// Couldn't connect to an upstream host, or the connection broke.
+    Connect,
+    /// Upstream answered with an error status other than a 5xx.
+    UpstreamStatus,
+    /// Upstream answered 5xx.
+    UpstreamDown,
+    /// Upstream's response couldn't be read or parsed.
+    Parse,
+    /// Any other failure of an upstream request.
+    Request,
+    Input,
+    NotPlaylist,
+    NoVariants,
+    /// Usher said why it has no playlist for us; see the body's `error_code`.
+    Usher,
+    AudioOnly,
+    QualityRestricted,
+    NotImage,
+    TooLarge,
+    Throttled,
+    PersistedQueryNotFound,
+    NoToken,
+    Gql,
+    NotJson,
+    Maintenance,
+    Offline,
+    NoVods,
+    NoClip,
+    Unsupported,
+    NotAllowed,
+    Panicked,
+    TooManyHops,
+}
+
+impl ErrorKind {
+    pub fn status_code(self) -> u16 {
+        // codes are nonsense, just to make it slightly easier to distinguish them
+        match self {
+            ErrorKind::Timeout => 504,
+            ErrorKind::Dns | ErrorKind::Connect | ErrorKind::Request => 510,
+            // the real code is passed through by Error::status_code
+            ErrorKind::UpstreamStatus => 502,
+            ErrorKind::UpstreamDown => 502,
+            ErrorKind::Parse => 501,
+            ErrorKind::Input => 400,
+            ErrorKind::NotPlaylist => 502,
+            ErrorKind::NoVariants => 502,
+            // Error::status_code picks one from usher's error_code
+            ErrorKind::Usher => 502,
+            ErrorKind::AudioOnly => 502,
+            ErrorKind::QualityRestricted => 403,
+            ErrorKind::NotImage => 502,
+            ErrorKind::TooLarge => 502,
+            ErrorKind::Throttled => 429,
+            ErrorKind::PersistedQueryNotFound => 502,
+            ErrorKind::NoToken => 404,
+            ErrorKind::Gql => 502,
+            ErrorKind::NotJson => 502,
+            ErrorKind::Maintenance => 503,
+            ErrorKind::Offline => 404,
+            ErrorKind::NoVods => 404,
+            ErrorKind::NoClip => 404,
+            ErrorKind::Unsupported => 501,
+            ErrorKind::NotAllowed => 403,
+            ErrorKind::Panicked => 500,
+            ErrorKind::TooManyHops => 508,
+        }
+    }
+}
+
+/// An error as clients get it. Fields that don't apply to an error are left out, except those
+/// every client is expected to look at.
+#[derive(Debug, Serialize)]
+struct ErrorBody<'a> {
+    result: &'static str,
+    kind: ErrorKind,
+    stage: &'a str,
+    display: String,
+    /// The host an upstream request went to, which for GQL and usher is the front.
+    host: Option<String>,
+    upstream_status: Option<u16>,
+    retryable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<&'a str>,
+    /// Usher's `error_code`, for clients to tell e.g. an offline channel from a geoblock.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_code: Option<&'a str>,
+    /// What GQL said went wrong, in its own words.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gql_errors: Option<Vec<&'a str>>,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Http(e) if e.is_timeout() => ErrorKind::Timeout,
+            Error::Http(e) if e.is_connect() && is_dns(e) => ErrorKind::Dns,
+            Error::Http(e) if e.is_connect() => ErrorKind::Connect,
+            Error::Http(e) if e.is_status() => ErrorKind::UpstreamStatus,
+            Error::Http(e) if e.is_body() || e.is_decode() => ErrorKind::Parse,
+            Error::Http(_) => ErrorKind::Request,
+            Error::Serde(_) => ErrorKind::Parse,
+            #[cfg(feature = "fast-json")]
+            Error::SimdJson(_) => ErrorKind::Parse,
+            Error::Input(_) => ErrorKind::Input,
+            #[cfg(feature = "resolve")]
+            Error::Resolve(_) => ErrorKind::Dns,
+            Error::NotPlaylist => ErrorKind::NotPlaylist,
+            Error::NoVariants => ErrorKind::NoVariants,
+            Error::Usher { .. } => ErrorKind::Usher,
+            Error::AudioOnly => ErrorKind::AudioOnly,
+            Error::QualityRestricted => ErrorKind::QualityRestricted,
+            Error::NotImage => ErrorKind::NotImage,
+            Error::TooLarge(_) => ErrorKind::TooLarge,
+            Error::Throttled(_) => ErrorKind::Throttled,
+            Error::UpstreamDown { .. } => ErrorKind::UpstreamDown,
+            Error::PersistedQueryNotFound => ErrorKind::PersistedQueryNotFound,
+            Error::NoToken => ErrorKind::NoToken,
+            Error::Gql(_) => ErrorKind::Gql,
+            Error::NotJson(_) => ErrorKind::NotJson,
+            Error::Maintenance(_) => ErrorKind::Maintenance,
+            Error::Offline(_) => ErrorKind::Offline,
+            Error::NoVods(_) => ErrorKind::NoVods,
+            Error::NoClip(_) => ErrorKind::NoClip,
+            Error::Unsupported(_) => ErrorKind::Unsupported,
+            Error::NotAllowed(_) => ErrorKind::NotAllowed,
+            Error::Panicked => ErrorKind::Panicked,
+            Error::TooManyHops(_) => ErrorKind::TooManyHops,
+            Error::Relayed { body, .. } => {
+                serde_json::from_value(body["kind"].clone()).unwrap_or(ErrorKind::Request)
+            }
+            Error::Shared(e) => e.kind(),
+        }
+    }
+
+    pub fn status_code(&self) -> u16 {
+        match self.inner() {
+            Error::Relayed { status, .. } => return *status,
+            Error::Usher { status, code, .. } => return usher_status_code(*status, code),
+            _ => {}
+        }
+        match self.kind() {
+            ErrorKind::UpstreamStatus => self.upstream_status().unwrap_or(510),
+            kind => kind.status_code(),
+        }
+    }
+
+    /// Whether this is a timeout, failed connection, or server error, where trying again as-is
+    /// might work.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Http(e) => {
+                e.is_timeout() || e.is_connect() || e.status().is_some_and(|s| s.is_server_error())
+            }
+            Error::UpstreamDown { .. } => true,
+            Error::Relayed { body, .. } => body["retryable"].as_bool().unwrap_or(false),
+            Error::Shared(e) => e.is_transient(),
+            _ => false,
+        }
+    }
+
+    /// Whether the connection upstream timed out or couldn't be made, as when the front drops it.
+    /// Nothing was answered, so sending the request again can't do harm.
+    pub fn is_dropped(&self) -> bool {
+        match self {
+            Error::Http(e) => e.is_timeout() || e.is_connect(),
+            Error::Shared(e) => e.is_dropped(),
+            _ => false,
+        }
+    }
+
+    /// Whether connecting failed outright: not a timeout, which is how a blocked address
+    /// usually fails, and not a failed lookup. For an address from the resolver overrides, this
+    /// means it may no longer be the host's.
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            Error::Http(e) => e.is_connect() && !e.is_timeout() && !is_dns(e),
+            Error::Shared(e) => e.is_unreachable(),
+            _ => false,
+        }
+    }
+
+    /// Whether upstream answered 403, which from usher means it didn't accept the token.
+    pub fn is_forbidden(&self) -> bool {
+        self.upstream_status() == Some(403)
+    }
+
+    /// Whether upstream answered 404, which from usher means the channel isn't live.
+    pub fn is_not_found(&self) -> bool {
+        self.upstream_status() == Some(404)
+    }
+
+    /// Whether GQL answered but wouldn't give out a token: a 4xx, or a response with the token
+    /// missing or `null`, or errors in its place. Unlike a timeout or a stale hash, asking
+    /// differently might help.
+    pub fn is_refused(&self) -> bool {
+        match self {
+            Error::Http(e) => e.status().is_some_and(|s| s.is_client_error()),
+            Error::Serde(_) | Error::NoToken | Error::Gql(_) => true,
+            #[cfg(feature = "fast-json")]
+            Error::SimdJson(_) => true,
+            Error::Shared(e) => e.is_refused(),
+            _ => false,
+        }
+    }
+
+    /// The status upstream answered with, if it answered with an error.
+    pub fn upstream_status(&self) -> Option<u16> {
+        match self {
+            Error::Http(e) => e.status().map(|s| s.as_u16()),
+            Error::UpstreamDown { status, .. } => Some(*status),
+            Error::Usher { status, .. } => Some(*status).filter(|status| *status >= 400),
+            Error::Relayed { body, .. } => body["upstream_status"].as_u64().map(|s| s as u16),
+            Error::Shared(e) => e.upstream_status(),
+            _ => None,
+        }
+    }
+
+    /// The host an upstream request that failed went to.
+    fn host(&self) -> Option<String> {
+        match self {
+            Error::Http(e) => e.url().and_then(|url| url.host_str()).map(String::from),
+            Error::Shared(e) => e.host(),
+            _ => None,
+        }
+    }
+
+    /// How long the client should wait before asking again, sent as `Retry-After`.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::Throttled(wait) => Some(*wait),
+            Error::Relayed { body, .. } => body["retry_after"].as_u64().map(Duration::from_secs),
+            Error::Shared(e) => e.retry_after(),
+            _ => None,
+        }
+    }
+
+    /// The error without any [`Error::Shared`] around it.
+    fn inner(&self) -> &Error {
+        match self {
+            Error::Shared(e) => e.inner(),
+            e => e,
+        }
+    }
+
+    pub fn to_json(&self, stage: &str) -> serde_json::Value {
+        if let Error::Relayed { body, .. } = self.inner() {
+            return body.clone();
+        }
+        let kind = self.kind();
+        let mut body = ErrorBody {
+            result: "error",
+            kind,
+            stage,
+            display: self.to_string(),
+            host: self.host(),
+            upstream_status: self.upstream_status(),
+            retryable: self.is_transient() || kind == ErrorKind::Throttled,
+            hint: (kind == ErrorKind::UpstreamDown).then_some("upstream outage"),
+            reason: None,
+            retry_after: self.retry_after().map(ceil_secs),
+            body_length: None,
+            limit_bytes: None,
+            channel: None,
+            message: None,
+            error_code: None,
+            gql_errors: None,
+        };
+        match self.inner() {
+            Error::NotJson(length) => body.body_length = Some(*length),
+            Error::TooLarge(limit) => body.limit_bytes = Some(*limit),
+            Error::Offline(channel) => {
+                body.reason = Some("offline");
+                body.channel = Some(channel);
+            }
+            Error::NoVods(channel) => {
+                body.reason = Some("no_vods");
+                body.channel = Some(channel);
+            }
+            Error::NoClip(_) => body.reason = Some("no_clip"),
+            Error::NoToken => body.reason = Some("token_null"),
+            Error::Usher { code, message, .. } => {
+                body.error_code = Some(code);
+                body.message = Some(message);
+                if USHER_OFFLINE_CODES.contains(&code.as_str()) {
+                    body.reason = Some("offline");
+                }
+            }
+            Error::Gql(errors) => body.gql_errors = Some(gql_messages(errors)),
+            Error::Maintenance(message) => body.message = Some(message),
+            Error::Input(message) => body.message = Some(message),
+            Error::Unsupported(message) | Error::NotAllowed(message) => {
+                body.message = Some(message)
+            }
+            _ => {}
+        }
+        serde_json::to_value(body).expect("error bodies always serialize")
+    }
+}
+
+/// Usher's `error_code`s for when there's nothing to play: the channel isn't live, or doesn't
+/// exist at all.
+const USHER_OFFLINE_CODES: [&str; 1] = ["does_not_exist"];
+
+/// Usher's `error_code`s for when there's something to play, but not for us.
+const USHER_FORBIDDEN_CODES: [&str; 3] =
+    ["content_geoblocked", "unauthorized_entitlements", "vod_manifest_restricted"];
+
+/// What to answer a usher error with: 404 if there's nothing to play, 403 if we may not play
+/// it, and otherwise usher's own status if it was a 4xx.
+fn usher_status_code(status: u16, code: &str) -> u16 {
+    if USHER_OFFLINE_CODES.contains(&code) {
+        404
+    } else if USHER_FORBIDDEN_CODES.contains(&code) {
+        403
+    } else if (400..500).contains(&status) {
+        status
+    } else {
+        ErrorKind::Usher.status_code()
+    }
+}
+
+fn gql_messages(errors: &[GqlError]) -> Vec<&str> {
+    errors.iter().map(|e| e.message.as_str()).collect()
+}
+
+/// Whether a failed connection failed at the name lookup.
+fn is_dns(e: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(e);
+    while let Some(e) = source {
+        if e.to_string().starts_with("dns error") {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// Whole seconds, rounded up so a client never comes back too early.
+pub fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// Holds an Error and the stage at which it occurred (input, GQL token, or M3U playlist).
+/// The server responds with it as JSON, see [`Error::to_json`].
+pub(crate) struct ErrorResponder(pub(crate) Error, pub(crate) &'static str);
+
+impl fmt::Display for ErrorResponder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl fmt::Debug for ErrorResponder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+impl std::error::Error for ErrorResponder {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+pub(crate) trait ResultExt<T> {
+    /// Convert the Error in this Result (if present) into an ErrorResponder.
+    fn into_responder(self, stage: &'static str) -> Result<T, ErrorResponder>;
+}
+
+impl<T> ResultExt<T> for Result<T, Error> {
+    fn into_responder(self, stage: &'static str) -> Result<T, ErrorResponder> {
+        self.map_err(|e| ErrorResponder(e, stage))
+    }
+}
@@ -0,0 +1,930 @@
+//! Twitch's GQL API, which hands out the access tokens usher wants.
+
+use std::collections::HashMap;
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use once_cell::sync::Lazy;
+use percent_encoding::percent_decode_str;
+use rand::Rng;
+use reqwest::header::{HeaderValue, RETRY_AFTER};
+use reqwest::{Client, StatusCode};
+use serde::de::Error as _;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::client::{
+    check_status, client, dns_client, dns_front_client, front_client, send, GQL_RETRIES,
+    GQL_RETRY_DELAY,
+};
+use crate::config::{env_flag, DirectGql, Upstream, DIRECT_GQL_URL};
+use crate::latency::{self, Stage};
+use crate::{generate_id, get_rng, Error};
+
+/// Client-ID of Twitch's web player. Shown in the clear if you load the main page.
+/// Try `curl -s https://www.twitch.tv | tidy -q | grep '"Client-ID":"'`.
+pub const TWITCH_CLIENT: &str = "kimne78kx3ncx6brgo4mv6wki5h1ko";
+
+/// What GQL's front is told the request is for.
+pub const GQL_HOST: &str = "gql.twitch.tv";
+
+/// Hash of the PlaybackAccessToken persisted query, as sent by the web player.
+pub const PLAYBACK_ACCESS_TOKEN_HASH: &str =
+    "0828119ded1c13477966434e15800ff57ddacf13ba1911c129dc2200705b0712";
+
+/// Hash of the VideoAccessToken_Clip persisted query, which clips get their token from instead.
+pub const CLIP_ACCESS_TOKEN_HASH: &str =
+    "36b89d2507fce29e5ca551df756d27c1cfe079e2609642b4390aa4c35796eb11";
+
+/// The player GQL is told is asking. `CITY17_EMBED_FALLBACK` can retry as `embed` instead.
+pub const PLAYER_TYPE: &str = "site";
+
+/// The players a request can ask for a token as with `?player_type=`, for trying out how Twitch
+/// treats them without a rebuild.
+pub const PLAYER_TYPES: [&str; 3] = [PLAYER_TYPE, "embed", "popout"];
+
+/// Check a `player_type` parameter against [`PLAYER_TYPES`], rather than pass Twitch whatever
+/// came in.
+pub fn validate_player_type(player_type: &str) -> Result<&'static str, Error> {
+    let known = PLAYER_TYPES.iter().find(|known| **known == player_type);
+    known.copied().ok_or(Error::Input("player_type must be site, embed, or popout".into()))
+}
+
+/// Check a channel name before it goes anywhere near GQL, returning it lowercased.
+///
+/// Names copied out of a URL are cleaned up first: percent-decoded, then trimmed of whitespace
+/// and trailing slashes. Twitch logins are 1-25 ASCII letters, digits, and underscores, so
+/// anything non-ASCII is rejected as such rather than as a bad character. Path separators and
+/// control characters are called out separately since they can only be someone poking at the
+/// route.
+pub fn validate_channel(channel: &str) -> Result<String, Error> {
+    let decoded = percent_decode_str(channel).decode_utf8_lossy();
+    let channel = decoded.trim().trim_end_matches('/').trim_end();
+    if !channel.is_ascii() {
+        return Err(Error::Input("channel must be ASCII, as Twitch logins are".into()));
+    }
+    if channel.contains(|c: char| c == '/' || c == '\\' || c.is_control()) {
+        return Err(Error::Input("channel contains a path separator or control character".into()));
+    }
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    if channel.is_empty() || channel.len() > 25 || !channel.chars().all(valid_char) {
+        return Err(Error::Input("channel must be 1-25 characters of A-Z, 0-9, and _".into()));
+    }
+    Ok(channel.to_lowercase())
+}
+
+/// Body of the PlaybackAccessToken request for `var`, using the persisted query `hash`. Clips
+/// get their token from [`clip_request`] instead.
+pub fn access_token_request<'a>(var: &'a Variables, hash: &'a str) -> AccessTokenRequest<'a> {
+    let (login, vod_id) = match var {
+        Variables::Channel(channel) => (channel.as_str(), ""),
+        Variables::VOD(id) => ("", id.as_str()),
+    };
+    AccessTokenRequest {
+        extensions: RequestExtensions {
+            persisted_query: PersistedQuery { sha256_hash: hash, version: 1 },
+        },
+        operation_name: "PlaybackAccessToken",
+        variables: AccessTokenVariables {
+            is_live: matches!(var, Variables::Channel(_)),
+            is_vod: matches!(var, Variables::VOD(_)),
+            login,
+            player_type: PLAYER_TYPE,
+            vod_id,
+        },
+    }
+}
+
+/// The PlaybackAccessToken request. Only the variables change between requests.
+///
+/// Fields are in alphabetical order, as they were when this was built with `json!`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessTokenRequest<'a> {
+    pub extensions: RequestExtensions<'a>,
+    pub operation_name: &'static str,
+    pub variables: AccessTokenVariables<'a>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestExtensions<'a> {
+    pub persisted_query: PersistedQuery<'a>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PersistedQuery<'a> {
+    pub sha256_hash: &'a str,
+    pub version: u32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessTokenVariables<'a> {
+    pub is_live: bool,
+    pub is_vod: bool,
+    pub login: &'a str,
+    pub player_type: &'a str,
+    #[serde(rename = "vodID")]
+    pub vod_id: &'a str,
+}
+
+/// Parse GQL's response to the PlaybackAccessToken request.
+pub fn parse_access_token_response(body: &[u8]) -> Result<AccessTokenResponse, Error> {
+    check_json(body)?;
+    serde_json::from_slice::<Envelope>(body)?.into_response()
+}
+
+/// Parse GQL's response to the PlaybackAccessToken request, taking ownership of the body so that
+/// with the `fast-json` feature it can be parsed in place by simd-json.
+pub fn parse_access_token_response_owned(body: Vec<u8>) -> Result<AccessTokenResponse, Error> {
+    #[cfg(feature = "fast-json")]
+    {
+        check_json(&body)?;
+        let mut body = body;
+        simd_json::serde::from_slice::<Envelope>(&mut body)?.into_response()
+    }
+    #[cfg(not(feature = "fast-json"))]
+    parse_access_token_response(&body)
+}
+
+/// Catch a body that can't be GQL's before a parser gives a confusing error about it. A front
+/// that's misrouting requests answers with nothing, or an HTML page.
+fn check_json(body: &[u8]) -> Result<(), Error> {
+    match body.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'{') => Ok(()),
+        _ => Err(Error::NotJson(body.len())),
+    }
+}
+
+/// Everything GQL might send back. A stale hash still gets a 200, just with errors in place of
+/// the data.
+#[derive(Deserialize)]
+struct Envelope {
+    data: Option<EnvelopeData>,
+    extensions: Option<Extensions>,
+    #[serde(default)]
+    errors: Vec<GqlError>,
+}
+
+/// [`Data`] with the token allowed to be `null`, which GQL sends along with errors when only
+/// that part of the query failed.
+#[derive(Deserialize)]
+struct EnvelopeData {
+    #[serde(rename = "streamPlaybackAccessToken", alias = "videoPlaybackAccessToken")]
+    playback_access_token: Option<PlaybackAccessToken>,
+}
+
+impl Envelope {
+    fn into_response(self) -> Result<AccessTokenResponse, Error> {
+        if self.errors.iter().any(|e| e.message == "PersistedQueryNotFound") {
+            return Err(Error::PersistedQueryNotFound);
+        }
+        let token = match self.data.map(|data| data.playback_access_token) {
+            Some(Some(token)) => token,
+            // Twitch's reasons beat a complaint about a missing field or token
+            _ if !self.errors.is_empty() => return Err(Error::Gql(self.errors)),
+            Some(None) => return Err(Error::NoToken),
+            None => return Err(serde_json::Error::missing_field("data").into()),
+        };
+        let data = Data { playback_access_token: token };
+        let extensions =
+            self.extensions.ok_or_else(|| serde_json::Error::missing_field("extensions"))?;
+        Ok(AccessTokenResponse { data, extensions, errors: self.errors, remote_addr: None })
+    }
+}
+
+#[allow(dead_code)] // only read when debugging
+#[derive(Clone, Debug, Deserialize)]
+pub struct AccessTokenResponse {
+    pub data: Data,
+    pub extensions: Extensions,
+    /// Errors GQL sent along with a token anyway, about parts of the query that failed.
+    #[serde(default)]
+    pub errors: Vec<GqlError>,
+    /// Which address GQL's front answered from. Not part of the response itself.
+    #[serde(skip)]
+    pub remote_addr: Option<SocketAddr>,
+}
+
+/// One of the errors GQL can answer a 200 with, e.g. for a failed integrity check.
+#[derive(Clone, Debug, Deserialize)]
+pub struct GqlError {
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Data {
+    /// The signed access token itself.
+    ///
+    /// Can in fact be `null`, for example if the VOD ID is wrong or pointing to a deleted VOD.
+    /// That's [`Error::NoToken`] before it gets here, or [`Error::Gql`] with GQL's reasons if
+    /// it gave any.
+    // Name depends on whether it's a livestream or a VOD.
+    #[serde(rename = "streamPlaybackAccessToken", alias = "videoPlaybackAccessToken")]
+    pub playback_access_token: PlaybackAccessToken,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug, Deserialize)]
+pub struct PlaybackAccessToken {
+    pub value: String,
+    pub signature: String,
+    #[serde(rename = "__typename")]
+    pub typename: String,
+}
+
+impl PlaybackAccessToken {
+    /// When usher stops accepting this token, as a Unix timestamp.
+    ///
+    /// `value` is itself JSON, but its format isn't ours, so this is `None` rather than an error
+    /// if it doesn't parse or has no `expires`.
+    pub fn expires(&self) -> Option<i64> {
+        #[derive(Deserialize)]
+        struct Value {
+            expires: i64,
+        }
+        serde_json::from_str::<Value>(&self.value).ok().map(|v| v.expires)
+    }
+
+    /// What the token says about ads, or `None` like [`expires`](Self::expires) if `value`
+    /// doesn't parse. Flags that are missing count as off.
+    pub fn ad_flags(&self) -> Option<AdFlags> {
+        serde_json::from_str(&self.value).ok()
+    }
+
+    /// The query for usher. `allow_source` off asks for only the transcodes, which is what
+    /// usher gives anonymous viewers when the source is restricted to subscribers.
+    pub fn gen_query<'a>(
+        &'a self,
+        p: &'a str,
+        play_session_id: &'a str,
+        codecs: &'a str,
+        allow_source: bool,
+    ) -> [(&'a str, &'a str); 12] {
+        // XXX should probably send slightly different things for a VOD? it's working so I haven't
+        //  bothered to check
+        [
+            ("player_backend", "mediaplayer"),
+            ("playlist_include_framerate", "true"),
+            ("reassignments_supported", "true"),
+            ("supported_codecs", codecs),
+            ("play_session_id", play_session_id),
+            ("cdm", "wv"),
+            ("player_version", "1.4.0"),
+            ("fast_bread", "true"), // enables low latency for live
+            ("token", &self.value),
+            ("sig", &self.signature),
+            ("allow_source", if allow_source { "true" } else { "false" }),
+            ("p", p),
+        ]
+    }
+}
+
+/// The ad settings in a token's `value`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AdFlags {
+    pub show_ads: bool,
+    pub hide_ads: bool,
+    pub server_ads: bool,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug, Deserialize)]
+pub struct Extensions {
+    #[serde(rename = "durationMilliseconds")]
+    pub duration_milliseconds: i64,
+    #[serde(rename = "operationName")]
+    pub operation_name: String,
+    #[serde(rename = "requestID")]
+    pub request_id: String,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Variables {
+    Channel(String),
+    VOD(String),
+}
+
+impl Variables {
+    /// The playlist's URL under usher's `base`, which ends with a slash.
+    pub fn get_url(&self, base: &str) -> String {
+        let endpoint = match &self {
+            Self::Channel(channel) => format!("api/channel/hls/{}.m3u8", channel),
+            Self::VOD(id) => format!("vod/{}.m3u8", id),
+        };
+        format!("{}{}", base, endpoint)
+    }
+    pub fn data(&self) -> &str {
+        match self {
+            Self::Channel(d) | Self::VOD(d) => d,
+        }
+    }
+}
+
+/// Persisted query hashes to try, in order. Twitch rotates the hash now and then, so setting
+/// `CITY17_GQL_HASHES` to a comma-separated list lets the next one be staged ahead of time.
+pub(crate) static GQL_HASHES: Lazy<Vec<String>> = Lazy::new(|| {
+    let hashes = env::var("CITY17_GQL_HASHES").unwrap_or_default();
+    let hashes: Vec<String> =
+        hashes.split(',').map(str::trim).filter(|h| !h.is_empty()).map(String::from).collect();
+    if hashes.is_empty() {
+        vec![PLAYBACK_ACCESS_TOKEN_HASH.to_owned()]
+    } else {
+        hashes
+    }
+});
+
+/// Twitch sometimes treats the `site` and `embed` players differently, so a token refused to
+/// one may be handed to the other. With `CITY17_EMBED_FALLBACK=1`, a request that GQL answers
+/// without a token, or with a 4xx, is tried once more as `embed`.
+static EMBED_FALLBACK: Lazy<bool> = Lazy::new(|| env_flag("CITY17_EMBED_FALLBACK"));
+
+/// Asks Twitch for an access token as `player_type`, falling back to the `embed` player if
+/// that's turned on and `site` didn't get one. Other player types are only ever asked as is.
+pub async fn get_access_token(
+    var: &Variables,
+    player_type: &str,
+    upstream: &Upstream,
+) -> Result<AccessTokenResponse, Error> {
+    let result = get_access_token_as(var, player_type, upstream).await;
+    if !*EMBED_FALLBACK || player_type != PLAYER_TYPE {
+        return result;
+    }
+    match result {
+        Err(e) if e.is_refused() => {
+            log::info!("GQL refused a token for {:?} as {}, trying embed: {}", var, PLAYER_TYPE, e);
+            let result = get_access_token_as(var, "embed", upstream).await;
+            if result.is_ok() {
+                log::info!("got a token for {:?} as embed", var);
+            }
+            result
+        }
+        result => {
+            if result.is_ok() {
+                log::debug!("got a token for {:?} as {}", var, PLAYER_TYPE);
+            }
+            result
+        }
+    }
+}
+
+/// Asks Twitch for an access token as `player_type`, moving on to the next persisted query hash
+/// if Twitch doesn't recognize the current one.
+async fn get_access_token_as(
+    var: &Variables,
+    player_type: &str,
+    upstream: &Upstream,
+) -> Result<AccessTokenResponse, Error> {
+    for hash in GQL_HASHES.iter() {
+        match request_access_token(var, hash, player_type, upstream).await {
+            Err(Error::PersistedQueryNotFound) => {
+                log::warn!("persisted query hash {} not found", hash);
+            }
+            result => return result,
+        }
+    }
+    Err(Error::PersistedQueryNotFound)
+}
+
+/// Asks Twitch for an access token using a randomly-generated ID.
+///
+/// Could *probably* also skip this step and use your real ID. Faster but less private, which
+/// may be a dealbreaker. Might be required server-side if you watch any subscriber-only VODs,
+/// but you wouldn't get ads anyway so the extension's fail-safe should prevent it from
+/// actually breaking client-side.
+///
+/// A viewer who does want their real ID used, for those VODs, can send their token along; see
+/// [`post_via`].
+async fn request_access_token(
+    var: &Variables,
+    hash: &str,
+    player_type: &str,
+    upstream: &Upstream,
+) -> Result<AccessTokenResponse, Error> {
+    let (body, remote_addr) = request_access_token_body(var, hash, player_type, upstream).await?;
+    let response = parse_access_token_response_owned(body.into())?;
+    Ok(AccessTokenResponse { remote_addr, ..response })
+}
+
+/// GQL's response to the PlaybackAccessToken request as `site`, with the first persisted query
+/// hash, as it came. For capturing fixtures.
+#[cfg(feature = "server")]
+pub(crate) async fn access_token_body(
+    var: &Variables,
+    upstream: &Upstream,
+) -> Result<Bytes, Error> {
+    let (body, _) = request_access_token_body(var, &GQL_HASHES[0], PLAYER_TYPE, upstream).await?;
+    Ok(body)
+}
+
+async fn request_access_token_body(
+    var: &Variables,
+    hash: &str,
+    player_type: &str,
+    upstream: &Upstream,
+) -> Result<(Bytes, Option<SocketAddr>), Error> {
+    let mut request = access_token_request(var, hash);
+    request.variables.player_type = player_type;
+    let mut retries = 0;
+    loop {
+        match post_from(&request, upstream).await {
+            // never after an answer, even a 4xx: GQL would only say the same again
+            Err(e) if e.is_dropped() && retries < GQL_RETRIES => {
+                let delay = retry_delay(retries);
+                // the rest of the fetch has to fit in what's left of it too
+                if !upstream.has_time_for(delay, latency::timeout(Stage::Gql, upstream.timeout)) {
+                    return Err(e);
+                }
+                log::info!(
+                    "GQL dropped a token request for {:?}, retrying in {:?}: {}",
+                    var,
+                    delay,
+                    e
+                );
+                sleep(delay).await;
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// How long to wait before retry number `retry`, counting from 0: [`GQL_RETRY_DELAY`] doubled
+/// that many times, plus jitter.
+fn retry_delay(retry: u32) -> Duration {
+    let delay = GQL_RETRY_DELAY * 2u32.pow(retry);
+    delay.mul_f64(get_rng().gen_range(1.0..=2.0))
+}
+
+/// Send `request` to GQL and return its response as it came, unless GQL asked us to back off.
+async fn post<T: Serialize>(request: &T, upstream: &Upstream) -> Result<Bytes, Error> {
+    let (body, _) = post_from(request, upstream).await?;
+    Ok(body)
+}
+
+/// The Device-ID for a new GQL request.
+pub(crate) fn device_id(upstream: &Upstream) -> String {
+    match &upstream.fixed_ids {
+        Some(ids) => ids.device_id.clone(),
+        None => generate_id(),
+    }
+}
+
+/// The headers every GQL request is sent with, as device `device_id`.
+pub fn gql_headers(device_id: &str) -> [(&'static str, &str); 3] {
+    // Send a request to fastly (accessible in China)
+    // and tell it we want to talk to Twitch's GQL API (blocked in China)
+    // This workaround is necessary even with the hard-coded resolver due to TLS SNI
+    // sending the hostname in the clear.
+    [("Host", GQL_HOST), ("Client-ID", TWITCH_CLIENT), ("Device-ID", device_id)]
+}
+
+/// The headers a GQL request for `upstream` is sent with, other than a viewer's token: those
+/// from [`gql_headers`], less the Device-ID when there is a token. The token already says who's
+/// asking, and a made-up device next to a real account would only stand out.
+pub(crate) fn headers_for<'a>(
+    device_id: &'a str,
+    upstream: &Upstream,
+) -> Vec<(&'static str, &'a str)> {
+    let headers = gql_headers(device_id);
+    let viewer = upstream.oauth.is_some();
+    headers.iter().copied().filter(|(name, _)| !viewer || *name != "Device-ID").collect()
+}
+
+/// Where GQL requests carrying a viewer's token go: straight to GQL, where the certificate is
+/// checked, rather than through the front, whose isn't.
+pub(crate) fn viewer_gql_url(upstream: &Upstream) -> &str {
+    upstream.direct_gql.as_ref().map_or(DIRECT_GQL_URL, |direct| direct.url.as_str())
+}
+
+/// [`post`], along with the address that answered. Goes through GQL's front, or straight to GQL
+/// if [`Upstream::direct_gql`] says to always, the front dropped the request, or it carries a
+/// viewer's token.
+async fn post_from<T: Serialize>(
+    request: &T,
+    upstream: &Upstream,
+) -> Result<(Bytes, Option<SocketAddr>), Error> {
+    if let Some(wait) = cooldown_remaining() {
+        return Err(Error::Throttled(wait));
+    }
+    if upstream.oauth.is_some() {
+        let via: Via = (client()?, viewer_gql_url(upstream), dns_client);
+        return post_via(request, via, upstream).await;
+    }
+    let fronted = (front_client()?, upstream.gql_url.as_str(), dns_front_client as _);
+    let direct = match &upstream.direct_gql {
+        Some(direct) if direct.always => {
+            return post_via(request, direct_via(direct)?, upstream).await
+        }
+        Some(direct) => direct,
+        None => return post_via(request, fronted, upstream).await,
+    };
+    match post_via(request, fronted, upstream).await {
+        Err(e) if e.is_dropped() => {
+            log::warn!(
+                "GQL's front dropped a request, sending it to {} instead: {}",
+                direct.url,
+                e
+            );
+            post_via(request, direct_via(direct)?, upstream).await
+        }
+        result => result,
+    }
+}
+
+/// What a request to GQL goes through: the client, the URL, and the client [`send`] falls back
+/// to if the address it connected to was an override that stopped working.
+type Via<'a> = (&'static Client, &'a str, fn() -> Result<&'static Client, Error>);
+
+fn direct_via(direct: &DirectGql) -> Result<Via<'_>, Error> {
+    Ok((client()?, direct.url.as_str(), dns_client))
+}
+
+/// Send `request` to GQL through `via`, once.
+async fn post_via<T: Serialize>(
+    request: &T,
+    (client, url, fallback): Via<'_>,
+    upstream: &Upstream,
+) -> Result<(Bytes, Option<SocketAddr>), Error> {
+    let id = device_id(upstream);
+    let started = Instant::now();
+    let timeout = upstream.within_deadline(latency::timeout(Stage::Gql, upstream.timeout));
+    let mut builder = client.post(url).timeout(timeout);
+    for (name, value) in headers_for(&id, upstream) {
+        builder = builder.header(name, value);
+    }
+    // Only when the viewer sent their own token, and then only straight to GQL. Twitch then
+    // knows which account asked, and that it asked from this server; that's the price of
+    // subscriber-only VODs. The token isn't logged or put in errors.
+    if let Some(token) = &upstream.oauth {
+        builder = builder.header("Authorization", format!("OAuth {}", token.secret()));
+    }
+    let posted = async {
+        let response = send(builder.json(request), fallback).await?;
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Error::Throttled(start_cooldown(response.headers().get(RETRY_AFTER))));
+        }
+        let remote_addr = response.remote_addr();
+        let body = check_status(response, "GQL")?.bytes().await?;
+        Ok((body, remote_addr))
+    };
+    let posted = posted.await;
+    latency::record_outcome(Stage::Gql, started, timeout, &posted);
+    posted
+}
+
+/// The query for a channel's newest archived broadcast. Not a persisted query, so there's no
+/// hash to go stale; GQL still answers plain queries from the web player's Client-ID.
+pub const LATEST_VOD_QUERY: &str = "query LatestVod($login: String!) { user(login: $login) { \
+    videos(first: 1, type: ARCHIVE, sort: TIME) { edges { node { id } } } } }";
+
+/// Body of the query for `channel`'s newest archived broadcast.
+pub fn latest_vod_request(channel: &str) -> LoginQuery<'_> {
+    LoginQuery {
+        operation_name: "LatestVod",
+        query: LATEST_VOD_QUERY,
+        variables: LoginVariables { login: channel },
+    }
+}
+
+/// A plain query that only needs a channel's login.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoginQuery<'a> {
+    pub operation_name: &'static str,
+    pub query: &'static str,
+    pub variables: LoginVariables<'a>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LoginVariables<'a> {
+    pub login: &'a str,
+}
+
+/// Parse GQL's answer to [`latest_vod_request`]: the newest VOD's ID, or `None` if the channel
+/// doesn't exist or has no VODs.
+pub fn parse_latest_vod_response(body: &[u8]) -> Result<Option<u64>, Error> {
+    #[derive(Deserialize)]
+    struct Envelope {
+        data: Option<UserData>,
+    }
+    #[derive(Deserialize)]
+    struct UserData {
+        user: Option<User>,
+    }
+    #[derive(Deserialize)]
+    struct User {
+        videos: Option<Videos>,
+    }
+    #[derive(Deserialize)]
+    struct Videos {
+        edges: Vec<Edge>,
+    }
+    #[derive(Deserialize)]
+    struct Edge {
+        node: Video,
+    }
+    #[derive(Deserialize)]
+    struct Video {
+        id: String,
+    }
+
+    check_json(body)?;
+    let data = serde_json::from_slice::<Envelope>(body)?.data;
+    let data = data.ok_or_else(|| serde_json::Error::missing_field("data"))?;
+    let video = data.user.and_then(|u| u.videos).and_then(|v| v.edges.into_iter().next());
+    match video {
+        Some(edge) => match edge.node.id.parse() {
+            Ok(id) => Ok(Some(id)),
+            Err(_) => Err(serde_json::Error::custom("video ID isn't a number").into()),
+        },
+        None => Ok(None),
+    }
+}
+
+/// How long a channel's newest VOD is remembered. A new one only appears when a stream ends, so
+/// this mostly saves GQL from clients that retry or poll.
+pub const LATEST_VOD_TTL: Duration = Duration::from_secs(30);
+
+/// A channel's newest VOD, if it had one, and when that was looked up.
+type LatestVod = (Instant, Option<u64>);
+
+/// Recent answers to [`latest_vod`], by channel.
+static LATEST_VODS: Lazy<Mutex<HashMap<String, LatestVod>>> = Lazy::new(Mutex::default);
+
+/// The ID of `channel`'s newest archived broadcast, or `None` if it has none.
+pub async fn latest_vod(channel: &str, upstream: &Upstream) -> Result<Option<u64>, Error> {
+    if let Some((at, id)) = LATEST_VODS.lock().unwrap().get(channel) {
+        if at.elapsed() < LATEST_VOD_TTL {
+            return Ok(*id);
+        }
+    }
+    let body = post(&latest_vod_request(channel), upstream).await?;
+    let id = parse_latest_vod_response(&body)?;
+    let mut latest = LATEST_VODS.lock().unwrap();
+    latest.retain(|_, (at, _)| at.elapsed() < LATEST_VOD_TTL);
+    latest.insert(channel.to_owned(), (Instant::now(), id));
+    Ok(id)
+}
+
+/// The query for who a channel is hosting. Twitch stopped letting channels host in 2022, but the
+/// field is still there and some rerun setups still fill it.
+pub const HOST_TARGET_QUERY: &str =
+    "query HostTarget($login: String!) { user(login: $login) { hosting { login } } }";
+
+/// Body of the query for who `channel` is pointing its viewers at.
+pub fn host_target_request(channel: &str) -> LoginQuery<'_> {
+    LoginQuery {
+        operation_name: "HostTarget",
+        query: HOST_TARGET_QUERY,
+        variables: LoginVariables { login: channel },
+    }
+}
+
+/// Parse GQL's answer to [`host_target_request`]: the hosted channel's login, if there is one.
+pub fn parse_host_target_response(body: &[u8]) -> Result<Option<String>, Error> {
+    #[derive(Deserialize)]
+    struct Envelope {
+        data: Option<UserData>,
+    }
+    #[derive(Deserialize)]
+    struct UserData {
+        user: Option<User>,
+    }
+    #[derive(Deserialize)]
+    struct User {
+        hosting: Option<Hosting>,
+    }
+    #[derive(Deserialize)]
+    struct Hosting {
+        login: String,
+    }
+
+    check_json(body)?;
+    let data = serde_json::from_slice::<Envelope>(body)?.data;
+    let data = data.ok_or_else(|| serde_json::Error::missing_field("data"))?;
+    Ok(data.user.and_then(|u| u.hosting).map(|h| h.login.to_lowercase()))
+}
+
+/// Who `channel` is hosting, according to GQL.
+pub async fn host_target(channel: &str, upstream: &Upstream) -> Result<Option<String>, Error> {
+    let body = post(&host_target_request(channel), upstream).await?;
+    parse_host_target_response(&body)
+}
+
+/// The query for a live stream's preview image. Asked for without a size, the URL comes back as
+/// a template with `{width}` and `{height}` in it.
+pub const PREVIEW_QUERY: &str =
+    "query StreamPreview($login: String!) { user(login: $login) { stream { previewImageURL } } }";
+
+/// Body of the query for `channel`'s preview image.
+pub fn preview_request(channel: &str) -> LoginQuery<'_> {
+    LoginQuery {
+        operation_name: "StreamPreview",
+        query: PREVIEW_QUERY,
+        variables: LoginVariables { login: channel },
+    }
+}
+
+/// Parse GQL's answer to [`preview_request`]: the preview URL template, or `None` if the
+/// channel isn't live.
+pub fn parse_preview_response(body: &[u8]) -> Result<Option<String>, Error> {
+    #[derive(Deserialize)]
+    struct Envelope {
+        data: Option<UserData>,
+    }
+    #[derive(Deserialize)]
+    struct UserData {
+        user: Option<User>,
+    }
+    #[derive(Deserialize)]
+    struct User {
+        stream: Option<Stream>,
+    }
+    #[derive(Deserialize)]
+    struct Stream {
+        #[serde(rename = "previewImageURL")]
+        preview_image_url: Option<String>,
+    }
+
+    check_json(body)?;
+    let data = serde_json::from_slice::<Envelope>(body)?.data;
+    let data = data.ok_or_else(|| serde_json::Error::missing_field("data"))?;
+    Ok(data.user.and_then(|u| u.stream).and_then(|s| s.preview_image_url))
+}
+
+/// The URL template of `channel`'s preview image, if it's live.
+pub async fn preview_template(channel: &str, upstream: &Upstream) -> Result<Option<String>, Error> {
+    let body = post(&preview_request(channel), upstream).await?;
+    parse_preview_response(&body)
+}
+
+/// The query for what a live stream is set up as, beyond what the playlist says. For now just
+/// whether the broadcaster picked low latency, which changes how much a player should buffer.
+pub const STREAM_METADATA_QUERY: &str =
+    "query StreamMetadata($login: String!) { user(login: $login) { stream { isLowLatency } } }";
+
+/// Body of the query for `channel`'s stream metadata.
+pub fn stream_metadata_request(channel: &str) -> LoginQuery<'_> {
+    LoginQuery {
+        operation_name: "StreamMetadata",
+        query: STREAM_METADATA_QUERY,
+        variables: LoginVariables { login: channel },
+    }
+}
+
+/// Parse GQL's answer to [`stream_metadata_request`]: whether the stream is low latency, or
+/// `None` if the channel isn't live or GQL didn't say.
+pub fn parse_stream_metadata_response(body: &[u8]) -> Result<Option<bool>, Error> {
+    #[derive(Deserialize)]
+    struct Envelope {
+        data: Option<UserData>,
+    }
+    #[derive(Deserialize)]
+    struct UserData {
+        user: Option<User>,
+    }
+    #[derive(Deserialize)]
+    struct User {
+        stream: Option<Stream>,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Stream {
+        is_low_latency: Option<bool>,
+    }
+
+    check_json(body)?;
+    let data = serde_json::from_slice::<Envelope>(body)?.data;
+    let data = data.ok_or_else(|| serde_json::Error::missing_field("data"))?;
+    Ok(data.user.and_then(|u| u.stream).and_then(|s| s.is_low_latency))
+}
+
+/// Whether `channel`'s stream is low latency, if it's live.
+pub async fn low_latency(channel: &str, upstream: &Upstream) -> Result<Option<bool>, Error> {
+    let body = post(&stream_metadata_request(channel), upstream).await?;
+    parse_stream_metadata_response(&body)
+}
+
+/// Body of the VideoAccessToken_Clip request for the clip `slug`.
+pub fn clip_request(slug: &str) -> ClipRequest<'_> {
+    ClipRequest {
+        extensions: RequestExtensions {
+            persisted_query: PersistedQuery { sha256_hash: CLIP_ACCESS_TOKEN_HASH, version: 1 },
+        },
+        operation_name: "VideoAccessToken_Clip",
+        variables: SlugVariables { slug },
+    }
+}
+
+/// The VideoAccessToken_Clip request.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClipRequest<'a> {
+    pub extensions: RequestExtensions<'a>,
+    pub operation_name: &'static str,
+    pub variables: SlugVariables<'a>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SlugVariables<'a> {
+    pub slug: &'a str,
+}
+
+/// What GQL says about a clip: the token that signs its URLs, and the qualities it comes in.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ClipAccess {
+    #[serde(rename = "playbackAccessToken")]
+    pub token: PlaybackAccessToken,
+    #[serde(rename = "videoQualities")]
+    pub qualities: Vec<ClipQuality>,
+}
+
+/// One quality of a clip. Its URL doesn't work until it's signed.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ClipQuality {
+    /// The height, like `1080`.
+    pub quality: String,
+    #[serde(rename = "frameRate")]
+    pub frame_rate: Option<f64>,
+    #[serde(rename = "sourceURL")]
+    pub source_url: String,
+}
+
+/// Parse GQL's answer to [`clip_request`], or `None` if there's no such clip.
+pub fn parse_clip_response(body: &[u8]) -> Result<Option<ClipAccess>, Error> {
+    #[derive(Deserialize)]
+    struct Envelope {
+        data: Option<ClipData>,
+        #[serde(default)]
+        errors: Vec<GqlError>,
+    }
+    #[derive(Deserialize)]
+    struct ClipData {
+        clip: Option<ClipAccess>,
+    }
+
+    check_json(body)?;
+    let envelope = serde_json::from_slice::<Envelope>(body)?;
+    if envelope.errors.iter().any(|e| e.message == "PersistedQueryNotFound") {
+        return Err(Error::PersistedQueryNotFound);
+    }
+    let data = envelope.data.ok_or_else(|| serde_json::Error::missing_field("data"))?;
+    Ok(data.clip)
+}
+
+/// The clip `slug`'s token and qualities, according to GQL.
+pub async fn clip_access(slug: &str, upstream: &Upstream) -> Result<Option<ClipAccess>, Error> {
+    let body = post(&clip_request(slug), upstream).await?;
+    parse_clip_response(&body)
+}
+
+/// Longest we'll stop asking GQL for after a 429, whatever its `Retry-After` says. Twitch's
+/// throttling during incidents clears in seconds, and waiting longer only strands viewers.
+pub const MAX_COOLDOWN: Duration = Duration::from_secs(5);
+
+/// How long to stop asking after a 429 without a usable `Retry-After`.
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(1);
+
+/// Until when token requests are answered with [`Error::Throttled`] instead of going to GQL.
+/// Shared by the whole process, so viewers retrying can't keep the throttle going.
+static COOLDOWN_UNTIL: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// How much longer token requests are being held back after a 429, if they are.
+pub fn cooldown_remaining() -> Option<Duration> {
+    let until = (*COOLDOWN_UNTIL.lock().unwrap())?;
+    until.checked_duration_since(Instant::now()).filter(|left| !left.is_zero())
+}
+
+/// Stop asking GQL for as long as its `Retry-After` says, within reason, returning how long.
+/// Only the delay-seconds form is understood; an HTTP date gets the default.
+fn start_cooldown(retry_after: Option<&HeaderValue>) -> Duration {
+    let seconds = retry_after.and_then(|v| v.to_str().ok()).and_then(|v| v.trim().parse().ok());
+    let wait = seconds.map_or(DEFAULT_COOLDOWN, Duration::from_secs).min(MAX_COOLDOWN);
+    log::warn!("GQL is rate limiting, holding token requests for {:?}", wait);
+    let until = Instant::now() + wait;
+    let mut cooldown = COOLDOWN_UNTIL.lock().unwrap();
+    // a later 429 can extend the wait, never cut it short
+    *cooldown = Some(cooldown.map_or(until, |current| current.max(until)));
+    wait
+}
+
+/// Connect to GQL's front and see that something answers. Any status will do, since all that's
+/// being checked is that the connection and TLS work.
+#[cfg(feature = "server")]
+pub(crate) async fn probe_front(upstream: &Upstream) -> Result<(), Error> {
+    front_client()?
+        .head(&upstream.gql_url)
+        .header("Host", GQL_HOST)
+        .timeout(upstream.timeout)
+        .send()
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,101 @@
+//! Which instance this is. Both platforms scale out to several, and when a problem comes and
+//! goes it's often one bad instance, so the ID goes on every response, log line, and error body.
+
+use std::borrow::Cow;
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use once_cell::sync::Lazy;
+
+use crate::generate_id;
+
+/// How long an instance ID is. Enough to tell a few dozen instances apart at a glance.
+const ID_LENGTH: usize = 8;
+
+/// Where the platforms put their own ID for the instance: Azure's, then Aliyun's.
+const PLATFORM_VARIABLES: [&str; 2] = ["WEBSITE_INSTANCE_ID", "FC_INSTANCE_ID"];
+
+static INSTANCE_ID: Lazy<String> = Lazy::new(|| {
+    let platform = PLATFORM_VARIABLES.iter().find_map(|name| short_id(&env::var(name).ok()?));
+    platform.unwrap_or_else(|| generate_id()[..ID_LENGTH].to_owned())
+});
+
+/// This instance's ID: the end of the platform's ID for it if there is one, so it can be found
+/// in the platform's logs, otherwise random. It stays the same until the process exits.
+pub fn instance_id() -> &'static str {
+    &INSTANCE_ID
+}
+
+/// The last few letters and digits of a platform's instance ID, which vary more than the start.
+pub fn short_id(platform_id: &str) -> Option<String> {
+    let alphanumeric: Vec<char> = platform_id.chars().filter(char::is_ascii_alphanumeric).collect();
+    let start = alphanumeric.len().checked_sub(ID_LENGTH)?;
+    Some(alphanumeric[start..].iter().collect())
+}
+
+/// `text` with the value of any `oauth=` query parameter left out. Rocket logs each request's
+/// URI, and a viewer's token can be in it.
+pub fn redact_oauth(text: &str) -> Cow<'_, str> {
+    const PARAM: &str = "oauth=";
+    if !text.contains(PARAM) {
+        return Cow::Borrowed(text);
+    }
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(PARAM) {
+        let value = start + PARAM.len();
+        redacted.push_str(&rest[..value]);
+        rest = &rest[value..];
+        let end = rest.find(|c: char| c == '&' || c == '#' || c.is_whitespace());
+        redacted.push_str("<redacted>");
+        rest = &rest[end.unwrap_or(rest.len())..];
+    }
+    redacted.push_str(rest);
+    Cow::Owned(redacted)
+}
+
+/// Log through [`InstanceLogger`] at `level`. Has to come before Rocket sets up its own
+/// logger, which then stays out of the way.
+pub fn init_logger(level: LevelFilter) {
+    static LOGGER_SET: AtomicBool = AtomicBool::new(false);
+    if log::set_boxed_logger(Box::new(InstanceLogger)).is_ok() {
+        LOGGER_SET.store(true, Ordering::Release);
+    }
+    // someone else's logger gets to pick its own level
+    if LOGGER_SET.load(Ordering::Acquire) {
+        log::set_max_level(level);
+    }
+}
+
+/// Rocket's logger with the instance ID at the start of each line, and without the colors,
+/// which the platforms' log viewers don't show anyway. Viewers' OAuth tokens are left out.
+struct InstanceLogger;
+
+impl Log for InstanceLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // as with Rocket, Hyper's messages are only for debugging
+        let from_hyper = record.module_path().is_some_and(|m| m.starts_with("hyper"));
+        if from_hyper && log::max_level() < LevelFilter::Debug {
+            return;
+        }
+        // Rocket logs its launch details as warnings so they're always shown, and indents
+        // lines whose target ends in _
+        let level = match record.target() {
+            target if target.contains("rocket::launch") => Level::Info,
+            _ => record.level(),
+        };
+        let indent = if record.target().ends_with('_') { "   >> " } else { "" };
+        let message = record.args().to_string();
+        println!("[{}] {:<5} {}{}", instance_id(), level, indent, redact_oauth(&message));
+    }
+
+    fn flush(&self) {}
+}
@@ -0,0 +1,81 @@
+//! Pinging the deployment's own public URL now and then, so a consumption plan doesn't scale it
+//! to zero between viewers. A cold start costs seconds, and it comes exactly when someone has
+//! just pressed play.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rocket::fairing::AdHoc;
+use rocket::Shutdown;
+use tokio::time::timeout;
+
+use crate::client::{check_status, client};
+use crate::config::{first_refresh_delay, jittered_interval, KeepWarm, DEFAULT_REFRESH_JITTER};
+use crate::{get_rng, Error};
+
+/// The least time between logged failures, so a deployment whose URL is wrong doesn't log one
+/// every few minutes for as long as it runs.
+const FAILURE_LOG_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Starts pinging once the server is up, until it shuts down.
+pub fn keep_warm_fairing(keep_warm: KeepWarm) -> AdHoc {
+    AdHoc::on_liftoff("Keep-warm ping", |rocket| {
+        let shutdown = rocket.shutdown();
+        Box::pin(async move {
+            log::info!("pinging {} about every {:?}", keep_warm.url, keep_warm.interval);
+            tokio::spawn(ping_until_shutdown(keep_warm, shutdown));
+        })
+    })
+}
+
+async fn ping_until_shutdown(keep_warm: KeepWarm, mut shutdown: Shutdown) {
+    let mut rng = get_rng();
+    // deployments that scale up together shouldn't all ping at the same moment
+    let mut wait = first_refresh_delay(&mut rng, keep_warm.interval, DEFAULT_REFRESH_JITTER);
+    let mut failures = FailureLog::default();
+    loop {
+        if timeout(wait, &mut shutdown).await.is_ok() {
+            log::debug!("keep-warm ping stopped");
+            return;
+        }
+        wait = jittered_interval(&mut rng, keep_warm.interval, DEFAULT_REFRESH_JITTER);
+        if keep_warm.quiet.is_some_and(|quiet| quiet.contains(utc_hour(SystemTime::now()))) {
+            continue;
+        }
+        if let Err(e) = ping(&keep_warm.url).await {
+            failures.record(&keep_warm.url, &e);
+        }
+    }
+}
+
+async fn ping(url: &str) -> Result<(), Error> {
+    check_status(client()?.get(url).send().await?, "keep-warm")?;
+    Ok(())
+}
+
+/// The hour of the day `time` falls in, in UTC.
+fn utc_hour(time: SystemTime) -> u32 {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    (secs / 3600 % 24) as u32
+}
+
+/// When a failed ping was last logged, and how many have failed since.
+#[derive(Debug, Default)]
+struct FailureLog {
+    logged_at: Option<Instant>,
+    unlogged: u32,
+}
+
+impl FailureLog {
+    fn record(&mut self, url: &str, e: &Error) {
+        if self.logged_at.is_some_and(|at| at.elapsed() < FAILURE_LOG_INTERVAL) {
+            self.unlogged += 1;
+            return;
+        }
+        match self.unlogged {
+            0 => log::warn!("keep-warm ping to {} failed: {}", url, e),
+            n => log::warn!("keep-warm ping to {} failed: {} ({} more since the last)", url, e, n),
+        }
+        self.logged_at = Some(Instant::now());
+        self.unlogged = 0;
+    }
+}
@@ -0,0 +1,151 @@
+//! Per-request timeouts that follow how fast upstream has actually been answering.
+//!
+//! [`REQUEST_TIMEOUT`](crate::client::REQUEST_TIMEOUT) is sized for the worst case from
+//! Shanghai. When the link is healthy, a connection that hangs for that long has almost surely
+//! gone nowhere, and waiting it out leaves no time for the retry after it. So once a stage has
+//! enough successful requests behind it, its timeout is twice their p95, kept between
+//! [`MIN_TIMEOUT`] and the configured timeout. A request that times out counts as having taken
+//! all of its timeout, and after [`TIMEOUT_STREAK`] of them in a row the stage is given the
+//! configured timeout again, so a slowdown past the adapted timeout can't lock it in.
+//! `CITY17_ADAPTIVE_TIMEOUT=0` turns this off.
+
+use std::collections::VecDeque;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use crate::{Error, ErrorKind};
+
+/// The shortest an adaptive timeout gets, however fast upstream has been. A single slow
+/// handshake over a lossy link can take this long.
+pub const MIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Successful requests a stage needs before its timeout adapts.
+pub const MIN_SAMPLES: usize = 20;
+
+/// How many of the latest latencies are kept per stage.
+pub const WINDOW: usize = 200;
+
+/// Timeouts in a row after which a stage's requests get the configured timeout, until one
+/// succeeds.
+pub const TIMEOUT_STREAK: u32 = 3;
+
+/// The upstream stages that get their own timeout.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Gql,
+    Usher,
+}
+
+/// The latest latencies of one stage's requests, and how many of the last in a row timed out.
+#[derive(Clone, Debug, Default)]
+pub struct LatencyWindow {
+    samples: VecDeque<Duration>,
+    timeouts: u32,
+}
+
+impl LatencyWindow {
+    pub fn record(&mut self, took: Duration) {
+        self.push(took);
+        self.timeouts = 0;
+    }
+
+    /// Note a request that gave up after `timeout`, which it took at least.
+    pub fn record_timeout(&mut self, timeout: Duration) {
+        self.push(timeout);
+        self.timeouts += 1;
+    }
+
+    fn push(&mut self, took: Duration) {
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(took);
+    }
+
+    /// How many latencies there are to go by, up to [`WINDOW`].
+    pub fn samples(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// The p95 latency, once there are enough samples for it to mean something.
+    pub fn p95(&self) -> Option<Duration> {
+        if self.samples.len() < MIN_SAMPLES {
+            return None;
+        }
+        let mut sorted: Vec<_> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        Some(percentile(&sorted, 95.0))
+    }
+
+    /// The timeout for the next request, given the configured one: twice the p95, clamped to
+    /// [`MIN_TIMEOUT`]..=`configured`, or just `configured` until there are enough samples or
+    /// after [`TIMEOUT_STREAK`] timeouts in a row.
+    pub fn timeout(&self, configured: Duration) -> Duration {
+        if self.timeouts >= TIMEOUT_STREAK {
+            return configured;
+        }
+        match self.p95() {
+            // a configured timeout under the minimum still wins
+            Some(p95) => (p95 * 2).max(MIN_TIMEOUT).min(configured),
+            None => configured,
+        }
+    }
+}
+
+static ADAPTIVE: Lazy<bool> =
+    Lazy::new(|| !matches!(env::var("CITY17_ADAPTIVE_TIMEOUT").as_deref(), Ok("0") | Ok("false")));
+
+static GQL: Lazy<Mutex<LatencyWindow>> = Lazy::new(Mutex::default);
+static USHER: Lazy<Mutex<LatencyWindow>> = Lazy::new(Mutex::default);
+
+fn window(stage: Stage) -> &'static Mutex<LatencyWindow> {
+    match stage {
+        Stage::Gql => &GQL,
+        Stage::Usher => &USHER,
+    }
+}
+
+/// The `p`th percentile of `sorted`, by nearest rank, or zero if it's empty.
+pub fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.clamp(1, sorted.len().max(1)) - 1).copied().unwrap_or_default()
+}
+
+/// Note how long a successful request to `stage` took.
+pub fn record(stage: Stage, took: Duration) {
+    window(stage).lock().unwrap().record(took);
+}
+
+/// Note how a request to `stage` that was given `timeout` and started at `started` went: how
+/// long it took if it succeeded, or all of `timeout` if it ran out of time. Other failures say
+/// nothing about how fast upstream is.
+pub fn record_outcome<T>(
+    stage: Stage,
+    started: Instant,
+    timeout: Duration,
+    outcome: &Result<T, Error>,
+) {
+    match outcome {
+        Ok(_) => record(stage, started.elapsed()),
+        Err(e) if e.kind() == ErrorKind::Timeout => {
+            window(stage).lock().unwrap().record_timeout(timeout)
+        }
+        Err(_) => {}
+    }
+}
+
+/// The timeout to give the next request to `stage`, where `configured` is the most it gets.
+pub fn timeout(stage: Stage, configured: Duration) -> Duration {
+    if !*ADAPTIVE {
+        return configured;
+    }
+    window(stage).lock().unwrap().timeout(configured)
+}
+
+/// How many requests to `stage` its timeout is going by, up to [`WINDOW`].
+pub fn samples(stage: Stage) -> usize {
+    window(stage).lock().unwrap().samples()
+}
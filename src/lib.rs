@@ -0,0 +1,72 @@
+//! City17 itself, with `main.rs` left to read the settings and launch. Split out of the binary so
+//! the pieces can be benchmarked and tested on their own: `routes::build_rocket` gives a server
+//! that Rocket's local client can drive without binding a port.
+//!
+//! Without the `server` feature (on by default) there's no Rocket, just [`City17Client`] and the
+//! types it returns, for fetching playlists from another program. The subcommands, fixture
+//! scrubbing, clips, previews, and the logger are the server's and go with it.
+
+use pcg_rand::Pcg64;
+use rand::distributions::Alphanumeric;
+use rand::{Rng, SeedableRng};
+
+#[cfg(feature = "server")]
+pub mod cache;
+#[cfg(feature = "server")]
+pub mod cli;
+pub mod client;
+#[cfg(feature = "server")]
+pub mod clip;
+#[cfg(any(feature = "azure", feature = "standalone"))]
+pub mod compress;
+pub mod config;
+pub mod dns;
+#[cfg(feature = "server")]
+pub mod dryrun;
+pub mod embed;
+pub mod error;
+#[cfg(feature = "server")]
+pub mod fixture;
+pub mod gql;
+#[cfg(feature = "server")]
+pub mod instance;
+#[cfg(feature = "server")]
+pub mod keepwarm;
+pub mod latency;
+pub mod playlist;
+#[cfg(feature = "server")]
+pub mod preview;
+#[cfg(feature = "server")]
+pub mod relay;
+#[cfg(feature = "server")]
+pub mod responders;
+#[cfg(feature = "server")]
+pub mod routes;
+pub mod usher;
+
+pub use embed::{City17Client, Config, Fetched, Options};
+pub use error::{Error, ErrorKind};
+
+#[cfg(all(
+    feature = "server",
+    not(any(feature = "aliyun", feature = "azure", feature = "standalone"))
+))]
+compile_error!(
+    "the server's routes need a platform: enable the aliyun, azure, or standalone feature"
+);
+
+#[cfg(all(feature = "standalone", any(feature = "aliyun", feature = "azure")))]
+compile_error!("standalone mounts the routes at the root: build it with --no-default-features");
+
+pub fn get_rng() -> impl Rng {
+    Pcg64::from_entropy()
+}
+
+/// Generate an ID suitable for use both as a Device-ID and a play_session_id.
+/// The latter must be lowercased.
+///
+/// Both are 32-character alphanumeric strings.
+pub fn generate_id() -> String {
+    let mut pcg = get_rng();
+    std::iter::repeat(()).map(|_| pcg.sample(Alphanumeric)).map(char::from).take(32).collect()
+}
@@ -0,0 +1,179 @@
+//! Looking inside the playlists usher sends back.
+
+use std::cmp::Reverse;
+
+use serde::Serialize;
+
+/// Every playlist starts with this, so it's all we need to see before committing to a 200.
+pub const M3U8_MAGIC: &[u8] = b"#EXTM3U";
+
+/// Codecs we tell usher we support. Firefox only sends avc1.
+pub const CODECS: &str = "vp09,avc1";
+
+/// Whether more than half of a master playlist's variants are VP9.
+pub fn is_vp9_dominant(m3u8: &[u8]) -> bool {
+    let m3u8 = String::from_utf8_lossy(m3u8);
+    let variants = m3u8.lines().filter(|l| l.starts_with("#EXT-X-STREAM-INF:"));
+    let (vp9, total) =
+        variants.fold((0, 0), |(vp9, total), l| (vp9 + l.contains("vp09") as usize, total + 1));
+    vp9 * 2 > total
+}
+
+/// Whether a master playlist has renditions but all of them are audio_only, which some players
+/// treat as an error rather than playing the audio.
+pub fn is_audio_only(m3u8: &[u8]) -> bool {
+    let m3u8 = String::from_utf8_lossy(m3u8);
+    let (_, renditions, _) = split_renditions(&m3u8);
+    !renditions.is_empty() && renditions.iter().all(|r| r.audio_only)
+}
+
+/// When a live stream started, as a Unix timestamp, from the master playlist's
+/// `#EXT-X-TWITCH-INFO`: usher's clock (`SERVER-TIME`) less how long the stream has been going
+/// (`STREAM-TIME`). Good to about a second, and free since the playlist is fetched anyway.
+pub fn stream_started_at(m3u8: &[u8]) -> Option<i64> {
+    let m3u8 = String::from_utf8_lossy(m3u8);
+    let info = m3u8.lines().find_map(|l| l.strip_prefix("#EXT-X-TWITCH-INFO:"))?;
+    let seconds = |name| attribute(info, name)?.trim_matches('"').parse::<f64>().ok();
+    let started = seconds("SERVER-TIME")? - seconds("STREAM-TIME")?;
+    started.is_finite().then(|| started.round() as i64)
+}
+
+/// The most renditions `?max_variants` can ask for. Twitch's ladders top out around a dozen.
+pub const MAX_VARIANTS: usize = 16;
+
+/// Keep only the `max` highest-bandwidth renditions of a master playlist, plus audio_only if
+/// it's there. The source is always one of them, even when a transcode claims more bandwidth.
+/// Everything else, including the order, is left as it was.
+pub fn limit_renditions(m3u8: &[u8], max: usize) -> String {
+    let m3u8 = String::from_utf8_lossy(m3u8);
+    let (header, renditions, trailer) = split_renditions(&m3u8);
+    let mut by_bandwidth: Vec<&Rendition> = renditions.iter().filter(|r| !r.audio_only).collect();
+    by_bandwidth.sort_by_key(|r| (Reverse(r.source), Reverse(r.bandwidth)));
+    let kept: Vec<usize> = by_bandwidth.iter().take(max).map(|r| r.start).collect();
+    let mut limited = String::with_capacity(m3u8.len());
+    limited.push_str(header);
+    for rendition in &renditions {
+        if rendition.audio_only || kept.contains(&rendition.start) {
+            limited.push_str(rendition.text);
+        }
+    }
+    limited.push_str(trailer);
+    limited
+}
+
+/// The URI of the highest-bandwidth rendition called `name`, like `720p60` or `audio_only`.
+/// `source` picks the rendition Twitch marks as the source, whatever its resolution.
+pub fn rendition_url<'a>(m3u8: &'a str, name: &str) -> Option<&'a str> {
+    let (_, renditions, _) = split_renditions(m3u8);
+    let matches = |r: &&Rendition| {
+        r.name.eq_ignore_ascii_case(name) || (name.eq_ignore_ascii_case("source") && r.source)
+    };
+    let best = renditions.iter().filter(matches).max_by_key(|r| r.bandwidth)?;
+    best.uri()
+}
+
+/// A master playlist's renditions in the order they're listed, for clients that would rather
+/// not parse M3U8 themselves.
+pub fn variants(m3u8: &str) -> Vec<Variant<'_>> {
+    let (_, renditions, _) = split_renditions(m3u8);
+    let variants = renditions.iter().filter_map(|r| {
+        let stream_inf = r.text.lines().find_map(|l| l.strip_prefix("#EXT-X-STREAM-INF:"));
+        let stream_inf = |name| stream_inf.and_then(|attributes| attribute(attributes, name));
+        Some(Variant {
+            name: r.name,
+            bandwidth: r.bandwidth,
+            resolution: stream_inf("RESOLUTION"),
+            frame_rate: stream_inf("FRAME-RATE").and_then(|f| f.parse().ok()),
+            url: r.uri()?,
+            audio_only: r.audio_only,
+        })
+    });
+    variants.collect()
+}
+
+/// One rendition of a master playlist, as [`variants`] describes it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Variant<'a> {
+    pub name: &'a str,
+    pub bandwidth: u64,
+    pub resolution: Option<&'a str>,
+    pub frame_rate: Option<f64>,
+    pub url: &'a str,
+    pub audio_only: bool,
+}
+
+/// One variant of a master playlist: its `#EXT-X-MEDIA` and `#EXT-X-STREAM-INF` lines and URI.
+struct Rendition<'a> {
+    /// Offset into the playlist, which identifies it.
+    start: usize,
+    text: &'a str,
+    /// `NAME` from `#EXT-X-MEDIA`, which is what players show, e.g. `720p60`.
+    name: &'a str,
+    bandwidth: u64,
+    /// Whether Twitch marks it as the source, e.g. `1080p60 (source)`.
+    source: bool,
+    audio_only: bool,
+}
+
+/// Split a master playlist into whatever comes before the first rendition, the renditions, and
+/// whatever is left after the last one.
+fn split_renditions(m3u8: &str) -> (&str, Vec<Rendition<'_>>, &str) {
+    let mut renditions = Vec::new();
+    let mut header_end = None;
+    let mut block_start = None;
+    let mut last_end = 0;
+    let mut pos = 0;
+    for line in m3u8.split_inclusive('\n') {
+        let end = pos + line.len();
+        let line = line.trim_end();
+        let is_variant_tag =
+            line.starts_with("#EXT-X-MEDIA:") || line.starts_with("#EXT-X-STREAM-INF:");
+        if block_start.is_none() && is_variant_tag {
+            // anything between the previous rendition and this one goes with this one, rather
+            // than being lost
+            block_start = Some(if renditions.is_empty() { pos } else { last_end });
+            header_end.get_or_insert(pos);
+        }
+        if let Some(start) = block_start.filter(|_| !line.is_empty() && !line.starts_with('#')) {
+            renditions.push(Rendition::new(start, &m3u8[start..end]));
+            block_start = None;
+            last_end = end;
+        }
+        pos = end;
+    }
+    let header_end = header_end.unwrap_or(m3u8.len());
+    (&m3u8[..header_end], renditions, &m3u8[last_end.max(header_end)..])
+}
+
+impl<'a> Rendition<'a> {
+    fn new(start: usize, text: &'a str) -> Self {
+        let bandwidth = text
+            .lines()
+            .filter_map(|l| l.strip_prefix("#EXT-X-STREAM-INF:"))
+            .find_map(|attributes| attribute(attributes, "BANDWIDTH"))
+            .and_then(|b| b.parse().ok())
+            .unwrap_or(0);
+        let name = text
+            .lines()
+            .filter_map(|l| l.strip_prefix("#EXT-X-MEDIA:"))
+            .find_map(|attributes| attribute(attributes, "NAME"))
+            .map_or("", |name| name.trim_matches('"'));
+        let source = name.ends_with("(source)");
+        let audio_only = text.contains("\"audio_only\"");
+        Self { start, text, name, bandwidth, source, audio_only }
+    }
+
+    fn uri(&self) -> Option<&'a str> {
+        self.text.lines().map(str::trim).rfind(|l| !l.is_empty() && !l.starts_with('#'))
+    }
+}
+
+/// Find an unquoted attribute's value in an attribute list, like `BANDWIDTH` in
+/// `BANDWIDTH=630000,RESOLUTION=640x360`.
+fn attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    attributes.match_indices(name).find_map(|(i, _)| {
+        let starts_attribute = i == 0 || attributes[..i].ends_with(',');
+        let value = attributes[i + name.len()..].strip_prefix('=')?;
+        starts_attribute.then(|| value.split(',').next().unwrap_or(value))
+    })
+}
@@ -0,0 +1,85 @@
+//! Stream preview thumbnails. The image itself is on Twitch's CDN, but its URL only comes from
+//! GQL, which is blocked where this runs.
+
+use bytes::{Bytes, BytesMut};
+use futures_util::StreamExt;
+use reqwest::header::CONTENT_TYPE;
+
+use crate::client::{check_status, client};
+use crate::config::Upstream;
+use crate::gql::preview_template;
+use crate::Error;
+
+/// The sizes Twitch renders previews at. Others would work too, but each new one is rendered on
+/// demand, so there's no reason to let clients ask for arbitrary ones.
+pub const PREVIEW_SIZES: [(u32, u32); 5] =
+    [(80, 45), (320, 180), (640, 360), (1280, 720), (1920, 1080)];
+
+/// What `?w=&h=` default to.
+pub const DEFAULT_PREVIEW_SIZE: (u32, u32) = (640, 360);
+
+/// The most a proxied preview can be. A 1080p JPEG preview is a few hundred KB.
+pub const PREVIEW_MAX_BYTES: usize = 1024 * 1024;
+
+/// How long clients and CDNs can keep a proxied preview. Twitch only refreshes them every few
+/// minutes.
+pub const PREVIEW_MAX_AGE: u32 = 60;
+
+/// The size asked for by `?w=&h=`, which has to be one of [`PREVIEW_SIZES`].
+pub fn preview_size(width: Option<u32>, height: Option<u32>) -> Result<(u32, u32), Error> {
+    let size = match (width, height) {
+        (None, None) => return Ok(DEFAULT_PREVIEW_SIZE),
+        (Some(width), Some(height)) => (width, height),
+        _ => return Err(Error::Input("preview needs both w and h, or neither".into())),
+    };
+    if PREVIEW_SIZES.contains(&size) {
+        Ok(size)
+    } else {
+        Err(Error::Input(
+            "preview size must be 80x45, 320x180, 640x360, 1280x720, or 1920x1080".into(),
+        ))
+    }
+}
+
+/// Put a size into a preview URL template.
+pub fn fill_template(template: &str, (width, height): (u32, u32)) -> String {
+    template.replace("{width}", &width.to_string()).replace("{height}", &height.to_string())
+}
+
+/// The URL of `channel`'s preview at `size`.
+pub async fn preview_url(
+    channel: &str,
+    size: (u32, u32),
+    upstream: &Upstream,
+) -> Result<String, Error> {
+    match preview_template(channel, upstream).await? {
+        Some(template) => Ok(fill_template(&template, size)),
+        None => Err(Error::Offline(channel.to_owned())),
+    }
+}
+
+/// Fetch a preview image, returning it and its content type. Anything that isn't an image, or
+/// is over [`PREVIEW_MAX_BYTES`], is an error rather than something to pass along.
+pub async fn fetch_preview(url: &str, upstream: &Upstream) -> Result<(Bytes, String), Error> {
+    let response = client()?.get(url).timeout(upstream.timeout).send().await?;
+    let response = check_status(response, "preview")?;
+    let content_type = response.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    let content_type = match content_type {
+        Some(image) if image.starts_with("image/") => image.to_owned(),
+        _ => return Err(Error::NotImage),
+    };
+    if response.content_length().is_some_and(|length| length > PREVIEW_MAX_BYTES as u64) {
+        return Err(Error::TooLarge(PREVIEW_MAX_BYTES));
+    }
+    // the length may not have been sent, so count as it arrives too
+    let mut body = BytesMut::new();
+    let mut chunks = response.bytes_stream();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > PREVIEW_MAX_BYTES {
+            return Err(Error::TooLarge(PREVIEW_MAX_BYTES));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok((body.freeze(), content_type))
+}
@@ -0,0 +1,184 @@
+//! Relay mode: with `CITY17_UPSTREAM` set, playlists come from another City17 instead of from
+//! Twitch. For a server with good peering to an instance that can reach Twitch, but a poor
+//! route to Twitch itself.
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use reqwest::header::{HeaderMap, ACCEPT};
+use reqwest::Response;
+
+use crate::cache::CacheStatus;
+use crate::client::client;
+use crate::config::{Relay, Upstream};
+use crate::error::{ErrorResponder, ResultExt};
+use crate::gql::{Variables, PLAYER_TYPE};
+use crate::playlist::M3U8_MAGIC;
+use crate::usher::{Attempts, FetchInfo};
+use crate::Error;
+
+/// How many instances a request can be relayed through. Only a misconfiguration would chain
+/// more than two, and this stops one that goes in a circle.
+pub const MAX_HOPS: u32 = 3;
+
+/// How many times a request has been relayed already. Missing means none.
+pub const HOP_HEADER: &str = "X-City17-Hop";
+
+/// Stage names the other instance can report timings, attempts, and errors under. Anything
+/// else it sends is dropped, or for errors reported as `relay`.
+const STAGES: [&str; 9] =
+    ["gql", "gql-retry", "usher", "usher-avc", "relay", "GQL", "M3U", "input", "maintenance"];
+
+/// Fetch `var`'s playlist from the instance `relay` points at, for a request that has come
+/// through `hops` instances already, with its stream metadata if `meta`. Its timings, attempts, and what it knows about the stream
+/// are kept, and its errors are passed on as they are, with its attempts copied into
+/// `attempts`.
+pub(crate) async fn fetch(
+    var: &Variables,
+    player_type: &str,
+    meta: bool,
+    relay: &Relay,
+    hops: u32,
+    upstream: &Upstream,
+    attempts: &mut Attempts,
+) -> Result<(Bytes, CacheStatus, FetchInfo), ErrorResponder> {
+    if hops >= MAX_HOPS {
+        return Err(ErrorResponder(Error::TooManyHops(hops), "relay"));
+    }
+    if upstream.oauth.is_some() && !relay.base.starts_with("https://") {
+        let e = Error::NotAllowed("a viewer's OAuth token is only relayed over https");
+        return Err(ErrorResponder(e, "relay"));
+    }
+    let (url, headers) = relay_request(var, player_type, meta, relay, hops);
+    let started = Instant::now();
+    // the other instance may have to retry both of its stages, but not past this fetch's deadline
+    let timeout = upstream.within_deadline(upstream.timeout * 4);
+    let mut request = client().into_responder("relay")?.get(url).timeout(timeout);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    if let Some(key) = &relay.key {
+        request = request.header("X-API-Key", key);
+    }
+    // the viewer's token, so the other instance can send it on to GQL
+    if let Some(token) = &upstream.oauth {
+        request = request.header("Authorization", format!("OAuth {}", token.secret()));
+    }
+    let response = request.send().await.map_err(Error::from).into_responder("relay")?;
+    if !response.status().is_success() {
+        *attempts = relayed_attempts(response.headers());
+        return Err(relayed_error(response).await);
+    }
+    let headers = response.headers().clone();
+    let body = response.bytes().await.map_err(Error::from).into_responder("relay")?;
+    if !body.starts_with(M3U8_MAGIC) {
+        return Err(ErrorResponder(Error::NotPlaylist, "relay"));
+    }
+    let mut info = relayed_info(&headers);
+    info.timings.push(("relay", started.elapsed()));
+    let cache = header(&headers, "X-Cache").and_then(CacheStatus::from_header);
+    Ok((body, cache.unwrap_or(CacheStatus::Bypass), info))
+}
+
+/// The URL and headers to ask the other instance for `var` as `player_type` with, besides its
+/// key. `meta` has it look up the stream's metadata too, since this instance can't.
+pub(crate) fn relay_request(
+    var: &Variables,
+    player_type: &str,
+    meta: bool,
+    relay: &Relay,
+    hops: u32,
+) -> (String, [(&'static str, String); 2]) {
+    let url = match var {
+        Variables::Channel(channel) => format!("{}/live/{}", relay.base, channel),
+        Variables::VOD(id) => format!("{}/vod/{}", relay.base, id),
+    };
+    let mut query = Vec::new();
+    if player_type != PLAYER_TYPE {
+        query.push(format!("player_type={}", player_type));
+    }
+    if meta {
+        query.push("meta=1".to_owned());
+    }
+    let url = match query.is_empty() {
+        true => url,
+        false => format!("{}?{}", url, query.join("&")),
+    };
+    let headers = [
+        (ACCEPT.as_str(), "application/vnd.apple.mpegurl".to_owned()),
+        (HOP_HEADER, (hops + 1).to_string()),
+    ];
+    (url, headers)
+}
+
+/// The other instance's error as it sent it, or if it isn't one of ours (a gateway's error
+/// page, say) an error about the response itself.
+async fn relayed_error(response: Response) -> ErrorResponder {
+    let status = response.status();
+    let unrecognized = response.error_for_status_ref().err();
+    let body = match response.bytes().await {
+        Ok(body) => body,
+        Err(e) => return ErrorResponder(e.into(), "relay"),
+    };
+    match serde_json::from_slice::<serde_json::Value>(&body) {
+        Ok(body) if body["kind"].is_string() => {
+            let stage = body["stage"].as_str().and_then(known_stage).unwrap_or("relay");
+            ErrorResponder(Error::Relayed { status: status.as_u16(), body }, stage)
+        }
+        _ if status.is_server_error() => ErrorResponder(
+            Error::UpstreamDown { upstream: "relay", status: status.as_u16() },
+            "relay",
+        ),
+        _ => match unrecognized {
+            Some(e) => ErrorResponder(e.into(), "relay"),
+            None => ErrorResponder(Error::NotPlaylist, "relay"),
+        },
+    }
+}
+
+/// What the other instance's headers say about the playlist.
+fn relayed_info(headers: &HeaderMap) -> FetchInfo {
+    let number = |name| header(headers, name).and_then(|v| v.parse().ok());
+    let ip = |name| header(headers, name).and_then(|v| v.parse().ok());
+    let timings = stage_pairs(headers, "Server-Timing").into_iter().filter_map(|(stage, dur)| {
+        let millis: f64 = dur.strip_prefix("dur=")?.parse().ok()?;
+        Some((stage, Duration::from_secs_f64(millis / 1000.0)))
+    });
+    FetchInfo {
+        expires: number("X-City17-Token-Expires"),
+        timings: timings.collect(),
+        attempts: relayed_attempts(headers),
+        audio_only: false,
+        started_at: number("X-Stream-Started-At"),
+        low_latency: header(headers, "X-Stream-Low-Latency").and_then(|v| v.parse().ok()),
+        redirected_from: header(headers, "X-Redirected-From").map(String::from),
+        // where the other instance got it from, which is what matters for its fronts
+        gql_ip: ip("X-Upstream-GQL-IP"),
+        usher_ip: ip("X-Upstream-Usher-IP"),
+        // only known to the other instance
+        ads: None,
+        request_id: None,
+        quality_restricted: header(headers, "X-Quality-Restricted") == Some("true"),
+    }
+}
+
+/// The other instance's `X-City17-Attempts`, which it sends with errors too.
+fn relayed_attempts(headers: &HeaderMap) -> Attempts {
+    let attempts = stage_pairs(headers, "X-City17-Attempts").into_iter();
+    attempts.filter_map(|(stage, tries)| Some((stage, tries.parse().ok()?))).collect()
+}
+
+/// A header's `stage=...` or `stage;...` entries, for the stages we know.
+fn stage_pairs(headers: &HeaderMap, name: &str) -> Vec<(&'static str, String)> {
+    let value = header(headers, name).unwrap_or_default();
+    let pairs = value.split(", ").filter_map(|pair| pair.split_once(['=', ';']));
+    pairs.filter_map(|(stage, rest)| Some((known_stage(stage)?, rest.to_owned()))).collect()
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn known_stage(stage: &str) -> Option<&'static str> {
+    STAGES.iter().copied().find(|known| *known == stage)
+}
@@ -0,0 +1,332 @@
+//! Turning playlists and errors into responses.
+
+use std::env;
+use std::io;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use futures_util::stream::{self, StreamExt};
+use once_cell::sync::Lazy;
+use rocket::http::{ContentType, Header, Status};
+use rocket::response::Builder as ResponseBuilder;
+use rocket::response::Responder;
+use rocket::{Request, Response};
+use tokio_util::io::StreamReader;
+
+use crate::cache::CacheStatus;
+use crate::clip::ClipUrl;
+#[cfg(any(feature = "azure", feature = "standalone"))]
+use crate::compress::{accepts_gzip, gzip, GZIP_MIN_BYTES};
+use crate::error::{ceil_secs, ErrorResponder, ResultExt};
+use crate::instance::instance_id;
+use crate::playlist::{limit_renditions, variants};
+use crate::preview::PREVIEW_MAX_AGE;
+use crate::routes::PlaylistOptions;
+use crate::usher::{format_attempts, millis, Attempts, FetchInfo, Playlist};
+use crate::Error;
+
+/// A few legacy players mishandle playlists served without an explicit charset. Setting
+/// `CITY17_PLAYLIST_CHARSET` (e.g. to `utf-8`) adds it to the content type.
+static PLAYLIST_CHARSET: Lazy<Option<String>> =
+    Lazy::new(|| env::var("CITY17_PLAYLIST_CHARSET").ok().filter(|c| !c.is_empty()));
+
+/// Every upstream try a request has made, including ones that ended in an error, so its error
+/// response can say how hard it tried too. One per request, through `Request::local_cache`.
+#[derive(Debug, Default)]
+pub(crate) struct AttemptLog(Mutex<Attempts>);
+
+impl AttemptLog {
+    /// Count `attempts` in, on top of any made earlier for the same request.
+    pub(crate) fn add(&self, attempts: &[(&'static str, u32)]) {
+        let mut log = self.0.lock().unwrap();
+        for &(stage, tries) in attempts {
+            match log.iter_mut().find(|(s, _)| *s == stage) {
+                Some((_, total)) => *total += tries,
+                None => log.push((stage, tries)),
+            }
+        }
+    }
+}
+
+/// Holds a playlist, how it was produced, and what else was learned fetching it.
+pub(crate) struct M3U8Responder(pub(crate) Playlist, pub(crate) CacheStatus, pub(crate) FetchInfo);
+
+impl M3U8Responder {
+    /// Apply whatever the client asked to have done to the playlist, which means waiting for
+    /// all of it to arrive.
+    pub(crate) async fn transform(self, options: &PlaylistOptions) -> Result<Self, ErrorResponder> {
+        let max = match options.max_renditions {
+            Some(max) => max,
+            None => return Ok(self),
+        };
+        let M3U8Responder(playlist, cache, info) = self;
+        let body = playlist.collect().await.map_err(Error::from).into_responder("M3U")?;
+        let body = Bytes::from(limit_renditions(&body, max));
+        Ok(M3U8Responder(body.into(), cache, info))
+    }
+}
+
+impl<'a> Responder<'a, 'static> for M3U8Responder {
+    #[cfg_attr(not(any(feature = "azure", feature = "standalone")), allow(unused_variables))]
+    fn respond_to(self, req: &'a Request<'_>) -> rocket::response::Result<'static> {
+        let M3U8Responder(playlist, cache, info) = self;
+        // Aliyun doesn't allow Gzip, so only Azure and standalone get it
+        // exact type from twitch
+        let mut content_type = ContentType::new("application", "vnd.apple.mpegurl");
+        if let Some(charset) = PLAYLIST_CHARSET.as_deref() {
+            content_type = content_type.with_params(("charset", charset));
+        }
+        let mut response = Response::build();
+        response.header(Header::new("Cache-Control", "no-store")).header(content_type);
+        info_headers(&mut response, cache, &info);
+        match playlist {
+            Playlist::Full(body) => {
+                #[cfg(any(feature = "azure", feature = "standalone"))]
+                let body = maybe_gzip(req, &mut response, body);
+                response.sized_body(body.len(), io::Cursor::new(body));
+            }
+            Playlist::Streaming { head, rest } => {
+                // The status is already sent by the time the rest fails, so all we can do is
+                // cut it short.
+                let rest = rest.map(|chunk| {
+                    chunk.map_err(|e| {
+                        log::warn!("usher failed mid-playlist: {}", e);
+                        io::Error::other(e)
+                    })
+                });
+                let body = stream::once(async { Ok(head) }).chain(rest);
+                response.streamed_body(StreamReader::new(body));
+            }
+        }
+        response.ok()
+    }
+}
+
+/// Headers saying how a playlist was produced and what else was learned fetching it.
+fn info_headers(response: &mut ResponseBuilder<'_>, cache: CacheStatus, info: &FetchInfo) {
+    response.header(Header::new("X-Cache", cache.as_str()));
+    if let Some(expires) = info.expires {
+        response.header(Header::new("X-City17-Token-Expires", expires.to_string()));
+    }
+    if let Some(started) = info.started_at {
+        response.header(Header::new("X-Stream-Started-At", started.to_string()));
+    }
+    if let Some(low_latency) = info.low_latency {
+        response.header(Header::new("X-Stream-Low-Latency", low_latency.to_string()));
+    }
+    if !info.timings.is_empty() {
+        response.header(Header::new("Server-Timing", info.server_timing()));
+    }
+    if !info.attempts.is_empty() {
+        response.header(Header::new("X-City17-Attempts", info.attempts()));
+    }
+    if info.audio_only {
+        response.header(Header::new("X-City17-Audio-Only", "true"));
+    }
+    if info.quality_restricted {
+        response.header(Header::new("X-Quality-Restricted", "true"));
+    }
+    if let Some(channel) = &info.redirected_from {
+        response.header(Header::new("X-Redirected-From", channel.clone()));
+    }
+    if let Some(ip) = info.gql_ip {
+        response.header(Header::new("X-Upstream-GQL-IP", ip.to_string()));
+    }
+    if let Some(ip) = info.usher_ip {
+        response.header(Header::new("X-Upstream-Usher-IP", ip.to_string()));
+    }
+}
+
+/// What a playlist endpoint can answer with, chosen by the request's `Accept`, or
+/// `?include=token` for [`Bundle`](Self::Bundle).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum PlaylistFormat {
+    M3U8,
+    /// The renditions and what's known about the stream, for dashboards.
+    Json,
+    /// The playlist itself along with what its token says and how long it took, for clients
+    /// that would otherwise need a second round trip for the token.
+    Bundle,
+}
+
+/// A playlist in the format the client asked for, with `Vary: Accept` so caches keep the two
+/// apart.
+pub(crate) struct Negotiated(pub(crate) M3U8Responder, pub(crate) PlaylistFormat);
+
+impl Negotiated {
+    /// JSON needs the whole playlist, so wait for it if that's what was asked for.
+    pub(crate) async fn new(
+        responder: M3U8Responder,
+        format: PlaylistFormat,
+    ) -> Result<Self, ErrorResponder> {
+        if format == PlaylistFormat::M3U8 {
+            return Ok(Negotiated(responder, format));
+        }
+        let M3U8Responder(playlist, cache, info) = responder;
+        let body = playlist.collect().await.map_err(Error::from).into_responder("M3U")?;
+        Ok(Negotiated(M3U8Responder(body.into(), cache, info), format))
+    }
+}
+
+impl<'a> Responder<'a, 'static> for Negotiated {
+    fn respond_to(self, req: &'a Request<'_>) -> rocket::response::Result<'static> {
+        let Negotiated(responder, format) = self;
+        let mut response = match (format, responder) {
+            (PlaylistFormat::M3U8, responder) => responder.respond_to(req)?,
+            (format, M3U8Responder(Playlist::Full(body), cache, info)) => {
+                let json = playlist_json(format, &String::from_utf8_lossy(&body), &info);
+                let json = json.to_string();
+                let mut response = Response::build();
+                response.header(ContentType::JSON).header(Header::new("Cache-Control", "no-store"));
+                info_headers(&mut response, cache, &info);
+                response.sized_body(json.len(), io::Cursor::new(json)).finalize()
+            }
+            (_, _) => {
+                log::error!("JSON asked for but the playlist wasn't collected");
+                return Err(Status::InternalServerError);
+            }
+        };
+        response.adjoin_header(Header::new("Vary", "Accept"));
+        Ok(response)
+    }
+}
+
+fn playlist_json(format: PlaylistFormat, m3u8: &str, info: &FetchInfo) -> serde_json::Value {
+    if format == PlaylistFormat::Json {
+        return serde_json::json!({
+            "renditions": variants(m3u8),
+            "expires": info.expires,
+            "started_at": info.started_at,
+            "low_latency": info.low_latency,
+            "audio_only": info.audio_only,
+            "redirected_from": info.redirected_from,
+        });
+    }
+    let timings =
+        info.timings.iter().map(|(stage, took)| (stage.to_string(), millis(*took).into()));
+    serde_json::json!({
+        "m3u8": m3u8,
+        "token": {
+            "expires": info.expires,
+            "ads": info.ads,
+            "request_id": info.request_id,
+        },
+        "timings": timings.collect::<serde_json::Map<_, _>>(),
+    })
+}
+
+/// What a playlist request would have sent upstream, from [`dryrun`](crate::dryrun).
+pub(crate) struct DryRun(pub(crate) serde_json::Value);
+
+impl<'a> Responder<'a, 'static> for DryRun {
+    fn respond_to(self, _: &'a Request<'_>) -> rocket::response::Result<'static> {
+        let json = self.0.to_string();
+        Response::build()
+            .header(ContentType::JSON)
+            .header(Header::new("Cache-Control", "no-store"))
+            .sized_body(json.len(), io::Cursor::new(json))
+            .ok()
+    }
+}
+
+/// A preview's URL as JSON, or with `?proxy=1` the image itself.
+pub(crate) enum PreviewResponder {
+    Url(String),
+    Image { body: Bytes, content_type: String },
+}
+
+impl<'a> Responder<'a, 'static> for PreviewResponder {
+    fn respond_to(self, _: &'a Request<'_>) -> rocket::response::Result<'static> {
+        let mut response = Response::build();
+        match self {
+            PreviewResponder::Url(url) => {
+                let json = serde_json::json!({ "url": url }).to_string();
+                response
+                    .header(ContentType::JSON)
+                    .header(Header::new("Cache-Control", "no-store"))
+                    .sized_body(json.len(), io::Cursor::new(json));
+            }
+            PreviewResponder::Image { body, content_type } => {
+                let cache_control = format!("public, max-age={}", PREVIEW_MAX_AGE);
+                response
+                    .raw_header("Content-Type", content_type)
+                    .header(Header::new("Cache-Control", cache_control))
+                    .sized_body(body.len(), io::Cursor::new(body));
+            }
+        }
+        response.ok()
+    }
+}
+
+/// A clip's qualities and their signed URLs, or the URL of just one of them.
+pub(crate) enum ClipResponder {
+    Qualities { slug: String, urls: Vec<ClipUrl> },
+    Url(String),
+}
+
+impl<'a> Responder<'a, 'static> for ClipResponder {
+    fn respond_to(self, _: &'a Request<'_>) -> rocket::response::Result<'static> {
+        let json = match self {
+            ClipResponder::Qualities { slug, urls } => {
+                serde_json::json!({ "slug": slug, "qualities": urls })
+            }
+            ClipResponder::Url(url) => serde_json::json!({ "url": url }),
+        };
+        let json = json.to_string();
+        Response::build()
+            .header(ContentType::JSON)
+            // the signatures expire
+            .header(Header::new("Cache-Control", "no-store"))
+            .sized_body(json.len(), io::Cursor::new(json))
+            .ok()
+    }
+}
+
+/// Gzip a playlist if the client accepts it and it's big enough to be worth it. Streamed
+/// playlists are left alone since their size isn't known up front.
+///
+/// There's no ETag to weaken for the encoded copy: playlists are `no-store` and every fetch
+/// carries a fresh token, so a client never has anything worth revalidating.
+#[cfg(any(feature = "azure", feature = "standalone"))]
+fn maybe_gzip(req: &Request<'_>, response: &mut ResponseBuilder<'_>, body: Bytes) -> Bytes {
+    response.header(Header::new("Vary", "Accept-Encoding"));
+    let accepted = req.headers().get("Accept-Encoding").any(accepts_gzip);
+    if body.len() < GZIP_MIN_BYTES || !accepted {
+        return body;
+    }
+    match gzip(&body) {
+        Ok(encoded) => {
+            response.header(Header::new("Content-Encoding", "gzip"));
+            encoded.into()
+        }
+        Err(e) => {
+            log::warn!("gzip failed, sending uncompressed: {}", e);
+            body
+        }
+    }
+}
+
+/// Responds in JSON format for programmatic handling.
+impl<'a> Responder<'a, 'a> for ErrorResponder {
+    fn respond_to(self, req: &'a Request<'_>) -> rocket::response::Result<'a> {
+        let mut json = self.0.to_json(self.1);
+        // a relayed error keeps the instance it came from
+        if let Some(body) = json.as_object_mut() {
+            body.entry("instance").or_insert_with(|| instance_id().into());
+        }
+        let json = json.to_string();
+        // a relayed instance's status, or whatever is in front of it, may be one Rocket doesn't
+        // know
+        let status = Status::from_code(self.0.status_code()).unwrap_or(Status::BadGateway);
+        let mut response = Response::build();
+        response.status(status).sized_body(json.len(), io::Cursor::new(json));
+        if let Some(wait) = self.0.retry_after() {
+            response.raw_header("Retry-After", ceil_secs(wait).to_string());
+        }
+        let attempts = format_attempts(&req.local_cache(AttemptLog::default).0.lock().unwrap());
+        if !attempts.is_empty() {
+            response.raw_header("X-City17-Attempts", attempts);
+        }
+        response.ok()
+    }
+}
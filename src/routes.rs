@@ -0,0 +1,861 @@
+//! The server: its routes, request guards, and how they're put together.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use futures_util::future::join;
+
+use once_cell::sync::Lazy;
+use rocket::fairing::AdHoc;
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::content::RawJson;
+use rocket::shield::{Permission, Policy, Shield};
+use rocket::{
+    catch, catchers, delete, get, put, routes, Build, Either, FromForm, Request, Rocket, State,
+};
+use tokio::try_join;
+
+use crate::cache::{fetch_live, CacheStatus, PLAYLIST_CACHE};
+use crate::client::client_fairing;
+#[cfg(feature = "resolve")]
+use crate::client::{override_ips, refresh_fairing};
+use crate::clip::{clip_urls, pick_quality, validate_clip_slug};
+use crate::config::{
+    env_flag, get_max_header_bytes, split_list, workers_for_cpus, OAuthToken, Settings, Upstream,
+    DEFAULT_MAX_HEADER_BYTES,
+};
+use crate::error::{ErrorResponder, ResultExt};
+use crate::gql::{
+    host_target, latest_vod, low_latency, validate_channel, validate_player_type, Variables,
+    PLAYER_TYPE,
+};
+use crate::instance::{init_logger, instance_id, redact_oauth};
+use crate::keepwarm::keep_warm_fairing;
+use crate::latency::{self, Stage};
+use crate::playlist::{is_audio_only, MAX_VARIANTS};
+use crate::preview::{fetch_preview, preview_size, preview_url};
+use crate::responders::{
+    AttemptLog, ClipResponder, DryRun, M3U8Responder, Negotiated, PlaylistFormat, PreviewResponder,
+};
+use crate::usher::{fetch_playlist, front_fairing, Attempts, Playlist};
+use crate::Error;
+use crate::{dryrun, relay};
+
+/// Build the server. Nothing goes upstream until a request comes in, other than checking a
+/// front from `CITY17_USHER_FRONT`.
+pub fn build_rocket(settings: Settings) -> Rocket<Build> {
+    // respects cgroup CPU limits, which is what a container gets sized by
+    let cpus = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let config = rocket::Config {
+        port: settings.port,
+        address: settings.address,
+        workers: settings.workers.unwrap_or_else(|| workers_for_cpus(cpus)),
+        keep_alive: settings.keep_alive,
+        ..Default::default()
+    };
+    // use a non-default Shield that only blocks FLoC and adds a CORS header, each optional
+    // the default also has NoSniff and anti-framejacking stuff that we don't need
+    let mut shield = Shield::new();
+    if settings.permissions_policy {
+        shield = shield.enable(Permission::default());
+    }
+    if settings.cors {
+        shield = shield.enable(LaxCORSOrigin);
+    }
+    #[cfg(not(feature = "resolve"))]
+    let routes = routes![
+        process_live,
+        process_vod,
+        process_latest_vod,
+        clip,
+        preview,
+        health,
+        ready,
+        enable_maintenance,
+        disable_maintenance,
+        timeouts
+    ];
+    #[cfg(feature = "resolve")]
+    let routes = routes![
+        process_live,
+        process_vod,
+        process_latest_vod,
+        clip,
+        preview,
+        health,
+        ready,
+        enable_maintenance,
+        disable_maintenance,
+        timeouts,
+        resolve
+    ];
+    // before Rocket would set up its own
+    init_logger(config.log_level.into());
+    let rocket = rocket::custom(&config);
+    match config.address {
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            log::info!("listening on [::]:{}, IPv4 included if the OS is dual-stack", config.port)
+        }
+        address => log::info!("listening on {} only", SocketAddr::new(address, config.port)),
+    }
+    match settings.workers {
+        Some(workers) => log::info!("using {} workers from CITY17_WORKERS", workers),
+        None => log::info!("{} CPUs available, using {} workers", cpus, config.workers),
+    }
+    let rocket = match config.keep_alive {
+        0 => rocket,
+        seconds => rocket.attach(keep_alive_fairing(seconds)),
+    };
+    let rocket = match settings.keep_warm {
+        Some(keep_warm) => rocket.attach(keep_warm_fairing(keep_warm)),
+        None => rocket,
+    };
+    #[cfg(feature = "resolve")]
+    let rocket = rocket.attach(refresh_fairing());
+    rocket
+        .attach(client_fairing())
+        .attach(instance_fairing())
+        .attach(shield)
+        .attach(front_fairing(settings.upstream))
+        .register("/", catchers![not_found, headers_too_large])
+        .mount(settings.route_prefix, routes)
+}
+
+/// CORS header to allow all origins.
+#[derive(Copy, Clone, Debug, Default)]
+struct LaxCORSOrigin;
+
+impl Policy for LaxCORSOrigin {
+    const NAME: &'static str = "Access-Control-Allow-Origin";
+
+    fn header(&self) -> Header<'static> {
+        Header::new(Self::NAME, "*")
+    }
+}
+
+/// Tell clients how long their connection will stay open, so they reuse it between polls.
+fn keep_alive_fairing(seconds: u32) -> AdHoc {
+    AdHoc::on_response("Keep-Alive hints", move |_, response| {
+        Box::pin(async move {
+            response.set_raw_header("Connection", "keep-alive");
+            response.set_raw_header("Keep-Alive", format!("timeout={}", seconds));
+        })
+    })
+}
+
+/// Say which instance answered, on every response.
+fn instance_fairing() -> AdHoc {
+    AdHoc::on_response("Instance ID", |_, response| {
+        Box::pin(async move {
+            response.set_raw_header("X-Instance", instance_id());
+        })
+    })
+}
+
+/// Catch 404 and show what URL was requested, less any OAuth token.
+#[catch(404)]
+fn not_found(req: &Request) -> String {
+    format!("{} does not exist", redact_oauth(&req.uri().to_string()))
+}
+
+#[catch(431)]
+fn headers_too_large() -> &'static str {
+    "request headers are too large"
+}
+
+/// Cap on the total size of a request's headers, so nobody can make us chew through huge ones.
+/// Set `CITY17_MAX_HEADER_BYTES` to change it; one that doesn't parse stops the server at launch.
+static MAX_HEADER_BYTES: Lazy<usize> =
+    Lazy::new(|| get_max_header_bytes().unwrap_or(DEFAULT_MAX_HEADER_BYTES));
+
+/// Request guard that rejects requests whose headers add up to more than [`MAX_HEADER_BYTES`]
+/// with a 431, before anything else is done with them. Rocket runs guards in the order they're
+/// declared, so it's every route's first parameter.
+pub(crate) struct HeaderLimit;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for HeaderLimit {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let size: usize = req.headers().iter().map(|h| h.name().len() + h.value().len()).sum();
+        if size > *MAX_HEADER_BYTES {
+            Outcome::Error((Status::RequestHeaderFieldsTooLarge, ()))
+        } else {
+            Outcome::Success(HeaderLimit)
+        }
+    }
+}
+
+/// Request guard picking a playlist endpoint's format from `Accept`. JSON is only sent when it's
+/// preferred over anything a player could mean; no header, `*/*`, or a tie gets the playlist.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PlaylistFormat {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let accept = match req.accept() {
+            Some(accept) => accept,
+            None => return Outcome::Success(PlaylistFormat::M3U8),
+        };
+        let (mut json, mut playlist) = (0.0, 0.0);
+        for media in accept.iter() {
+            let weight = media.weight_or(1.0);
+            let media = media.media_type();
+            if media.top() == "application" && media.sub() == "json" {
+                json = weight.max(json);
+            } else if ["vnd.apple.mpegurl", "x-mpegurl", "mpegurl", "*"]
+                .iter()
+                .any(|s| media.sub() == *s)
+            {
+                playlist = weight.max(playlist);
+            }
+        }
+        Outcome::Success(if json > playlist { PlaylistFormat::Json } else { PlaylistFormat::M3U8 })
+    }
+}
+
+/// Request guard reading how many instances have relayed this request, from
+/// [`HOP_HEADER`](relay::HOP_HEADER).
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Hops(u32);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Hops {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let hops = req.headers().get_one(relay::HOP_HEADER).and_then(|h| h.parse().ok());
+        Outcome::Success(Hops(hops.unwrap_or(0)))
+    }
+}
+
+/// Request guard for the request's [`AttemptLog`], shared with its error responder.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r AttemptLog {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(req.local_cache(AttemptLog::default))
+    }
+}
+
+/// Request guard for the [`Upstream`] to fetch a playlist with: the configured one, plus the
+/// viewer's own Twitch token if the request has `Authorization: OAuth <token>` (any case) or
+/// `?oauth=`, for subscriber-only VODs. The token isn't checked until [`ViewerUpstream::get`].
+pub(crate) struct ViewerUpstream<'r> {
+    upstream: &'r Upstream,
+    token: Option<&'r str>,
+}
+
+impl<'r> ViewerUpstream<'r> {
+    /// The upstream, with the viewer's token in it if they sent a valid one.
+    fn get(&self) -> Result<Cow<'r, Upstream>, Error> {
+        let raw = match self.token {
+            Some(raw) => raw,
+            None => return Ok(Cow::Borrowed(self.upstream)),
+        };
+        let oauth = Some(OAuthToken::parse(raw)?);
+        Ok(Cow::Owned(Upstream { oauth, ..self.upstream.clone() }))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ViewerUpstream<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let upstream = match req.rocket().state::<Upstream>() {
+            Some(upstream) => upstream,
+            None => return Outcome::Error((Status::InternalServerError, ())),
+        };
+        // other schemes are for something else in front of this, not Twitch
+        let header = req.headers().get_one("Authorization").and_then(|value| {
+            let scheme = value.get(..6).filter(|scheme| scheme.eq_ignore_ascii_case("OAuth "))?;
+            Some(&value[scheme.len()..])
+        });
+        let token = header.or_else(|| req.query_value::<&str>("oauth").and_then(Result::ok));
+        Outcome::Success(ViewerUpstream { upstream, token })
+    }
+}
+
+/// Request guard for admin endpoints: the `X-API-Key` header must match `CITY17_ADMIN_KEY`.
+/// Without that variable set, admin endpoints act like they don't exist.
+pub(crate) struct AdminKey;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminKey {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        static KEY: Lazy<Option<String>> =
+            Lazy::new(|| env::var("CITY17_ADMIN_KEY").ok().filter(|k| !k.is_empty()));
+        match (KEY.as_deref(), req.headers().get_one("X-API-Key")) {
+            (None, _) => Outcome::Error((Status::NotFound, ())),
+            (Some(key), Some(given)) if same_key(key, given) => Outcome::Success(AdminKey),
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Compares keys without stopping at the first differing byte, so response timing doesn't tell
+/// a guesser how much of the key they have right.
+fn same_key(key: &str, given: &str) -> bool {
+    key.len() == given.len()
+        && key.bytes().zip(given.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// When set, live and VOD requests get a 503 with this message instead of going upstream.
+/// Starts out as `CITY17_MAINTENANCE` and can be changed at runtime by the admin endpoints.
+static MAINTENANCE: Lazy<RwLock<Option<String>>> =
+    Lazy::new(|| RwLock::new(env::var("CITY17_MAINTENANCE").ok().filter(|m| !m.is_empty())));
+
+/// Turn on maintenance mode, with the request body as the message shown to clients.
+#[put("/admin/maintenance", data = "<message>")]
+fn enable_maintenance(_limit: HeaderLimit, message: String, _key: AdminKey) -> &'static str {
+    let message = if message.is_empty() { "try again later".to_owned() } else { message };
+    log::warn!("maintenance mode on: {}", message);
+    *MAINTENANCE.write().unwrap() = Some(message);
+    "maintenance mode on"
+}
+
+#[delete("/admin/maintenance")]
+fn disable_maintenance(_limit: HeaderLimit, _key: AdminKey) -> &'static str {
+    log::warn!("maintenance mode off");
+    *MAINTENANCE.write().unwrap() = None;
+    "maintenance mode off"
+}
+
+/// The timeout each upstream stage gets right now and how many requests it's going by, to see
+/// whether adaptive timeouts have kicked in. Until a stage has [`latency::MIN_SAMPLES`], its
+/// timeout is the configured one.
+#[get("/timeouts")]
+fn timeouts(_limit: HeaderLimit, upstream: &State<Upstream>) -> RawJson<String> {
+    use serde_json::json;
+
+    let stage = |stage| {
+        json!({
+            "timeout": latency::timeout(stage, upstream.timeout).as_secs_f64(),
+            "samples": latency::samples(stage),
+        })
+    };
+    RawJson(json!({ "gql": stage(Stage::Gql), "usher": stage(Stage::Usher) }).to_string())
+}
+
+/// For load balancers and orchestrators to check that the process is up. Nothing goes upstream,
+/// and it answers 200 even in maintenance mode, with the message in `maintenance`. While token
+/// requests are held back after a 429 from GQL, `cooldown_ms` says for how much longer.
+#[get("/health")]
+fn health(_limit: HeaderLimit) -> RawJson<String> {
+    use serde_json::json;
+
+    let cooldown_ms = crate::gql::cooldown_remaining().map(|wait| wait.as_millis() as u64);
+    let body = json!({ "status": "ok", "maintenance": maintenance(), "cooldown_ms": cooldown_ms });
+    RawJson(body.to_string())
+}
+
+/// How long `/ready` waits on each front, well under the few seconds load balancers usually
+/// give a check, so a front that hangs shows up as not ready instead of as a hung check.
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// For load balancers to check that the fronts can be reached from here right now, which is
+/// what usually breaks. 200 if both GQL's and usher's answer within [`READY_TIMEOUT`], 503 if
+/// not or in maintenance mode, with how long each took either way.
+#[get("/ready")]
+async fn ready(_limit: HeaderLimit, upstream: &State<Upstream>) -> (Status, RawJson<String>) {
+    use serde_json::json;
+
+    let mut quick = upstream.inner().clone();
+    quick.timeout = quick.timeout.min(READY_TIMEOUT);
+    let start = Instant::now();
+    let gql = ready_check(crate::gql::probe_front(&quick));
+    let usher = ready_check(crate::usher::probe_front(&quick));
+    let (gql, usher) = join(gql, usher).await;
+    let maintenance = maintenance();
+    let ready = maintenance.is_none() && gql["ok"] == true && usher["ok"] == true;
+    let status = if ready { Status::Ok } else { Status::ServiceUnavailable };
+    let body = json!({
+        "ready": ready,
+        "time": start.elapsed().as_secs_f64(),
+        "gql": gql,
+        "usher": usher,
+        "maintenance": maintenance,
+    });
+    (status, RawJson(body.to_string()))
+}
+
+/// How a `/ready` probe went, and how long it took.
+async fn ready_check(probe: impl Future<Output = Result<(), Error>>) -> serde_json::Value {
+    use serde_json::json;
+
+    let start = Instant::now();
+    let result = probe.await;
+    let mut check = json!({ "ok": result.is_ok(), "time": start.elapsed().as_secs_f64() });
+    if let Err(e) = result {
+        check["kind"] = json!(e.kind());
+    }
+    check
+}
+
+/// Endpoint to print resolved IPs, and the addresses the client uses for the domain in place of
+/// a lookup, in the order they're tried, or null if it looks it up like any other. Useful when
+/// running inside China to find current IPs for CDNs and such things, for hardcoding into
+/// HardResolver. `domain` needs a port, e.g. `usher.ttvnw.net:443`.
+/// Not enabled by default both because it's useless outside of that and for legal reasons.
+#[cfg(feature = "resolve")]
+#[get("/resolve/<domain>")]
+fn resolve(_limit: HeaderLimit, domain: &str) -> Result<String, ErrorResponder> {
+    use std::net::ToSocketAddrs;
+
+    use serde_json::json;
+
+    let start = Instant::now();
+    let addrs = domain.to_socket_addrs().map_err(Error::Resolve).into_responder("resolve")?;
+    let addrs = addrs.collect::<Vec<_>>();
+    let end = Instant::now();
+    let body = json!({
+        "time": end.duration_since(start).as_secs_f64(),
+        "addrs": addrs,
+        "overrides": override_ips(domain.rsplit_once(':').map_or(domain, |(host, _)| host)),
+    });
+    Ok(body.to_string())
+}
+
+#[get("/live/<channel>?<options..>")]
+async fn process_live(
+    _limit: HeaderLimit,
+    channel: &str,
+    options: PlaylistOptions,
+    format: PlaylistFormat,
+    hops: Hops,
+    log: &AttemptLog,
+    upstream: ViewerUpstream<'_>,
+) -> Result<Either<Negotiated, DryRun>, ErrorResponder> {
+    let format = options.validate(format).into_responder("input")?;
+    let channel = validate_channel(channel).into_responder("input")?;
+    let upstream = &*upstream.get().into_responder("input")?;
+    if options.dry_run()? {
+        let plan = dryrun::plan(
+            &Variables::Channel(channel),
+            options.player_type(),
+            options.meta(),
+            hops.0,
+            upstream,
+        );
+        return Ok(Either::Right(DryRun(plan)));
+    }
+    let responder =
+        match process(Variables::Channel(channel.clone()), &options, hops, log, upstream).await {
+            Err(e) if options.follows() && e.1 == "M3U" && e.0.is_not_found() => {
+                follow_redirect(&channel, &options, hops, log, upstream).await.ok_or(e)?
+            }
+            result => result?,
+        };
+    Ok(Either::Left(Negotiated::new(responder, format).await?))
+}
+
+/// Serve whatever offline `channel` is pointing its viewers at, if anything: first a target
+/// from `CITY17_REDIRECTS`, then whoever GQL says it's hosting. `None` if there's no target or
+/// it can't be served either, so the caller can report the channel as offline.
+async fn follow_redirect(
+    channel: &str,
+    options: &PlaylistOptions,
+    hops: Hops,
+    log: &AttemptLog,
+    upstream: &Upstream,
+) -> Option<M3U8Responder> {
+    let target = match REDIRECTS.get(channel) {
+        Some(target) => target.clone(),
+        None => match host_target(channel, upstream).await {
+            Ok(target) => target.filter(|t| t != channel && validate_channel(t).is_ok())?,
+            Err(e) => {
+                log::info!("couldn't look up where {} redirects: {}", channel, e);
+                return None;
+            }
+        },
+    };
+    match process(Variables::Channel(target.clone()), options, hops, log, upstream).await {
+        Ok(M3U8Responder(playlist, cache, mut info)) => {
+            log::debug!("{} is offline, serving {} instead", channel, target);
+            info.redirected_from = Some(channel.to_owned());
+            Some(M3U8Responder(playlist, cache, info))
+        }
+        Err(e) => {
+            log::info!("{} redirects to {}, which failed too: {}", channel, target, e);
+            None
+        }
+    }
+}
+
+/// Offline channels to serve another channel in place of, with `?follow=1`, as a
+/// comma-separated list of `from=to`, e.g. a streamer's rerun channel.
+static REDIRECTS: Lazy<HashMap<String, String>> = Lazy::new(|| {
+    let raw = env::var("CITY17_REDIRECTS").unwrap_or_default();
+    let redirects = split_list(&raw).filter_map(|entry| {
+        let parsed = entry.split_once('=').and_then(|(from, to)| {
+            Some((validate_channel(from.trim()).ok()?, validate_channel(to.trim()).ok()?))
+        });
+        if parsed.is_none() {
+            log::warn!("ignoring {:?} in CITY17_REDIRECTS, it isn't channel=channel", entry);
+        }
+        parsed
+    });
+    redirects.collect()
+});
+
+#[get("/vod/<id>?<options..>")]
+async fn process_vod(
+    _limit: HeaderLimit,
+    id: u64,
+    options: PlaylistOptions,
+    format: PlaylistFormat,
+    hops: Hops,
+    log: &AttemptLog,
+    upstream: ViewerUpstream<'_>,
+) -> Result<Either<Negotiated, DryRun>, ErrorResponder> {
+    let format = options.validate(format).into_responder("input")?;
+    check_vods_enabled()?;
+    check_vod_allowed(id)?;
+    let upstream = &*upstream.get().into_responder("input")?;
+    if options.dry_run()? {
+        let plan = dryrun::plan(
+            &Variables::VOD(id.to_string()),
+            options.player_type(),
+            false,
+            hops.0,
+            upstream,
+        );
+        return Ok(Either::Right(DryRun(plan)));
+    }
+    let responder = process(Variables::VOD(id.to_string()), &options, hops, log, upstream).await?;
+    Ok(Either::Left(Negotiated::new(responder, format).await?))
+}
+
+/// The channel's most recent VOD, as if it had been asked for by ID.
+#[get("/vod/latest/<channel>?<options..>")]
+async fn process_latest_vod(
+    _limit: HeaderLimit,
+    channel: &str,
+    options: PlaylistOptions,
+    format: PlaylistFormat,
+    hops: Hops,
+    log: &AttemptLog,
+    upstream: ViewerUpstream<'_>,
+) -> Result<Either<Negotiated, DryRun>, ErrorResponder> {
+    let format = options.validate(format).into_responder("input")?;
+    check_vods_enabled()?;
+    let channel = validate_channel(channel).into_responder("input")?;
+    // finding the VOD goes upstream before `process` would check
+    check_maintenance()?;
+    let upstream = &*upstream.get().into_responder("input")?;
+    if options.dry_run()? {
+        return Ok(Either::Right(DryRun(dryrun::plan_latest_vod(
+            &channel,
+            options.player_type(),
+            hops.0,
+            upstream,
+        ))));
+    }
+    let id = latest_vod(&channel, upstream).await.into_responder("GQL")?;
+    let id = id.ok_or(Error::NoVods(channel)).into_responder("GQL")?;
+    check_vod_allowed(id)?;
+    let responder = process(Variables::VOD(id.to_string()), &options, hops, log, upstream).await?;
+    Ok(Either::Left(Negotiated::new(responder, format).await?))
+}
+
+/// A clip's qualities and their signed MP4 URLs, or with `?quality=` the URL of just that one.
+#[get("/clip/<slug>?<quality>")]
+async fn clip(
+    _limit: HeaderLimit,
+    slug: &str,
+    quality: Option<&str>,
+    upstream: &State<Upstream>,
+) -> Result<ClipResponder, ErrorResponder> {
+    check_maintenance()?;
+    let slug = validate_clip_slug(slug).into_responder("input")?;
+    let urls = clip_urls(&slug, upstream).await.into_responder("GQL")?;
+    match quality {
+        Some(name) => {
+            let url = pick_quality(&urls, name).into_responder("input")?;
+            Ok(ClipResponder::Url(url.url.clone()))
+        }
+        None => Ok(ClipResponder::Qualities { slug, urls }),
+    }
+}
+
+/// The channel's live preview image: its URL as JSON, or with `?proxy=1` the image itself.
+#[get("/preview/<channel>?<options..>")]
+async fn preview(
+    _limit: HeaderLimit,
+    channel: &str,
+    options: PreviewOptions,
+    upstream: &State<Upstream>,
+) -> Result<PreviewResponder, ErrorResponder> {
+    check_maintenance()?;
+    let channel = validate_channel(channel).into_responder("input")?;
+    let size = preview_size(options.w, options.h).into_responder("input")?;
+    let url = preview_url(&channel, size, upstream).await.into_responder("GQL")?;
+    if !is_on(options.proxy.as_deref()) {
+        return Ok(PreviewResponder::Url(url));
+    }
+    let (body, content_type) = fetch_preview(&url, upstream).await.into_responder("preview")?;
+    Ok(PreviewResponder::Image { body, content_type })
+}
+
+#[derive(Debug, Default, FromForm)]
+pub(crate) struct PreviewOptions {
+    w: Option<u32>,
+    h: Option<u32>,
+    /// `1` to send the image rather than its URL.
+    proxy: Option<String>,
+}
+
+fn check_vods_enabled() -> Result<(), ErrorResponder> {
+    if *VODS_DISABLED {
+        let e = Error::Unsupported("VODs are turned off on this instance");
+        return Err(ErrorResponder(e, "unsupported"));
+    }
+    Ok(())
+}
+
+fn check_vod_allowed(id: u64) -> Result<(), ErrorResponder> {
+    if matches!(&*VOD_ALLOWLIST, Some(ids) if !ids.contains(&id)) {
+        let e = Error::NotAllowed("this instance only serves certain VODs");
+        return Err(ErrorResponder(e, "allowlist"));
+    }
+    Ok(())
+}
+
+/// Query parameters that change what's done to a playlist on its way out.
+#[derive(Debug, Default, FromForm)]
+pub(crate) struct PlaylistOptions {
+    /// Keep only this many renditions, the source and then the highest bandwidth.
+    /// audio_only is always kept, so 0 leaves just that. `max_renditions` is the older name.
+    #[field(name = "max_variants")]
+    #[field(name = "max_renditions")]
+    pub(crate) max_renditions: Option<usize>,
+    /// `1` to serve the channel an offline one points at instead, if there is one.
+    pub(crate) follow: Option<String>,
+    /// `token` to answer with the playlist, its token's details, and timings as one JSON
+    /// document, whatever `Accept` says.
+    pub(crate) include: Option<String>,
+    /// `1` to describe the upstream requests instead of sending them. Needs `CITY17_DEBUG=1`.
+    pub(crate) dryrun: Option<String>,
+    /// The player to ask GQL for a token as, one of [`PLAYER_TYPES`](crate::gql::PLAYER_TYPES).
+    /// Anything but the default skips the live playlist cache.
+    pub(crate) player_type: Option<String>,
+    /// `1` to also ask GQL about a live stream, which for now says whether it's low latency.
+    pub(crate) meta: Option<String>,
+}
+
+impl PlaylistOptions {
+    fn follows(&self) -> bool {
+        is_on(self.follow.as_deref())
+    }
+
+    fn meta(&self) -> bool {
+        is_on(self.meta.as_deref())
+    }
+
+    /// Check the options make sense, and pick the format to answer in given the one `Accept`
+    /// asked for.
+    fn validate(&self, accepted: PlaylistFormat) -> Result<PlaylistFormat, Error> {
+        if let Some(max) = self.max_renditions {
+            if max > MAX_VARIANTS {
+                let message = format!("max_variants must be from 0 to {}", MAX_VARIANTS);
+                return Err(Error::Input(message.into()));
+            }
+        }
+        if let Some(player_type) = &self.player_type {
+            validate_player_type(player_type)?;
+        }
+        match self.include.as_deref() {
+            None => Ok(accepted),
+            Some("token") => Ok(PlaylistFormat::Bundle),
+            Some(_) => Err(Error::Input("include must be token".into())),
+        }
+    }
+
+    /// The player to ask for a token as, once [`validate`](Self::validate) has checked it.
+    fn player_type(&self) -> &'static str {
+        let player_type = self.player_type.as_deref().map(validate_player_type);
+        player_type.and_then(Result::ok).unwrap_or(PLAYER_TYPE)
+    }
+
+    fn dry_run(&self) -> Result<bool, ErrorResponder> {
+        match is_on(self.dryrun.as_deref()) {
+            true if !*dryrun::DEBUG => {
+                let e = Error::NotAllowed("dry runs need CITY17_DEBUG=1 on the server");
+                Err(ErrorResponder(e, "input"))
+            }
+            on => Ok(on),
+        }
+    }
+}
+
+/// Whether an on/off query parameter is set to on, the same way [`env_flag`] reads variables.
+fn is_on(value: Option<&str>) -> bool {
+    matches!(value, Some("1") | Some("true"))
+}
+
+/// With `CITY17_DISABLE_VODS=1`, the VOD route stays mounted but answers 501, so clients can
+/// tell a disabled capability apart from a bad path.
+static VODS_DISABLED: Lazy<bool> = Lazy::new(|| env_flag("CITY17_DISABLE_VODS"));
+
+/// The only VODs served when `CITY17_VOD_ALLOWLIST` is set, as a comma-separated list of IDs.
+/// Unset or empty serves any VOD.
+static VOD_ALLOWLIST: Lazy<Option<HashSet<u64>>> = Lazy::new(|| {
+    let raw = env::var("CITY17_VOD_ALLOWLIST").ok().filter(|list| !list.trim().is_empty())?;
+    let ids = split_list(&raw).filter_map(|id| match id.trim_start_matches('v').parse() {
+        Ok(id) => Some(id),
+        Err(_) => {
+            log::warn!("ignoring {:?} in CITY17_VOD_ALLOWLIST, it isn't a VOD ID", id);
+            None
+        }
+    });
+    Some(ids.collect())
+});
+
+async fn process(
+    var: Variables,
+    options: &PlaylistOptions,
+    hops: Hops,
+    log: &AttemptLog,
+    upstream: &Upstream,
+) -> Result<M3U8Responder, ErrorResponder> {
+    let channel = match &var {
+        Variables::Channel(channel) if options.meta() => Some(channel.clone()),
+        _ => None,
+    };
+    let fetching = fetch(var, options.player_type(), channel.is_some(), hops, log, upstream);
+    // a relayed playlist comes with the other instance's metadata
+    let channel = channel.filter(|_| upstream.relay.is_none());
+    // sent alongside the token request rather than after the playlist, and given up on as soon
+    // as the playlist fails
+    let metadata = async {
+        match &channel {
+            Some(channel) => Ok(stream_metadata(channel, &upstream.with_deadline()).await),
+            None => Ok(None),
+        }
+    };
+    let (M3U8Responder(playlist, cache, mut info), low_latency) = try_join!(fetching, metadata)?;
+    if channel.is_some() {
+        info.low_latency = low_latency;
+    }
+    check_audio_only(M3U8Responder(playlist, cache, info))?.transform(options).await
+}
+
+/// Whether `channel` is low latency, for `?meta=1`. The playlist is fine without it, so a
+/// failed lookup only leaves it out.
+async fn stream_metadata(channel: &str, upstream: &Upstream) -> Option<bool> {
+    match low_latency(channel, upstream).await {
+        Ok(low_latency) => low_latency,
+        Err(e) => {
+            log::info!("couldn't look up {}'s stream metadata: {}", channel, e);
+            None
+        }
+    }
+}
+
+/// What to do with a playlist that has only audio renditions, from `CITY17_AUDIO_ONLY`:
+/// `header` (the default) serves it with `X-City17-Audio-Only: true`, `pass` serves it without,
+/// and `error` answers with an error saying only audio is available.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum AudioOnly {
+    Header,
+    Pass,
+    Error,
+}
+
+static AUDIO_ONLY: Lazy<AudioOnly> = Lazy::new(|| {
+    let mode = env::var("CITY17_AUDIO_ONLY");
+    match mode.as_deref() {
+        Err(_) | Ok("header") => AudioOnly::Header,
+        Ok("pass") => AudioOnly::Pass,
+        Ok("error") => AudioOnly::Error,
+        Ok(other) => {
+            log::warn!("CITY17_AUDIO_ONLY={:?} isn't header, pass, or error; using header", other);
+            AudioOnly::Header
+        }
+    }
+});
+
+/// Handle audio-only playlists as configured. Only playlists we already have in full (live
+/// ones, which go through the cache) are checked; streamed VODs go out as they arrive.
+fn check_audio_only(responder: M3U8Responder) -> Result<M3U8Responder, ErrorResponder> {
+    let M3U8Responder(playlist, cache, mut info) = responder;
+    let audio_only = matches!(&playlist, Playlist::Full(body) if is_audio_only(body));
+    match *AUDIO_ONLY {
+        _ if !audio_only => {}
+        AudioOnly::Header => info.audio_only = true,
+        AudioOnly::Pass => {}
+        AudioOnly::Error => return Err(ErrorResponder(Error::AudioOnly, "M3U")),
+    }
+    Ok(M3U8Responder(playlist, cache, info))
+}
+
+/// The maintenance message, if in maintenance mode.
+fn maintenance() -> Option<String> {
+    MAINTENANCE.read().unwrap().clone()
+}
+
+fn check_maintenance() -> Result<(), ErrorResponder> {
+    match maintenance() {
+        Some(message) => Err(ErrorResponder(Error::Maintenance(message), "maintenance")),
+        None => Ok(()),
+    }
+}
+
+async fn fetch(
+    var: Variables,
+    player_type: &'static str,
+    meta: bool,
+    hops: Hops,
+    log: &AttemptLog,
+    upstream: &Upstream,
+) -> Result<M3U8Responder, ErrorResponder> {
+    check_maintenance()?;
+    // one clock for GQL, usher, and all their retries
+    let upstream = &upstream.with_deadline();
+    let mut attempts = Attempts::new();
+    let result = fetch_upstream(var, player_type, meta, hops, upstream, &mut attempts).await;
+    log.add(&attempts);
+    result
+}
+
+async fn fetch_upstream(
+    var: Variables,
+    player_type: &'static str,
+    meta: bool,
+    hops: Hops,
+    upstream: &Upstream,
+    attempts: &mut Attempts,
+) -> Result<M3U8Responder, ErrorResponder> {
+    if let Some(relay) = &upstream.relay {
+        // the other instance has its own cache
+        let (body, cache, info) =
+            relay::fetch(&var, player_type, meta, relay, hops.0, upstream, attempts).await?;
+        return Ok(M3U8Responder(body.into(), cache, info));
+    }
+    // what a viewer's own token gets them isn't for anyone else
+    let viewers_own = upstream.oauth.is_some();
+    if viewers_own || !matches!(var, Variables::Channel(_)) || player_type != PLAYER_TYPE {
+        let (playlist, info) = fetch_playlist(&var, player_type, upstream, attempts).await?;
+        return Ok(M3U8Responder(playlist, CacheStatus::Bypass, info));
+    }
+    if let Some((body, mut info)) = PLAYLIST_CACHE.get(&var) {
+        // this request didn't wait on any of them
+        info.timings.clear();
+        info.attempts.clear();
+        return Ok(M3U8Responder(body.into(), CacheStatus::Hit, info));
+    }
+    let ((body, info), status) = fetch_live(var, upstream, attempts).await?;
+    Ok(M3U8Responder(body.into(), status, info))
+}
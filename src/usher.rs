@@ -0,0 +1,522 @@
+//! Getting playlists from usher, Twitch's playlist server, once GQL has given us a token.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use futures_util::stream::{BoxStream, StreamExt, TryStreamExt};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use reqwest::{Client, StatusCode};
+#[cfg(feature = "server")]
+use rocket::fairing::AdHoc;
+use serde::Deserialize;
+
+use crate::client::{
+    alt_front_client, check_status, client, dns_client, dns_front_client, front_client, send,
+};
+#[cfg(feature = "server")]
+use crate::config::DEFAULT_USHER_FRONT;
+use crate::config::{env_flag, Upstream};
+use crate::error::{ErrorResponder, ResultExt};
+use crate::gql::{get_access_token, AccessTokenResponse, AdFlags, PlaybackAccessToken, Variables};
+use crate::latency::{self, Stage};
+use crate::playlist::{is_vp9_dominant, CODECS, M3U8_MAGIC};
+use crate::{generate_id, get_rng, Error};
+
+/// What we know about a fetched playlist besides its body.
+#[derive(Clone, Debug, Default)]
+pub struct FetchInfo {
+    /// When the token expires, if it could be decoded. Sent as `X-City17-Token-Expires` so
+    /// clients can refresh just before it stops working.
+    pub expires: Option<i64>,
+    /// How long each upstream stage took, sent as `Server-Timing`.
+    pub timings: Vec<(&'static str, Duration)>,
+    /// How many tries each upstream stage needed, sent as `X-City17-Attempts`. Counts that rise
+    /// across requests are an early sign that a front or IP is going bad.
+    pub attempts: Attempts,
+    /// The playlist has no video renditions, sent as `X-City17-Audio-Only`.
+    pub audio_only: bool,
+    /// When the stream started, as a Unix timestamp, sent as `X-Stream-Started-At`. Only known
+    /// for live playlists, which are read in full anyway.
+    pub started_at: Option<i64>,
+    /// Whether the stream is low latency, sent as `X-Stream-Low-Latency`. Only looked up for
+    /// live playlists asked for with `?meta=1`.
+    pub low_latency: Option<bool>,
+    /// The channel that was asked for, when it was offline and this is the one it points at
+    /// instead. Sent as `X-Redirected-From`.
+    pub redirected_from: Option<String>,
+    /// The address GQL's token came from, sent as `X-Upstream-GQL-IP`. With several addresses
+    /// for a front, this tells which one a request went through.
+    pub gql_ip: Option<IpAddr>,
+    /// The address the playlist came from, sent as `X-Upstream-Usher-IP`.
+    pub usher_ip: Option<IpAddr>,
+    /// What the token says about ads. Only sent with `?include=token`.
+    pub ads: Option<AdFlags>,
+    /// GQL's ID for the token request, for matching it up with Twitch's side. Only sent with
+    /// `?include=token`.
+    pub request_id: Option<String>,
+    /// Usher restricted the quality, so the playlist was fetched again without the source.
+    /// Sent as `X-Quality-Restricted`.
+    pub quality_restricted: bool,
+}
+
+impl FetchInfo {
+    /// [`timings`](Self::timings) as a `Server-Timing` value.
+    pub fn server_timing(&self) -> String {
+        let stages = self.timings.iter();
+        let stages = stages.map(|(stage, took)| format!("{};dur={:.1}", stage, millis(*took)));
+        stages.collect::<Vec<_>>().join(", ")
+    }
+
+    /// [`attempts`](Self::attempts) as e.g. `gql=1, usher=2`.
+    pub fn attempts(&self) -> String {
+        format_attempts(&self.attempts)
+    }
+
+    /// Note what's known about the token in `response`, which is the one the playlist is for.
+    fn token(&mut self, response: &AccessTokenResponse) {
+        self.gql_ip = response.remote_addr.map(|addr| addr.ip());
+        self.ads = response.data.playback_access_token.ad_flags();
+        self.request_id = Some(response.extensions.request_id.clone());
+    }
+
+    /// [`gql_ip`](Self::gql_ip) and [`usher_ip`](Self::usher_ip) for logging, e.g.
+    /// `gql=151.101.110.167, usher=-` if usher's address isn't known.
+    pub fn upstream_ips(&self) -> String {
+        let ip = |ip: Option<IpAddr>| ip.map_or_else(|| "-".to_owned(), |ip| ip.to_string());
+        format!("gql={}, usher={}", ip(self.gql_ip), ip(self.usher_ip))
+    }
+}
+
+/// How many tries each upstream stage has taken, in the order they were first tried.
+pub type Attempts = Vec<(&'static str, u32)>;
+
+/// `attempts` as e.g. `gql=1, usher=2`, the `X-City17-Attempts` format.
+pub fn format_attempts(attempts: &[(&'static str, u32)]) -> String {
+    let stages = attempts.iter().map(|(stage, tries)| format!("{}={}", stage, tries));
+    stages.collect::<Vec<_>>().join(", ")
+}
+
+/// Count another try at `stage`.
+fn attempt(attempts: &mut Attempts, stage: &'static str) {
+    match attempts.iter_mut().find(|(s, _)| *s == stage) {
+        Some((_, tries)) => *tries += 1,
+        None => attempts.push((stage, 1)),
+    }
+}
+
+pub(crate) fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Fetch a playlist, along with its token's expiry and how long each stage took. Tries are
+/// counted into `attempts` as they're made, so they're known even if the fetch fails.
+pub(crate) async fn fetch_playlist(
+    var: &Variables,
+    player_type: &str,
+    upstream: &Upstream,
+    attempts: &mut Attempts,
+) -> Result<(Playlist, FetchInfo), ErrorResponder> {
+    if *USHER_PREWARM && usher_gone_idle() {
+        tokio::spawn(prewarm_usher(upstream.clone()));
+    }
+    // the server starts the clock when the request comes in; anything else starts it here
+    let own_deadline;
+    let upstream = match upstream.deadline {
+        Some(_) => upstream,
+        None => {
+            own_deadline = upstream.with_deadline();
+            &own_deadline
+        }
+    };
+    let result = try_fetch_playlist(var, player_type, upstream, attempts).await;
+    if let Err(ErrorResponder(e, stage)) = &result {
+        let attempts = format_attempts(attempts);
+        log::info!("fetching {:?} failed at {}, attempts: {}: {}", var, stage, attempts, e);
+    }
+    result
+}
+
+async fn try_fetch_playlist(
+    var: &Variables,
+    player_type: &str,
+    upstream: &Upstream,
+    attempts: &mut Attempts,
+) -> Result<(Playlist, FetchInfo), ErrorResponder> {
+    let mut info = FetchInfo::default();
+    let started = Instant::now();
+    attempt(attempts, "gql");
+    let response = get_access_token(var, player_type, upstream).await.into_responder("GQL")?;
+    info.token(&response);
+    let mut token = response.data.playback_access_token;
+    info.timings.push(("gql", started.elapsed()));
+    let url = var.get_url(&upstream.usher_base);
+    // kept when retrying with a new token or without the source, so usher sees one session
+    // rather than a new viewer each attempt
+    let mut session = session_id(upstream);
+    let started = Instant::now();
+    let usher_timeout = || latency::timeout(Stage::Usher, upstream.timeout);
+    let has_time = || upstream.has_time_for(Duration::ZERO, usher_timeout());
+    attempt(attempts, "usher");
+    let playlist = match get_m3u8(&url, &token, &session, CODECS, true, upstream).await {
+        // only once: if the transcodes are restricted too, there's nothing else to ask for
+        Err(Error::QualityRestricted) if has_time() => {
+            log::info!("usher restricted the quality of {:?}, retrying without the source", var);
+            info.quality_restricted = true;
+            attempt(attempts, "usher");
+            get_m3u8(&url, &token, &session, CODECS, false, upstream).await
+        }
+        Err(e) if e.is_forbidden() && has_time() => {
+            log::info!("usher rejected the token for {:?}, getting a new one", var);
+            let started = Instant::now();
+            attempt(attempts, "gql");
+            let response =
+                get_access_token(var, player_type, upstream).await.into_responder("GQL")?;
+            info.token(&response);
+            token = response.data.playback_access_token;
+            info.timings.push(("gql-retry", started.elapsed()));
+            attempt(attempts, "usher");
+            get_m3u8(&url, &token, &session, CODECS, true, upstream).await
+        }
+        // the token is still good, so there's no need to ask GQL again, but the session may be
+        // what usher is choking on
+        Err(mut e) if e.is_transient() => {
+            let mut tries = 1;
+            loop {
+                if tries == USHER_TRIES || !has_time() {
+                    break Err(e);
+                }
+                // a stalled connection may just be that address having a bad day
+                let elsewhere = e.is_dropped() && upstream.usher_front.is_some();
+                let whereto = if elsewhere { "from the front's next address" } else { "again" };
+                log::info!(
+                    "usher failed for {:?}, retrying {} with the same token: {}",
+                    var,
+                    whereto,
+                    e
+                );
+                tries += 1;
+                session = session_id(upstream);
+                attempt(attempts, "usher");
+                let retry = get_m3u8_via(&url, &token, &session, CODECS, true, upstream, elsewhere);
+                match retry.await {
+                    Err(next) if next.is_transient() => e = next,
+                    result => break result,
+                }
+            }
+        }
+        result => result,
+    };
+    let (playlist, usher_addr) = playlist.into_responder("M3U")?;
+    info.usher_ip = usher_addr.map(|addr| addr.ip());
+    info.timings.push(("usher", started.elapsed()));
+    info.attempts = attempts.clone();
+    log::debug!(
+        "fetched {:?}, attempts: {}, upstream IPs: {}",
+        var,
+        info.attempts(),
+        info.upstream_ips()
+    );
+    info.expires = token.expires();
+    if !*AVC_FALLBACK {
+        return Ok((playlist, info));
+    }
+    let body = playlist.collect().await.map_err(Error::from).into_responder("M3U")?;
+    if !is_vp9_dominant(&body) {
+        return Ok((body.into(), info));
+    }
+    log::info!("{:?} is mostly VP9, refetching with only AVC", var);
+    let started = Instant::now();
+    let allow_source = !info.quality_restricted;
+    let avc = get_m3u8(&url, &token, &session, "avc1", allow_source, upstream).await;
+    let (playlist, usher_addr) = avc.into_responder("M3U")?;
+    info.usher_ip = usher_addr.map(|addr| addr.ip());
+    info.timings.push(("usher-avc", started.elapsed()));
+    Ok((playlist, info))
+}
+
+/// Most tries at usher when it times out or answers 5xx, the first included. However many are
+/// left, they stop once the fetch's deadline doesn't leave time for another; see
+/// [`Upstream::has_time_for`].
+pub const USHER_TRIES: u32 = 3;
+
+/// A play_session_id for a new viewer.
+pub(crate) fn session_id(upstream: &Upstream) -> String {
+    match &upstream.fixed_ids {
+        Some(ids) => ids.play_session_id.clone(),
+        None => generate_id().to_lowercase(),
+    }
+}
+
+/// A connection to usher is opened while the GQL request is in flight, so the playlist request
+/// finds it in the pool instead of waiting on a handshake; compare the `usher` stage in
+/// `Server-Timing` with and without it. It costs usher a HEAD request, so it's off unless
+/// `CITY17_USHER_PREWARM=1`, and even then only sent when the pooled connection has likely been
+/// closed, see [`usher_gone_idle`].
+static USHER_PREWARM: Lazy<bool> = Lazy::new(|| env_flag("CITY17_USHER_PREWARM"));
+
+/// How long the client keeps an idle connection in its pool, reqwest's default.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// When a playlist fetch last went to usher.
+static USHER_LAST_USED: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Whether usher has gone unused long enough that there's no connection to it left in the pool,
+/// as of the fetch about to use it.
+fn usher_gone_idle() -> bool {
+    let mut last_used = USHER_LAST_USED.lock().unwrap();
+    let idle = last_used.is_none_or(|at| at.elapsed() >= POOL_IDLE_TIMEOUT);
+    *last_used = Some(Instant::now());
+    idle
+}
+
+/// Get a connection to usher's front into the client's pool. Nothing waits on it.
+async fn prewarm_usher(upstream: Upstream) {
+    if let Err(e) = probe_front(&upstream).await {
+        log::debug!("usher prewarm failed: {:?}", e);
+    }
+}
+
+/// Connect to usher's front and see that something answers. Any status will do, since all
+/// that's being checked is that the connection and TLS work.
+pub(crate) async fn probe_front(upstream: &Upstream) -> Result<(), Error> {
+    let (client, url) = via_front(&upstream.usher_base, upstream, false)?;
+    client.head(url).header("Host", USHER_HOST).timeout(upstream.timeout).send().await?;
+    Ok(())
+}
+
+/// Check that usher's front passes requests on to usher, rather than just answering TLS. A
+/// playlist request without a token gets one of usher's JSON errors, where a front that doesn't
+/// know usher answers with its own HTML page.
+pub async fn check_front(upstream: &Upstream) -> Result<(), Error> {
+    let url = format!("{}api/channel/hls/city17.m3u8", upstream.usher_base);
+    let (client, url) = via_front(&url, upstream, false)?;
+    let request = client.get(url).header("Host", USHER_HOST).timeout(upstream.timeout);
+    let response = request.send().await?;
+    let status = response.status();
+    if status.is_server_error() {
+        return Err(Error::UpstreamDown { upstream: "usher", status: status.as_u16() });
+    }
+    let body = response.bytes().await?;
+    if body.starts_with(M3U8_MAGIC) || serde_json::from_slice::<serde_json::Value>(&body).is_ok() {
+        Ok(())
+    } else {
+        Err(Error::NotPlaylist)
+    }
+}
+
+/// Manages `upstream` once its usher front has been checked, if it's one from
+/// `CITY17_USHER_FRONT`. One that fails [`check_front`] is swapped for the built-in front
+/// rather than preferred over it.
+#[cfg(feature = "server")]
+pub fn front_fairing(mut upstream: Upstream) -> AdHoc {
+    AdHoc::on_ignite("usher front", |rocket| async move {
+        let custom = upstream.usher_front.clone().filter(|front| front != DEFAULT_USHER_FRONT);
+        if let Some(front) = custom {
+            match check_front(&upstream).await {
+                Ok(()) => log::info!("fronting usher with {}", front),
+                Err(e) => {
+                    log::warn!(
+                        "{} doesn't pass requests on to usher, using {}: {}",
+                        front,
+                        DEFAULT_USHER_FRONT,
+                        e
+                    );
+                    upstream.usher_front = Some(DEFAULT_USHER_FRONT.to_owned());
+                }
+            }
+        }
+        rocket.manage(upstream)
+    })
+}
+
+/// `url` as it's actually requested, through usher's front if it has one.
+pub fn front_url(url: &str, upstream: &Upstream) -> String {
+    match &upstream.usher_front {
+        Some(front) => url.replace(USHER_HOST, front),
+        None => url.to_owned(),
+    }
+}
+
+/// [`front_url`], and the client for it. Only fronted requests need their certificate's
+/// hostname let slide. `elsewhere` starts from the front's next built-in address.
+fn via_front(
+    url: &str,
+    upstream: &Upstream,
+    elsewhere: bool,
+) -> Result<(&'static Client, String), Error> {
+    let client = match (&upstream.usher_front, elsewhere) {
+        (Some(_), true) => alt_front_client()?,
+        (Some(_), false) => front_client()?,
+        (None, _) => client()?,
+    };
+    Ok((client, front_url(url, upstream)))
+}
+
+/// Some players can't decode the VP9 renditions usher hands out when it's told they're
+/// supported, and end up with a black screen. With `CITY17_AVC_FALLBACK=1`, a playlist that's
+/// mostly VP9 is fetched again asking for AVC only, at the cost of another round trip.
+static AVC_FALLBACK: Lazy<bool> = Lazy::new(|| env_flag("CITY17_AVC_FALLBACK"));
+
+/// A master playlist from usher or the cache.
+pub enum Playlist {
+    /// Entirely in memory, e.g. from the cache.
+    Full(Bytes),
+    /// From usher with its start checked, and the rest still in flight.
+    Streaming { head: Bytes, rest: BoxStream<'static, reqwest::Result<Bytes>> },
+}
+
+/// What usher's front is told the request is for.
+pub const USHER_HOST: &str = "usher.ttvnw.net";
+
+pub(crate) async fn get_m3u8(
+    url: &str,
+    token: &PlaybackAccessToken,
+    play_session_id: &str,
+    codecs: &str,
+    allow_source: bool,
+    upstream: &Upstream,
+) -> Result<(Playlist, Option<SocketAddr>), Error> {
+    get_m3u8_via(url, token, play_session_id, codecs, allow_source, upstream, false).await
+}
+
+/// [`get_m3u8`], starting from the front's next built-in address if `elsewhere`.
+async fn get_m3u8_via(
+    url: &str,
+    token: &PlaybackAccessToken,
+    play_session_id: &str,
+    codecs: &str,
+    allow_source: bool,
+    upstream: &Upstream,
+    elsewhere: bool,
+) -> Result<(Playlist, Option<SocketAddr>), Error> {
+    let p = match &upstream.fixed_ids {
+        Some(ids) => ids.p,
+        None => get_rng().gen_range(0..=9_999_999),
+    };
+    let p = p.to_string();
+    let started = Instant::now();
+    let (client, url) = via_front(url, upstream, elsewhere)?;
+    let timeout = upstream.within_deadline(latency::timeout(Stage::Usher, upstream.timeout));
+    let request = client
+        .get(url)
+        .query(&token.gen_query(&p, play_session_id, codecs, allow_source))
+        .header("Host", USHER_HOST)
+        .timeout(timeout);
+    let fallback = if upstream.usher_front.is_some() { dns_front_client } else { dns_client };
+    let fetched = async {
+        let response = send(request, fallback).await?;
+        let remote_addr = response.remote_addr();
+        let status = response.status();
+        let refused = response.error_for_status_ref().err();
+        if let Some(e) = refused.filter(|_| status.is_client_error()) {
+            // usher says why in the body
+            let body = response.bytes().await.unwrap_or_default();
+            return Err(usher_error(status, &body).unwrap_or_else(|| e.into()));
+        }
+        let mut rest = check_status(response, "usher")?.bytes_stream().boxed();
+        // Once the body starts going out we can't switch to a JSON error, so check it first.
+        let mut head = rest.next().await.transpose()?.unwrap_or_default();
+        while head.len() < M3U8_MAGIC.len() {
+            // only copies if usher sends a uselessly tiny first chunk
+            match rest.next().await {
+                Some(chunk) => head = [head, chunk?].concat().into(),
+                None => break,
+            }
+        }
+        if !head.starts_with(M3U8_MAGIC) {
+            // usher's errors sometimes come with a 200
+            if !head.starts_with(b"[") {
+                return Err(Error::NotPlaylist);
+            }
+            let body = Playlist::Streaming { head, rest }.collect().await?;
+            return Err(usher_error(status, &body).unwrap_or(Error::NotPlaylist));
+        }
+        // the first variant is near the top, so this rarely waits for more than the first chunk
+        while !contains(&head, VARIANT_TAG) {
+            match rest.next().await {
+                Some(chunk) => head = [head, chunk?].concat().into(),
+                None => return Err(Error::NoVariants),
+            }
+        }
+        Ok((Playlist::Streaming { head, rest }, remote_addr))
+    };
+    let fetched = fetched.await;
+    latency::record_outcome(Stage::Usher, started, timeout, &fetched);
+    fetched
+}
+
+/// Starts each variant of a master playlist. One with none of them can't be played.
+const VARIANT_TAG: &[u8] = b"#EXT-X-STREAM-INF:";
+
+/// The `error_code`s usher answers 403 with when the token is fine, but the viewer isn't
+/// allowed every quality.
+const RESTRICTED_ERROR_CODES: [&str; 1] = ["restricted_bitrates"];
+
+/// One of the errors usher answers with in place of a playlist, as e.g.
+/// `[{"error":"Can not find channel","error_code":"does_not_exist","type":"error"}]`.
+#[derive(Deserialize)]
+struct UsherError {
+    #[serde(default)]
+    error: String,
+    error_code: Option<String>,
+}
+
+/// The error in a body usher answered with `status`, if it's usher's JSON: a restricted
+/// quality, which is worth asking differently for, or else the first error with a code.
+pub fn usher_error(status: StatusCode, body: &[u8]) -> Option<Error> {
+    let errors: Vec<UsherError> = serde_json::from_slice(body).ok()?;
+    let codes = || errors.iter().filter_map(|e| e.error_code.as_deref());
+    if codes().any(|code| RESTRICTED_ERROR_CODES.contains(&code)) {
+        return Some(Error::QualityRestricted);
+    }
+    let first = errors.iter().find(|e| e.error_code.is_some())?;
+    Some(Error::Usher {
+        status: status.as_u16(),
+        code: first.error_code.clone()?,
+        message: first.error.clone(),
+    })
+}
+
+/// Whether an error body from usher says the quality is restricted, rather than the token
+/// being bad or the content being geoblocked.
+pub fn is_restricted(body: &[u8]) -> bool {
+    matches!(usher_error(StatusCode::FORBIDDEN, body), Some(Error::QualityRestricted))
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+impl Playlist {
+    /// Wait for the rest of the playlist to arrive. Doesn't copy if it all came in one chunk,
+    /// which is how usher usually sends it.
+    pub async fn collect(self) -> reqwest::Result<Bytes> {
+        let (head, mut rest) = match self {
+            Self::Full(body) => return Ok(body),
+            Self::Streaming { head, rest } => (head, rest),
+        };
+        let next = match rest.next().await {
+            Some(next) => next?,
+            None => return Ok(head),
+        };
+        let mut body = BytesMut::with_capacity(head.len() + next.len());
+        body.extend_from_slice(&head);
+        body.extend_from_slice(&next);
+        let body = rest
+            .try_fold(body, |mut body, chunk| async move {
+                body.extend_from_slice(&chunk);
+                Ok(body)
+            })
+            .await?;
+        Ok(body.freeze())
+    }
+}
+
+impl From<Bytes> for Playlist {
+    fn from(body: Bytes) -> Self {
+        Self::Full(body)
+    }
+}
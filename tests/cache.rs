@@ -0,0 +1,56 @@
+//! The live playlist cache's expiry and size cap.
+
+#![cfg(feature = "server")]
+
+use std::thread;
+
+use bytes::Bytes;
+use city17::cache::{CacheStatus, PlaylistCache, PLAYLIST_TTL};
+use city17::gql::Variables;
+use city17::usher::FetchInfo;
+
+fn channel(name: &str) -> Variables {
+    Variables::Channel(name.to_owned())
+}
+
+fn playlist(len: usize) -> (Bytes, FetchInfo) {
+    (Bytes::from(vec![b'#'; len]), FetchInfo { expires: Some(1627001200), ..FetchInfo::default() })
+}
+
+#[test]
+fn hit_until_expired() {
+    let cache = PlaylistCache::new(1024);
+    cache.insert(channel("a"), playlist(10));
+    let (body, info) = cache.get(&channel("a")).unwrap();
+    assert_eq!(body.len(), 10);
+    assert_eq!(info.expires, Some(1627001200));
+    assert!(cache.get(&channel("b")).is_none());
+    thread::sleep(PLAYLIST_TTL);
+    assert!(cache.get(&channel("a")).is_none());
+}
+
+#[test]
+fn oldest_evicted_over_cap() {
+    let cache = PlaylistCache::new(100);
+    cache.insert(channel("a"), playlist(40));
+    cache.insert(channel("b"), playlist(40));
+    cache.insert(channel("c"), playlist(40));
+    assert!(cache.get(&channel("a")).is_none());
+    assert!(cache.get(&channel("b")).is_some());
+    assert!(cache.get(&channel("c")).is_some());
+}
+
+#[test]
+fn too_big_to_cache() {
+    let cache = PlaylistCache::new(100);
+    cache.insert(channel("a"), playlist(101));
+    assert!(cache.get(&channel("a")).is_none());
+}
+
+#[test]
+fn cache_header_values() {
+    let statuses =
+        [CacheStatus::Hit, CacheStatus::Miss, CacheStatus::Coalesced, CacheStatus::Bypass];
+    let values: Vec<_> = statuses.iter().map(|s| s.as_str()).collect();
+    assert_eq!(values, ["HIT", "MISS", "COALESCED", "BYPASS"]);
+}
@@ -0,0 +1,227 @@
+//! Sanitized captures of what GQL and usher really send, run through the same code as live
+//! responses. Capture new ones with `city17 dump-gql --sanitize`, or scrub an existing capture with
+//! `cargo run --example sanitize_fixture`.
+
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::time::Duration;
+
+use city17::config::Upstream;
+use city17::gql::parse_access_token_response;
+use city17::playlist::{is_vp9_dominant, limit_renditions};
+use city17::usher::is_restricted;
+use city17::Error;
+use rocket::http::Status;
+use rocket::local::asynchronous::Client;
+use wiremock::matchers::{method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use common::PREFIX;
+
+const TOKEN_LIVE: &[u8] = include_bytes!("fixtures/token_live.json");
+const TOKEN_VOD: &[u8] = include_bytes!("fixtures/token_vod.json");
+const TOKEN_NULL: &[u8] = include_bytes!("fixtures/token_null.json");
+const GQL_ERROR: &[u8] = include_bytes!("fixtures/gql_error.json");
+const NOT_FOUND: &[u8] = include_bytes!("fixtures/gql_persisted_query_not_found.json");
+const GEOBLOCKED: &[u8] = include_bytes!("fixtures/usher/geoblocked.json");
+const OFFLINE: &[u8] = include_bytes!("fixtures/usher/offline.json");
+const RESTRICTED: &[u8] = include_bytes!("fixtures/usher/restricted.json");
+const MASTER_LIVE: &[u8] = include_bytes!("fixtures/master_live.m3u8");
+const MASTER_LARGE: &[u8] = include_bytes!("fixtures/master_large.m3u8");
+
+async fn client(server: &MockServer) -> Client {
+    common::client(Upstream { timeout: Duration::from_secs(2), ..common::upstream(server) }).await
+}
+
+/// A server whose GQL hands out the live token and whose usher answers `channel` with `response`.
+async fn usher_answering(channel: &str, response: ResponseTemplate) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(TOKEN_LIVE, "application/json"))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("/api/channel/hls/{}.m3u8", channel)))
+        .respond_with(response)
+        .mount(&server)
+        .await;
+    server
+}
+
+#[test]
+fn tokens() {
+    let live = parse_access_token_response(TOKEN_LIVE).unwrap();
+    assert!(live.data.playback_access_token.value.contains(r#""channel":"examplechannel""#));
+    assert_eq!(live.extensions.operation_name, "PlaybackAccessToken");
+    let vod = parse_access_token_response(TOKEN_VOD).unwrap();
+    assert!(vod.data.playback_access_token.value.contains(r#""vod_id":1234567890"#));
+}
+
+#[test]
+fn gql_failures() {
+    // offline channel or deleted VOD
+    let null = parse_access_token_response(TOKEN_NULL).unwrap_err();
+    assert!(matches!(null, Error::NoToken), "{:?}", null);
+    let error = parse_access_token_response(GQL_ERROR).unwrap_err();
+    match &error {
+        Error::Gql(errors) => assert_eq!(errors[0].message, "service timeout"),
+        _ => panic!("{:?}", error),
+    }
+    let stale = parse_access_token_response(NOT_FOUND).unwrap_err();
+    assert!(matches!(stale, Error::PersistedQueryNotFound), "{:?}", stale);
+    // only the token failed, and GQL says why
+    let partial = br#"{"errors":[{"message":"failed integrity check","path":["streamPlaybackAccessToken"]}],"data":{"streamPlaybackAccessToken":null}}"#;
+    match parse_access_token_response(partial).unwrap_err() {
+        Error::Gql(errors) => assert_eq!(errors[0].message, "failed integrity check"),
+        error => panic!("{:?}", error),
+    }
+    let html = parse_access_token_response(b"\n<html>Fastly error: unknown domain</html>");
+    assert!(matches!(html, Err(Error::NotJson(42))), "{:?}", html);
+}
+
+#[rocket::async_test]
+async fn gql_errors_are_passed_on() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(GQL_ERROR, "application/json"))
+        .mount(&server)
+        .await;
+    let client = client(&server).await;
+    let response = client.get(format!("{}/live/gqlerrorchannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::BadGateway);
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!((&body["kind"], &body["stage"]), (&"gql".into(), &"GQL".into()));
+    assert_eq!(body["gql_errors"], serde_json::json!(["service timeout"]));
+}
+
+#[rocket::async_test]
+async fn usher_geoblocked() {
+    let forbidden = ResponseTemplate::new(403).set_body_raw(GEOBLOCKED, "application/json");
+    let server = usher_answering("geoblockedchannel", forbidden).await;
+    let client = client(&server).await;
+    let response = client.get(format!("{}/live/geoblockedchannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Forbidden);
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!((&body["kind"], &body["stage"]), (&"usher".into(), &"M3U".into()));
+    assert_eq!(body["error_code"], "content_geoblocked");
+    assert_eq!(body["message"], "Content is restricted in your area");
+}
+
+#[rocket::async_test]
+async fn usher_offline() {
+    let not_found = ResponseTemplate::new(404).set_body_raw(OFFLINE, "application/json");
+    let server = usher_answering("offlinecapturechannel", not_found).await;
+    let client = client(&server).await;
+    let response = client.get(format!("{}/live/offlinecapturechannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(body["stage"], "M3U");
+    assert_eq!(
+        (&body["error_code"], &body["reason"]),
+        (&"does_not_exist".into(), &"offline".into())
+    );
+}
+
+#[rocket::async_test]
+async fn usher_errors_are_read_even_with_a_200() {
+    let ok = ResponseTemplate::new(200).set_body_raw(OFFLINE, "application/json");
+    let server = usher_answering("okofflinechannel", ok).await;
+    let client = client(&server).await;
+    let response = client.get(format!("{}/live/okofflinechannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!((&body["kind"], &body["error_code"]), (&"usher".into(), &"does_not_exist".into()));
+    assert_eq!(body["upstream_status"], serde_json::Value::Null);
+}
+
+#[test]
+fn usher_restrictions() {
+    assert!(is_restricted(RESTRICTED));
+    assert!(!is_restricted(GEOBLOCKED));
+    assert!(!is_restricted(OFFLINE));
+    assert!(!is_restricted(b"<html>Fastly error: unknown domain</html>"));
+}
+
+/// Usher for `channel`, answering `response` when the source is or isn't asked for.
+async fn usher_by_source(
+    server: &MockServer,
+    channel: &str,
+    source: bool,
+    response: ResponseTemplate,
+) {
+    Mock::given(method("GET"))
+        .and(path(format!("/api/channel/hls/{}.m3u8", channel)))
+        .and(query_param("allow_source", source.to_string()))
+        .respond_with(response)
+        .with_priority(1)
+        .expect(1)
+        .mount(server)
+        .await;
+}
+
+#[rocket::async_test]
+async fn restricted_quality_is_asked_for_again_without_the_source() {
+    let server = usher_answering("restrictedchannel", ResponseTemplate::new(500)).await;
+    let restricted = ResponseTemplate::new(403).set_body_raw(RESTRICTED, "application/json");
+    usher_by_source(&server, "restrictedchannel", true, restricted).await;
+    let transcodes = ResponseTemplate::new(200).set_body_raw(MASTER_LIVE, "text/plain");
+    usher_by_source(&server, "restrictedchannel", false, transcodes).await;
+    let client = client(&server).await;
+
+    let response = client.get(format!("{}/live/restrictedchannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("X-Quality-Restricted"), Some("true"));
+    assert_eq!(response.headers().get_one("X-City17-Attempts"), Some("gql=1, usher=2"));
+    assert_eq!(response.into_bytes().await.unwrap(), MASTER_LIVE);
+}
+
+#[rocket::async_test]
+async fn no_variants_is_not_retried() {
+    let empty = "#EXTM3U\n#EXT-X-TWITCH-INFO:NODE=\"video-edge-000000.pdx01\"\n";
+    let empty = ResponseTemplate::new(200).set_body_string(empty);
+    let server = usher_answering("emptychannel", ResponseTemplate::new(500)).await;
+    usher_by_source(&server, "emptychannel", true, empty.clone()).await;
+    // the source wasn't what was missing, so it isn't asked for without
+    Mock::given(method("GET"))
+        .and(path("/api/channel/hls/emptychannel.m3u8"))
+        .and(query_param("allow_source", "false"))
+        .respond_with(empty)
+        .with_priority(1)
+        .expect(0)
+        .mount(&server)
+        .await;
+    let client = client(&server).await;
+
+    let response = client.get(format!("{}/live/emptychannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::BadGateway);
+    assert_eq!(response.headers().get_one("X-City17-Attempts"), Some("gql=1, usher=1"));
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!((&body["kind"], &body["stage"]), (&"no_variants".into(), &"M3U".into()));
+}
+
+#[rocket::async_test]
+async fn large_master_playlist() {
+    let playlist =
+        ResponseTemplate::new(200).set_body_raw(MASTER_LARGE, "application/vnd.apple.mpegurl");
+    let server = usher_answering("largechannel", playlist).await;
+    let client = client(&server).await;
+    let response = client.get(format!("{}/live/largechannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), MASTER_LARGE);
+
+    assert!(!is_vp9_dominant(MASTER_LARGE));
+    let limited = limit_renditions(MASTER_LARGE, 3);
+    let kept: Vec<_> = limited.lines().filter(|l| l.starts_with("https://")).collect();
+    assert_eq!(kept.len(), 4, "{}", limited);
+    assert!(kept[0].ends_with("REDACTED-chunked.m3u8"));
+    assert!(kept[3].ends_with("REDACTED-audio_only.m3u8"));
+}
@@ -0,0 +1,35 @@
+//! Channel names as users paste them, cleaned up or rejected before anything goes upstream.
+
+use city17::gql::validate_channel;
+use city17::ErrorKind;
+
+#[test]
+fn plain_names_are_lowercased() {
+    assert_eq!(validate_channel("Some_Channel").unwrap(), "some_channel");
+}
+
+#[test]
+fn encoded_uppercase() {
+    assert_eq!(validate_channel("%53ome%5FChannel").unwrap(), "some_channel");
+    assert_eq!(validate_channel("%25").unwrap_err().kind(), ErrorKind::Input);
+}
+
+#[test]
+fn encoded_spaces_and_trailing_slashes() {
+    assert_eq!(validate_channel("%20somechannel%20").unwrap(), "somechannel");
+    assert_eq!(validate_channel(" somechannel/ ").unwrap(), "somechannel");
+    assert_eq!(validate_channel("somechannel%2F%2F").unwrap(), "somechannel");
+    // inside the name they're still wrong
+    assert!(validate_channel("some%20channel").is_err());
+    assert!(validate_channel("some%2Fchannel").is_err());
+    assert!(validate_channel("%20/").is_err());
+}
+
+#[test]
+fn multibyte_is_rejected_as_non_ascii() {
+    let message = |raw| validate_channel(raw).unwrap_err().to_string();
+    let non_ascii = "bad input: channel must be ASCII, as Twitch logins are";
+    assert_eq!(message("%E3%81%82"), non_ascii);
+    assert_eq!(message("あ"), non_ascii);
+    assert_eq!(message("somechannel%E3%81"), non_ascii);
+}
@@ -0,0 +1,274 @@
+//! `city17 fetch`, `selftest`, `bench`, `probe-ips`, and `dump-gql`: argument parsing, picking a
+//! rendition, ranking addresses, and exit codes.
+
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::time::Duration;
+
+use city17::cli::{parse, rank, run, Bench, Candidate, Command, Dump, Fetch, DEFAULT_CHANNEL};
+use city17::config::Upstream;
+use city17::gql::Variables;
+use city17::playlist::rendition_url;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const TOKEN_LIVE: &[u8] = include_bytes!("fixtures/token_live.json");
+const MASTER_LARGE: &[u8] = include_bytes!("fixtures/master_large.m3u8");
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|a| a.to_string()).collect()
+}
+
+#[test]
+fn parses_fetch() {
+    assert_eq!(parse(&[]), Ok(None));
+    let live = parse(&args(&["fetch", "live", "SomeChannel", "--quality", "720p60", "--json"]));
+    let expected = Fetch {
+        var: Variables::Channel("somechannel".into()),
+        quality: Some("720p60".into()),
+        json: true,
+    };
+    assert_eq!(live, Ok(Some(Command::Fetch(expected))));
+    let vod = parse(&args(&["fetch", "vod", "v1234567890"]));
+    let expected = Fetch { var: Variables::VOD("1234567890".into()), quality: None, json: false };
+    assert_eq!(vod, Ok(Some(Command::Fetch(expected))));
+}
+
+#[test]
+fn parses_selftest() {
+    let default = Command::SelfTest { channel: DEFAULT_CHANNEL.into() };
+    assert_eq!(parse(&args(&["selftest"])), Ok(Some(default)));
+    let channel = Command::SelfTest { channel: "somechannel".into() };
+    assert_eq!(parse(&args(&["selftest", "SomeChannel"])), Ok(Some(channel)));
+}
+
+#[test]
+fn parses_bench() {
+    assert_eq!(parse(&args(&["bench"])), Ok(Some(Command::Bench(Bench::default()))));
+    let bench = parse(&args(&[
+        "bench",
+        "--channel",
+        "SomeChannel",
+        "--count",
+        "50",
+        "--concurrency",
+        "4",
+        "--delay-ms",
+        "0",
+        "--token-only",
+    ]));
+    let expected = Bench {
+        channel: "somechannel".into(),
+        count: 50,
+        concurrency: 4,
+        delay: Duration::ZERO,
+        token_only: true,
+    };
+    assert_eq!(bench, Ok(Some(Command::Bench(expected))));
+}
+
+#[test]
+fn parses_probe_ips() {
+    assert_eq!(parse(&args(&["probe-ips"])), Ok(Some(Command::ProbeIps { attempts: 3 })));
+    let five = parse(&args(&["probe-ips", "--attempts", "5"]));
+    assert_eq!(five, Ok(Some(Command::ProbeIps { attempts: 5 })));
+}
+
+#[test]
+fn parses_port() {
+    assert_eq!(parse(&args(&["--port", "8080"])), Ok(Some(Command::Serve { port: 8080 })));
+    assert!(parse(&args(&["--port"])).is_err());
+    assert!(parse(&args(&["--port", "0"])).is_err());
+    assert!(parse(&args(&["--port", "8080", "fetch"])).is_err());
+}
+
+#[test]
+fn parses_dump_gql() {
+    let dump = parse(&args(&["dump-gql", "vod", "1234567890", "--usher", "--sanitize"]));
+    let expected = Dump { var: Variables::VOD("1234567890".into()), sanitize: true, usher: true };
+    assert_eq!(dump, Ok(Some(Command::DumpGql(expected))));
+    let dump = parse(&args(&["dump-gql", "live", "somechannel"]));
+    let expected =
+        Dump { var: Variables::Channel("somechannel".into()), sanitize: false, usher: false };
+    assert_eq!(dump, Ok(Some(Command::DumpGql(expected))));
+}
+
+#[test]
+fn ranks_reliable_then_fast() {
+    let candidate = |host, last, millis: &[u64]| Candidate {
+        host,
+        ip: [10, 0, 0, last].into(),
+        sources: vec!["dns"],
+        latencies: millis.iter().copied().map(Duration::from_millis).collect(),
+        last_error: None,
+    };
+    let mut candidates = vec![
+        candidate("www.fastly.com", 1, &[50, 50, 50]),
+        candidate("fastly.net", 2, &[10, 10]),
+        candidate("fastly.net", 3, &[90, 80, 70]),
+        candidate("fastly.net", 4, &[30, 40, 30]),
+        candidate("fastly.net", 5, &[]),
+    ];
+    rank(&mut candidates);
+    let order: Vec<_> = candidates.iter().map(|c| c.ip.to_string()).collect();
+    assert_eq!(order, ["10.0.0.4", "10.0.0.3", "10.0.0.2", "10.0.0.5", "10.0.0.1"]);
+}
+
+#[test]
+fn rejects_bad_arguments() {
+    for bad in [
+        &["serve"][..],
+        &["fetch"],
+        &["fetch", "live"],
+        &["fetch", "live", "a/b"],
+        &["fetch", "vod", "latest"],
+        &["fetch", "live", "somechannel", "--quality"],
+        &["fetch", "live", "somechannel", "--verbose"],
+        &["fetch", "live", "one", "two"],
+        &["selftest", "a/b"],
+        &["selftest", "one", "two"],
+        &["bench", "somechannel"],
+        &["bench", "--count", "0"],
+        &["bench", "--count", "many"],
+        &["bench", "--concurrency", "0"],
+        &["bench", "--concurrency", "9"],
+        &["bench", "--delay-ms"],
+        &["dump-gql"],
+        &["dump-gql", "live", "somechannel", "--json"],
+        &["probe-ips", "--attempts", "0"],
+        &["probe-ips", "--attempts", "11"],
+        &["probe-ips", "--attempts"],
+        &["probe-ips", "--attempts", "3", "extra"],
+    ] {
+        assert!(parse(&args(bad)).is_err(), "{:?}", bad);
+    }
+}
+
+#[test]
+fn picks_renditions_by_name() {
+    let m3u8 = String::from_utf8_lossy(MASTER_LARGE);
+    let url = |name| rendition_url(&m3u8, name).map(|u| u.rsplit('/').next().unwrap());
+    // both codecs are called 720p60; the higher-bandwidth AVC one wins
+    assert_eq!(url("720p60"), Some("REDACTED-720p60.m3u8"));
+    assert_eq!(url("720p"), Some("REDACTED-720p30.m3u8"));
+    assert_eq!(url("source"), Some("REDACTED-chunked.m3u8"));
+    assert_eq!(url("audio_only"), Some("REDACTED-audio_only.m3u8"));
+    assert_eq!(url("4320p"), None);
+}
+
+/// The binary is only built with the server.
+#[cfg(feature = "server")]
+#[test]
+fn usage_errors_exit_2() {
+    let output =
+        std::process::Command::new(env!("CARGO_BIN_EXE_city17")).args(["fetch", "live"]).output();
+    let output = output.unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr);
+    assert!(stderr.contains("usage: city17 fetch"), "{}", stderr);
+    assert!(output.stdout.is_empty());
+}
+
+async fn serving(channel: &str, usher: ResponseTemplate) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(TOKEN_LIVE, "application/json"))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("/api/channel/hls/{}.m3u8", channel)))
+        .respond_with(usher)
+        .mount(&server)
+        .await;
+    server
+}
+
+fn upstream(server: &MockServer) -> Upstream {
+    Upstream { timeout: Duration::from_secs(2), ..common::upstream(server) }
+}
+
+fn fetch(channel: &str, quality: Option<&str>) -> Command {
+    let var = Variables::Channel(channel.into());
+    Command::Fetch(Fetch { var, quality: quality.map(String::from), json: false })
+}
+
+fn self_test(channel: &str) -> Command {
+    Command::SelfTest { channel: channel.into() }
+}
+
+#[rocket::async_test]
+async fn run_exit_codes() {
+    let playlist =
+        ResponseTemplate::new(200).set_body_raw(MASTER_LARGE, "application/vnd.apple.mpegurl");
+    let server = serving("clichannel", playlist).await;
+    assert_eq!(run(fetch("clichannel", None), &upstream(&server)).await, 0);
+    assert_eq!(run(fetch("clichannel", Some("720p60")), &upstream(&server)).await, 0);
+    assert_eq!(run(fetch("clichannel", Some("4320p")), &upstream(&server)).await, 1);
+
+    let server = serving("cliofflinechannel", ResponseTemplate::new(404)).await;
+    assert_eq!(run(fetch("cliofflinechannel", None), &upstream(&server)).await, 1);
+}
+
+#[rocket::async_test]
+async fn selftest_exit_codes() {
+    let playlist =
+        ResponseTemplate::new(200).set_body_raw(MASTER_LARGE, "application/vnd.apple.mpegurl");
+    let server = serving("selftestchannel", playlist).await;
+    assert_eq!(run(self_test("selftestchannel"), &upstream(&server)).await, 0);
+
+    // an offline channel still shows usher can be reached
+    let server = serving("selftestofflinechannel", ResponseTemplate::new(404)).await;
+    assert_eq!(run(self_test("selftestofflinechannel"), &upstream(&server)).await, 0);
+
+    let server = serving("selftestblockedchannel", ResponseTemplate::new(403)).await;
+    assert_eq!(run(self_test("selftestblockedchannel"), &upstream(&server)).await, 1);
+}
+
+fn bench(channel: &str, token_only: bool) -> Command {
+    let channel = channel.into();
+    Command::Bench(Bench { channel, count: 5, concurrency: 2, delay: Duration::ZERO, token_only })
+}
+
+#[rocket::async_test]
+async fn bench_runs_count_times() {
+    let playlist =
+        ResponseTemplate::new(200).set_body_raw(MASTER_LARGE, "application/vnd.apple.mpegurl");
+    let server = serving("benchchannel", playlist).await;
+    assert_eq!(run(bench("benchchannel", false), &upstream(&server)).await, 0);
+    let gets = |requests: Vec<wiremock::Request>| {
+        requests.iter().filter(|r| r.method == wiremock::http::Method::Get).count()
+    };
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(gets(requests.clone()), 5);
+    assert_eq!(requests.iter().filter(|r| r.url.path() == "/gql").count(), 5);
+
+    let server = serving("benchtokenchannel", ResponseTemplate::new(500)).await;
+    assert_eq!(run(bench("benchtokenchannel", true), &upstream(&server)).await, 0);
+    assert_eq!(gets(server.received_requests().await.unwrap()), 0);
+
+    let server = serving("benchofflinechannel", ResponseTemplate::new(404)).await;
+    assert_eq!(run(bench("benchofflinechannel", false), &upstream(&server)).await, 1);
+}
+
+fn dump(channel: &str, usher: bool) -> Command {
+    let var = Variables::Channel(channel.into());
+    Command::DumpGql(Dump { var, sanitize: true, usher })
+}
+
+#[rocket::async_test]
+async fn dump_gql_exit_codes() {
+    let playlist =
+        ResponseTemplate::new(200).set_body_raw(MASTER_LARGE, "application/vnd.apple.mpegurl");
+    let server = serving("dumpchannel", playlist).await;
+    assert_eq!(run(dump("dumpchannel", false), &upstream(&server)).await, 0);
+    assert_eq!(run(dump("dumpchannel", true), &upstream(&server)).await, 0);
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.iter().filter(|r| r.url.path().ends_with(".m3u8")).count(), 1);
+
+    let server = serving("dumpofflinechannel", ResponseTemplate::new(404)).await;
+    assert_eq!(run(dump("dumpofflinechannel", false), &upstream(&server)).await, 0);
+    assert_eq!(run(dump("dumpofflinechannel", true), &upstream(&server)).await, 1);
+}
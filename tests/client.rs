@@ -0,0 +1,70 @@
+//! The HTTP client builds with the resolver overrides and custom DNS in place.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use city17::client::{group_by_host, override_ips, parse_resolve, resolve_entry};
+
+#[test]
+fn clients_build() {
+    city17::client::build_client().unwrap();
+    city17::client::build_front_client().unwrap();
+    city17::client::build_alt_front_client().unwrap();
+}
+
+#[test]
+fn built_in_overrides() {
+    let ips = |ips: &[[u8; 4]]| ips.iter().map(|ip| Ipv4Addr::from(*ip).into()).collect();
+    assert_eq!(override_ips("fastly.net"), Some(ips(&[[151, 101, 110, 167]])));
+    let usher_front = ips(&[[192, 108, 239, 254], [23, 160, 0, 254]]);
+    assert_eq!(override_ips("www.fastly.com"), Some(usher_front));
+    assert_eq!(override_ips("gql.twitch.tv"), None);
+}
+
+#[test]
+fn resolve_entries() {
+    let addr = SocketAddr::from((Ipv4Addr::new(151, 101, 110, 167), 443));
+    assert_eq!(resolve_entry("fastly.net=151.101.110.167"), Some(("fastly.net", addr)));
+    assert_eq!(resolve_entry(" fastly.net = 151.101.110.167 "), Some(("fastly.net", addr)));
+    assert_eq!(resolve_entry("fastly.net=151.101.110.167:443"), Some(("fastly.net", addr)));
+    let other_port = resolve_entry("usher.ttvnw.net=23.160.0.254:8443").unwrap().1;
+    assert_eq!(other_port, SocketAddr::from((Ipv4Addr::new(23, 160, 0, 254), 8443)));
+    let v6: SocketAddr = "[2a04:4e42::1]:443".parse().unwrap();
+    assert_eq!(resolve_entry("www.fastly.com=2a04:4e42::1").unwrap().1, v6);
+    assert_eq!(resolve_entry("www.fastly.com=[2a04:4e42::1]:443").unwrap().1, v6);
+    let bad = ["fastly.net", "=151.101.110.167", "fastly.net=", "fastly.net=fastly.com"];
+    for bad in bad.iter().chain(&["fastly.net=151.101.110.167:https", "fastly.net=1.2.3.4:99999"]) {
+        assert_eq!(resolve_entry(bad), None, "{}", bad);
+    }
+}
+
+#[test]
+fn hosts_listed_twice_get_both_addresses_in_order() {
+    let entries = [
+        "www.fastly.com=23.160.0.254",
+        "fastly.net=151.101.110.167",
+        "www.fastly.com=192.108.239.254:443",
+    ];
+    let entries = entries.iter().chain(&["www.fastly.com=23.160.0.254"]);
+    let grouped = group_by_host(entries.map(|entry| {
+        let (host, addr) = resolve_entry(entry).unwrap();
+        (host.to_owned(), addr)
+    }));
+    let ip = |ip: [u8; 4]| IpAddr::from(ip);
+    let usher_front = vec![ip([23, 160, 0, 254]), ip([192, 108, 239, 254])];
+    let expected = [
+        ("www.fastly.com".to_owned(), usher_front),
+        ("fastly.net".to_owned(), vec![ip([151, 101, 110, 167])]),
+    ];
+    assert_eq!(grouped, expected);
+}
+
+#[test]
+fn resolve_values() {
+    let value = "fastly.net=151.101.110.167:443; www.fastly.com=23.160.0.254, ";
+    let entries = parse_resolve(value).unwrap();
+    let hosts: Vec<_> = entries.iter().map(|(host, _)| host.as_str()).collect();
+    assert_eq!(hosts, ["fastly.net", "www.fastly.com"]);
+    assert_eq!(parse_resolve("").unwrap(), []);
+    let error = parse_resolve("fastly.net=151.101.110.167,www.fastly.com=").unwrap_err();
+    assert_eq!(error, "has \"www.fastly.com=\", which isn't host=ip[:port]");
+}
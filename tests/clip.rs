@@ -0,0 +1,95 @@
+//! Clips, whose URLs are signed with a token from GQL rather than fetched through usher.
+
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::time::Duration;
+
+use city17::clip::validate_clip_slug;
+use city17::config::Upstream;
+use city17::gql::{clip_request, GQL_HOST};
+use rocket::http::Status;
+use rocket::local::asynchronous::{Client, LocalResponse};
+use serde_json::Value;
+use wiremock::matchers::{body_json, header, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use common::PREFIX;
+
+const CLIP: &[u8] = include_bytes!("fixtures/gql_clip.json");
+const NO_CLIP: &[u8] = include_bytes!("fixtures/gql_no_clip.json");
+
+/// The signature and token in `fixtures/gql_clip.json`, as they go in a URL.
+const SIGNED: &str = "?sig=0123456789abcdef0123456789abcdef01234567&token=%7B%22authorization%22";
+
+async fn client(slug: &str, answer: &'static [u8]) -> (MockServer, Client) {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .and(header("Host", GQL_HOST))
+        .and(body_json(clip_request(slug)))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(answer, "application/json"))
+        .mount(&server)
+        .await;
+    let upstream = Upstream { timeout: Duration::from_secs(2), ..common::upstream(&server) };
+    let client = common::client(upstream).await;
+    (server, client)
+}
+
+async fn json(response: LocalResponse<'_>) -> Value {
+    serde_json::from_str(&response.into_string().await.unwrap()).unwrap()
+}
+
+#[rocket::async_test]
+async fn qualities_come_with_signed_urls() {
+    let (_server, client) = client("ExampleClipSlug-AbC123", CLIP).await;
+    let response = client.get(format!("{}/clip/ExampleClipSlug-AbC123", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("Cache-Control"), Some("no-store"));
+    let body = json(response).await;
+    assert_eq!(body["slug"], "ExampleClipSlug-AbC123");
+    let qualities = body["qualities"].as_array().unwrap();
+    let names: Vec<_> = qualities.iter().map(|q| q["quality"].as_str().unwrap()).collect();
+    assert_eq!(names, ["1080", "720", "480"]);
+    assert_eq!(qualities[2]["frame_rate"], 30.0);
+    let url = qualities[1]["url"].as_str().unwrap();
+    assert!(url.starts_with("https://production.assets.clips.twitchcdn.net/"), "{}", url);
+    assert!(url.contains(&format!("REDACTED-720.mp4{}", SIGNED)), "{}", url);
+}
+
+#[rocket::async_test]
+async fn one_quality_by_name() {
+    let (_server, client) = client("ExampleClipSlug", CLIP).await;
+    for (name, file) in [("720p", "REDACTED-720.mp4"), ("source", "REDACTED.mp4")] {
+        let uri = format!("{}/clip/ExampleClipSlug?quality={}", PREFIX, name);
+        let response = client.get(uri).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let url = json(response).await["url"].as_str().unwrap().to_owned();
+        assert!(url.contains(&format!("{}{}", file, SIGNED)), "{}", url);
+    }
+
+    let uri = format!("{}/clip/ExampleClipSlug?quality=360", PREFIX);
+    let response = client.get(uri).dispatch().await;
+    assert_eq!(response.status(), Status::BadRequest);
+    assert_eq!(json(response).await["message"], "the clip has no quality with that name");
+}
+
+#[rocket::async_test]
+async fn missing_clip_is_the_usual_error() {
+    let (_server, client) = client("DeletedClipSlug", NO_CLIP).await;
+    let response = client.get(format!("{}/clip/DeletedClipSlug", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    let body = json(response).await;
+    assert_eq!((&body["result"], &body["kind"]), (&"error".into(), &"no_clip".into()));
+    assert_eq!((&body["stage"], &body["reason"]), (&"GQL".into(), &"no_clip".into()));
+}
+
+#[test]
+fn slugs() {
+    assert_eq!(validate_clip_slug(" CamelCaseWords ").unwrap(), "CamelCaseWords");
+    assert_eq!(validate_clip_slug("Words-x_Y9").unwrap(), "Words-x_Y9");
+    for bad in ["", "has space", "dots.here", "a/b", &"x".repeat(101)] {
+        assert!(validate_clip_slug(bad).is_err(), "{}", bad);
+    }
+}
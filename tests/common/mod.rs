@@ -0,0 +1,53 @@
+//! Setup shared by the integration tests, so a new setting only needs filling in here. Each test
+//! file uses a different part of it.
+
+#![allow(dead_code)]
+
+#[cfg(feature = "server")]
+use std::net::Ipv4Addr;
+
+#[cfg(feature = "server")]
+use city17::config::Settings;
+use city17::config::{Upstream, DEFAULT_ROUTE_PREFIX};
+#[cfg(feature = "server")]
+use city17::routes::build_rocket;
+#[cfg(feature = "server")]
+use rocket::local::asynchronous::Client;
+use wiremock::MockServer;
+
+/// Where the routes are mounted, ready to have a path appended: empty for the root's `/`.
+pub const PREFIX: &str = if DEFAULT_ROUTE_PREFIX.len() == 1 { "" } else { DEFAULT_ROUTE_PREFIX };
+
+/// A server that's only ever driven through Rocket's local client, so the port goes unused.
+#[cfg(feature = "server")]
+pub fn settings(upstream: Upstream) -> Settings {
+    Settings {
+        port: 9000,
+        address: Ipv4Addr::LOCALHOST.into(),
+        workers: Some(1),
+        keep_alive: 0,
+        cors: true,
+        permissions_policy: true,
+        keep_warm: None,
+        route_prefix: DEFAULT_ROUTE_PREFIX.to_owned(),
+        upstream,
+    }
+}
+
+/// GQL and usher both on `server`.
+pub fn upstream(server: &MockServer) -> Upstream {
+    Upstream {
+        gql_url: format!("{}/gql", server.uri()),
+        usher_base: format!("{}/", server.uri()),
+        // the mock is reached directly, not through a front
+        usher_front: None,
+        // and GQL has no direct fallback unless a test asks for one
+        direct_gql: None,
+        ..Upstream::default()
+    }
+}
+
+#[cfg(feature = "server")]
+pub async fn client(upstream: Upstream) -> Client {
+    Client::untracked(build_rocket(settings(upstream))).await.unwrap()
+}
@@ -0,0 +1,33 @@
+//! Gzip for the Azure build's playlist responses.
+#![cfg(any(feature = "azure", feature = "standalone"))]
+
+use std::io::Read;
+
+use city17::compress::{accepts_gzip, gzip, GZIP_MIN_BYTES};
+use flate2::read::GzDecoder;
+
+const MASTER_LIVE: &[u8] = include_bytes!("fixtures/master_live.m3u8");
+
+#[test]
+fn master_playlist_round_trips() {
+    assert!(MASTER_LIVE.len() > GZIP_MIN_BYTES);
+    let encoded = gzip(MASTER_LIVE).unwrap();
+    assert_eq!(&encoded[..2], [0x1f, 0x8b], "gzip magic");
+    assert!(encoded.len() < MASTER_LIVE.len() / 2);
+    let mut decoded = Vec::new();
+    GzDecoder::new(&encoded[..]).read_to_end(&mut decoded).unwrap();
+    assert_eq!(decoded, MASTER_LIVE);
+}
+
+#[test]
+fn accept_encoding() {
+    assert!(accepts_gzip("gzip"));
+    assert!(accepts_gzip("gzip, deflate, br"));
+    assert!(accepts_gzip("br;q=1.0, GZIP;q=0.5"));
+    assert!(accepts_gzip("*"));
+    assert!(!accepts_gzip(""));
+    assert!(!accepts_gzip("identity"));
+    assert!(!accepts_gzip("deflate, br"));
+    assert!(!accepts_gzip("gzip;q=0"));
+    assert!(!accepts_gzip("gzip; q=0.0, br"));
+}
@@ -0,0 +1,92 @@
+//! Settings from `city17.toml`, which stand in for environment variables.
+
+#![cfg(feature = "server")]
+
+use std::env;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use city17::config::{parse_timeout, resolve_entries, ConfigFile, Settings, PORT_KEY};
+
+#[test]
+fn every_setting() {
+    let raw = r#"
+        resolve = ["fastly.net=151.101.110.167", "www.fastly.com=23.160.0.254"]
+        timeout = 10
+        port = 8000
+        usher_front = "fastly.net"
+        front_host = "twitch.map.fastly.net"
+        front_ip = ["151.101.2.167", "151.101.66.167"]
+    "#;
+    let file = ConfigFile::parse(raw).unwrap();
+    let variables = [
+        ("CITY17_RESOLVE", "fastly.net=151.101.110.167,www.fastly.com=23.160.0.254"),
+        ("CITY17_TIMEOUT", "10"),
+        (PORT_KEY, "8000"),
+        ("CITY17_USHER_FRONT", "fastly.net"),
+        ("CITY17_FRONT_HOST", "twitch.map.fastly.net"),
+        ("CITY17_FRONT_IP", "151.101.2.167,151.101.66.167"),
+    ];
+    let variables: Vec<_> = variables.iter().map(|(k, v)| (*k, v.to_string())).collect();
+    assert_eq!(file.variables(), variables);
+}
+
+#[test]
+fn empty_file_sets_nothing() {
+    assert_eq!(ConfigFile::parse("").unwrap(), ConfigFile::default());
+    assert!(ConfigFile::default().variables().is_empty());
+}
+
+#[test]
+fn malformed_files_say_what_is_wrong() {
+    for bad in ["port = 99999", "timeout = \"ten\"", "prot = 8000", "resolve = \"fastly.net"] {
+        assert!(ConfigFile::parse(bad).is_err(), "{}", bad);
+    }
+    let error = ConfigFile::parse("prot = 8000").unwrap_err();
+    assert!(error.contains("unknown field `prot`"), "{}", error);
+}
+
+#[test]
+fn timeouts() {
+    assert_eq!(parse_timeout("10"), Ok(Duration::from_secs(10)));
+    assert_eq!(parse_timeout(" 3\n"), Ok(Duration::from_secs(3)));
+    for bad in ["0", "", "-1", "2.5", "ten"] {
+        assert!(parse_timeout(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn settings_take_the_file_where_the_environment_is_silent() {
+    env::set_var(PORT_KEY, "8001");
+    let file = ConfigFile::parse("timeout = 10\nport = 8000\nusher_front = \"off\"").unwrap();
+    let settings = Settings::with_file(&file).unwrap();
+    assert_eq!(settings.port, 8001);
+    assert_eq!(settings.upstream.timeout, Duration::from_secs(10));
+    assert_eq!(settings.upstream.usher_front, None);
+    // the file never makes it into the environment
+    assert!(env::var_os("CITY17_TIMEOUT").is_none());
+}
+
+#[test]
+fn gql_front_from_the_file() {
+    let raw = r#"
+        front_host = "twitch.map.fastly.net"
+        front_ip = ["151.101.2.167", "151.101.66.167"]
+        resolve = ["www.fastly.com=23.160.0.254"]
+    "#;
+    let file = ConfigFile::parse(raw).unwrap();
+    let settings = Settings::with_file(&file).unwrap();
+    assert_eq!(settings.upstream.gql_url, "https://twitch.map.fastly.net/gql");
+
+    let entry = |host: &str, addr: &str| (host.to_owned(), addr.parse::<SocketAddr>().unwrap());
+    let entries = [
+        entry("twitch.map.fastly.net", "151.101.2.167:443"),
+        entry("twitch.map.fastly.net", "151.101.66.167:443"),
+        entry("www.fastly.com", "23.160.0.254:443"),
+    ];
+    assert_eq!(resolve_entries(&file.variables()), Ok(entries.to_vec()));
+
+    let typo = ConfigFile::parse("front_ip = [\"151.101.2\"]").unwrap();
+    let error = Settings::with_file(&typo).unwrap_err();
+    assert!(error.starts_with("CITY17_FRONT_IP"), "{}", error);
+}
@@ -0,0 +1,113 @@
+//! Lookups over TCP, against a canned answer.
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use city17::config::parse_dns_server;
+use city17::dns::{a_query, lookup_a, parse_a_records, response_error};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// An answer to `a_query("fastly.net")`: a CNAME, then two A records named by pointers to it.
+fn answer() -> Vec<u8> {
+    let mut response = a_query("fastly.net").unwrap();
+    // a response, with three answers
+    response[2] |= 0x80;
+    response[7] = 3;
+    // fastly.net CNAME map.fastly.net, the target's tail pointing at the question
+    response.extend_from_slice(&[0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 6]);
+    response.extend_from_slice(&[3, b'm', b'a', b'p', 0xC0, 12]);
+    for ip in [[151, 101, 110, 167], [151, 101, 130, 167]] {
+        response.extend_from_slice(&[0xC0, 40, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        response.extend_from_slice(&ip);
+    }
+    response
+}
+
+#[test]
+fn queries() {
+    let query = a_query("fastly.net.").unwrap();
+    assert_eq!(&query[2..6], &[1, 0, 0, 1]);
+    assert_eq!(&query[12..], b"\x06fastly\x03net\x00\x00\x01\x00\x01");
+    for bad in ["", "fastly..net", &"a".repeat(64)] {
+        assert!(a_query(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn answers() {
+    let ips = parse_a_records(&answer()).unwrap();
+    assert_eq!(ips, [Ipv4Addr::new(151, 101, 110, 167), Ipv4Addr::new(151, 101, 130, 167)]);
+
+    // the query itself, cut short, or for someone else
+    assert_eq!(parse_a_records(&a_query("fastly.net").unwrap()), None);
+    let answer = answer();
+    assert_eq!(parse_a_records(&answer[..answer.len() - 2]), None);
+    let mut other = answer.clone();
+    other[0] ^= 0xFF;
+    assert_eq!(parse_a_records(&other), None);
+}
+
+/// A DNS server that answers one query for fastly.net with `answer`.
+async fn serve_once(answer: Vec<u8>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server: SocketAddr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let length = stream.read_u16().await.unwrap();
+        let mut query = vec![0; usize::from(length)];
+        stream.read_exact(&mut query).await.unwrap();
+        assert_eq!(query, a_query("fastly.net").unwrap());
+        stream.write_all(&(answer.len() as u16).to_be_bytes()).await.unwrap();
+        stream.write_all(&answer).await.unwrap();
+    });
+    server
+}
+
+#[tokio::test]
+async fn lookups_go_over_tcp() {
+    let server = serve_once(answer()).await;
+    let ips = lookup_a(server, "fastly.net").await.unwrap();
+    assert_eq!(ips.len(), 2);
+}
+
+#[test]
+fn failures_in_the_header() {
+    assert!(response_error(&answer()).is_none());
+    for (rcode, name) in [(2, "SERVFAIL"), (3, "NXDOMAIN"), (5, "REFUSED")] {
+        let mut failed = answer();
+        failed[3] |= rcode;
+        let e = response_error(&failed).unwrap();
+        assert!(e.to_string().contains(name), "{}", e);
+    }
+    let mut truncated = answer();
+    truncated[2] |= 0x02;
+    assert!(response_error(&truncated).unwrap().to_string().contains("truncated"));
+}
+
+#[tokio::test]
+async fn failed_lookups_are_errors() {
+    // NXDOMAIN, with the A records left in to be sure they're not used anyway
+    let mut nxdomain = answer();
+    nxdomain[3] |= 3;
+    let server = serve_once(nxdomain).await;
+    let e = lookup_a(server, "fastly.net").await.unwrap_err();
+    assert!(e.to_string().contains("NXDOMAIN"), "{}", e);
+
+    let mut truncated = answer();
+    truncated[2] |= 0x02;
+    let server = serve_once(truncated).await;
+    assert!(lookup_a(server, "fastly.net").await.is_err());
+}
+
+#[test]
+fn dns_servers() {
+    assert_eq!(parse_dns_server("8.8.8.8"), Ok("8.8.8.8:53".parse().unwrap()));
+    assert_eq!(parse_dns_server(" 1.1.1.1:5353 "), Ok("1.1.1.1:5353".parse().unwrap()));
+    assert_eq!(
+        parse_dns_server("[2001:4860:4860::8888]:53"),
+        Ok("[2001:4860:4860::8888]:53".parse().unwrap())
+    );
+    for bad in ["dns.google", "8.8.8", "8.8.8.8:dns", ""] {
+        assert!(parse_dns_server(bad).is_err(), "{}", bad);
+    }
+}
@@ -0,0 +1,80 @@
+//! `?dryrun=1`, which describes the upstream requests instead of sending them.
+
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::env;
+use std::time::Duration;
+
+use city17::config::Upstream;
+use city17::dryrun::PLACEHOLDER;
+use rocket::http::Status;
+use rocket::local::asynchronous::Client;
+use serde_json::Value;
+use wiremock::MockServer;
+
+use common::PREFIX;
+
+async fn client(server: &MockServer) -> Client {
+    env::set_var("CITY17_DEBUG", "1");
+    common::client(Upstream { timeout: Duration::from_secs(2), ..common::upstream(server) }).await
+}
+
+async fn plan(client: &Client, uri: String) -> Value {
+    let response = client.get(uri).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("Cache-Control"), Some("no-store"));
+    serde_json::from_str(&response.into_string().await.unwrap()).unwrap()
+}
+
+#[rocket::async_test]
+async fn live_plans_token_and_playlist_requests() {
+    let server = MockServer::start().await;
+    let client = client(&server).await;
+
+    let body = plan(&client, format!("{}/live/dryrunchannel?dryrun=1", PREFIX)).await;
+    assert_eq!(body["dry_run"], true);
+    let requests = body["requests"].as_array().unwrap();
+    assert_eq!(requests.len(), 2);
+
+    let gql = &requests[0];
+    assert_eq!((&gql["stage"], &gql["method"]), (&"gql".into(), &"POST".into()));
+    assert_eq!(gql["url"], format!("{}/gql", server.uri()));
+    assert_eq!(gql["headers"]["Device-ID"], PLACEHOLDER);
+    assert_eq!(gql["body"]["variables"]["login"], "dryrunchannel");
+
+    let usher = &requests[1];
+    assert_eq!((&usher["stage"], &usher["method"]), (&"usher".into(), &"GET".into()));
+    assert!(usher["url"].as_str().unwrap().ends_with("/api/channel/hls/dryrunchannel.m3u8"));
+    assert_eq!(usher["query"]["token"], PLACEHOLDER);
+    assert_eq!(usher["query"]["sig"], PLACEHOLDER);
+
+    assert!(server.received_requests().await.unwrap().is_empty());
+}
+
+#[rocket::async_test]
+async fn meta_plans_the_metadata_query_last() {
+    let server = MockServer::start().await;
+    let client = client(&server).await;
+
+    let body = plan(&client, format!("{}/live/dryrunchannel?dryrun=1&meta=1", PREFIX)).await;
+    let stages: Vec<_> = body["requests"].as_array().unwrap().iter().map(|r| &r["stage"]).collect();
+    assert_eq!(stages, ["gql", "usher", "gql"]);
+    assert_eq!(body["requests"][2]["body"]["operationName"], "StreamMetadata");
+    // VODs have nothing to ask about
+    let body = plan(&client, format!("{}/vod/1234567890?dryrun=1&meta=1", PREFIX)).await;
+    assert_eq!(body["requests"].as_array().unwrap().len(), 2);
+}
+
+#[rocket::async_test]
+async fn latest_vod_plans_the_lookup_first() {
+    let server = MockServer::start().await;
+    let client = client(&server).await;
+
+    let body = plan(&client, format!("{}/vod/latest/dryrunchannel?dryrun=1", PREFIX)).await;
+    let stages: Vec<_> = body["requests"].as_array().unwrap().iter().map(|r| &r["stage"]).collect();
+    assert_eq!(stages, ["gql", "gql", "usher"]);
+    assert_eq!(body["requests"][1]["body"]["variables"]["vodID"], PLACEHOLDER);
+    assert!(server.received_requests().await.unwrap().is_empty());
+}
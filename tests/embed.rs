@@ -0,0 +1,64 @@
+//! The library surface, which has to work without the server.
+
+mod common;
+
+use std::time::Duration;
+
+use city17::config::Upstream;
+use city17::{City17Client, Config, ErrorKind, Options};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const TOKEN_LIVE: &[u8] = include_bytes!("fixtures/token_live.json");
+const MASTER_LIVE: &[u8] = include_bytes!("fixtures/master_live.m3u8");
+
+async fn client(server: &MockServer) -> City17Client {
+    let upstream = Upstream { timeout: Duration::from_secs(2), ..common::upstream(server) };
+    City17Client::new(Config { upstream }).unwrap()
+}
+
+#[tokio::test]
+async fn live_playlist() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(TOKEN_LIVE, "application/json"))
+        .expect(2)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/api/channel/hls/embeddedchannel.m3u8"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(MASTER_LIVE, "text/plain"))
+        .expect(2)
+        .mount(&server)
+        .await;
+    let client = client(&server).await;
+
+    let fetched = client.get_live_playlist("EmbeddedChannel", Options::default()).await.unwrap();
+    assert_eq!(fetched.m3u8.as_bytes(), MASTER_LIVE);
+    assert_eq!(fetched.info.expires, Some(1627001200));
+    assert_eq!(fetched.info.started_at, Some(1626988480));
+    assert!(!fetched.info.audio_only);
+    let variants = fetched.variants();
+    assert_eq!(variants.len(), 8);
+    assert_eq!(variants[0].resolution, Some("1920x1080"));
+
+    let options = Options { max_renditions: Some(2) };
+    let fetched = client.get_live_playlist("embeddedchannel", options).await.unwrap();
+    let names: Vec<_> = fetched.variants().iter().map(|v| v.name).collect();
+    assert_eq!(names, ["1080p60 (source)", "936p60", "audio_only"]);
+}
+
+#[tokio::test]
+async fn errors_are_the_structured_enum() {
+    let server = MockServer::start().await;
+    let client = client(&server).await;
+
+    let e = client.get_live_playlist("not/a/channel", Options::default()).await.unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Input);
+    assert!(server.received_requests().await.unwrap().is_empty());
+
+    // nothing's mounted, so GQL answers 404
+    let e = client.get_vod_playlist(1234567890, Options::default()).await.unwrap_err();
+    assert_eq!((e.kind(), e.upstream_status()), (ErrorKind::UpstreamStatus, Some(404)));
+}
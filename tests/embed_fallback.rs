@@ -0,0 +1,71 @@
+//! `CITY17_EMBED_FALLBACK`, which is read once, so it gets a test binary of its own.
+
+mod common;
+
+use std::env;
+use std::time::Duration;
+
+use city17::config::Upstream;
+use city17::gql::{
+    access_token_request, get_access_token, Variables, PLAYBACK_ACCESS_TOKEN_HASH, PLAYER_TYPE,
+};
+use wiremock::matchers::{body_json, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const TOKEN_LIVE: &[u8] = include_bytes!("fixtures/token_live.json");
+const TOKEN_NULL: &[u8] = include_bytes!("fixtures/token_null.json");
+
+/// GQL answering `var`'s token request as `player_type` with `response`, exactly `times` times.
+fn gql_as(var: &Variables, player_type: &str, response: ResponseTemplate, times: u64) -> Mock {
+    let mut request = access_token_request(var, PLAYBACK_ACCESS_TOKEN_HASH);
+    request.variables.player_type = player_type;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .and(body_json(serde_json::to_value(request).unwrap()))
+        .respond_with(response)
+        .expect(times)
+}
+
+fn upstream(server: &MockServer) -> Upstream {
+    Upstream { timeout: Duration::from_secs(2), ..common::upstream(server) }
+}
+
+fn token(body: &'static [u8]) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_raw(body, "application/json")
+}
+
+#[rocket::async_test]
+async fn embed_is_tried_only_when_site_is_refused() {
+    env::set_var("CITY17_EMBED_FALLBACK", "1");
+
+    // no token for site, one for embed
+    let server = MockServer::start().await;
+    let var = Variables::Channel("embedonlychannel".into());
+    gql_as(&var, "site", token(TOKEN_NULL), 1).mount(&server).await;
+    gql_as(&var, "embed", token(TOKEN_LIVE), 1).mount(&server).await;
+    let response = get_access_token(&var, PLAYER_TYPE, &upstream(&server)).await.unwrap();
+    assert!(response.data.playback_access_token.value.contains("examplechannel"));
+
+    // a 4xx counts as refused too; the last error, a missing token, is the one reported
+    let server = MockServer::start().await;
+    let var = Variables::Channel("refusedchannel".into());
+    gql_as(&var, "site", ResponseTemplate::new(403), 1).mount(&server).await;
+    gql_as(&var, "embed", token(TOKEN_NULL), 1).mount(&server).await;
+    let error = get_access_token(&var, PLAYER_TYPE, &upstream(&server)).await.unwrap_err();
+    assert_eq!(error.status_code(), 404, "{:?}", error);
+
+    // a server error has nothing to do with the player type
+    let server = MockServer::start().await;
+    let var = Variables::Channel("brokenchannel".into());
+    gql_as(&var, "site", ResponseTemplate::new(500), 1).mount(&server).await;
+    gql_as(&var, "embed", token(TOKEN_LIVE), 0).mount(&server).await;
+    let error = get_access_token(&var, PLAYER_TYPE, &upstream(&server)).await.unwrap_err();
+    assert_eq!(error.upstream_status(), Some(500));
+
+    // and site working means embed is never asked
+    let server = MockServer::start().await;
+    let var = Variables::Channel("sitechannel".into());
+    gql_as(&var, "site", token(TOKEN_LIVE), 1).mount(&server).await;
+    gql_as(&var, "embed", token(TOKEN_LIVE), 0).mount(&server).await;
+    get_access_token(&var, PLAYER_TYPE, &upstream(&server)).await.unwrap();
+}
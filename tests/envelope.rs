@@ -0,0 +1,91 @@
+//! What wraps every response: caching and content headers on playlists, the CORS header from the
+//! Shield on everything, and the shape of error bodies.
+
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::time::Duration;
+
+use city17::config::Upstream;
+use rocket::http::{Header, Status};
+use rocket::local::asynchronous::{Client, LocalResponse};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use common::PREFIX;
+
+const TOKEN_LIVE: &[u8] = include_bytes!("fixtures/token_live.json");
+const MASTER_LIVE: &[u8] = include_bytes!("fixtures/master_live.m3u8");
+
+async fn client(server: &MockServer) -> Client {
+    common::client(Upstream { timeout: Duration::from_secs(2), ..common::upstream(server) }).await
+}
+
+fn cors(response: &LocalResponse<'_>) -> Option<String> {
+    response.headers().get_one("Access-Control-Allow-Origin").map(str::to_owned)
+}
+
+#[rocket::async_test]
+async fn playlist_envelope() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(TOKEN_LIVE, "application/json"))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/api/channel/hls/envelopechannel.m3u8"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(MASTER_LIVE, "text/plain"))
+        .mount(&server)
+        .await;
+    let client = client(&server).await;
+
+    let response = client.get(format!("{}/live/envelopechannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(cors(&response).as_deref(), Some("*"));
+    let headers = response.headers();
+    assert_eq!(headers.get_one("Cache-Control"), Some("no-store"));
+    // ours, not whatever usher happened to send
+    assert_eq!(headers.get_one("Content-Type"), Some("application/vnd.apple.mpegurl"));
+}
+
+#[rocket::async_test]
+async fn upstream_error_envelope() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&server)
+        .await;
+    let client = client(&server).await;
+
+    let response = client.get(format!("{}/live/brokenchannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::BadGateway);
+    assert_eq!(cors(&response).as_deref(), Some("*"));
+    assert!(response.headers().get_one("Cache-Control").is_none());
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(body["result"], "error");
+    assert_eq!(body["stage"], "GQL");
+    assert_eq!((&body["kind"], &body["upstream_status"]), (&"upstream_down".into(), &500.into()));
+    assert_eq!(body["retryable"], true);
+    assert!(!body["display"].as_str().unwrap().is_empty());
+}
+
+#[rocket::async_test]
+async fn catchers_get_cors_too() {
+    let server = MockServer::start().await;
+    let client = client(&server).await;
+
+    let response = client.get("/no/such/thing?x=1").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(cors(&response).as_deref(), Some("*"));
+    assert_eq!(response.into_string().await.unwrap(), "/no/such/thing?x=1 does not exist");
+
+    let request = client.get(format!("{}/live/examplechannel", PREFIX));
+    let response = request.header(Header::new("X-Padding", "x".repeat(16 * 1024))).dispatch().await;
+    assert_eq!(response.status(), Status::RequestHeaderFieldsTooLarge);
+    assert_eq!(cors(&response).as_deref(), Some("*"));
+    assert!(server.received_requests().await.unwrap().is_empty());
+}
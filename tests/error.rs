@@ -0,0 +1,251 @@
+//! Errors and the status codes and JSON they're served as.
+//!
+//! Clients parse error bodies, so their shape is pinned in `fixtures/error_bodies.json`. Changing
+//! a field or status code there should be a deliberate decision.
+
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use city17::config::Upstream;
+use city17::error::ErrorKind;
+use city17::gql::GqlError;
+use city17::instance::instance_id;
+use city17::usher::USHER_TRIES;
+use city17::Error;
+use rocket::local::asynchronous::{Client, LocalResponse};
+use serde_json::{json, Value};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use common::PREFIX;
+
+const TOKEN_LIVE: &[u8] = include_bytes!("fixtures/token_live.json");
+const NOT_FOUND: &[u8] = include_bytes!("fixtures/gql_persisted_query_not_found.json");
+const FASTLY_502: &[u8] = include_bytes!("fixtures/fastly_502.html");
+
+fn golden(name: &str) -> Value {
+    let all: Value = serde_json::from_str(include_str!("fixtures/error_bodies.json")).unwrap();
+    all.get(name).unwrap_or_else(|| panic!("no golden body for {}", name)).clone()
+}
+
+fn rendered(error: &Error, stage: &str) -> Value {
+    json!({ "status": error.status_code(), "body": error.to_json(stage) })
+}
+
+/// A served error, less the instance that served it, which is random.
+async fn served(response: LocalResponse<'_>) -> Value {
+    let status = response.status().code;
+    let mut body: Value = serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    let instance = body.as_object_mut().unwrap().remove("instance");
+    assert_eq!(instance, Some(instance_id().into()));
+    json!({ "status": status, "body": body })
+}
+
+async fn client(server: &MockServer) -> Client {
+    common::client(Upstream { timeout: Duration::from_secs(2), ..common::upstream(server) }).await
+}
+
+#[test]
+fn status_codes() {
+    assert_eq!(Error::Input("bad".into()).status_code(), 400);
+    assert_eq!(Error::NotPlaylist.status_code(), 502);
+    assert_eq!(Error::Maintenance("later".to_owned()).status_code(), 503);
+    assert_eq!(Error::Unsupported("off").status_code(), 501);
+    assert_eq!(Error::Throttled(Duration::from_secs(1)).status_code(), 429);
+    assert_eq!(Error::UpstreamDown { upstream: "GQL", status: 503 }.status_code(), 502);
+    assert_eq!(Error::Shared(Arc::new(Error::Panicked)).status_code(), 500);
+}
+
+#[test]
+fn json_shape() {
+    let json = Error::Input("channel must be 1-25 characters".into()).to_json("input");
+    assert_eq!(json["result"], "error");
+    assert_eq!(json["kind"], "input");
+    assert_eq!(json["stage"], "input");
+    assert_eq!(json["display"], "bad input: channel must be 1-25 characters");
+    assert_eq!(json["message"], "channel must be 1-25 characters");
+    assert_eq!((&json["host"], &json["upstream_status"]), (&Value::Null, &Value::Null));
+    assert_eq!(json["retryable"], false);
+}
+
+/// The same kind of error always gets the same status, whatever variant it came from.
+#[test]
+fn status_follows_kind() {
+    let serde = serde_json::from_str::<Value>("").unwrap_err();
+    for error in [Error::Serde(serde), Error::Unsupported("off"), Error::Panicked] {
+        assert_eq!(error.status_code(), error.kind().status_code(), "{:?}", error);
+    }
+    assert_eq!(ErrorKind::Timeout.status_code(), 504);
+}
+
+/// A real failed request of each kind reqwest reports, from `url` with a `timeout`.
+async fn http_error(url: &str, timeout: Duration) -> Error {
+    let client = reqwest::Client::builder().timeout(timeout).build().unwrap();
+    let result = client.get(url).send().await.and_then(|r| r.error_for_status());
+    Error::Http(result.unwrap_err())
+}
+
+async fn http_error_at(server: &MockServer, path: &str) -> Error {
+    http_error(&format!("{}{}", server.uri(), path), Duration::from_millis(200)).await
+}
+
+#[rocket::async_test]
+async fn golden_bodies() {
+    let server = MockServer::start().await;
+    Mock::given(path("/slow"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(2)))
+        .mount(&server)
+        .await;
+    Mock::given(path("/forbidden")).respond_with(ResponseTemplate::new(403)).mount(&server).await;
+    let serde = serde_json::from_str::<Value>("").unwrap_err();
+    // as another instance sent it, for a channel that isn't live
+    let relayed = json!({
+        "result": "error",
+        "kind": "upstream_status",
+        "stage": "M3U",
+        "display": "http error",
+        "host": "127.0.0.1",
+        "upstream_status": 404,
+        "retryable": false,
+    });
+    let cases = [
+        ("timeout", http_error_at(&server, "/slow").await, "GQL"),
+        ("connect", http_error("http://127.0.0.1:1/", Duration::from_secs(2)).await, "GQL"),
+        ("dns", http_error("http://city17.invalid/", Duration::from_secs(2)).await, "M3U"),
+        ("upstream_status", http_error_at(&server, "/forbidden").await, "M3U"),
+        (
+            "input",
+            Error::Input("channel must be 1-25 characters of A-Z, 0-9, and _".into()),
+            "input",
+        ),
+        ("serde", Error::Serde(serde), "GQL"),
+        ("not_playlist", Error::NotPlaylist, "M3U"),
+        ("no_variants", Error::NoVariants, "M3U"),
+        (
+            "usher",
+            Error::Usher {
+                status: 404,
+                code: "does_not_exist".to_owned(),
+                message: "Can not find channel".to_owned(),
+            },
+            "M3U",
+        ),
+        ("audio_only", Error::AudioOnly, "M3U"),
+        ("quality_restricted", Error::QualityRestricted, "M3U"),
+        ("not_image", Error::NotImage, "preview"),
+        ("too_large", Error::TooLarge(1048576), "preview"),
+        ("throttled", Error::Throttled(Duration::from_millis(1500)), "GQL"),
+        ("upstream_down", Error::UpstreamDown { upstream: "usher", status: 503 }, "M3U"),
+        ("persisted_query_not_found", Error::PersistedQueryNotFound, "GQL"),
+        ("not_json", Error::NotJson(15), "GQL"),
+        ("gql", Error::Gql(vec![GqlError { message: "failed integrity check".to_owned() }]), "GQL"),
+        ("maintenance", Error::Maintenance("back at 12:00 UTC".to_owned()), "maintenance"),
+        ("offline", Error::Offline("examplechannel".to_owned()), "GQL"),
+        ("no_vods", Error::NoVods("examplechannel".to_owned()), "GQL"),
+        ("no_clip", Error::NoClip("ExampleClipSlug".to_owned()), "GQL"),
+        ("no_token", Error::NoToken, "GQL"),
+        ("unsupported", Error::Unsupported("VODs are turned off on this instance"), "unsupported"),
+        ("not_allowed", Error::NotAllowed("this instance only serves certain VODs"), "allowlist"),
+        ("panicked", Error::Panicked, "M3U"),
+        ("too_many_hops", Error::TooManyHops(3), "relay"),
+        ("relayed", Error::Relayed { status: 404, body: relayed }, "M3U"),
+        ("shared_not_playlist", Error::Shared(Arc::new(Error::NotPlaylist)), "M3U"),
+        ("shared_not_json", Error::Shared(Arc::new(Error::NotJson(0))), "GQL"),
+        (
+            "shared_upstream_down",
+            Error::Shared(Arc::new(Error::UpstreamDown { upstream: "GQL", status: 502 })),
+            "GQL",
+        ),
+        (
+            "shared_persisted_query_not_found",
+            Error::Shared(Arc::new(Error::PersistedQueryNotFound)),
+            "GQL",
+        ),
+    ];
+    let all: Value = serde_json::from_str(include_str!("fixtures/error_bodies.json")).unwrap();
+    assert_eq!(all.as_object().unwrap().len(), cases.len(), "every golden body is checked");
+    for (name, error, stage) in cases {
+        assert_eq!(rendered(&error, stage), golden(name), "{}", name);
+    }
+}
+
+/// Live fetches can be shared between requests, so their errors reach clients wrapped.
+#[rocket::async_test]
+async fn served_bodies_match() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(NOT_FOUND, "application/json"))
+        .mount(&server)
+        .await;
+    let client = client(&server).await;
+    let response = client.get(format!("{}/live/{}", PREFIX, "no-dashes")).dispatch().await;
+    assert_eq!(served(response).await, golden("input"));
+    let response = client.get(format!("{}/live/unknownhashchannel", PREFIX)).dispatch().await;
+    assert_eq!(served(response).await, golden("shared_persisted_query_not_found"));
+
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(TOKEN_LIVE, "application/json"))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/api/channel/hls/captiveportalchannel.m3u8"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("<html>sign in</html>"))
+        .mount(&server)
+        .await;
+    let client = self::client(&server).await;
+    let response = client.get(format!("{}/live/captiveportalchannel", PREFIX)).dispatch().await;
+    assert_eq!(served(response).await, golden("shared_not_playlist"));
+
+    // a front answering for something that isn't GQL
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+    let client = self::client(&server).await;
+    let response = client.get(format!("{}/live/misroutedchannel", PREFIX)).dispatch().await;
+    assert_eq!(served(response).await, golden("shared_not_json"));
+
+    // an outage, with the front's error page in place of GQL's JSON
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .respond_with(ResponseTemplate::new(502).set_body_raw(FASTLY_502, "text/html"))
+        .mount(&server)
+        .await;
+    let client = self::client(&server).await;
+    let response = client.get(format!("{}/live/outagechannel", PREFIX)).dispatch().await;
+    assert_eq!(served(response).await, golden("shared_upstream_down"));
+}
+
+/// Usher's outages come through the same front, and look the same.
+#[rocket::async_test]
+async fn usher_outage() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(TOKEN_LIVE, "application/json"))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/api/channel/hls/usheroutagechannel.m3u8"))
+        .respond_with(ResponseTemplate::new(503).set_body_raw(FASTLY_502, "text/html"))
+        .expect(u64::from(USHER_TRIES))
+        .mount(&server)
+        .await;
+    let client = client(&server).await;
+    let response = client.get(format!("{}/live/usheroutagechannel", PREFIX)).dispatch().await;
+    let served = served(response).await;
+    assert_eq!(served["status"], 502);
+    assert_eq!(served["body"]["stage"], "M3U");
+    assert_eq!(served["body"]["upstream_status"], 503);
+    assert_eq!(served["body"]["hint"], "upstream outage");
+}
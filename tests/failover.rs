@@ -0,0 +1,39 @@
+//! Falling back to DNS when an address from `CITY17_RESOLVE`, which is read once, doesn't take
+//! connections.
+
+mod common;
+
+use std::env;
+use std::time::Duration;
+
+use city17::client::is_overridden;
+use city17::config::Upstream;
+use city17::gql::{get_access_token, Variables, PLAYER_TYPE};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const TOKEN_LIVE: &[u8] = include_bytes!("fixtures/token_live.json");
+
+#[rocket::async_test]
+async fn unreachable_overrides_fall_back_to_dns() {
+    // the mock server only listens on 127.0.0.1, so connecting is refused at once
+    env::set_var("CITY17_RESOLVE", "localhost=127.0.0.2");
+    assert!(is_overridden("localhost") && is_overridden("fastly.net"));
+    assert!(!is_overridden("127.0.0.1"));
+
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(TOKEN_LIVE, "application/json"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let upstream = Upstream {
+        gql_url: format!("http://localhost:{}/gql", server.address().port()),
+        timeout: Duration::from_secs(2),
+        ..common::upstream(&server)
+    };
+    let var = Variables::Channel("failoverchannel".into());
+    let response = get_access_token(&var, PLAYER_TYPE, &upstream).await.unwrap();
+    assert_eq!(response.remote_addr.map(|addr| addr.port()), Some(server.address().port()));
+}
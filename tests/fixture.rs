@@ -0,0 +1,43 @@
+//! Scrubbing captures for `tests/fixtures`, as done by `city17 dump-gql --sanitize` and the
+//! `sanitize_fixture` example.
+
+#![cfg(feature = "server")]
+
+use city17::fixture::{redact_playlist_queries, sanitize_json, sanitize_playlist};
+use serde_json::{json, Value};
+
+#[test]
+fn tokens_keep_their_shape() {
+    let value = json!({ "user_ip": "198.51.100.23", "user_id": 4242, "channel": "somechannel" });
+    let mut response = json!({
+        "data": { "streamPlaybackAccessToken": {
+            "value": value.to_string(),
+            "signature": "3f9a1c0de7b2465a8c1fe0d9b7a6c5e4d3c2b1a0",
+        }},
+        "extensions": { "requestID": "01HREALREQUESTID1234567890" },
+    });
+    sanitize_json(&mut response);
+    let token = &response["data"]["streamPlaybackAccessToken"];
+    assert_eq!(token["signature"], "0".repeat(40));
+    let value: Value = serde_json::from_str(token["value"].as_str().unwrap()).unwrap();
+    assert_eq!(
+        value,
+        json!({ "user_ip": "203.0.113.7", "user_id": null, "channel": "somechannel" })
+    );
+    assert_eq!(response["extensions"]["requestID"], "01FAKEREQUESTID00000000000");
+}
+
+#[test]
+fn playlist_urls_lose_their_signatures() {
+    let m3u8 = "#EXTM3U\n\
+        #EXT-X-STREAM-INF:BANDWIDTH=630000,VIDEO=\"360p30\"\n\
+        https://video-weaver.tyo01.hls.ttvnw.net/v1/playlist/CpoEsigned.m3u8\n\
+        #EXT-X-STREAM-INF:BANDWIDTH=160000,VIDEO=\"audio_only\"\n\
+        https://example.cloudfront.net/vod/audio_only/index-dvr.m3u8?sig=abc&token=def\n";
+    let redacted = redact_playlist_queries(m3u8);
+    assert!(redacted.contains("/v1/playlist/CpoEsigned.m3u8\n"), "{}", redacted);
+    assert!(redacted.ends_with("index-dvr.m3u8?REDACTED\n"), "{}", redacted);
+    let sanitized = sanitize_playlist(m3u8);
+    assert!(sanitized.contains("/v1/playlist/REDACTED-360p30.m3u8\n"), "{}", sanitized);
+    assert!(sanitized.ends_with("index-dvr.m3u8?REDACTED\n"), "{}", sanitized);
+}
@@ -0,0 +1,24 @@
+//! Moving GQL's front with `CITY17_FRONT_HOST` and `CITY17_FRONT_IP`, which are read once.
+
+use std::env;
+use std::net::IpAddr;
+
+use city17::client::{is_overridden, override_ips};
+use city17::config::Settings;
+
+#[test]
+fn front_settings_reach_the_url_and_the_overrides() {
+    env::set_var("CITY17_FRONT_HOST", "Twitch.Map.Fastly.net");
+    env::set_var("CITY17_FRONT_IP", "151.101.2.167, 151.101.66.167");
+    env::set_var("CITY17_RESOLVE", "twitch.map.fastly.net=151.101.130.167");
+    let settings = Settings::from_env().unwrap();
+    assert_eq!(settings.upstream.gql_url, "https://twitch.map.fastly.net/gql");
+
+    let ips: Vec<IpAddr> = ["151.101.2.167", "151.101.66.167", "151.101.130.167"]
+        .iter()
+        .map(|ip| ip.parse().unwrap())
+        .collect();
+    assert_eq!(override_ips("twitch.map.fastly.net"), Some(ips));
+    // the built-in front keeps its addresses, for anything still pointed at it
+    assert!(is_overridden("fastly.net") && is_overridden("www.fastly.com"));
+}
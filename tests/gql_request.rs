@@ -0,0 +1,34 @@
+//! The GQL request body is built from a struct now, and has to stay byte-for-byte what the
+//! `json!` version sent.
+
+use city17::gql::{access_token_request, Variables, PLAYBACK_ACCESS_TOKEN_HASH};
+
+#[test]
+fn live_body_unchanged() {
+    let var = Variables::Channel("examplechannel".to_owned());
+    let body = serde_json::to_string(&access_token_request(&var, PLAYBACK_ACCESS_TOKEN_HASH));
+    assert_eq!(
+        body.unwrap(),
+        concat!(
+            r#"{"extensions":{"persistedQuery":{"sha256Hash":"#,
+            r#""0828119ded1c13477966434e15800ff57ddacf13ba1911c129dc2200705b0712","version":1}},"#,
+            r#""operationName":"PlaybackAccessToken","variables":{"isLive":true,"isVod":false,"#,
+            r#""login":"examplechannel","playerType":"site","vodID":""}}"#,
+        )
+    );
+}
+
+#[test]
+fn vod_body_unchanged() {
+    let var = Variables::VOD("1234567890".to_owned());
+    let body = serde_json::to_string(&access_token_request(&var, PLAYBACK_ACCESS_TOKEN_HASH));
+    assert_eq!(
+        body.unwrap(),
+        concat!(
+            r#"{"extensions":{"persistedQuery":{"sha256Hash":"#,
+            r#""0828119ded1c13477966434e15800ff57ddacf13ba1911c129dc2200705b0712","version":1}},"#,
+            r#""operationName":"PlaybackAccessToken","variables":{"isLive":false,"isVod":true,"#,
+            r#""login":"","playerType":"site","vodID":"1234567890"}}"#,
+        )
+    );
+}
@@ -0,0 +1,71 @@
+//! Random IDs and the query usher gets, for whatever tokens GQL hands out.
+
+use std::collections::HashSet;
+
+use city17::generate_id;
+use city17::gql::PlaybackAccessToken;
+use proptest::prelude::*;
+
+const KEYS: [&str; 12] = [
+    "player_backend",
+    "playlist_include_framerate",
+    "reassignments_supported",
+    "supported_codecs",
+    "play_session_id",
+    "cdm",
+    "player_version",
+    "fast_bread",
+    "token",
+    "sig",
+    "allow_source",
+    "p",
+];
+
+#[test]
+fn ids_are_32_alphanumerics() {
+    for _ in 0..1000 {
+        let id = generate_id();
+        assert_eq!(id.len(), 32, "{}", id);
+        assert!(id.chars().all(|c| c.is_ascii_alphanumeric()), "{}", id);
+        // play_session_id
+        let session = id.to_lowercase();
+        assert_eq!(session.len(), 32, "{}", session);
+        assert!(session.chars().all(|c| c.is_ascii_digit() || c.is_ascii_lowercase()));
+    }
+}
+
+#[test]
+fn consecutive_ids_differ() {
+    let ids: Vec<_> = (0..1000).map(|_| generate_id()).collect();
+    assert!(ids.windows(2).all(|pair| pair[0] != pair[1]));
+    assert_eq!(ids.iter().collect::<HashSet<_>>().len(), ids.len());
+}
+
+proptest! {
+    #[test]
+    fn query_carries_the_token_as_is(
+        value in any::<String>(),
+        signature in any::<String>(),
+        p in "[0-9]{1,7}",
+        session in "[0-9a-z]{32}",
+        codecs in "(vp09,)?avc1",
+        allow_source in any::<bool>(),
+    ) {
+        let token = PlaybackAccessToken {
+            value: value.clone(),
+            signature: signature.clone(),
+            typename: "PlaybackAccessToken".to_owned(),
+        };
+        let query = token.gen_query(&p, &session, &codecs, allow_source);
+        let keys: Vec<_> = query.iter().map(|(k, _)| *k).collect();
+        prop_assert_eq!(&keys[..], &KEYS[..]);
+        prop_assert!(query.iter().all(|(k, _)| !k.is_empty()));
+        let get = |key| query.iter().find(|(k, _)| *k == key).unwrap().1;
+        prop_assert_eq!(get("token").as_bytes(), value.as_bytes());
+        prop_assert_eq!(get("sig").as_bytes(), signature.as_bytes());
+        prop_assert_eq!(get("p"), p.as_str());
+        prop_assert_eq!(get("play_session_id"), session.as_str());
+        prop_assert_eq!(get("supported_codecs"), codecs.as_str());
+        prop_assert_eq!(get("allow_source"), allow_source.to_string());
+    }
+}
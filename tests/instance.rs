@@ -0,0 +1,52 @@
+//! The instance ID on responses, which has to stay put for as long as the process runs.
+
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::env;
+
+use city17::config::Upstream;
+use city17::instance::{instance_id, redact_oauth, short_id};
+use rocket::http::Status;
+use serde_json::Value;
+
+use common::PREFIX;
+
+#[rocket::async_test]
+async fn same_instance_on_every_response() {
+    env::remove_var("WEBSITE_INSTANCE_ID");
+    env::set_var("FC_INSTANCE_ID", "c-6540a1b2-53f6ab90d4e4");
+    assert_eq!(instance_id(), "ab90d4e4");
+
+    let client = common::client(Upstream::default()).await;
+    let missing = client.get("/nowhere").dispatch().await;
+    assert_eq!(missing.headers().get_one("X-Instance"), Some(instance_id()));
+
+    let error = client.get(format!("{}/live/no-dashes", PREFIX)).dispatch().await;
+    assert_eq!(error.status(), Status::BadRequest);
+    assert_eq!(error.headers().get_one("X-Instance"), Some(instance_id()));
+    let body: Value = serde_json::from_str(&error.into_string().await.unwrap()).unwrap();
+    assert_eq!(body["instance"], instance_id());
+
+    // a second server in the same process is the same instance
+    let client = common::client(Upstream::default()).await;
+    let missing = client.get("/nowhere").dispatch().await;
+    assert_eq!(missing.headers().get_one("X-Instance"), Some(instance_id()));
+}
+
+#[test]
+fn short_ids() {
+    assert_eq!(short_id("c-6540a1b2-53f6ab90d4e4").as_deref(), Some("ab90d4e4"));
+    let azure = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    assert_eq!(short_id(azure).as_deref(), Some("7852b855"));
+    assert_eq!(short_id("a-b-c"), None);
+}
+
+#[test]
+fn oauth_tokens_stay_out_of_logs() {
+    let line = "GET /vod/1234?oauth=abc123&format=json application/json";
+    assert_eq!(redact_oauth(line), "GET /vod/1234?oauth=<redacted>&format=json application/json");
+    assert_eq!(redact_oauth("GET /vod/1234?oauth=abc123"), "GET /vod/1234?oauth=<redacted>");
+    assert_eq!(redact_oauth("GET /live/channel"), "GET /live/channel");
+}
@@ -0,0 +1,30 @@
+//! Whichever JSON backend is compiled in has to read the captured GQL responses exactly the
+//! same way serde_json does. CI runs this both with and without `fast-json`.
+
+use std::fs;
+
+use city17::gql::{parse_access_token_response, parse_access_token_response_owned};
+
+#[test]
+fn backends_agree_on_fixtures() {
+    let mut checked = 0;
+    for entry in fs::read_dir("tests/fixtures").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension() != Some("json".as_ref()) {
+            continue;
+        }
+        let body = fs::read(&path).unwrap();
+        match (parse_access_token_response(&body), parse_access_token_response_owned(body)) {
+            (Ok(reference), Ok(owned)) => {
+                assert_eq!(format!("{:?}", reference), format!("{:?}", owned), "{}", path.display())
+            }
+            // each backend has its own error type, but they must fail the same way
+            (Err(reference), Err(owned)) => {
+                assert_eq!(reference.status_code(), owned.status_code(), "{}", path.display())
+            }
+            (reference, owned) => panic!("{}: {:?} vs {:?}", path.display(), reference, owned),
+        }
+        checked += 1;
+    }
+    assert!(checked > 0, "no fixtures found");
+}
@@ -0,0 +1,69 @@
+//! The keep-warm ping, against a mock of the deployment's public URL.
+
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::net::{Ipv4Addr, TcpListener};
+use std::time::Duration;
+
+use city17::config::{parse_quiet_hours, KeepWarm, QuietHours, Settings, Upstream};
+use city17::routes::build_rocket;
+use rocket::tokio::time::{sleep, timeout};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+async fn pings(server: &MockServer) -> usize {
+    server.received_requests().await.unwrap().len()
+}
+
+#[rocket::async_test]
+async fn pings_until_shutdown() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/health"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+    let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+    let keep_warm = KeepWarm {
+        url: format!("{}/health", server.uri()),
+        interval: Duration::from_millis(50),
+        quiet: None,
+    };
+    let settings =
+        Settings { port, keep_warm: Some(keep_warm), ..common::settings(Upstream::default()) };
+    let rocket = build_rocket(settings).ignite().await.unwrap();
+    let shutdown = rocket.shutdown();
+    let running = rocket::tokio::spawn(rocket.launch());
+    timeout(Duration::from_secs(5), async {
+        while pings(&server).await < 3 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("no pings");
+
+    shutdown.notify();
+    running.await.unwrap().unwrap();
+    // one may have been in flight
+    sleep(Duration::from_millis(100)).await;
+    let stopped = pings(&server).await;
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(pings(&server).await, stopped);
+}
+
+#[test]
+fn quiet_hours() {
+    let night = parse_quiet_hours("1-7").unwrap();
+    assert_eq!(night, QuietHours { start: 1, end: 7 });
+    assert!(!night.contains(0) && night.contains(1) && night.contains(6) && !night.contains(7));
+
+    let overnight = parse_quiet_hours(" 22 - 6 ").unwrap();
+    assert!(overnight.contains(23) && overnight.contains(0) && overnight.contains(5));
+    assert!(!overnight.contains(6) && !overnight.contains(12) && !overnight.contains(21));
+
+    for bad in ["", "1", "1-24", "7-7", "one-seven", "1-7-9"] {
+        assert!(parse_quiet_hours(bad).is_err(), "{}", bad);
+    }
+}
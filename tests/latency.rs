@@ -0,0 +1,97 @@
+//! Adaptive timeouts, and the percentiles they go by, from synthetic latencies.
+
+use std::iter::repeat_n;
+use std::time::Duration;
+
+use city17::latency::{
+    percentile, LatencyWindow, MIN_SAMPLES, MIN_TIMEOUT, TIMEOUT_STREAK, WINDOW,
+};
+
+const CONFIGURED: Duration = Duration::from_secs(7);
+
+fn window(millis: impl IntoIterator<Item = u64>) -> LatencyWindow {
+    let mut window = LatencyWindow::default();
+    for ms in millis {
+        window.record(Duration::from_millis(ms));
+    }
+    window
+}
+
+#[test]
+fn configured_until_there_are_enough_samples() {
+    let few = window(repeat_n(100, MIN_SAMPLES - 1));
+    assert_eq!(few.p95(), None);
+    assert_eq!(few.timeout(CONFIGURED), CONFIGURED);
+    let enough = window(repeat_n(100, MIN_SAMPLES));
+    assert_eq!(enough.p95(), Some(Duration::from_millis(100)));
+}
+
+#[test]
+fn twice_the_p95_clamped() {
+    // 95 fast requests and 5 slow ones: the p95 is still fast
+    let healthy = window((0..100).map(|i| if i % 20 == 0 { 3000 } else { 1500 }));
+    assert_eq!(healthy.p95(), Some(Duration::from_millis(1500)));
+    assert_eq!(healthy.timeout(CONFIGURED), Duration::from_secs(3));
+
+    let fast = window(repeat_n(150, 50));
+    assert_eq!(fast.timeout(CONFIGURED), MIN_TIMEOUT);
+
+    let slow = window(repeat_n(5000, 50));
+    assert_eq!(slow.timeout(CONFIGURED), CONFIGURED);
+
+    // a configured timeout under the minimum isn't raised
+    assert_eq!(fast.timeout(Duration::from_millis(500)), Duration::from_millis(500));
+}
+
+#[test]
+fn old_samples_age_out() {
+    let mut recovered = window(repeat_n(5000, WINDOW));
+    for _ in 0..WINDOW {
+        recovered.record(Duration::from_millis(1200));
+    }
+    assert_eq!(recovered.timeout(CONFIGURED), Duration::from_millis(2400));
+}
+
+#[test]
+fn timeouts_in_a_row_give_back_the_configured_timeout() {
+    let mut window = window(repeat_n(150, WINDOW));
+    for _ in 1..TIMEOUT_STREAK {
+        window.record_timeout(MIN_TIMEOUT);
+        assert_eq!(window.timeout(CONFIGURED), MIN_TIMEOUT);
+    }
+    window.record_timeout(MIN_TIMEOUT);
+    assert_eq!(window.timeout(CONFIGURED), CONFIGURED);
+    // until one gets through
+    window.record(Duration::from_millis(150));
+    assert_eq!(window.timeout(CONFIGURED), MIN_TIMEOUT);
+}
+
+#[test]
+fn follows_upstream_when_it_slows_down() {
+    let mut window = window(repeat_n(500, WINDOW));
+    let slow = Duration::from_secs(3);
+    assert!(window.timeout(CONFIGURED) < slow);
+    let mut answered = Vec::new();
+    for _ in 0..100 {
+        let timeout = window.timeout(CONFIGURED);
+        if slow <= timeout {
+            window.record(slow);
+            answered.push(true);
+        } else {
+            window.record_timeout(timeout);
+            answered.push(false);
+        }
+    }
+    // a few time out before the timeout catches up, and then none do
+    assert!(answered[..TIMEOUT_STREAK as usize].iter().all(|ok| !ok));
+    assert!(answered[answered.len() / 2..].iter().all(|ok| *ok));
+    assert!(window.timeout(CONFIGURED) > slow);
+}
+
+#[test]
+fn percentiles() {
+    let samples: Vec<_> = (1..=10).map(Duration::from_millis).collect();
+    let at = |p| percentile(&samples, p).as_millis();
+    assert_eq!([at(0.0), at(50.0), at(90.0), at(99.0), at(100.0)], [1, 5, 9, 10, 10]);
+    assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+}
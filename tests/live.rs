@@ -0,0 +1,66 @@
+//! Smoke tests against the real Twitch, through exactly the code a deployed server runs. They
+//! catch what mocks can't, like a rotated persisted query hash. Ignored by default; run them with
+//!
+//! ```text
+//! cargo test --test live -- --ignored
+//! ```
+//!
+//! The live test needs a channel that's live right now: `CITY17_LIVE_TEST_CHANNEL` picks one.
+//! VODs expire, so the VOD test only runs when `CITY17_LIVE_TEST_VOD` names one. Without network
+//! access to Twitch they pass with a note saying they were skipped.
+
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::env;
+use std::time::Duration;
+
+use city17::config::Upstream;
+use rocket::http::Status;
+use rocket::local::asynchronous::Client;
+
+use common::PREFIX;
+
+const DEFAULT_CHANNEL: &str = "monstercat";
+
+async fn client() -> Client {
+    common::client(Upstream { timeout: Duration::from_secs(20), ..Upstream::default() }).await
+}
+
+/// Fetch a playlist, or `None` if Twitch couldn't be reached at all.
+async fn playlist(uri: String) -> Option<String> {
+    let client = client().await;
+    let response = client.get(uri).dispatch().await;
+    let status = response.status();
+    let body = response.into_string().await.unwrap_or_default();
+    // 504 is a timeout and 510 an error with no status, like a failed connection
+    if matches!(status.code, 504 | 510) && body.contains("\"stage\":\"GQL\"") {
+        eprintln!("skipping, Twitch is unreachable from here: {}", body);
+        return None;
+    }
+    assert_eq!(status, Status::Ok, "{}", body);
+    Some(body)
+}
+
+#[rocket::async_test]
+#[ignore]
+async fn live_channel() {
+    let channel = env::var("CITY17_LIVE_TEST_CHANNEL").unwrap_or_else(|_| DEFAULT_CHANNEL.into());
+    // a 404 here from usher ("stage":"M3U") means the channel isn't live; pick another
+    if let Some(body) = playlist(format!("{}/live/{}", PREFIX, channel)).await {
+        assert!(body.starts_with("#EXTM3U"), "{}", body);
+    }
+}
+
+#[rocket::async_test]
+#[ignore]
+async fn vod() {
+    let id = match env::var("CITY17_LIVE_TEST_VOD") {
+        Ok(id) => id,
+        Err(_) => return eprintln!("skipping, CITY17_LIVE_TEST_VOD isn't set"),
+    };
+    if let Some(body) = playlist(format!("{}/vod/{}", PREFIX, id)).await {
+        assert!(body.starts_with("#EXTM3U"), "{}", body);
+    }
+}
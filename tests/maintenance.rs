@@ -0,0 +1,42 @@
+//! `CITY17_MAINTENANCE`, which is read once, so it gets a test binary of its own.
+
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::env;
+use std::time::Duration;
+
+use city17::config::Upstream;
+use rocket::http::Status;
+use serde_json::Value;
+use wiremock::MockServer;
+
+use common::PREFIX;
+
+#[rocket::async_test]
+async fn health_and_ready_say_when_in_maintenance() {
+    env::set_var("CITY17_MAINTENANCE", "back at 12:00 UTC");
+    // any status will do for /ready, so both fronts are up
+    let server = MockServer::start().await;
+    let upstream = Upstream { timeout: Duration::from_secs(2), ..common::upstream(&server) };
+    let client = common::client(upstream).await;
+
+    let response = client.get(format!("{}/health", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let body: Value = serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(body["maintenance"], "back at 12:00 UTC");
+
+    let response = client.get(format!("{}/ready", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::ServiceUnavailable);
+    let body: Value = serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!((&body["ready"], &body["gql"]["ok"]), (&false.into(), &true.into()));
+    assert_eq!(body["maintenance"], "back at 12:00 UTC");
+
+    let response = client.get(format!("{}/live/somechannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::ServiceUnavailable);
+    let response = client.get(format!("{}/vod/latest/somechannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::ServiceUnavailable);
+    // only /ready's probes went upstream
+    assert_eq!(server.received_requests().await.unwrap().len(), 2);
+}
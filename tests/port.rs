@@ -0,0 +1,31 @@
+//! Reading the port from `FUNCTIONS_CUSTOMHANDLER_PORT`'s value.
+
+use city17::config::parse_port;
+
+#[test]
+fn valid() {
+    assert_eq!(parse_port(Some("8080"), 9000), Ok(8080));
+    assert_eq!(parse_port(Some(" 8080\n"), 9000), Ok(8080));
+}
+
+#[test]
+fn missing() {
+    assert_eq!(parse_port(None, 9000), Ok(9000));
+}
+
+#[test]
+fn empty() {
+    assert_eq!(parse_port(Some(""), 9000), Err("\"\" is not a port".to_owned()));
+}
+
+#[test]
+fn garbage() {
+    assert_eq!(parse_port(Some("abc"), 9000), Err("\"abc\" is not a port".to_owned()));
+    assert!(parse_port(Some("-1"), 9000).is_err());
+    assert!(parse_port(Some("65536"), 9000).is_err());
+}
+
+#[test]
+fn zero_is_left_to_the_caller() {
+    assert_eq!(parse_port(Some("0"), 9000), Ok(0));
+}
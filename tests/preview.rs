@@ -0,0 +1,95 @@
+//! Preview thumbnails: picking a size, and the route's JSON and proxied image.
+
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::time::Duration;
+
+use city17::config::Upstream;
+use city17::gql::preview_request;
+use city17::preview::{fill_template, preview_size, PREVIEW_MAX_BYTES};
+use rocket::http::{ContentType, Status};
+use rocket::local::asynchronous::Client;
+use serde_json::{json, Value};
+use wiremock::matchers::{body_json, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use common::PREFIX;
+
+const TEMPLATE: &str = "live_user_{login}-{width}x{height}.jpg";
+
+#[test]
+fn sizes() {
+    assert_eq!(preview_size(None, None).unwrap(), (640, 360));
+    assert_eq!(preview_size(Some(1920), Some(1080)).unwrap(), (1920, 1080));
+    for (w, h) in [(Some(641), Some(360)), (Some(640), None), (None, Some(360))] {
+        assert!(preview_size(w, h).is_err(), "{:?}x{:?}", w, h);
+    }
+    assert_eq!(fill_template("a-{width}x{height}.jpg", (80, 45)), "a-80x45.jpg");
+}
+
+/// GQL answering `channel`'s preview query with a template on `server`, or `null` if it isn't
+/// live.
+async fn gql_preview(server: &MockServer, channel: &str, live: bool) {
+    let url = format!("{}/previews/{}", server.uri(), TEMPLATE.replace("{login}", channel));
+    let stream = if live { json!({ "previewImageURL": url }) } else { Value::Null };
+    let body = json!({ "data": { "user": { "stream": stream } } });
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .and(body_json(serde_json::to_value(preview_request(channel)).unwrap()))
+        .respond_with(ResponseTemplate::new(200).set_body_json(body))
+        .mount(server)
+        .await;
+}
+
+async fn client(server: &MockServer) -> Client {
+    common::client(Upstream { timeout: Duration::from_secs(2), ..common::upstream(server) }).await
+}
+
+#[rocket::async_test]
+async fn url_and_proxied_image() {
+    let server = MockServer::start().await;
+    gql_preview(&server, "previewchannel", true).await;
+    gql_preview(&server, "hugepreviewchannel", true).await;
+    gql_preview(&server, "previewofflinechannel", false).await;
+    Mock::given(method("GET"))
+        .and(path("/previews/live_user_previewchannel-320x180.jpg"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(&b"\xff\xd8\xff"[..], "image/jpeg"))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/previews/live_user_hugepreviewchannel-640x360.jpg"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_raw(vec![0; PREVIEW_MAX_BYTES + 1], "image/jpeg"),
+        )
+        .mount(&server)
+        .await;
+    let client = client(&server).await;
+
+    let response = client.get(format!("{}/preview/PreviewChannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    let body: Value = serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    let url = body["url"].as_str().unwrap();
+    assert!(url.ends_with("/previews/live_user_previewchannel-640x360.jpg"), "{}", url);
+
+    let uri = format!("{}/preview/previewchannel?w=320&h=180&proxy=1", PREFIX);
+    let response = client.get(uri).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JPEG));
+    assert_eq!(response.headers().get_one("Cache-Control"), Some("public, max-age=60"));
+    assert_eq!(response.into_bytes().await.unwrap(), b"\xff\xd8\xff");
+
+    let uri = format!("{}/preview/hugepreviewchannel?proxy=1", PREFIX);
+    let response = client.get(uri).dispatch().await;
+    assert_eq!(response.status(), Status::BadGateway);
+
+    let uri = format!("{}/preview/previewchannel?w=123&h=456", PREFIX);
+    assert_eq!(client.get(uri).dispatch().await.status(), Status::BadRequest);
+
+    let response = client.get(format!("{}/preview/previewofflinechannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    let body: Value = serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(body["reason"], "offline");
+}
@@ -0,0 +1,52 @@
+//! `CITY17_REDIRECTS`, which is read once, so it gets a test binary of its own.
+
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::env;
+use std::time::Duration;
+
+use city17::config::Upstream;
+use rocket::http::Status;
+use wiremock::matchers::{body_string_contains, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use common::PREFIX;
+
+const TOKEN_LIVE: &[u8] = include_bytes!("fixtures/token_live.json");
+const MASTER_LIVE: &[u8] = include_bytes!("fixtures/master_live.m3u8");
+
+#[rocket::async_test]
+async fn configured_target_is_served_without_asking_gql() {
+    env::set_var("CITY17_REDIRECTS", "MainChannel=rerunchannel, bad/entry, a=b=c");
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(body_string_contains("HostTarget"))
+        .respond_with(ResponseTemplate::new(500))
+        .with_priority(1)
+        .expect(0)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(TOKEN_LIVE, "application/json"))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/api/channel/hls/mainchannel.m3u8"))
+        .respond_with(ResponseTemplate::new(404))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/api/channel/hls/rerunchannel.m3u8"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(MASTER_LIVE, "text/plain"))
+        .mount(&server)
+        .await;
+    let upstream = Upstream { timeout: Duration::from_secs(2), ..common::upstream(&server) };
+    let client = common::client(upstream).await;
+
+    let response = client.get(format!("{}/live/mainchannel?follow=1", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("X-Redirected-From"), Some("mainchannel"));
+}
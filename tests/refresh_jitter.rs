@@ -0,0 +1,58 @@
+//! Jitter for background refresh tasks stays within its bounds.
+
+use std::time::Duration;
+
+use city17::client::{parse_refresh_interval, MAX_REFRESH_MINUTES};
+use city17::config::{first_refresh_delay, jittered_interval, DEFAULT_REFRESH_JITTER};
+use city17::get_rng;
+
+const INTERVAL: Duration = Duration::from_secs(600);
+
+#[test]
+fn first_delay_within_jitter() {
+    let mut rng = get_rng();
+    for _ in 0..1000 {
+        let delay = first_refresh_delay(&mut rng, INTERVAL, DEFAULT_REFRESH_JITTER);
+        assert!(delay <= INTERVAL.mul_f64(DEFAULT_REFRESH_JITTER), "{:?}", delay);
+    }
+}
+
+#[test]
+fn interval_within_jitter() {
+    let mut rng = get_rng();
+    let (low, high) = (INTERVAL.mul_f64(0.8), INTERVAL.mul_f64(1.2));
+    for _ in 0..1000 {
+        let interval = jittered_interval(&mut rng, INTERVAL, DEFAULT_REFRESH_JITTER);
+        assert!(low <= interval && interval <= high, "{:?}", interval);
+    }
+}
+
+#[test]
+fn jitter_spreads_instances() {
+    let mut rng = get_rng();
+    let delays: Vec<_> =
+        (0..20).map(|_| first_refresh_delay(&mut rng, INTERVAL, DEFAULT_REFRESH_JITTER)).collect();
+    assert!(delays.iter().any(|d| *d != delays[0]));
+}
+
+#[test]
+fn no_jitter() {
+    let mut rng = get_rng();
+    assert_eq!(first_refresh_delay(&mut rng, INTERVAL, 0.0), Duration::ZERO);
+    assert_eq!(jittered_interval(&mut rng, INTERVAL, 0.0), INTERVAL);
+    // out-of-range jitter is clamped rather than producing a negative interval
+    assert!(jittered_interval(&mut rng, INTERVAL, 5.0) <= INTERVAL * 2);
+}
+
+#[test]
+fn refresh_intervals() {
+    assert_eq!(parse_refresh_interval("30"), Ok(Duration::from_secs(30 * 60)));
+    assert_eq!(parse_refresh_interval("0"), Ok(Duration::ZERO));
+    let longest = parse_refresh_interval(&MAX_REFRESH_MINUTES.to_string()).unwrap();
+    // the longest still leaves room for jitter
+    let mut rng = get_rng();
+    assert!(jittered_interval(&mut rng, longest, DEFAULT_REFRESH_JITTER) > Duration::ZERO);
+    for bad in ["", "-1", "ten", "1.5", "307445734561825861", "18446744073709551615"] {
+        assert!(parse_refresh_interval(bad).is_err(), "{}", bad);
+    }
+}
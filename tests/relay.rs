@@ -0,0 +1,215 @@
+//! Relaying through another instance, with that instance running for real on a local port.
+
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::net::{Ipv4Addr, TcpListener};
+use std::time::{Duration, Instant};
+
+use city17::config::{Relay, Settings, Upstream};
+use city17::relay::{HOP_HEADER, MAX_HOPS};
+use city17::routes::build_rocket;
+use rocket::http::{Header, Status};
+use rocket::local::asynchronous::Client;
+use rocket::tokio::net::TcpStream;
+use rocket::tokio::time::sleep;
+use serde_json::Value;
+use wiremock::matchers::{body_string_contains, header, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use common::PREFIX;
+
+const TOKEN_LIVE: &[u8] = include_bytes!("fixtures/token_live.json");
+const MASTER_LIVE: &[u8] = include_bytes!("fixtures/master_live.m3u8");
+const STREAM_METADATA: &[u8] = include_bytes!("fixtures/gql_stream_metadata.json");
+
+fn settings(port: u16, upstream: Upstream) -> Settings {
+    Settings { port, ..common::settings(upstream) }
+}
+
+/// Upstream that's `server`, or another instance at `relay` if there is one.
+fn upstream(server: &MockServer, relay: Option<String>) -> Upstream {
+    let relay = relay.map(|base| Relay { base, key: Some("secret".to_owned()) });
+    Upstream { timeout: Duration::from_secs(2), relay, ..common::upstream(server) }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port()
+}
+
+/// Launch an instance on `port` and wait for it to take connections, returning its prefix.
+async fn launch(port: u16, upstream: Upstream) -> String {
+    let rocket = build_rocket(settings(port, upstream)).ignite().await.unwrap();
+    rocket::tokio::spawn(rocket.launch());
+    while TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await.is_err() {
+        sleep(Duration::from_millis(10)).await;
+    }
+    format!("http://127.0.0.1:{}{}", port, PREFIX)
+}
+
+/// The instance taking requests, relaying to `base`.
+async fn relaying(server: &MockServer, base: String) -> Client {
+    common::client(upstream(server, Some(base))).await
+}
+
+async fn twitch() -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .and(body_string_contains("StreamMetadata"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(STREAM_METADATA, "application/json"))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(TOKEN_LIVE, "application/json"))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/api/channel/hls/relayedchannel.m3u8"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(MASTER_LIVE, "text/plain"))
+        .mount(&server)
+        .await;
+    server
+}
+
+#[rocket::async_test]
+async fn playlist_and_what_is_known_about_it_come_through() {
+    let server = twitch().await;
+    let remote = launch(free_port(), upstream(&server, None)).await;
+    let unused = MockServer::start().await;
+    let client = relaying(&unused, remote).await;
+
+    // the other instance looks up the metadata, since this one has no route to Twitch
+    let response = client.get(format!("{}/live/relayedchannel?meta=1", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let headers = response.headers();
+    assert_eq!(headers.get_one("X-Cache"), Some("MISS"));
+    assert_eq!(headers.get_one("X-Stream-Low-Latency"), Some("true"));
+    assert_eq!(headers.get_one("X-City17-Token-Expires"), Some("1627001200"));
+    assert_eq!(headers.get_one("X-Stream-Started-At"), Some("1626988480"));
+    assert_eq!(headers.get_one("X-City17-Attempts"), Some("gql=1, usher=1"));
+    // where the other instance's playlist came from, not the other instance
+    let mock_ip = server.address().ip().to_string();
+    assert_eq!(headers.get_one("X-Upstream-Usher-IP"), Some(mock_ip.as_str()));
+    let timing = headers.get_one("Server-Timing").unwrap();
+    assert!(timing.starts_with("gql;dur=") && timing.contains(", relay;dur="), "{}", timing);
+    assert_eq!(response.into_bytes().await.unwrap(), MASTER_LIVE);
+    assert!(unused.received_requests().await.unwrap().is_empty());
+}
+
+#[rocket::async_test]
+async fn errors_come_through_as_they_were() {
+    let server = twitch().await;
+    let remote = launch(free_port(), upstream(&server, None)).await;
+    let direct = reqwest::get(format!("{}/live/offlinerelayedchannel", remote)).await.unwrap();
+    assert_eq!(direct.status(), 404);
+    let attempts = direct.headers()["X-City17-Attempts"].to_str().unwrap().to_owned();
+    assert_eq!(attempts, "gql=1, usher=1");
+    let direct: Value = direct.json().await.unwrap();
+    assert_eq!(direct["stage"], "M3U");
+
+    let client = relaying(&server, remote).await;
+    let response = client.get(format!("{}/live/offlinerelayedchannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(response.headers().get_one("X-City17-Attempts"), Some(attempts.as_str()));
+    let body: Value = serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(body, direct);
+}
+
+#[rocket::async_test]
+async fn unknown_relayed_statuses_are_bad_gateways() {
+    // 425 Too Early isn't among Rocket's statuses
+    let remote = MockServer::start().await;
+    let error = r#"{"result":"error","kind":"timeout","stage":"M3U","retryable":true}"#;
+    Mock::given(method("GET"))
+        .and(path("/prefix/live/relayedchannel"))
+        .respond_with(ResponseTemplate::new(425).set_body_raw(error, "application/json"))
+        .expect(1)
+        .mount(&remote)
+        .await;
+    let client = relaying(&remote, format!("{}/prefix", remote.uri())).await;
+
+    let response = client.get(format!("{}/live/relayedchannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::BadGateway);
+    let body: Value = serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!((&body["kind"], &body["stage"]), (&"timeout".into(), &"M3U".into()));
+}
+
+#[rocket::async_test]
+async fn relay_requests_say_how_far_they_have_come() {
+    let remote = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/prefix/vod/1234567890"))
+        .and(header(HOP_HEADER, "2"))
+        .and(header("X-API-Key", "secret"))
+        .and(header("Accept", "application/vnd.apple.mpegurl"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(MASTER_LIVE, "text/plain"))
+        .expect(1)
+        .mount(&remote)
+        .await;
+    let client = relaying(&remote, format!("{}/prefix", remote.uri())).await;
+
+    let request = client.get(format!("{}/vod/1234567890", PREFIX));
+    let response = request.header(Header::new(HOP_HEADER, "1")).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("X-Cache"), Some("BYPASS"));
+    assert_eq!(response.into_bytes().await.unwrap(), MASTER_LIVE);
+}
+
+#[rocket::async_test]
+async fn relay_requests_stop_at_the_deadline() {
+    let remote = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/prefix/live/relayedchannel"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_raw(MASTER_LIVE, "text/plain")
+                .set_delay(Duration::from_secs(6)),
+        )
+        .mount(&remote)
+        .await;
+    // four of its 2-second timeouts would wait out the delay, the 2-second deadline doesn't
+    let relay = Some(Relay { base: format!("{}/prefix", remote.uri()), key: None });
+    let upstream = Upstream { budget: Duration::from_secs(1), relay, ..upstream(&remote, None) };
+    let client = common::client(upstream).await;
+
+    let started = Instant::now();
+    let response = client.get(format!("{}/live/relayedchannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::GatewayTimeout);
+    assert!(started.elapsed() < Duration::from_secs(4), "{:?}", started.elapsed());
+    let body: Value = serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!((&body["kind"], &body["stage"]), (&"timeout".into(), &"relay".into()));
+}
+
+#[rocket::async_test]
+async fn an_instance_relaying_to_itself_gives_up() {
+    let unused = MockServer::start().await;
+    let port = free_port();
+    let base = format!("http://127.0.0.1:{}{}", port, PREFIX);
+    let looping = launch(port, upstream(&unused, Some(base))).await;
+
+    let response = reqwest::get(format!("{}/live/relayedchannel", looping)).await.unwrap();
+    assert_eq!(response.status(), 508);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!((&body["kind"], &body["stage"]), (&"too_many_hops".into(), &"relay".into()));
+    let display = format!("relayed through {} instances already", MAX_HOPS);
+    assert!(body["display"].as_str().unwrap().starts_with(&display), "{}", body);
+    assert!(unused.received_requests().await.unwrap().is_empty());
+}
+
+#[rocket::async_test]
+async fn viewer_tokens_are_only_relayed_over_https() {
+    // stands in for the other instance, over plain http
+    let other = MockServer::start().await;
+    let unused = MockServer::start().await;
+    let client = relaying(&unused, format!("{}{}", other.uri(), PREFIX)).await;
+
+    let request = client.get(format!("{}/live/relayedchannel", PREFIX));
+    let response = request.header(Header::new("Authorization", "OAuth abc123")).dispatch().await;
+    assert_eq!(response.status(), Status::Forbidden);
+    let body: Value = serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(body["stage"], "relay");
+    assert!(other.received_requests().await.unwrap().is_empty());
+}
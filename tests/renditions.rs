@@ -0,0 +1,94 @@
+//! Trimming a master playlist's rendition ladder with `?max_variants=N`, and reading when the
+//! stream started out of its header.
+
+use city17::playlist::{is_audio_only, limit_renditions, stream_started_at};
+
+const MASTER_LIVE: &[u8] = include_bytes!("fixtures/master_live.m3u8");
+
+fn groups(m3u8: &str) -> Vec<&str> {
+    m3u8.lines()
+        .filter(|l| l.starts_with("#EXT-X-STREAM-INF:"))
+        .map(|l| l.rsplit("VIDEO=\"").next().unwrap().split('"').next().unwrap())
+        .collect()
+}
+
+#[test]
+fn keeps_highest_bandwidth_and_audio() {
+    let limited = limit_renditions(MASTER_LIVE, 2);
+    assert_eq!(groups(&limited), ["chunked", "936p60", "audio_only"]);
+    assert!(limited.starts_with("#EXTM3U\n#EXT-X-TWITCH-INFO:"));
+    // each kept rendition still has its media line and URI
+    assert_eq!(limited.lines().count(), 2 + 3 * 3);
+    assert!(limited.ends_with("REDACTED-audio_only.m3u8\n"));
+}
+
+#[test]
+fn zero_leaves_only_audio() {
+    assert_eq!(groups(&limit_renditions(MASTER_LIVE, 0)), ["audio_only"]);
+}
+
+#[test]
+fn large_cap_changes_nothing() {
+    assert_eq!(limit_renditions(MASTER_LIVE, 8).as_bytes(), MASTER_LIVE);
+    assert_eq!(limit_renditions(MASTER_LIVE, usize::MAX).as_bytes(), MASTER_LIVE);
+}
+
+#[test]
+fn top_three_of_eight() {
+    assert_eq!(
+        groups(&limit_renditions(MASTER_LIVE, 3)),
+        ["chunked", "936p60", "720p60", "audio_only"]
+    );
+}
+
+#[test]
+fn source_is_kept_over_higher_bandwidth_transcodes() {
+    let m3u8 = "#EXTM3U\n\
+        #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"chunked\",NAME=\"1080p60 (source)\"\n\
+        #EXT-X-STREAM-INF:BANDWIDTH=6000000,VIDEO=\"chunked\"\nsource.m3u8\n\
+        #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"1080p60__hevc\",NAME=\"1080p60\"\n\
+        #EXT-X-STREAM-INF:BANDWIDTH=8000000,VIDEO=\"1080p60__hevc\"\nhevc.m3u8\n\
+        #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"1080p60\",NAME=\"1080p60\"\n\
+        #EXT-X-STREAM-INF:BANDWIDTH=7000000,VIDEO=\"1080p60\"\navc.m3u8\n";
+    assert_eq!(groups(&limit_renditions(m3u8.as_bytes(), 1)), ["chunked"]);
+    assert_eq!(groups(&limit_renditions(m3u8.as_bytes(), 2)), ["chunked", "1080p60__hevc"]);
+}
+
+#[test]
+fn order_is_kept_when_bandwidths_are_shuffled() {
+    let m3u8 = "#EXTM3U\n\
+        #EXT-X-STREAM-INF:BANDWIDTH=630000,VIDEO=\"360p30\"\nlow.m3u8\n\
+        #EXT-X-STREAM-INF:AVERAGE-BANDWIDTH=1,BANDWIDTH=3422999,VIDEO=\"720p60\"\nhigh.m3u8\n\
+        #EXT-X-STREAM-INF:BANDWIDTH=1427999,VIDEO=\"480p30\"\nmid.m3u8\n";
+    assert_eq!(groups(&limit_renditions(m3u8.as_bytes(), 2)), ["720p60", "480p30"]);
+}
+
+#[test]
+fn audio_only() {
+    assert!(!is_audio_only(MASTER_LIVE));
+    assert!(is_audio_only(limit_renditions(MASTER_LIVE, 0).as_bytes()));
+    // nothing at all isn't audio-only
+    assert!(!is_audio_only(b"#EXTM3U\n"));
+}
+
+#[test]
+fn lines_between_renditions_are_kept() {
+    let m3u8 = String::from_utf8_lossy(MASTER_LIVE).replacen(
+        "REDACTED-chunked.m3u8\n",
+        "REDACTED-chunked.m3u8\n#EXT-X-SESSION-DATA:DATA-ID=\"example\"\n",
+        1,
+    );
+    assert_eq!(limit_renditions(m3u8.as_bytes(), usize::MAX), m3u8);
+    // they go with the rendition after them
+    assert!(limit_renditions(m3u8.as_bytes(), 1).find("SESSION-DATA").is_none());
+    assert!(limit_renditions(m3u8.as_bytes(), 2).contains("SESSION-DATA"));
+}
+
+#[test]
+fn start_time_from_twitch_info() {
+    // SERVER-TIME 1627000000.00 less STREAM-TIME 11520
+    assert_eq!(stream_started_at(MASTER_LIVE), Some(1626988480));
+    let missing = b"#EXTM3U\n#EXT-X-TWITCH-INFO:NODE=\"x\",SERVER-TIME=\"1627000000.00\"\n";
+    assert_eq!(stream_started_at(missing), None);
+    assert_eq!(stream_started_at(b"#EXTM3U\n"), None);
+}
@@ -0,0 +1,56 @@
+//! Refreshing the built-in addresses looks up the names behind the fronts, never the fronts.
+
+use std::env;
+use std::net::{IpAddr, Ipv4Addr};
+
+use city17::client::{override_ips, refresh_overrides, RESOLVE_OVERRIDES};
+use city17::dns::a_query;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// What the canned DNS server says each name is at.
+const ANSWERS: &[(&str, [u8; 4])] = &[
+    ("fastly.net", [203, 0, 113, 1]),
+    ("www.fastly.com", [203, 0, 113, 2]),
+    ("twitch.map.fastly.net", [198, 51, 100, 1]),
+    ("usher.ttvnw.net", [198, 51, 100, 2]),
+];
+
+/// An answer to `query` with the one A record [`ANSWERS`] has for it.
+fn answer(query: &[u8]) -> Vec<u8> {
+    let (_, ip) = ANSWERS.iter().find(|(host, _)| a_query(host).unwrap() == query).unwrap();
+    let mut response = query.to_vec();
+    response[2] |= 0x80;
+    response[7] = 1;
+    response.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+    response.extend_from_slice(ip);
+    response
+}
+
+#[tokio::test]
+async fn refreshes_never_use_the_fronts_own_addresses() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    env::set_var("CITY17_RESOLVE_DNS", listener.local_addr().unwrap().to_string());
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let length = stream.read_u16().await.unwrap();
+            let mut query = vec![0; usize::from(length)];
+            stream.read_exact(&mut query).await.unwrap();
+            let answer = answer(&query);
+            stream.write_all(&(answer.len() as u16).to_be_bytes()).await.unwrap();
+            stream.write_all(&answer).await.unwrap();
+        }
+    });
+
+    refresh_overrides().await;
+
+    let at = |ip: [u8; 4]| Some(vec![IpAddr::from(Ipv4Addr::from(ip))]);
+    assert_eq!(override_ips("fastly.net"), at([198, 51, 100, 1]));
+    assert_eq!(override_ips("www.fastly.com"), at([198, 51, 100, 2]));
+    for (host, lookup, _) in RESOLVE_OVERRIDES {
+        assert_ne!(host, lookup);
+        let own = ANSWERS.iter().find(|(name, _)| name == host).unwrap().1;
+        assert_ne!(override_ips(host), at(own), "{}", host);
+    }
+}
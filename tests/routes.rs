@@ -0,0 +1,162 @@
+//! The server as a whole, driven through Rocket's local client. Nothing here reaches upstream.
+
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::time::Duration;
+
+use city17::config::{parse_route_prefix, Settings, Upstream, DEFAULT_ROUTE_PREFIX};
+use city17::latency::{self, Stage, MIN_SAMPLES, MIN_TIMEOUT};
+use city17::routes::build_rocket;
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::asynchronous::Client;
+
+use common::PREFIX;
+
+fn settings() -> Settings {
+    common::settings(Upstream::default())
+}
+
+async fn client() -> Client {
+    Client::untracked(build_rocket(settings())).await.unwrap()
+}
+
+#[rocket::async_test]
+async fn bad_channel_is_a_json_input_error() {
+    let client = client().await;
+    let response = client.get(format!("{}/live/not-a-channel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::BadRequest);
+    assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), Some("*"));
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(body["result"], "error");
+    assert_eq!(body["stage"], "input");
+}
+
+#[rocket::async_test]
+async fn non_ascii_channel_says_so() {
+    let client = client().await;
+    let response = client.get(format!("{}/live/%E3%81%82", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::BadRequest);
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(body["message"], "channel must be ASCII, as Twitch logins are");
+}
+
+#[rocket::async_test]
+async fn dry_runs_need_debug() {
+    let client = client().await;
+    let response = client.get(format!("{}/live/examplechannel?dryrun=1", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Forbidden);
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(body["stage"], "input");
+}
+
+#[rocket::async_test]
+async fn health_is_ok_without_going_upstream() {
+    let client = client().await;
+    let response = client.get(format!("{}/health", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    let expected = serde_json::json!({ "status": "ok", "maintenance": null, "cooldown_ms": null });
+    assert_eq!(body, expected);
+}
+
+#[rocket::async_test]
+async fn unknown_path() {
+    let client = client().await;
+    let response = client.get("/nowhere").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(response.into_string().await.unwrap(), "/nowhere does not exist");
+}
+
+#[rocket::async_test]
+async fn huge_headers_are_refused() {
+    let client = client().await;
+    let request = client.get(format!("{}/live/examplechannel", PREFIX));
+    let response = request.header(Header::new("X-Padding", "x".repeat(16 * 1024))).dispatch().await;
+    assert_eq!(response.status(), Status::RequestHeaderFieldsTooLarge);
+    // before the admin key is looked at, which would make it a 404
+    let request = client.delete(format!("{}/admin/maintenance", PREFIX));
+    let response = request.header(Header::new("X-Padding", "x".repeat(16 * 1024))).dispatch().await;
+    assert_eq!(response.status(), Status::RequestHeaderFieldsTooLarge);
+}
+
+#[rocket::async_test]
+async fn admin_routes_hidden_without_a_key() {
+    let client = client().await;
+    let response = client.delete(format!("{}/admin/maintenance", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn cors_can_be_left_to_a_cdn() {
+    let settings = Settings { cors: false, ..settings() };
+    let client = Client::untracked(build_rocket(settings)).await.unwrap();
+    let response = client.get("/nowhere").dispatch().await;
+    assert!(response.headers().get_one("Access-Control-Allow-Origin").is_none());
+    assert_eq!(response.headers().get_one("Permissions-Policy"), Some("interest-cohort=()"));
+}
+
+#[rocket::async_test]
+async fn timeouts_show_what_each_stage_gets() {
+    // nothing in here goes upstream, so these are the only samples either stage has
+    for _ in 0..MIN_SAMPLES {
+        latency::record(Stage::Usher, Duration::from_millis(100));
+    }
+    let client = client().await;
+    let response = client.get(format!("{}/timeouts", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(body["usher"]["timeout"], MIN_TIMEOUT.as_secs_f64());
+    assert_eq!(body["usher"]["samples"], MIN_SAMPLES);
+    assert_eq!(body["gql"]["timeout"], Upstream::default().timeout.as_secs_f64());
+    assert_eq!(body["gql"]["samples"], 0);
+}
+
+#[cfg(feature = "resolve")]
+#[rocket::async_test]
+async fn failed_lookup_is_a_json_error() {
+    let client = client().await;
+    // no port, so it fails without going to DNS
+    let response = client.get(format!("{}/resolve/usher.ttvnw.net", PREFIX)).dispatch().await;
+    assert_eq!(response.status().code, 510);
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(body["result"], "error");
+    assert_eq!(body["kind"], "dns");
+    assert_eq!(body["stage"], "resolve");
+}
+
+#[rocket::async_test]
+async fn routes_move_with_the_prefix() {
+    assert_eq!(PREFIX, DEFAULT_ROUTE_PREFIX.trim_end_matches('/'));
+    for prefix in ["/2016-08-15/proxy/svc/fn/invoke", "/api", "/"] {
+        let settings = Settings { route_prefix: prefix.to_owned(), ..settings() };
+        let client = Client::untracked(build_rocket(settings)).await.unwrap();
+        let health = format!("{}/health", prefix.trim_end_matches('/'));
+        assert_eq!(client.get(health).dispatch().await.status(), Status::Ok, "{}", prefix);
+    }
+    let settings = Settings { route_prefix: "/api".to_owned(), ..settings() };
+    let client = Client::untracked(build_rocket(settings)).await.unwrap();
+    let response = client.get("/2016-08-15/proxy/a/prx/invoke/health").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn route_prefixes() {
+    assert_eq!(parse_route_prefix(DEFAULT_ROUTE_PREFIX).unwrap(), DEFAULT_ROUTE_PREFIX);
+    assert_eq!(
+        parse_route_prefix(" /2016-08-15/proxy/svc/fn/invoke/ ").unwrap(),
+        "/2016-08-15/proxy/svc/fn/invoke"
+    );
+    assert_eq!(parse_route_prefix("/").unwrap(), "/");
+    for bad in ["api", "/api//live", "/api/<channel>", "/api?x=1", "https://example.com/api"] {
+        assert!(parse_route_prefix(bad).is_err(), "{}", bad);
+    }
+}
@@ -0,0 +1,153 @@
+//! Bad settings stop the server with a readable message and a non-zero exit, not a panic.
+
+#![cfg(feature = "server")]
+
+use std::process::Command;
+
+use city17::config::PORT_KEY;
+
+#[test]
+fn bad_bind_address_exits_cleanly() {
+    let output = Command::new(env!("CARGO_BIN_EXE_city17"))
+        .env("CITY17_BIND_ADDRESS", "not-an-ip")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("CITY17_BIND_ADDRESS must be an IP address"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[test]
+fn port_zero_exits_cleanly() {
+    let output = Command::new(env!("CARGO_BIN_EXE_city17")).env(PORT_KEY, "0").output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains(&format!("{} must not be 0", PORT_KEY)), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[test]
+fn plain_http_usher_base_exits_cleanly() {
+    let output = Command::new(env!("CARGO_BIN_EXE_city17"))
+        .env("CITY17_USHER_BASE", "http://usher.ttvnw.net/")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("CITY17_USHER_BASE \"http://usher.ttvnw.net/\" must be"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[test]
+fn malformed_resolve_exits_cleanly() {
+    let output = Command::new(env!("CARGO_BIN_EXE_city17"))
+        .env("CITY17_RESOLVE", "fastly.net=151.101.110.167;www.fastly.com")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("CITY17_RESOLVE has \"www.fastly.com\", which isn't"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[test]
+fn malformed_config_file_exits_cleanly() {
+    let path = std::env::temp_dir().join("city17-startup-malformed.toml");
+    std::fs::write(&path, "port = \"eighty\"\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_city17")).env("CITY17_CONFIG", &path).output();
+    let output = output.unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("city17-startup-malformed.toml"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[test]
+fn environment_wins_over_config_file() {
+    let path = std::env::temp_dir().join("city17-startup-overridden.toml");
+    std::fs::write(&path, "timeout = 5\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_city17"))
+        .env("CITY17_CONFIG", &path)
+        .env("CITY17_TIMEOUT", "0")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("CITY17_TIMEOUT must be"), "{}", stderr);
+}
+
+#[test]
+fn bad_front_exits_cleanly() {
+    for (key, value) in
+        [("CITY17_FRONT_HOST", "https://fastly.net/"), ("CITY17_FRONT_IP", "fastly")]
+    {
+        let output = Command::new(env!("CARGO_BIN_EXE_city17")).env(key, value).output().unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(1), "{}", stderr);
+        assert!(stderr.contains(&format!("{} ", key)), "{}", stderr);
+        assert!(!stderr.contains("panicked"), "{}", stderr);
+    }
+}
+
+#[test]
+fn bad_numbers_exit_cleanly() {
+    let bad = [
+        ("CITY17_MAX_HEADER_BYTES", "8k"),
+        ("CITY17_MAX_HEADER_BYTES", "0"),
+        ("CITY17_DNS_NEGATIVE_TTL", "5s"),
+        ("CITY17_DNS_NEGATIVE_TTL", "-1"),
+        ("CITY17_WORKERS", "abc"),
+        ("CITY17_WORKERS", "0"),
+        ("CITY17_PLAYLIST_CACHE_BYTES", "1MB"),
+    ];
+    for (key, value) in bad {
+        let output = Command::new(env!("CARGO_BIN_EXE_city17")).env(key, value).output().unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(1), "{}", stderr);
+        assert!(stderr.contains(&format!("{} must be a number", key)), "{}", stderr);
+        assert!(!stderr.contains("panicked"), "{}", stderr);
+    }
+}
+
+#[test]
+fn bad_resolve_dns_exits_cleanly() {
+    let output =
+        Command::new(env!("CARGO_BIN_EXE_city17")).env("CITY17_RESOLVE_DNS", "dns.google").output();
+    let output = output.unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("CITY17_RESOLVE_DNS must be ip or ip:port"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[test]
+fn bad_route_prefix_exits_cleanly() {
+    let output = Command::new(env!("CARGO_BIN_EXE_city17"))
+        .env("CITY17_ROUTE_PREFIX", "/2016-08-15/proxy/<service>/prx/invoke")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("CITY17_ROUTE_PREFIX must be a path"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[test]
+fn selftest_tests_the_configured_upstream() {
+    let path = std::env::temp_dir().join("city17-startup-selftest.toml");
+    std::fs::write(&path, "timeout = 1\nusher_front = \"off\"\n").unwrap();
+    // nothing listens on port 1, so the playlist step can't pass with this
+    let output = Command::new(env!("CARGO_BIN_EXE_city17"))
+        .arg("selftest")
+        .env("CITY17_CONFIG", &path)
+        .env("CITY17_USHER_BASE", "https://127.0.0.1:1/")
+        .env("CITY17_DIRECT_GQL", "never")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{}", stdout);
+    assert!(stdout.contains("usher https://127.0.0.1:1/ via none, 1s timeout"), "{}", stdout);
+    assert!(stdout.contains("usher-tls  warn"), "{}", stdout);
+    assert!(stdout.contains("playlist   FAIL"), "{}", stdout);
+}
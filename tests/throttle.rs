@@ -0,0 +1,96 @@
+//! Backing off when GQL answers 429. The cooldown is shared by the whole process, so it gets a
+//! test binary of its own.
+
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::time::Duration;
+
+use city17::config::Upstream;
+use city17::gql::{cooldown_remaining, MAX_COOLDOWN};
+use rocket::http::Status;
+use rocket::local::asynchronous::{Client, LocalResponse};
+use serde_json::Value;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use common::PREFIX;
+
+const TOKEN_LIVE: &[u8] = include_bytes!("fixtures/token_live.json");
+const MASTER_LIVE: &[u8] = include_bytes!("fixtures/master_live.m3u8");
+
+/// A server whose GQL answers with `gql`, and whose usher has any channel's playlist.
+async fn serving(gql: ResponseTemplate) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/gql")).respond_with(gql).mount(&server).await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(MASTER_LIVE, "text/plain"))
+        .mount(&server)
+        .await;
+    server
+}
+
+async fn proxy(server: &MockServer) -> Client {
+    common::client(Upstream { timeout: Duration::from_secs(2), ..common::upstream(server) }).await
+}
+
+fn too_many(retry_after: &str) -> ResponseTemplate {
+    ResponseTemplate::new(429).insert_header("Retry-After", retry_after)
+}
+
+async fn gql_requests(server: &MockServer) -> usize {
+    let requests = server.received_requests().await.unwrap();
+    requests.iter().filter(|r| r.url.path() == "/gql").count()
+}
+
+/// The `Retry-After` header and JSON body of a response that must be a 429.
+async fn throttled(response: LocalResponse<'_>) -> (String, Value) {
+    assert_eq!(response.status(), Status::TooManyRequests);
+    let retry_after = response.headers().get_one("Retry-After").unwrap().to_owned();
+    let body = serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    (retry_after, body)
+}
+
+#[rocket::async_test]
+async fn cooldown_after_429() {
+    assert_eq!(cooldown_remaining(), None);
+
+    let throttling = serving(too_many("1")).await;
+    let client = proxy(&throttling).await;
+    let response = client.get(format!("{}/live/throttledchannel", PREFIX)).dispatch().await;
+    let (retry_after, body) = throttled(response).await;
+    assert_eq!(retry_after, "1");
+    assert_eq!((&body["stage"], &body["retry_after"]), (&"GQL".into(), &1.into()));
+    assert!(cooldown_remaining().is_some());
+    let health = client.get(format!("{}/health", PREFIX)).dispatch().await;
+    let health: Value = serde_json::from_str(&health.into_string().await.unwrap()).unwrap();
+    let cooldown_ms = health["cooldown_ms"].as_u64().unwrap();
+    assert!(cooldown_ms > 0 && cooldown_ms <= 1000, "{}", health);
+
+    // a healthy GQL isn't asked either until the cooldown is over
+    let healthy =
+        serving(ResponseTemplate::new(200).set_body_raw(TOKEN_LIVE, "application/json")).await;
+    let client = proxy(&healthy).await;
+    let response = client.get(format!("{}/live/cooledchannel", PREFIX)).dispatch().await;
+    let (retry_after, _) = throttled(response).await;
+    assert_eq!(retry_after, "1");
+    assert_eq!(gql_requests(&healthy).await, 0);
+
+    rocket::tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_eq!(cooldown_remaining(), None);
+    let health = client.get(format!("{}/health", PREFIX)).dispatch().await;
+    let health: Value = serde_json::from_str(&health.into_string().await.unwrap()).unwrap();
+    assert_eq!(health["cooldown_ms"], Value::Null);
+    let response = client.get(format!("{}/live/cooledchannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(gql_requests(&healthy).await, 1);
+
+    // an hour is out of the question
+    let throttling = serving(too_many("3600")).await;
+    let client = proxy(&throttling).await;
+    let response = client.get(format!("{}/live/longthrottledchannel", PREFIX)).dispatch().await;
+    let (retry_after, _) = throttled(response).await;
+    assert_eq!(retry_after, MAX_COOLDOWN.as_secs().to_string());
+    assert!(cooldown_remaining().unwrap() <= MAX_COOLDOWN);
+}
@@ -0,0 +1,28 @@
+//! The token's `expires` is read out of its JSON `value`, and quietly skipped when that isn't the
+//! shape we expect.
+
+use city17::gql::{parse_access_token_response, PlaybackAccessToken};
+
+const TOKEN_LIVE: &[u8] = include_bytes!("fixtures/token_live.json");
+
+fn token(value: &str) -> PlaybackAccessToken {
+    PlaybackAccessToken {
+        value: value.to_owned(),
+        signature: String::new(),
+        typename: "PlaybackAccessToken".to_owned(),
+    }
+}
+
+#[test]
+fn live_fixture_expiry() {
+    let response = parse_access_token_response(TOKEN_LIVE).unwrap();
+    assert_eq!(response.data.playback_access_token.expires(), Some(1627001200));
+}
+
+#[test]
+fn unexpected_formats_are_none() {
+    assert_eq!(token("").expires(), None);
+    assert_eq!(token("not json").expires(), None);
+    assert_eq!(token(r#"{"channel":"examplechannel"}"#).expires(), None);
+    assert_eq!(token(r#"{"expires":"tomorrow"}"#).expires(), None);
+}
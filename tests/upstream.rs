@@ -0,0 +1,997 @@
+//! The whole flow against mock GQL and usher servers, checking both what clients get back and
+//! exactly what was sent upstream.
+//!
+//! The playlist cache and in-flight map are shared by every server in the process, so each test
+//! uses its own channel.
+
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use city17::client::GQL_RETRIES;
+use city17::config::{
+    parse_direct_gql, DirectGql, FixedIds, OAuthToken, Upstream, DEFAULT_USHER_FRONT,
+    DIRECT_GQL_URL,
+};
+use city17::gql::{
+    access_token_request, host_target_request, latest_vod_request, stream_metadata_request,
+    Variables, PLAYBACK_ACCESS_TOKEN_HASH, TWITCH_CLIENT,
+};
+use city17::playlist::{limit_renditions, MAX_VARIANTS};
+use futures_util::future::join_all;
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::asynchronous::{Client, LocalResponse};
+use serde_json::Value;
+use wiremock::matchers::{body_json, header, method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use common::PREFIX;
+
+const TOKEN_LIVE: &[u8] = include_bytes!("fixtures/token_live.json");
+const TOKEN_VOD: &[u8] = include_bytes!("fixtures/token_vod.json");
+const MASTER_LIVE: &[u8] = include_bytes!("fixtures/master_live.m3u8");
+const LATEST_VOD: &[u8] = include_bytes!("fixtures/gql_latest_vod.json");
+const NO_VODS: &[u8] = include_bytes!("fixtures/gql_no_vods.json");
+const HOST_TARGET: &[u8] = include_bytes!("fixtures/gql_host_target.json");
+const STREAM_METADATA: &[u8] = include_bytes!("fixtures/gql_stream_metadata.json");
+
+fn upstream(server: &MockServer, timeout: Duration) -> Upstream {
+    Upstream { timeout, ..common::upstream(server) }
+}
+
+async fn client(server: &MockServer, timeout: Duration) -> Client {
+    common::client(upstream(server, timeout)).await
+}
+
+/// GQL answering the PlaybackAccessToken request for `var`, and nothing else.
+fn gql(var: &Variables, response: ResponseTemplate) -> Mock {
+    let body = serde_json::to_value(access_token_request(var, PLAYBACK_ACCESS_TOKEN_HASH));
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .and(header("Host", "gql.twitch.tv"))
+        .and(header("Client-ID", TWITCH_CLIENT))
+        .and(body_json(body.unwrap()))
+        .respond_with(response)
+}
+
+fn token(body: &'static [u8]) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_raw(body, "application/json")
+}
+
+fn usher_live(channel: &str) -> wiremock::MockBuilder {
+    Mock::given(method("GET"))
+        .and(path(format!("/api/channel/hls/{}.m3u8", channel)))
+        .and(header("Host", "usher.ttvnw.net"))
+}
+
+fn playlist() -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_raw(MASTER_LIVE, "application/vnd.apple.mpegurl")
+}
+
+async fn json_error(response: LocalResponse<'_>) -> Value {
+    serde_json::from_str(&response.into_string().await.unwrap()).unwrap()
+}
+
+fn is_id(id: &str) -> bool {
+    id.len() == 32 && id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+#[rocket::async_test]
+async fn live_playlist() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("livechannel".to_owned());
+    gql(&var, token(TOKEN_LIVE)).expect(1).mount(&server).await;
+    usher_live("livechannel")
+        .and(query_param("supported_codecs", "vp09,avc1"))
+        .and(query_param("allow_source", "true"))
+        .and(query_param("player_backend", "mediaplayer"))
+        .respond_with(playlist())
+        .expect(1)
+        .mount(&server)
+        .await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let response = client.get(format!("{}/live/LiveChannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let headers = response.headers();
+    assert_eq!(headers.get_one("Content-Type"), Some("application/vnd.apple.mpegurl"));
+    assert_eq!(headers.get_one("Cache-Control"), Some("no-store"));
+    assert_eq!(headers.get_one("X-Cache"), Some("MISS"));
+    assert_eq!(headers.get_one("X-City17-Token-Expires"), Some("1627001200"));
+    assert_eq!(headers.get_one("X-Stream-Started-At"), Some("1626988480"));
+    let timing = headers.get_one("Server-Timing").unwrap();
+    assert!(timing.starts_with("gql;dur=") && timing.contains(", usher;dur="), "{}", timing);
+    assert_eq!(headers.get_one("X-City17-Attempts"), Some("gql=1, usher=1"));
+    assert!(headers.get_one("X-City17-Audio-Only").is_none());
+    let mock_ip = server.address().ip().to_string();
+    assert_eq!(headers.get_one("X-Upstream-GQL-IP"), Some(mock_ip.as_str()));
+    assert_eq!(headers.get_one("X-Upstream-Usher-IP"), Some(mock_ip.as_str()));
+    assert_eq!(response.into_bytes().await.unwrap(), MASTER_LIVE);
+
+    let requests = server.received_requests().await.unwrap();
+    let gql = requests.iter().find(|r| r.method.as_ref() == "POST").unwrap();
+    assert!(is_id(gql.headers.get(&"Device-ID".into()).unwrap().as_str()));
+    let usher = requests.iter().find(|r| r.url.path().ends_with(".m3u8")).unwrap();
+    let query = |key: &str| {
+        let mut pairs = usher.url.query_pairs();
+        pairs.find(|(k, _)| k == key).map(|(_, v)| v.into_owned()).unwrap()
+    };
+    let token: Value = serde_json::from_slice(TOKEN_LIVE).unwrap();
+    let token = &token["data"]["streamPlaybackAccessToken"];
+    assert_eq!(query("token"), token["value"].as_str().unwrap());
+    assert_eq!(query("sig"), token["signature"].as_str().unwrap());
+    let session = query("play_session_id");
+    assert!(is_id(&session) && !session.chars().any(|c| c.is_ascii_uppercase()), "{}", session);
+    assert!(query("p").parse::<u32>().unwrap() <= 9_999_999);
+}
+
+#[rocket::async_test]
+async fn exact_upstream_requests_with_fixed_ids() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("fixedchannel".to_owned());
+    let device_id = "0123456789abcdefABCDEF0123456789";
+    gql(&var, token(TOKEN_LIVE)).expect(1).mount(&server).await;
+    usher_live("fixedchannel").respond_with(playlist()).expect(1).mount(&server).await;
+    let fixed_ids = FixedIds {
+        device_id: device_id.to_owned(),
+        play_session_id: "0123456789abcdef0123456789abcdef".to_owned(),
+        p: 1234567,
+    };
+    let upstream =
+        Upstream { fixed_ids: Some(fixed_ids), ..upstream(&server, Duration::from_secs(2)) };
+    let client = common::client(upstream).await;
+
+    let response = client.get(format!("{}/live/fixedchannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let token: Value = serde_json::from_slice(TOKEN_LIVE).unwrap();
+    let token = &token["data"]["streamPlaybackAccessToken"];
+    let expected = [
+        ("player_backend", "mediaplayer"),
+        ("playlist_include_framerate", "true"),
+        ("reassignments_supported", "true"),
+        ("supported_codecs", "vp09,avc1"),
+        ("play_session_id", "0123456789abcdef0123456789abcdef"),
+        ("cdm", "wv"),
+        ("player_version", "1.4.0"),
+        ("fast_bread", "true"),
+        ("token", token["value"].as_str().unwrap()),
+        ("sig", token["signature"].as_str().unwrap()),
+        ("allow_source", "true"),
+        ("p", "1234567"),
+    ];
+    let requests = server.received_requests().await.unwrap();
+    let gql = requests.iter().find(|r| r.method.as_ref() == "POST").unwrap();
+    assert_eq!(gql.headers.get(&"Device-ID".into()).unwrap().as_str(), device_id);
+    let usher = requests.iter().find(|r| r.url.path().ends_with(".m3u8")).unwrap();
+    let query: Vec<_> =
+        usher.url.query_pairs().map(|(k, v)| (k.into_owned(), v.into_owned())).collect();
+    let expected: Vec<_> = expected.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    assert_eq!(query, expected);
+}
+
+#[rocket::async_test]
+async fn audio_only_playlist_is_flagged() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("radiochannel".to_owned());
+    gql(&var, token(TOKEN_LIVE)).mount(&server).await;
+    let audio = limit_renditions(MASTER_LIVE, 0);
+    let audio_only = ResponseTemplate::new(200).set_body_string(audio.clone());
+    usher_live("radiochannel").respond_with(audio_only).mount(&server).await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let response = client.get(format!("{}/live/radiochannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("X-City17-Audio-Only"), Some("true"));
+    assert_eq!(response.into_string().await.unwrap(), audio);
+}
+
+#[rocket::async_test]
+async fn vod_playlist_streams_through() {
+    let server = MockServer::start().await;
+    let var = Variables::VOD("1234567890".to_owned());
+    gql(&var, token(TOKEN_VOD)).expect(1).mount(&server).await;
+    Mock::given(method("GET"))
+        .and(path("/vod/1234567890.m3u8"))
+        .respond_with(playlist())
+        .expect(1)
+        .mount(&server)
+        .await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let response = client.get(format!("{}/vod/1234567890", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("X-Cache"), Some("BYPASS"));
+    assert_eq!(response.into_bytes().await.unwrap(), MASTER_LIVE);
+}
+
+#[rocket::async_test]
+async fn viewer_tokens_go_straight_to_gql() {
+    let (front, direct) = (MockServer::start().await, MockServer::start().await);
+    let var = Variables::VOD("1234567890".to_owned());
+    gql(&var, token(TOKEN_VOD)).expect(2).mount(&front).await;
+    gql(&var, token(TOKEN_VOD)).expect(2).mount(&direct).await;
+    Mock::given(method("GET"))
+        .and(path("/vod/1234567890.m3u8"))
+        .respond_with(playlist())
+        .expect(4)
+        .mount(&front)
+        .await;
+    let upstream =
+        Upstream { timeout: Duration::from_secs(2), ..with_direct_gql(&front, &direct, false) };
+    let client = common::client(upstream).await;
+
+    let request = client.get(format!("{}/vod/1234567890", PREFIX));
+    let response = request.header(Header::new("Authorization", "oauth abc123")).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let response = client.get(format!("{}/vod/1234567890?oauth=abc123", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+
+    // without one, and with another scheme's, which isn't Twitch's to see
+    let response = client.get(format!("{}/vod/1234567890", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let request = client.get(format!("{}/vod/1234567890", PREFIX));
+    let response = request.header(Header::new("Authorization", "Bearer abc123")).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let sent = |requests: Vec<wiremock::Request>| -> Vec<_> {
+        let requests = requests.into_iter().filter(|r| r.method.as_ref() == "POST");
+        let header = |r: &wiremock::Request, name: &str| {
+            r.headers.get(&name.into()).map(|v| v.as_str().to_owned())
+        };
+        requests.map(|r| (header(&r, "Authorization"), header(&r, "Device-ID").is_some())).collect()
+    };
+    let viewer = (Some("OAuth abc123".to_owned()), false);
+    assert_eq!(sent(direct.received_requests().await.unwrap()), [viewer.clone(), viewer]);
+    assert_eq!(sent(front.received_requests().await.unwrap()), [(None, true), (None, true)]);
+}
+
+#[rocket::async_test]
+async fn bad_viewer_tokens_are_not_repeated() {
+    let server = MockServer::start().await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let response =
+        client.get(format!("{}/vod/1234567890?oauth=not-a-token", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::BadRequest);
+    let body = response.into_string().await.unwrap();
+    assert!(!body.contains("not-a-token"), "{}", body);
+    assert!(server.received_requests().await.unwrap().is_empty());
+
+    let response = client.get(format!("{}/nowhere?oauth=abc123", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    let body = response.into_string().await.unwrap();
+    assert!(body.ends_with("?oauth=<redacted> does not exist"), "{}", body);
+
+    let token = OAuthToken::parse("OAuth abc123").unwrap();
+    assert_eq!(token.secret(), "abc123");
+    let upstream = Upstream { oauth: Some(token), ..upstream(&server, Duration::from_secs(2)) };
+    assert!(!format!("{:?}", upstream).contains("abc123"));
+}
+
+/// GQL answering the query for `channel`'s newest VOD.
+fn gql_latest_vod(channel: &str, response: ResponseTemplate) -> Mock {
+    let body = serde_json::to_value(latest_vod_request(channel));
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .and(header("Host", "gql.twitch.tv"))
+        .and(body_json(body.unwrap()))
+        .respond_with(response)
+}
+
+#[rocket::async_test]
+async fn latest_vod_is_looked_up_then_served() {
+    let server = MockServer::start().await;
+    gql_latest_vod("latestvodchannel", token(LATEST_VOD)).expect(1).mount(&server).await;
+    gql(&Variables::VOD("1234567890".to_owned()), token(TOKEN_VOD)).expect(2).mount(&server).await;
+    Mock::given(method("GET"))
+        .and(path("/vod/1234567890.m3u8"))
+        .respond_with(playlist())
+        .expect(2)
+        .mount(&server)
+        .await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    // the second request finds the ID in the cache
+    for _ in 0..2 {
+        let response =
+            client.get(format!("{}/vod/latest/LatestVodChannel", PREFIX)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_bytes().await.unwrap(), MASTER_LIVE);
+    }
+}
+
+#[rocket::async_test]
+async fn latest_vod_of_a_channel_without_any() {
+    let server = MockServer::start().await;
+    gql_latest_vod("novodschannel", token(NO_VODS)).expect(1).mount(&server).await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let response = client.get(format!("{}/vod/latest/novodschannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    let body = json_error(response).await;
+    assert_eq!((&body["stage"], &body["reason"]), (&"GQL".into(), &"no_vods".into()));
+}
+
+/// GQL answering the query for who `channel` is hosting.
+fn gql_host_target(channel: &str, response: ResponseTemplate) -> Mock {
+    let body = serde_json::to_value(host_target_request(channel));
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .and(header("Host", "gql.twitch.tv"))
+        .and(body_json(body.unwrap()))
+        .respond_with(response)
+}
+
+#[rocket::async_test]
+async fn follow_serves_the_hosted_channel() {
+    let server = MockServer::start().await;
+    let offline = Variables::Channel("hostingchannel".to_owned());
+    let hosted = Variables::Channel("hostedchannel".to_owned());
+    gql(&offline, token(TOKEN_LIVE)).expect(2).mount(&server).await;
+    usher_live("hostingchannel").respond_with(ResponseTemplate::new(404)).mount(&server).await;
+    gql_host_target("hostingchannel", token(HOST_TARGET)).expect(1).mount(&server).await;
+    gql(&hosted, token(TOKEN_LIVE)).expect(1).mount(&server).await;
+    usher_live("hostedchannel").respond_with(playlist()).expect(1).mount(&server).await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    // only when asked
+    let response = client.get(format!("{}/live/hostingchannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert!(response.headers().get_one("X-Redirected-From").is_none());
+
+    let response = client.get(format!("{}/live/hostingchannel?follow=1", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("X-Redirected-From"), Some("hostingchannel"));
+    assert_eq!(response.into_bytes().await.unwrap(), MASTER_LIVE);
+}
+
+#[rocket::async_test]
+async fn follow_without_a_target_is_still_offline() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("loneofflinechannel".to_owned());
+    gql(&var, token(TOKEN_LIVE)).expect(1).mount(&server).await;
+    usher_live("loneofflinechannel").respond_with(ResponseTemplate::new(404)).mount(&server).await;
+    let nobody = r#"{"data":{"user":{"hosting":null}}}"#;
+    gql_host_target("loneofflinechannel", ResponseTemplate::new(200).set_body_string(nobody))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let response =
+        client.get(format!("{}/live/loneofflinechannel?follow=1", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(json_error(response).await["stage"], "M3U");
+}
+
+/// GQL answering the query for `channel`'s stream metadata.
+fn gql_stream_metadata(channel: &str, response: ResponseTemplate) -> Mock {
+    let body = serde_json::to_value(stream_metadata_request(channel));
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .and(header("Host", "gql.twitch.tv"))
+        .and(body_json(body.unwrap()))
+        .respond_with(response)
+}
+
+#[rocket::async_test]
+async fn meta_says_whether_the_stream_is_low_latency() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("lowlatencychannel".to_owned());
+    gql(&var, token(TOKEN_LIVE)).mount(&server).await;
+    usher_live("lowlatencychannel").respond_with(playlist()).mount(&server).await;
+    gql_stream_metadata("lowlatencychannel", token(STREAM_METADATA)).expect(2).mount(&server).await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    // only when asked
+    let response = client.get(format!("{}/live/lowlatencychannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert!(response.headers().get_one("X-Stream-Low-Latency").is_none());
+
+    let uri = format!("{}/live/lowlatencychannel?meta=1", PREFIX);
+    let response = client.get(uri.clone()).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("X-Stream-Low-Latency"), Some("true"));
+    assert_eq!(response.into_bytes().await.unwrap(), MASTER_LIVE);
+
+    let response = client.get(uri).header(Header::new("Accept", "application/json")).dispatch();
+    let body = json_error(response.await).await;
+    assert_eq!(body["low_latency"], true);
+}
+
+#[rocket::async_test]
+async fn meta_is_left_out_when_it_cannot_be_had() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("nometachannel".to_owned());
+    gql(&var, token(TOKEN_LIVE)).mount(&server).await;
+    usher_live("nometachannel").respond_with(playlist()).mount(&server).await;
+    let failed = ResponseTemplate::new(500);
+    gql_stream_metadata("nometachannel", failed).expect(1..).mount(&server).await;
+    gql(&Variables::VOD("1234567890".to_owned()), token(TOKEN_VOD)).mount(&server).await;
+    Mock::given(method("GET"))
+        .and(path("/vod/1234567890.m3u8"))
+        .respond_with(playlist())
+        .mount(&server)
+        .await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    // the playlist doesn't need it
+    let response = client.get(format!("{}/live/nometachannel?meta=1", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert!(response.headers().get_one("X-Stream-Low-Latency").is_none());
+    assert_eq!(response.into_bytes().await.unwrap(), MASTER_LIVE);
+
+    // and VODs have no stream to ask about
+    let response = client.get(format!("{}/vod/1234567890?meta=1", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert!(response.headers().get_one("X-Stream-Low-Latency").is_none());
+}
+
+#[rocket::async_test]
+async fn meta_is_looked_up_while_the_playlist_is_fetched() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("slowmetachannel".to_owned());
+    let delay = Duration::from_millis(600);
+    gql(&var, token(TOKEN_LIVE).set_delay(delay)).mount(&server).await;
+    usher_live("slowmetachannel").respond_with(playlist().set_delay(delay)).mount(&server).await;
+    let metadata = token(STREAM_METADATA).set_delay(delay * 2);
+    gql_stream_metadata("slowmetachannel", metadata).expect(1).mount(&server).await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let started = Instant::now();
+    let response = client.get(format!("{}/live/slowmetachannel?meta=1", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("X-Stream-Low-Latency"), Some("true"));
+    // one after the other would be 2.4 seconds
+    assert!(started.elapsed() < delay * 3, "{:?}", started.elapsed());
+}
+
+#[rocket::async_test]
+async fn a_failed_playlist_does_not_wait_for_meta() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("offlinemetachannel".to_owned());
+    gql(&var, token(TOKEN_LIVE)).mount(&server).await;
+    usher_live("offlinemetachannel").respond_with(ResponseTemplate::new(404)).mount(&server).await;
+    let metadata = token(STREAM_METADATA).set_delay(Duration::from_secs(5));
+    gql_stream_metadata("offlinemetachannel", metadata).mount(&server).await;
+    let client = client(&server, Duration::from_secs(10)).await;
+
+    let started = Instant::now();
+    let uri = format!("{}/live/offlinemetachannel?meta=1", PREFIX);
+    let response = client.get(uri).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+}
+
+#[rocket::async_test]
+async fn concurrent_requests_share_one_fetch_then_hit_the_cache() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("busychannel".to_owned());
+    let slow_token = token(TOKEN_LIVE).set_delay(Duration::from_millis(300));
+    gql(&var, slow_token).expect(1).mount(&server).await;
+    usher_live("busychannel").respond_with(playlist()).expect(1).mount(&server).await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let uri = format!("{}/live/busychannel", PREFIX);
+    let responses = join_all((0..3).map(|_| client.get(uri.clone()).dispatch())).await;
+    let mut statuses: Vec<_> =
+        responses.iter().map(|r| r.headers().get_one("X-Cache").unwrap()).collect();
+    statuses.sort_unstable();
+    assert_eq!(statuses, ["COALESCED", "COALESCED", "MISS"]);
+    for response in responses {
+        assert_eq!(response.into_bytes().await.unwrap(), MASTER_LIVE);
+    }
+
+    let response = client.get(uri).dispatch().await;
+    assert_eq!(response.headers().get_one("X-Cache"), Some("HIT"));
+    assert_eq!(response.headers().get_one("X-City17-Token-Expires"), Some("1627001200"));
+    assert_eq!(response.headers().get_one("X-Stream-Started-At"), Some("1626988480"));
+    assert!(response.headers().get_one("Server-Timing").is_none());
+    assert!(response.headers().get_one("X-City17-Attempts").is_none());
+}
+
+#[rocket::async_test]
+async fn an_abandoned_fetch_still_finishes() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("impatientchannel".to_owned());
+    let slow_token = token(TOKEN_LIVE).set_delay(Duration::from_millis(300));
+    gql(&var, slow_token).expect(1).mount(&server).await;
+    usher_live("impatientchannel").respond_with(playlist()).expect(1).mount(&server).await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let uri = format!("{}/live/impatientchannel", PREFIX);
+    // the viewer gives up before GQL answers
+    let dispatch = client.get(uri.clone()).dispatch();
+    assert!(tokio::time::timeout(Duration::from_millis(50), dispatch).await.is_err());
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // the fetch went on without them, and the next viewer gets what it found
+    let response = client.get(uri).dispatch().await;
+    assert_eq!(response.headers().get_one("X-Cache"), Some("HIT"));
+    assert_eq!(response.into_bytes().await.unwrap(), MASTER_LIVE);
+}
+
+#[rocket::async_test]
+async fn accept_picks_playlist_or_json() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("dashboardchannel".to_owned());
+    gql(&var, token(TOKEN_LIVE)).mount(&server).await;
+    usher_live("dashboardchannel").respond_with(playlist()).mount(&server).await;
+    let client = client(&server, Duration::from_secs(2)).await;
+    let uri = format!("{}/live/dashboardchannel", PREFIX);
+
+    for accept in [None, Some("*/*"), Some("application/vnd.apple.mpegurl")] {
+        let mut request = client.get(uri.clone());
+        if let Some(accept) = accept {
+            request = request.header(Header::new("Accept", accept));
+        }
+        let response = request.dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let content_type = response.content_type().unwrap();
+        assert_eq!(content_type.sub(), "vnd.apple.mpegurl", "{:?}", accept);
+        assert!(response.headers().get("Vary").any(|v| v == "Accept"));
+        assert_eq!(response.into_bytes().await.unwrap(), MASTER_LIVE);
+    }
+
+    let request = client.get(uri.clone()).header(Header::new("Accept", "application/json"));
+    let response = request.dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    assert!(response.headers().get("Vary").any(|v| v == "Accept"));
+    assert_eq!(response.headers().get_one("X-Stream-Started-At"), Some("1626988480"));
+    let body = json_error(response).await;
+    assert_eq!((&body["expires"], &body["started_at"]), (&1627001200.into(), &1626988480.into()));
+    let renditions = body["renditions"].as_array().unwrap();
+    assert_eq!(renditions.len(), 8);
+    assert_eq!(renditions[0]["name"], "1080p60 (source)");
+    assert_eq!(renditions[0]["resolution"], "1920x1080");
+    assert_eq!(renditions[0]["frame_rate"], 60.0);
+    assert_eq!(renditions[7]["audio_only"], true);
+    assert!(renditions[7]["url"].as_str().unwrap().ends_with("audio_only.m3u8"));
+
+    // a player listing JSON as a fallback still gets the playlist
+    let accept = "application/vnd.apple.mpegurl, application/json;q=0.5";
+    let response = client.get(uri).header(Header::new("Accept", accept)).dispatch().await;
+    assert_eq!(response.content_type().unwrap().sub(), "vnd.apple.mpegurl");
+}
+
+#[rocket::async_test]
+async fn include_token_bundles_playlist_and_token() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("bundlechannel".to_owned());
+    gql(&var, token(TOKEN_LIVE)).expect(1).mount(&server).await;
+    usher_live("bundlechannel").respond_with(playlist()).expect(1).mount(&server).await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let uri = format!("{}/live/bundlechannel?include=token", PREFIX);
+    let response = client.get(uri).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    let raw = response.into_string().await.unwrap();
+    // quoted attribute values and line breaks are escaped, not passed through
+    assert!(raw.contains(r#"NAME=\"1080p60 (source)\""#), "{}", raw);
+    assert!(raw.contains(r"\n#EXT-X-STREAM-INF") && !raw.contains('\n'), "{}", raw);
+    let body: Value = serde_json::from_str(&raw).unwrap();
+    assert_eq!(body["m3u8"].as_str().unwrap().as_bytes(), MASTER_LIVE);
+    let token = &body["token"];
+    assert_eq!(token["expires"], 1627001200);
+    assert_eq!(token["request_id"], "01FAKEREQUESTID00000000000");
+    let ads = serde_json::json!({ "show_ads": true, "hide_ads": false, "server_ads": true });
+    assert_eq!(token["ads"], ads);
+    let timings = body["timings"].as_object().unwrap();
+    assert!(timings["gql"].is_f64() && timings["usher"].is_f64(), "{:?}", timings);
+}
+
+#[rocket::async_test]
+async fn include_must_be_token() {
+    let server = MockServer::start().await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let uri = format!("{}/live/somechannel?include=everything", PREFIX);
+    let response = client.get(uri).dispatch().await;
+    assert_eq!(response.status(), Status::BadRequest);
+    assert_eq!(json_error(response).await["message"], "include must be token");
+    assert!(server.received_requests().await.unwrap().is_empty());
+}
+
+#[rocket::async_test]
+async fn max_variants_trims_the_ladder() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("ladderchannel".to_owned());
+    gql(&var, token(TOKEN_LIVE)).mount(&server).await;
+    let master_live = ResponseTemplate::new(200).set_body_raw(MASTER_LIVE, "text/plain");
+    usher_live("ladderchannel").respond_with(master_live).mount(&server).await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let uri = format!("{}/live/ladderchannel?max_variants=3", PREFIX);
+    let response = client.get(uri).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_string().await.unwrap(), limit_renditions(MASTER_LIVE, 3));
+
+    // audio only, under the older name
+    let uri = format!("{}/live/ladderchannel?max_renditions=0", PREFIX);
+    let response = client.get(uri).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_string().await.unwrap(), limit_renditions(MASTER_LIVE, 0));
+}
+
+#[rocket::async_test]
+async fn max_variants_is_bounded() {
+    let server = MockServer::start().await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let uri = format!("{}/live/somechannel?max_variants={}", PREFIX, MAX_VARIANTS + 1);
+    let response = client.get(uri).dispatch().await;
+    assert_eq!(response.status(), Status::BadRequest);
+    let message = format!("max_variants must be from 0 to {}", MAX_VARIANTS);
+    assert_eq!(json_error(response).await["message"], message);
+    assert!(server.received_requests().await.unwrap().is_empty());
+}
+
+#[rocket::async_test]
+async fn player_type_is_asked_for_and_not_cached() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("popoutchannel".to_owned());
+    let mut request = access_token_request(&var, PLAYBACK_ACCESS_TOKEN_HASH);
+    request.variables.player_type = "popout";
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .and(body_json(serde_json::to_value(request).unwrap()))
+        .respond_with(token(TOKEN_LIVE))
+        .expect(2)
+        .mount(&server)
+        .await;
+    usher_live("popoutchannel").respond_with(playlist()).expect(2).mount(&server).await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    for _ in 0..2 {
+        let uri = format!("{}/live/popoutchannel?player_type=popout", PREFIX);
+        let response = client.get(uri).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("X-Cache"), Some("BYPASS"));
+    }
+
+    let response =
+        client.get(format!("{}/vod/1234567890?player_type=mobile", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::BadRequest);
+    assert_eq!(json_error(response).await["message"], "player_type must be site, embed, or popout");
+}
+
+#[rocket::async_test]
+async fn usher_failure_retries_with_the_same_token_as_a_new_session() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("flakychannel".to_owned());
+    gql(&var, token(TOKEN_LIVE)).expect(1).mount(&server).await;
+    usher_live("flakychannel")
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .expect(1)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    usher_live("flakychannel").respond_with(playlist()).expect(1).mount(&server).await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let response = client.get(format!("{}/live/flakychannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("X-City17-Attempts"), Some("gql=1, usher=2"));
+
+    let requests = server.received_requests().await.unwrap();
+    let sessions: Vec<_> = requests
+        .iter()
+        .filter(|r| r.url.path().ends_with(".m3u8"))
+        .map(|r| r.url.query_pairs().find(|(k, _)| k == "play_session_id").unwrap().1.into_owned())
+        .collect();
+    assert_eq!(sessions.len(), 2);
+    assert_ne!(sessions[0], sessions[1]);
+}
+
+/// A client whose fetches get `budget` in all, and each request `timeout`.
+async fn budgeted(server: &MockServer, timeout: Duration, budget: Duration) -> Client {
+    common::client(Upstream { budget, ..upstream(server, timeout) }).await
+}
+
+#[rocket::async_test]
+async fn usher_retries_stop_at_the_deadline() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("slowflakychannel".to_owned());
+    gql(&var, token(TOKEN_LIVE)).mount(&server).await;
+    // a third try would start at 600ms with 400ms left, less than its 500ms timeout
+    let slow_503 = ResponseTemplate::new(503).set_delay(Duration::from_millis(300));
+    usher_live("slowflakychannel").respond_with(slow_503).expect(2).mount(&server).await;
+    let client = budgeted(&server, Duration::from_millis(500), Duration::from_millis(1000)).await;
+
+    let response = client.get(format!("{}/live/slowflakychannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::BadGateway);
+    assert_eq!(response.headers().get_one("X-City17-Attempts"), Some("gql=1, usher=2"));
+    assert_eq!(json_error(response).await["stage"], "M3U");
+}
+
+#[rocket::async_test]
+async fn slow_gql_leaves_usher_less_time() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("slowgqlchannel".to_owned());
+    let slow_token = token(TOKEN_LIVE).set_delay(Duration::from_millis(400));
+    gql(&var, slow_token).mount(&server).await;
+    // after GQL's 400ms and usher's 300ms, a retry would only have 300ms
+    let slow_503 = ResponseTemplate::new(503).set_delay(Duration::from_millis(300));
+    usher_live("slowgqlchannel").respond_with(slow_503).expect(1).mount(&server).await;
+    let client = budgeted(&server, Duration::from_millis(500), Duration::from_millis(1000)).await;
+
+    let started = std::time::Instant::now();
+    let response = client.get(format!("{}/live/slowgqlchannel", PREFIX)).dispatch().await;
+    assert!(started.elapsed() < Duration::from_millis(1000), "{:?}", started.elapsed());
+    assert_eq!(response.status(), Status::BadGateway);
+    assert_eq!(response.headers().get_one("X-City17-Attempts"), Some("gql=1, usher=1"));
+}
+
+#[rocket::async_test]
+async fn usher_403_gets_a_fresh_token() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("forbiddenchannel".to_owned());
+    gql(&var, token(TOKEN_LIVE)).expect(2).mount(&server).await;
+    usher_live("forbiddenchannel")
+        .respond_with(ResponseTemplate::new(403))
+        .expect(2)
+        .mount(&server)
+        .await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let response = client.get(format!("{}/live/forbiddenchannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Forbidden);
+    assert_eq!(response.headers().get_one("X-City17-Attempts"), Some("gql=2, usher=2"));
+    assert_eq!(json_error(response).await["stage"], "M3U");
+}
+
+/// Upstream where usher's requests go to `server` as usher's front.
+fn fronted(server: &MockServer) -> Upstream {
+    let port = server.address().port();
+    Upstream {
+        usher_base: format!("http://usher.ttvnw.net:{}/", port),
+        usher_front: Some("127.0.0.1".to_owned()),
+        ..upstream(server, Duration::from_secs(2))
+    }
+}
+
+#[rocket::async_test]
+async fn usher_goes_through_a_checked_front() {
+    let server = MockServer::start().await;
+    let missing_token = r#"[{"error":"Missing token","error_code":"missing_token"}]"#;
+    Mock::given(method("GET"))
+        .and(path("/api/channel/hls/city17.m3u8"))
+        .and(header("Host", "usher.ttvnw.net"))
+        .respond_with(ResponseTemplate::new(403).set_body_raw(missing_token, "application/json"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let var = Variables::Channel("frontedchannel".to_owned());
+    gql(&var, token(TOKEN_LIVE)).expect(1).mount(&server).await;
+    usher_live("frontedchannel")
+        .and(header("Host", "usher.ttvnw.net"))
+        .respond_with(playlist())
+        .expect(1)
+        .mount(&server)
+        .await;
+    let client = common::client(fronted(&server)).await;
+    let upstream = client.rocket().state::<Upstream>().unwrap();
+    assert_eq!(upstream.usher_front.as_deref(), Some("127.0.0.1"));
+
+    let response = client.get(format!("{}/live/frontedchannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn a_front_that_is_not_usher_is_not_preferred() {
+    let server = MockServer::start().await;
+    let front_page = "<!DOCTYPE html><html><body>Welcome</body></html>";
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(front_page, "text/html"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let client = common::client(fronted(&server)).await;
+    let upstream = client.rocket().state::<Upstream>().unwrap();
+    assert_eq!(upstream.usher_front.as_deref(), Some(DEFAULT_USHER_FRONT));
+}
+
+#[rocket::async_test]
+async fn gql_timeout() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("slowchannel".to_owned());
+    let never_on_time = token(TOKEN_LIVE).set_delay(Duration::from_secs(2));
+    gql(&var, never_on_time).expect(u64::from(1 + GQL_RETRIES)).mount(&server).await;
+    usher_live("slowchannel").respond_with(playlist()).expect(0).mount(&server).await;
+    let client = client(&server, Duration::from_millis(200)).await;
+
+    let response = client.get(format!("{}/live/slowchannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::GatewayTimeout);
+    assert_eq!(response.headers().get_one("X-City17-Attempts"), Some("gql=1"));
+    assert_eq!(json_error(response).await["stage"], "GQL");
+}
+
+#[rocket::async_test]
+async fn dropped_token_requests_are_sent_again() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("droppedchannel".to_owned());
+    let dropped = token(TOKEN_LIVE).set_delay(Duration::from_secs(2));
+    gql(&var, dropped).up_to_n_times(1).expect(1).mount(&server).await;
+    gql(&var, token(TOKEN_LIVE)).expect(1).mount(&server).await;
+    usher_live("droppedchannel").respond_with(playlist()).expect(1).mount(&server).await;
+    let client = client(&server, Duration::from_millis(300)).await;
+
+    let response = client.get(format!("{}/live/droppedchannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), MASTER_LIVE);
+}
+
+/// Upstream with GQL's front at `front` and GQL itself at `direct`.
+fn with_direct_gql(front: &MockServer, direct: &MockServer, always: bool) -> Upstream {
+    let direct_gql = DirectGql { url: format!("{}/gql", direct.uri()), always };
+    Upstream { direct_gql: Some(direct_gql), ..upstream(front, Duration::from_millis(300)) }
+}
+
+#[test]
+fn direct_gql_settings() {
+    let always = parse_direct_gql(" Always ").unwrap().unwrap();
+    assert!(always.always && always.url == DIRECT_GQL_URL);
+    assert!(!parse_direct_gql("fallback").unwrap().unwrap().always);
+    assert!(parse_direct_gql("never").unwrap().is_none());
+    assert!(!Upstream::default().direct_gql.unwrap().always);
+    for bad in ["", "sometimes", "1"] {
+        assert!(parse_direct_gql(bad).is_err(), "{}", bad);
+    }
+}
+
+#[rocket::async_test]
+async fn token_requests_the_front_dropped_go_direct() {
+    let (front, direct) = (MockServer::start().await, MockServer::start().await);
+    let var = Variables::Channel("frontdownchannel".to_owned());
+    let dropped = token(TOKEN_LIVE).set_delay(Duration::from_secs(2));
+    gql(&var, dropped).expect(1).mount(&front).await;
+    gql(&var, token(TOKEN_LIVE)).expect(1).mount(&direct).await;
+    usher_live("frontdownchannel").respond_with(playlist()).expect(1).mount(&front).await;
+    let client = common::client(with_direct_gql(&front, &direct, false)).await;
+
+    let response = client.get(format!("{}/live/frontdownchannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), MASTER_LIVE);
+}
+
+#[rocket::async_test]
+async fn front_answers_are_not_sent_direct() {
+    let (front, direct) = (MockServer::start().await, MockServer::start().await);
+    let var = Variables::Channel("frontupchannel".to_owned());
+    gql(&var, ResponseTemplate::new(400)).expect(1).mount(&front).await;
+    gql(&var, token(TOKEN_LIVE)).expect(0).mount(&direct).await;
+    let client = common::client(with_direct_gql(&front, &direct, false)).await;
+
+    let response = client.get(format!("{}/live/frontupchannel", PREFIX)).dispatch().await;
+    assert_ne!(response.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn always_direct_skips_the_front() {
+    let (front, direct) = (MockServer::start().await, MockServer::start().await);
+    let var = Variables::Channel("directchannel".to_owned());
+    gql(&var, token(TOKEN_LIVE)).expect(0).mount(&front).await;
+    gql(&var, token(TOKEN_LIVE)).expect(1).mount(&direct).await;
+    usher_live("directchannel").respond_with(playlist()).expect(1).mount(&front).await;
+    let client = common::client(with_direct_gql(&front, &direct, true)).await;
+
+    let response = client.get(format!("{}/live/directchannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn null_token() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("offlinechannel".to_owned());
+    let null = br#"{"data":{"streamPlaybackAccessToken":null},"extensions":{"durationMilliseconds":3,"operationName":"PlaybackAccessToken","requestID":"01FAKEREQUESTID00000000000"}}"#;
+    gql(&var, token(null)).expect(1).mount(&server).await;
+    usher_live("offlinechannel").respond_with(playlist()).expect(0).mount(&server).await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let response = client.get(format!("{}/live/offlinechannel", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    let body = json_error(response).await;
+    assert_eq!((&body["stage"], &body["reason"]), (&"GQL".into(), &"token_null".into()));
+}
+
+#[rocket::async_test]
+async fn a_failed_fetch_is_shared_then_forgotten() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("gonechannel".to_owned());
+    gql(&var, token(TOKEN_LIVE)).expect(2).mount(&server).await;
+    let gone = ResponseTemplate::new(404).set_delay(Duration::from_millis(300));
+    usher_live("gonechannel").respond_with(gone).expect(2).mount(&server).await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let uri = format!("{}/live/gonechannel", PREFIX);
+    let responses = join_all((0..5).map(|_| client.get(uri.clone()).dispatch())).await;
+    assert!(responses.iter().all(|r| r.status() == Status::NotFound));
+    // with usher's prewarm off by default, that's all that went upstream
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 2, "one token and one playlist request");
+    // failures aren't cached, and the finished fetch is out of the way
+    let response = client.get(uri).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[cfg(any(feature = "azure", feature = "standalone"))]
+#[rocket::async_test]
+async fn large_playlists_are_gzipped_for_clients_that_accept_it() {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use rocket::http::Header;
+
+    let server = MockServer::start().await;
+    let var = Variables::Channel("gzipchannel".to_owned());
+    gql(&var, token(TOKEN_LIVE)).mount(&server).await;
+    usher_live("gzipchannel").respond_with(playlist()).mount(&server).await;
+    let client = client(&server, Duration::from_secs(2)).await;
+    let uri = format!("{}/live/gzipchannel", PREFIX);
+
+    let response = client.get(&uri).header(Header::new("Accept-Encoding", "gzip")).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("Content-Encoding"), Some("gzip"));
+    assert_eq!(response.headers().get_one("Vary"), Some("Accept-Encoding"));
+    let encoded = response.into_bytes().await.unwrap();
+    assert!(encoded.len() < MASTER_LIVE.len());
+    let mut decoded = Vec::new();
+    GzDecoder::new(&encoded[..]).read_to_end(&mut decoded).unwrap();
+    assert_eq!(decoded, MASTER_LIVE);
+
+    let response = client.get(&uri).dispatch().await;
+    assert_eq!(response.headers().get_one("Content-Encoding"), None);
+    assert_eq!(response.headers().get_one("Vary"), Some("Accept-Encoding"));
+    assert_eq!(response.into_bytes().await.unwrap(), MASTER_LIVE);
+}
+
+#[cfg(any(feature = "azure", feature = "standalone"))]
+#[rocket::async_test]
+async fn small_playlists_go_out_uncompressed() {
+    let server = MockServer::start().await;
+    let var = Variables::Channel("smallchannel".to_owned());
+    gql(&var, token(TOKEN_LIVE)).mount(&server).await;
+    let small = "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1\nhttps://example.com/a.m3u8\n";
+    let small_playlist = ResponseTemplate::new(200).set_body_string(small);
+    usher_live("smallchannel").respond_with(small_playlist).mount(&server).await;
+    let client = client(&server, Duration::from_secs(2)).await;
+
+    let gzip = rocket::http::Header::new("Accept-Encoding", "gzip");
+    let response =
+        client.get(format!("{}/live/smallchannel", PREFIX)).header(gzip).dispatch().await;
+    assert_eq!(response.headers().get_one("Content-Encoding"), None);
+    assert_eq!(response.into_string().await.unwrap(), small);
+}
+
+#[rocket::async_test]
+async fn ready_when_both_fronts_answer() {
+    // any status will do
+    let server = MockServer::start().await;
+    let client = client(&server, Duration::from_secs(2)).await;
+    let response = client.get(format!("{}/ready", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let body: Value = serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(
+        (&body["ready"], &body["gql"]["ok"], &body["usher"]["ok"]),
+        (&true.into(), &true.into(), &true.into())
+    );
+    assert!(body["time"].as_f64().unwrap() < 2.0, "{}", body);
+    assert_eq!(body["maintenance"], Value::Null);
+    assert_eq!(server.received_requests().await.unwrap().len(), 2);
+
+    // nothing listens on port 1
+    let mut unreachable = upstream(&server, Duration::from_secs(2));
+    unreachable.usher_base = "http://127.0.0.1:1/".to_owned();
+    let client = common::client(unreachable).await;
+    let response = client.get(format!("{}/ready", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::ServiceUnavailable);
+    let body: Value = serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!((&body["ready"], &body["gql"]["ok"]), (&false.into(), &true.into()));
+    assert_eq!(body["usher"]["kind"], "connect");
+}
@@ -0,0 +1,94 @@
+//! Playlists as they come back from usher.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use city17::config::{
+    parse_front_host, parse_front_ips, parse_usher_base, parse_usher_front, Upstream,
+    DEFAULT_GQL_FRONT, DEFAULT_USHER_BASE, DEFAULT_USHER_FRONT,
+};
+use city17::gql::Variables;
+use city17::usher::{FetchInfo, Playlist};
+use futures_util::stream::{self, StreamExt};
+
+const MASTER_LIVE: &[u8] = include_bytes!("fixtures/master_live.m3u8");
+
+#[rocket::async_test]
+async fn streamed_playlist_collects_in_order() {
+    let chunks: Vec<_> = MASTER_LIVE.chunks(100).map(|c| Ok(Bytes::copy_from_slice(c))).collect();
+    let head = chunks[0].as_ref().unwrap().clone();
+    let rest = stream::iter(chunks.into_iter().skip(1)).boxed();
+    let body = Playlist::Streaming { head, rest }.collect().await.unwrap();
+    assert_eq!(body, MASTER_LIVE);
+}
+
+#[rocket::async_test]
+async fn full_playlist_collects_as_is() {
+    let body = Playlist::from(Bytes::from_static(MASTER_LIVE)).collect().await.unwrap();
+    assert_eq!(body, MASTER_LIVE);
+}
+
+#[test]
+fn server_timing() {
+    let timings = vec![("gql", Duration::from_micros(52_340)), ("usher", Duration::from_millis(7))];
+    let info = FetchInfo { timings, ..FetchInfo::default() };
+    assert_eq!(info.server_timing(), "gql;dur=52.3, usher;dur=7.0");
+}
+
+#[test]
+fn attempts() {
+    let info = FetchInfo { attempts: vec![("gql", 2), ("usher", 2)], ..FetchInfo::default() };
+    assert_eq!(info.attempts(), "gql=2, usher=2");
+}
+
+#[test]
+fn default_urls_are_unchanged() {
+    let base = Upstream::default().usher_base;
+    let live = Variables::Channel("examplechannel".to_owned()).get_url(&base);
+    assert_eq!(live, "https://usher.ttvnw.net/api/channel/hls/examplechannel.m3u8");
+    let vod = Variables::VOD("1234567890".to_owned()).get_url(&base);
+    assert_eq!(vod, "https://usher.ttvnw.net/vod/1234567890.m3u8");
+}
+
+#[test]
+fn usher_base() {
+    assert_eq!(parse_usher_base(DEFAULT_USHER_BASE).unwrap(), DEFAULT_USHER_BASE);
+    assert_eq!(
+        parse_usher_base("https://usher.example.com").unwrap(),
+        "https://usher.example.com/"
+    );
+    assert_eq!(
+        parse_usher_base(" https://example.com/usher ").unwrap(),
+        "https://example.com/usher/"
+    );
+    assert!(parse_usher_base("http://usher.ttvnw.net/").is_err());
+    assert!(parse_usher_base("usher.ttvnw.net").is_err());
+    assert!(parse_usher_base("").is_err());
+}
+
+#[test]
+fn usher_front() {
+    let default = Some(DEFAULT_USHER_FRONT.to_owned());
+    assert_eq!(parse_usher_front(DEFAULT_USHER_FRONT).unwrap(), default);
+    assert_eq!(parse_usher_front(" Front.Example.com ").unwrap().unwrap(), "front.example.com");
+    assert_eq!(parse_usher_front("off").unwrap(), None);
+    for bad in ["", "https://www.fastly.com", "www.fastly.com/", ".example.com", "a b"] {
+        assert!(parse_usher_front(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn gql_front() {
+    assert_eq!(parse_front_host(DEFAULT_GQL_FRONT).unwrap(), DEFAULT_GQL_FRONT);
+    assert_eq!(parse_front_host(" Twitch.Map.Fastly.net ").unwrap(), "twitch.map.fastly.net");
+    for bad in ["", "off.example.com/", "https://fastly.net", "fastly.net:443", "-fastly.net"] {
+        assert!(parse_front_host(bad).is_err(), "{}", bad);
+    }
+    let ips = parse_front_ips("151.101.110.167, 2a04:4e42::1").unwrap();
+    assert_eq!(
+        ips,
+        ["151.101.110.167".parse::<std::net::IpAddr>().unwrap(), "2a04:4e42::1".parse().unwrap()]
+    );
+    let error = parse_front_ips("151.101.110.167,fastly.net").unwrap_err();
+    assert_eq!(error, "has \"fastly.net\", which isn't an IP address");
+}
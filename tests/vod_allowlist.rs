@@ -0,0 +1,52 @@
+//! `CITY17_VOD_ALLOWLIST`, which is read once, so it gets a test binary of its own.
+
+#![cfg(feature = "server")]
+
+mod common;
+
+use std::env;
+use std::time::Duration;
+
+use city17::config::{split_list, Upstream};
+use rocket::http::Status;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use common::PREFIX;
+
+const TOKEN_VOD: &[u8] = include_bytes!("fixtures/token_vod.json");
+const MASTER_LIVE: &[u8] = include_bytes!("fixtures/master_live.m3u8");
+
+#[test]
+fn lists() {
+    assert_eq!(split_list(" 123, v456 ,,789,").collect::<Vec<_>>(), ["123", "v456", "789"]);
+    assert_eq!(split_list("").count(), 0);
+}
+
+#[rocket::async_test]
+async fn only_listed_vods_go_upstream() {
+    env::set_var("CITY17_VOD_ALLOWLIST", "1234567890, v42, not-an-id");
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/gql"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(TOKEN_VOD, "application/json"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/vod/1234567890.m3u8"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(MASTER_LIVE, "text/plain"))
+        .mount(&server)
+        .await;
+    let upstream = Upstream { timeout: Duration::from_secs(2), ..common::upstream(&server) };
+    let client = common::client(upstream).await;
+
+    let response = client.get(format!("{}/vod/987654321", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Forbidden);
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(body["stage"], "allowlist");
+
+    let response = client.get(format!("{}/vod/1234567890", PREFIX)).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+}
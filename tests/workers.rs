@@ -0,0 +1,14 @@
+//! Worker count picked when `CITY17_WORKERS` isn't set.
+
+use city17::config::workers_for_cpus;
+
+#[test]
+fn clamped_to_two_through_eight() {
+    let picked: Vec<usize> = (1..=12).map(workers_for_cpus).collect();
+    assert_eq!(picked, [2, 2, 3, 4, 5, 6, 7, 8, 8, 8, 8, 8]);
+}
+
+#[test]
+fn zero_cpus_still_gets_workers() {
+    assert_eq!(workers_for_cpus(0), 2);
+}